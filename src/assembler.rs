@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+
+use crate::{
+    cpu::CPU,
+    emulator::Emulator,
+    mmu::{MMU, RAM_ADDRESS},
+    ops_decode::{
+        encode_b_type, encode_i_type, encode_j_type, encode_r_type, encode_s_type, encode_u_type,
+    },
+};
+
+pub const ZERO: u8 = 0;
+pub const RA: u8 = 1;
+pub const SP: u8 = 2;
+pub const GP: u8 = 3;
+pub const TP: u8 = 4;
+pub const T0: u8 = 5;
+pub const T1: u8 = 6;
+pub const T2: u8 = 7;
+pub const S0: u8 = 8;
+pub const S1: u8 = 9;
+pub const A0: u8 = 10;
+pub const A1: u8 = 11;
+pub const A2: u8 = 12;
+pub const A3: u8 = 13;
+pub const A4: u8 = 14;
+pub const A5: u8 = 15;
+pub const A6: u8 = 16;
+pub const A7: u8 = 17;
+pub const S2: u8 = 18;
+pub const S3: u8 = 19;
+pub const S4: u8 = 20;
+pub const S5: u8 = 21;
+pub const S6: u8 = 22;
+pub const S7: u8 = 23;
+pub const S8: u8 = 24;
+pub const S9: u8 = 25;
+pub const S10: u8 = 26;
+pub const S11: u8 = 27;
+pub const T3: u8 = 28;
+pub const T4: u8 = 29;
+pub const T5: u8 = 30;
+pub const T6: u8 = 31;
+
+const OP_LUI: u8 = 0b0110111;
+const OP_AUIPC: u8 = 0b0010111;
+const OP_JAL: u8 = 0b1101111;
+const OP_JALR: u8 = 0b1100111;
+const OP_BRANCH: u8 = 0b1100011;
+const OP_LOAD: u8 = 0b0000011;
+const OP_STORE: u8 = 0b0100011;
+const OP_IMM: u8 = 0b0010011;
+const OP: u8 = 0b0110011;
+const OP_MISC_MEM: u8 = 0b0001111;
+const OP_SYSTEM: u8 = 0b1110011;
+const OP_AMO: u8 = 0b0101111;
+
+enum Fixup {
+    Branch,
+    Jal,
+    // auipc + addi pair
+    PcRel,
+}
+
+macro_rules! r_type {
+    ($($name:ident: $funct3:expr, $funct7:expr;)*) => {
+        $(pub fn $name(&mut self, rd: u8, rs1: u8, rs2: u8) -> &mut Self {
+            self.instr(encode_r_type(OP, rd, $funct3, rs1, rs2, $funct7))
+        })*
+    };
+}
+macro_rules! i_type {
+    ($($name:ident: $funct3:expr;)*) => {
+        $(pub fn $name(&mut self, rd: u8, rs1: u8, imm: i16) -> &mut Self {
+            self.instr(encode_i_type(OP_IMM, rd, $funct3, rs1, imm))
+        })*
+    };
+}
+macro_rules! shift_imm {
+    ($($name:ident: $funct3:expr, $funct7:expr;)*) => {
+        $(pub fn $name(&mut self, rd: u8, rs1: u8, shamt: u8) -> &mut Self {
+            self.instr(encode_r_type(OP_IMM, rd, $funct3, rs1, shamt & 0x1F, $funct7))
+        })*
+    };
+}
+macro_rules! load {
+    ($($name:ident: $funct3:expr;)*) => {
+        $(pub fn $name(&mut self, rd: u8, offset: i16, rs1: u8) -> &mut Self {
+            self.instr(encode_i_type(OP_LOAD, rd, $funct3, rs1, offset))
+        })*
+    };
+}
+macro_rules! store {
+    ($($name:ident: $funct3:expr;)*) => {
+        $(pub fn $name(&mut self, rs2: u8, offset: i16, rs1: u8) -> &mut Self {
+            self.instr(encode_s_type(OP_STORE, $funct3, rs1, rs2, offset))
+        })*
+    };
+}
+macro_rules! branch {
+    ($($name:ident: $funct3:expr;)*) => {
+        $(pub fn $name(&mut self, rs1: u8, rs2: u8, label: &str) -> &mut Self {
+            self.fixup(label, Fixup::Branch);
+            self.instr(encode_b_type(OP_BRANCH, $funct3, rs1, rs2, 0))
+        })*
+    };
+}
+macro_rules! csr {
+    ($($name:ident, $name_imm:ident: $funct3:expr;)*) => {
+        $(pub fn $name(&mut self, rd: u8, csr: u16, rs1: u8) -> &mut Self {
+            self.instr(encode_i_type(OP_SYSTEM, rd, $funct3, rs1, csr as i16))
+        }
+        pub fn $name_imm(&mut self, rd: u8, csr: u16, uimm: u8) -> &mut Self {
+            self.instr(encode_i_type(OP_SYSTEM, rd, $funct3 | 0b100, uimm & 0x1F, csr as i16))
+        })*
+    };
+}
+macro_rules! amo {
+    ($($name:ident: $funct5:expr;)*) => {
+        $(pub fn $name(&mut self, rd: u8, rs2: u8, rs1: u8) -> &mut Self {
+            self.instr(encode_r_type(OP_AMO, rd, 0b010, rs1, rs2, $funct5 << 2))
+        })*
+    };
+}
+
+// Builds RV32IMA machine code in tests, so they don't need cross toolchain.
+// Arguments go in the same order as in assembly, `lw a0, 4(a1)` is `lw(A0, 4, A1)`.
+#[derive(Default)]
+pub struct Assembler {
+    code: Vec<u32>,
+    labels: HashMap<String, usize>,
+    fixups: Vec<(usize, String, Fixup)>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    // Current offset from start of program in bytes
+    pub fn offset(&self) -> u32 {
+        self.code.len() as u32 * 4
+    }
+    pub fn instr(&mut self, instr: u32) -> &mut Self {
+        self.code.push(instr);
+        self
+    }
+    pub fn label(&mut self, name: &str) -> &mut Self {
+        let old = self.labels.insert(name.to_string(), self.code.len());
+        assert!(old.is_none(), "Label {name} defined twice");
+        self
+    }
+    fn fixup(&mut self, label: &str, kind: Fixup) {
+        self.fixups.push((self.code.len(), label.to_string(), kind));
+    }
+    // Resolves labels and returns instruction words.
+    pub fn assemble(&self) -> Vec<u32> {
+        let mut code = self.code.clone();
+        for (index, label, kind) in &self.fixups {
+            let target = *self.labels.get(label).unwrap_or_else(|| panic!("Unknown label {label}"));
+            let offset = (target as i32 - *index as i32) * 4;
+            let instr = code[*index];
+            match kind {
+                Fixup::Branch => {
+                    assert!((-4096..4096).contains(&offset), "Branch to {label} is out of range");
+                    code[*index] = instr | encode_b_type(0, 0, 0, 0, offset as i16);
+                }
+                Fixup::Jal => {
+                    assert!((-(1 << 20)..1 << 20).contains(&offset), "Jump to {label} is out of range");
+                    code[*index] = instr | encode_j_type(0, 0, offset);
+                }
+                Fixup::PcRel => {
+                    let upper = offset.wrapping_add(0x800);
+                    code[*index] = instr | encode_u_type(0, 0, upper);
+                    code[index + 1] |= encode_i_type(0, 0, 0, 0, (offset & 0xFFF) as i16);
+                }
+            }
+        }
+        code
+    }
+    pub fn assemble_bytes(&self) -> Vec<u8> {
+        self.assemble().into_iter().flat_map(u32::to_le_bytes).collect()
+    }
+    // Writes program to RAM starting at `address`.
+    pub fn load(&self, mmu: &mut MMU, address: u32) {
+        for (i, byte) in self.assemble_bytes().into_iter().enumerate() {
+            assert!(mmu.write_raw_to_ram(address + i as u32, byte));
+        }
+    }
+    // Hart with program loaded at start of RAM and pc pointing to it, fixture of tests
+    pub fn cpu(&self) -> CPU {
+        let (mut mmu, _audio) = MMU::new();
+        self.load(&mut mmu, RAM_ADDRESS);
+        let mut cpu = CPU::new(mmu);
+        cpu.pc = RAM_ADDRESS;
+        cpu
+    }
+    pub fn emulator(&self) -> Emulator {
+        Emulator::new(self.cpu())
+    }
+
+    pub fn lui(&mut self, rd: u8, imm20: u32) -> &mut Self {
+        self.instr(encode_u_type(OP_LUI, rd, (imm20 << 12) as i32))
+    }
+    pub fn auipc(&mut self, rd: u8, imm20: u32) -> &mut Self {
+        self.instr(encode_u_type(OP_AUIPC, rd, (imm20 << 12) as i32))
+    }
+    pub fn jal(&mut self, rd: u8, label: &str) -> &mut Self {
+        self.fixup(label, Fixup::Jal);
+        self.instr(encode_j_type(OP_JAL, rd, 0))
+    }
+    pub fn jalr(&mut self, rd: u8, rs1: u8, offset: i16) -> &mut Self {
+        self.instr(encode_i_type(OP_JALR, rd, 0, rs1, offset))
+    }
+    branch! {
+        beq: 0b000;
+        bne: 0b001;
+        blt: 0b100;
+        bge: 0b101;
+        bltu: 0b110;
+        bgeu: 0b111;
+    }
+    load! {
+        lb: 0b000;
+        lh: 0b001;
+        lw: 0b010;
+        lbu: 0b100;
+        lhu: 0b101;
+    }
+    store! {
+        sb: 0b000;
+        sh: 0b001;
+        sw: 0b010;
+    }
+    i_type! {
+        addi: 0b000;
+        slti: 0b010;
+        sltiu: 0b011;
+        xori: 0b100;
+        ori: 0b110;
+        andi: 0b111;
+    }
+    shift_imm! {
+        slli: 0b001, 0b0000000;
+        srli: 0b101, 0b0000000;
+        srai: 0b101, 0b0100000;
+    }
+    r_type! {
+        add: 0b000, 0b0000000;
+        sub: 0b000, 0b0100000;
+        sll: 0b001, 0b0000000;
+        slt: 0b010, 0b0000000;
+        sltu: 0b011, 0b0000000;
+        xor: 0b100, 0b0000000;
+        srl: 0b101, 0b0000000;
+        sra: 0b101, 0b0100000;
+        or: 0b110, 0b0000000;
+        and: 0b111, 0b0000000;
+        mul: 0b000, 0b0000001;
+        mulh: 0b001, 0b0000001;
+        mulhsu: 0b010, 0b0000001;
+        mulhu: 0b011, 0b0000001;
+        div: 0b100, 0b0000001;
+        divu: 0b101, 0b0000001;
+        rem: 0b110, 0b0000001;
+        remu: 0b111, 0b0000001;
+    }
+    pub fn fence(&mut self) -> &mut Self {
+        self.instr(0x0ff0000f)
+    }
+    pub fn fence_i(&mut self) -> &mut Self {
+        self.instr(encode_i_type(OP_MISC_MEM, 0, 0b001, 0, 0))
+    }
+    pub fn ecall(&mut self) -> &mut Self {
+        self.instr(encode_i_type(OP_SYSTEM, 0, 0, 0, 0))
+    }
+    pub fn ebreak(&mut self) -> &mut Self {
+        self.instr(encode_i_type(OP_SYSTEM, 0, 0, 0, 1))
+    }
+    pub fn sret(&mut self) -> &mut Self {
+        self.instr(encode_r_type(OP_SYSTEM, 0, 0, 0, 0b00010, 0b0001000))
+    }
+    pub fn mret(&mut self) -> &mut Self {
+        self.instr(encode_r_type(OP_SYSTEM, 0, 0, 0, 0b00010, 0b0011000))
+    }
+    pub fn wfi(&mut self) -> &mut Self {
+        self.instr(encode_r_type(OP_SYSTEM, 0, 0, 0, 0b00101, 0b0001000))
+    }
+    csr! {
+        csrrw, csrrwi: 0b001;
+        csrrs, csrrsi: 0b010;
+        csrrc, csrrci: 0b011;
+    }
+    pub fn lr_w(&mut self, rd: u8, rs1: u8) -> &mut Self {
+        self.instr(encode_r_type(OP_AMO, rd, 0b010, rs1, 0, 0b00010 << 2))
+    }
+    amo! {
+        sc_w: 0b00011;
+        amoswap_w: 0b00001;
+        amoadd_w: 0b00000;
+        amoxor_w: 0b00100;
+        amoand_w: 0b01100;
+        amoor_w: 0b01000;
+        amomin_w: 0b10000;
+        amomax_w: 0b10100;
+        amominu_w: 0b11000;
+        amomaxu_w: 0b11100;
+    }
+
+    // Pseudo-instructions
+    pub fn nop(&mut self) -> &mut Self {
+        self.addi(ZERO, ZERO, 0)
+    }
+    pub fn li(&mut self, rd: u8, value: u32) -> &mut Self {
+        let value = value as i32;
+        if (-2048..2048).contains(&value) {
+            return self.addi(rd, ZERO, value as i16);
+        }
+        let upper = value.wrapping_add(0x800) as u32 >> 12;
+        let lower = (value << 20 >> 20) as i16;
+        self.lui(rd, upper);
+        if lower != 0 {
+            self.addi(rd, rd, lower);
+        }
+        self
+    }
+    pub fn la(&mut self, rd: u8, label: &str) -> &mut Self {
+        self.fixup(label, Fixup::PcRel);
+        self.auipc(rd, 0).addi(rd, rd, 0)
+    }
+    pub fn mv(&mut self, rd: u8, rs1: u8) -> &mut Self {
+        self.addi(rd, rs1, 0)
+    }
+    pub fn not(&mut self, rd: u8, rs1: u8) -> &mut Self {
+        self.xori(rd, rs1, -1)
+    }
+    pub fn neg(&mut self, rd: u8, rs2: u8) -> &mut Self {
+        self.sub(rd, ZERO, rs2)
+    }
+    pub fn j(&mut self, label: &str) -> &mut Self {
+        self.jal(ZERO, label)
+    }
+    pub fn call(&mut self, label: &str) -> &mut Self {
+        self.jal(RA, label)
+    }
+    pub fn ret(&mut self) -> &mut Self {
+        self.jalr(ZERO, RA, 0)
+    }
+    pub fn csrr(&mut self, rd: u8, csr: u16) -> &mut Self {
+        self.csrrs(rd, csr, ZERO)
+    }
+    pub fn csrw(&mut self, csr: u16, rs1: u8) -> &mut Self {
+        self.csrrw(ZERO, csr, rs1)
+    }
+    // Trap handler of tests at `label`: counts traps in s11, keeps mcause in s10 and returns
+    // past trapping instruction. Clobbers t6.
+    pub fn skip_faults_handler(&mut self, label: &str) -> &mut Self {
+        self.label(label).addi(S11, S11, 1).csrr(S10, 0x342);
+        self.csrr(T6, 0x341).addi(T6, T6, 4).csrw(0x341, T6).mret()
+    }
+    // Opens all memory to S and U mode with PMP entry 0 like firmware does, clobbers t0
+    pub fn pmp_allow_all(&mut self) -> &mut Self {
+        self.li(T0, u32::MAX).csrw(0x3B0, T0).li(T0, 0x1F).csrw(0x3A0, T0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodings() {
+        // Reference encodings from llvm-mc
+        let mut asm = Assembler::new();
+        asm.sw(A0, -4, SP)
+            .sb(T1, 2047, A1)
+            .lr_w(A0, A1)
+            .sc_w(A2, A3, A1)
+            .amoadd_w(A0, A2, A1)
+            .csrrsi(A0, 0x300, 8)
+            .slli(A0, A1, 31)
+            .srai(A0, A1, 3)
+            .mulhsu(A0, A1, A2)
+            .remu(T0, T1, T2)
+            .fence()
+            .fence_i()
+            .mret()
+            .wfi()
+            .ebreak();
+        assert_eq!(
+            asm.assemble(),
+            [
+                0xfea12e23, 0x7e658fa3, 0x1005a52f, 0x18d5a62f, 0x00c5a52f, 0x30046573,
+                0x01f59513, 0x4035d513, 0x02c5a533, 0x027372b3, 0x0ff0000f, 0x0000100f,
+                0x30200073, 0x10500073, 0x00100073,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut asm = Assembler::new();
+        asm.label("start")
+            .beq(A0, A1, "forward")
+            .nop()
+            .label("forward")
+            .j("start")
+            .la(A0, "start");
+        assert_eq!(asm.assemble(), [0x00b50463, 0x00000013, 0xff9ff06f, 0x00000517, 0xff450513]);
+    }
+
+    #[test]
+    fn test_li() {
+        let mut asm = Assembler::new();
+        asm.li(A0, 5).li(A1, 0x80001000).li(A2, 0x12345fff).li(A3, -2048i32 as u32);
+        // li a2, 0x12345fff is lui a2, 0x12346; addi a2, a2, -1
+        assert_eq!(
+            asm.assemble(),
+            [0x00500513, 0x800015b7, 0x12346637, 0xfff60613, 0x80000693]
+        );
+    }
+}
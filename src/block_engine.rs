@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+
+use crate::{
+    cpu::CPU,
+    decode_cache::DecodedInstruction,
+    errors::EmulatorError,
+    mmu::PAGE_SIZE,
+    pmp::Access,
+};
+
+pub const MAX_BLOCK_LENGTH: usize = 64;
+
+// Straight-line instructions executed with one dispatch.
+pub struct BasicBlock {
+    pub start: u32,
+    pub instructions: Vec<DecodedInstruction>,
+    // Recently taken successors as (pc, block index), so chained blocks skip lookup
+    links: [Option<(u32, usize)>; 2],
+}
+
+#[derive(Default)]
+pub struct BlockCache {
+    blocks: Vec<BasicBlock>,
+    lookup: HashMap<u32, usize>,
+    // Decode cache generation that blocks were built from
+    generation: u64,
+}
+
+// Only these can't change control flow, privilege or interrupt state
+fn continues_block(instr: u32) -> bool {
+    matches!(
+        instr & 0x7F,
+        0b0110111 | 0b0010111 | 0b0000011 | 0b0100011 | 0b0010011 | 0b0110011
+    )
+}
+
+impl BlockCache {
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.lookup.clear();
+    }
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+    fn find_or_translate(&mut self, cpu: &mut CPU, pc: u32) -> Result<usize, crate::traps::Trap> {
+        if let Some(index) = self.lookup.get(&pc) {
+            return Ok(*index);
+        }
+        let mut instructions = vec![cpu.decode_at(pc)?];
+        let mut next = pc.wrapping_add(instructions[0].len as u32);
+        while continues_block(instructions.last().unwrap().instr)
+            && instructions.len() < MAX_BLOCK_LENGTH
+            && !next.is_multiple_of(PAGE_SIZE)
+        {
+            // Fetch faults and compressed instructions are left to the next block
+            match cpu.mmu.fetch_word(next) {
+                Ok(instr) if instr & 0b11 == 0b11 => {}
+                _ => break,
+            }
+            let Ok(decoded) = cpu.decode_at(next) else {
+                break;
+            };
+            instructions.push(decoded);
+            next = next.wrapping_add(decoded.len as u32);
+        }
+        self.blocks.push(BasicBlock {
+            start: pc,
+            instructions,
+            links: [None, None],
+        });
+        let index = self.blocks.len() - 1;
+        self.lookup.insert(pc, index);
+        Ok(index)
+    }
+    // Block that starts at pc after `from` block, remembered in its links
+    fn successor(&mut self, cpu: &mut CPU, from: usize, pc: u32) -> Result<usize, crate::traps::Trap> {
+        for (target, index) in self.blocks[from].links.iter().flatten() {
+            if *target == pc {
+                return Ok(*index);
+            }
+        }
+        let index = self.find_or_translate(cpu, pc)?;
+        let links = &mut self.blocks[from].links;
+        links[1] = links[0];
+        links[0] = Some((pc, index));
+        Ok(index)
+    }
+}
+
+// Executes `budget` instructions block by block, adding them to `executed`.
+// Traps stay precise, because pc is updated after every instruction and block is left on trap.
+pub fn run_blocks(
+    cpu: &mut CPU,
+    cache: &mut BlockCache,
+    budget: u64,
+    executed: &mut u64,
+) -> Result<(), EmulatorError> {
+    let end = *executed + budget;
+    let mut previous = None;
+    while *executed < end && !cpu.is_idle() {
+        if cpu.invalidate_written_code() || cache.generation != cpu.decode_cache.generation() {
+            cache.clear();
+            cache.generation = cpu.decode_cache.generation();
+            previous = None;
+        }
+        if cpu.check_interrupts()? {
+            previous = None;
+        }
+        let pc = cpu.pc;
+        let block = match previous {
+            Some(from) => cache.successor(cpu, from, pc),
+            None => cache.find_or_translate(cpu, pc),
+        };
+        let index = match block {
+            Ok(index) => index,
+            Err(trap) => {
+                cpu.process_trap(trap)?;
+                *executed += 1;
+                previous = None;
+                continue;
+            }
+        };
+        previous = Some(index);
+        let remaining = (end - *executed) as usize;
+        for decoded in cache.blocks[index].instructions.iter().take(remaining) {
+            // Block can cross PMP region, so fetch is checked per instruction
+            let res = cpu
+                .check_pmp(cpu.pc, decoded.len as u32, Access::Execute)
+                .and_then(|_| (decoded.handler)(cpu, decoded));
+            if let Err(trap) = res {
+                cpu.process_trap(trap)?;
+                *executed += 1;
+                previous = None;
+                break;
+            }
+            cpu.pc = cpu.pc.wrapping_add(decoded.len as u32);
+            cpu.instret += 1;
+            *executed += 1;
+            // Store could have changed rest of this block or finished simulation
+            if !cpu.mmu.invalidated_code_pages.is_empty() || cpu.stopped {
+                previous = None;
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        clint::CLINT_ADDRESS,
+        cpu::{ExecutionMode, CPU, EXECUTION_MODES, MSTATUS_MIE, MSTATUS_MPIE},
+        errors::EmulatorError,
+        mmu::RAM_ADDRESS,
+        ops_decode::encode_i_type,
+    };
+
+    // Runs program until trap handler is unset, returns cpu and executed instructions
+    fn run(asm: &Assembler, mode: ExecutionMode) -> (CPU, u64) {
+        let mut cpu = asm.cpu();
+        cpu.execution_mode = mode;
+        let mut executed = 0;
+        match cpu.run(100000, &mut executed) {
+            Err(EmulatorError::UnsetTrapHandler) => (cpu, executed),
+            r => panic!("Program didn't finish: {r:?}"),
+        }
+    }
+
+    // Runs program in every execution mode, returns cpu of block mode
+    fn run_all(asm: &Assembler) -> CPU {
+        let (interpreted, interpreted_count) = run(asm, ExecutionMode::Interpreter);
+        let mut result = None;
+        for &mode in &EXECUTION_MODES[1..] {
+            let (cpu, count) = run(asm, mode);
+            assert_eq!(cpu.get_registers(), interpreted.get_registers(), "{mode:?}");
+            assert_eq!(cpu.pc, interpreted.pc, "{mode:?}");
+            assert_eq!(count, interpreted_count, "{mode:?}");
+            if mode == ExecutionMode::BasicBlock {
+                result = Some(cpu);
+            }
+        }
+        result.unwrap()
+    }
+
+    #[test]
+    fn test_loop() {
+        let mut asm = Assembler::new();
+        asm.li(A0, 0).li(A1, 100).li(A2, 0x80001000);
+        asm.label("loop")
+            .add(A0, A0, A1)
+            .sw(A0, 0, A2)
+            .lw(A3, 0, A2)
+            .addi(A1, A1, -1)
+            .bne(A1, ZERO, "loop")
+            .ecall();
+        let cpu = run_all(&asm);
+        assert_eq!(cpu.get_registers()[A0 as usize], 5050);
+        assert!(cpu.blocks.len() >= 2);
+    }
+
+    #[test]
+    fn test_store_into_block() {
+        let mut asm = Assembler::new();
+        asm.la(A1, "target").li(A2, encode_i_type(0b0010011, A0, 0, A0, 100));
+        // Instruction after the store is in the same block
+        asm.sw(A2, 4, A1);
+        asm.label("target").addi(A0, A0, 1).addi(A0, A0, 1).ecall();
+        let cpu = run_all(&asm);
+        assert_eq!(cpu.get_registers()[A0 as usize], 101);
+    }
+
+    #[test]
+    fn test_precise_trap() {
+        let mut asm = Assembler::new();
+        asm.la(T0, "handler").csrw(0x305, T0);
+        asm.li(A0, 1).li(A1, 0x1000).lw(A2, 0, A1).li(A0, 2).ecall();
+        asm.label("handler").csrr(A3, 0x341).csrw(0x305, ZERO).ecall();
+        let cpu = run_all(&asm);
+        // Instructions after faulting load didn't execute
+        assert_eq!(cpu.get_registers()[A0 as usize], 1);
+        assert_eq!(cpu.get_registers()[A3 as usize], RAM_ADDRESS + 5 * 4);
+        assert_eq!(cpu.mcause, 5);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut asm = Assembler::new();
+        asm.la(T0, "handler").csrw(0x305, T0);
+        // Enable and raise machine software interrupt
+        asm.li(T0, 1).li(T1, CLINT_ADDRESS).sw(T0, 0, T1);
+        asm.li(T0, 8).csrrs(ZERO, 0x304, T0).csrrs(ZERO, 0x300, T0);
+        asm.label("spin").addi(A0, A0, 1).j("spin");
+        asm.label("handler")
+            .csrr(A1, 0x342)
+            .csrr(A2, 0x300)
+            .csrw(0x305, ZERO)
+            .ecall();
+        for &mode in EXECUTION_MODES {
+            let (cpu, _) = run(&asm, mode);
+            let regs = cpu.get_registers();
+            assert_eq!(regs[A0 as usize], 0, "{mode:?}");
+            assert_eq!(regs[A1 as usize], 0x80000003, "{mode:?}");
+            assert_eq!(regs[A2 as usize] as u64 & (MSTATUS_MIE | MSTATUS_MPIE), MSTATUS_MPIE);
+        }
+    }
+
+    #[test]
+    fn test_mret_restores_mie() {
+        let mut asm = Assembler::new();
+        asm.la(T0, "handler").csrw(0x305, T0).li(T0, 8).csrrs(ZERO, 0x300, T0);
+        asm.ecall().csrr(A1, 0x300).csrw(0x305, ZERO).ecall();
+        asm.label("handler")
+            .csrr(A0, 0x300)
+            .csrr(T1, 0x341)
+            .addi(T1, T1, 4)
+            .csrw(0x341, T1)
+            .mret();
+        let cpu = run_all(&asm);
+        let regs = cpu.get_registers();
+        // Inside of handler MIE is cleared and MPP is machine mode
+        assert_eq!(regs[A0 as usize] as u64 & (MSTATUS_MIE | MSTATUS_MPIE), MSTATUS_MPIE);
+        assert_eq!(regs[A0 as usize] >> 11 & 0b11, 3);
+        assert_eq!(regs[A1 as usize] as u64 & MSTATUS_MIE, MSTATUS_MIE);
+    }
+}
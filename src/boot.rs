@@ -0,0 +1,228 @@
+// Linux boot flow: kernel, optional initrd and device tree are placed in RAM and harts start in
+// S mode with built-in SBI, a0 holding hartid and a1 device tree address. Bootargs and initrd
+// range are written to /chosen of generated or given device tree.
+// Kernels that enable paging need Sv32 translation, which isn't implemented yet. NOMMU kernels
+// (CONFIG_RISCV_M_MODE, like the one mini-rv32ima boots) start at beginning of RAM in M mode.
+
+use crate::{
+    elf_analyzer::ElfError,
+    emulator::Emulator,
+    fdt::{self, FdtNode},
+    mmu::{MMU, PAGE_SIZE, RAM_ADDRESS, RAM_ADDRESS_END, RAM_SIZE},
+};
+
+// Raw Image is loaded here, like OpenSBI fw_jump does on rv32 where kernel is 4MB aligned
+pub const KERNEL_OFFSET: u32 = 0x40_0000;
+// Initrd goes this far after kernel start, or half of RAM if it is smaller
+pub const INITRD_OFFSET: u32 = 128 * 1024 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BootError {
+    // Given device tree blob can't be parsed
+    InvalidDtb,
+    // Images don't fit into RAM together
+    TooLarge,
+    // Kernel starts like ELF, but can't be loaded as one
+    InvalidElf(ElfError),
+}
+
+#[derive(Default)]
+pub struct LinuxBoot {
+    // Raw Image or ELF linked to physical addresses in RAM
+    pub kernel: Vec<u8>,
+    pub initrd: Option<Vec<u8>>,
+    // Kernel command line
+    pub bootargs: Option<String>,
+    // Used instead of generated device tree
+    pub dtb: Option<Vec<u8>>,
+    pub harts: usize,
+    // Kernel is built for M mode without MMU
+    pub nommu: bool,
+}
+
+impl LinuxBoot {
+    pub fn new(kernel: Vec<u8>) -> Self {
+        LinuxBoot {
+            kernel,
+            harts: 1,
+            ..Default::default()
+        }
+    }
+    pub fn build(self, mut mmu: MMU) -> Result<Emulator, BootError> {
+        // End of memory kernel takes, nothing else may be placed below it
+        let (emu, entry, kernel_end) = if self.kernel.starts_with(b"\x7fELF") {
+            let (emu, info) = Emulator::from_elf_info(self.kernel, mmu).map_err(BootError::InvalidElf)?;
+            (emu, info.entry, info.image_end.max(info.entry as u64))
+        } else {
+            let entry = RAM_ADDRESS + if self.nommu { 0 } else { KERNEL_OFFSET };
+            if self.kernel.len() > (RAM_ADDRESS_END - entry + 1) as usize {
+                return Err(BootError::TooLarge);
+            }
+            load(&mut mmu, entry, &self.kernel);
+            let mut cpu = crate::cpu::CPU::new(mmu);
+            cpu.pc = entry;
+            (Emulator::new(cpu), entry, entry as u64 + self.kernel.len() as u64)
+        };
+        let mut emu = emu.with_harts(self.harts);
+        if !self.nommu {
+            emu = emu.with_sbi();
+        }
+
+        let mut tree = match &self.dtb {
+            Some(dtb) => FdtNode::from_blob(dtb).ok_or(BootError::InvalidDtb)?,
+            None => fdt::generate(&emu),
+        };
+        let chosen = tree.child_mut("chosen");
+        if let Some(bootargs) = &self.bootargs {
+            chosen.set_property("bootargs", bootargs.bytes().chain([0]).collect());
+        }
+        let initrd_start = entry.saturating_add(INITRD_OFFSET.min(RAM_SIZE as u32 / 2)) & !(PAGE_SIZE - 1);
+        let initrd_end = initrd_start as u64 + self.initrd.as_ref().map_or(0, |i| i.len() as u64);
+        if self.initrd.is_some() && kernel_end > initrd_start as u64 {
+            return Err(BootError::TooLarge);
+        }
+        if self.initrd.is_some() {
+            chosen.set_property("linux,initrd-start", initrd_start.to_be_bytes().to_vec());
+            chosen.set_property("linux,initrd-end", (initrd_end as u32).to_be_bytes().to_vec());
+        }
+        let blob = tree.to_blob(0);
+        // Device tree takes end of RAM
+        let fdt_start = fdt::default_address(blob.len()) as u64;
+        if blob.len() > RAM_SIZE / 2 || initrd_end > fdt_start || kernel_end > fdt_start {
+            return Err(BootError::TooLarge);
+        }
+        if let Some(initrd) = &self.initrd {
+            load(&mut emu.harts[0].mmu, initrd_start, initrd);
+        }
+        emu.load_fdt(&blob);
+        Ok(emu)
+    }
+}
+
+fn load(mmu: &mut MMU, address: u32, bytes: &[u8]) {
+    for (i, byte) in bytes.iter().enumerate() {
+        mmu.write_raw_to_ram(address + i as u32, *byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        cpu::PrivilegeMode,
+        emulator::StopReason,
+        fdt::{FdtNode, FDT_MAGIC},
+        mmu::{MMU, RAM_ADDRESS, RAM_SIZE},
+        sbi::{EXT_LEGACY_CONSOLE_PUTCHAR, EXT_SRST},
+    };
+
+    use super::{BootError, LinuxBoot, KERNEL_OFFSET};
+
+    // Prints "K" through SBI and shuts down with code 5
+    fn kernel() -> Vec<u8> {
+        let mut asm = Assembler::new();
+        asm.mv(S0, A0).mv(S1, A1).lw(S2, 0, A1);
+        asm.li(A0, b'K' as u32).li(A7, EXT_LEGACY_CONSOLE_PUTCHAR).ecall();
+        asm.li(A0, 0).li(A1, 5).li(A7, EXT_SRST).li(A6, 0).ecall();
+        asm.assemble_bytes()
+    }
+
+    #[test]
+    fn test_boot() {
+        let mut boot = LinuxBoot::new(kernel());
+        boot.initrd = Some(vec![0xAB; 5000]);
+        boot.bootargs = Some("console=hvc0 earlycon=sbi".to_string());
+        boot.harts = 2;
+        let (mmu, _audio) = MMU::new();
+        let mut emu = boot.build(mmu).unwrap();
+        assert_eq!(emu.harts[0].pc, RAM_ADDRESS + KERNEL_OFFSET);
+        assert_eq!(emu.harts[0].privilege, PrivilegeMode::Supervisor);
+        assert_eq!(emu.run(1000), StopReason::PowerOff(5));
+        let regs = emu.harts[0].get_registers();
+        assert_eq!(regs[S0 as usize], 0);
+        assert_eq!(regs[S2 as usize], FDT_MAGIC.swap_bytes());
+        assert_eq!(emu.harts[0].mmu.uart.try_get_byte(), Some(b'K'));
+
+        let mmu = &emu.harts[0].mmu;
+        let blob: Vec<u8> = (0..4096).map(|i| mmu.read_raw_from_ram(regs[S1 as usize] + i).unwrap_or(0)).collect();
+        let tree = FdtNode::from_blob(&blob).unwrap();
+        assert_eq!(tree.child("cpus").unwrap().children.len(), 2);
+        let chosen = tree.child("chosen").unwrap();
+        assert_eq!(chosen.property("bootargs").unwrap(), b"console=hvc0 earlycon=sbi\0");
+        let cell = |name| u32::from_be_bytes(chosen.property(name).unwrap().try_into().unwrap());
+        let (start, end) = (cell("linux,initrd-start"), cell("linux,initrd-end"));
+        assert_eq!(end - start, 5000);
+        assert_eq!(mmu.read_raw_from_ram(start), Some(0xAB));
+        assert_eq!(mmu.read_raw_from_ram(end - 1), Some(0xAB));
+        assert_eq!(mmu.read_raw_from_ram(end), Some(0));
+    }
+
+    #[test]
+    fn test_nommu() {
+        let mut asm = Assembler::new();
+        asm.mv(S1, A1).li(A0, 0).csrrw(A0, 0x305, A0).ecall();
+        let mut boot = LinuxBoot::new(asm.assemble_bytes());
+        boot.nommu = true;
+        let mut emu = boot.build(MMU::new().0).unwrap();
+        assert_eq!(emu.harts[0].pc, RAM_ADDRESS);
+        assert_eq!(emu.harts[0].privilege, PrivilegeMode::Machine);
+        assert!(emu.harts[0].mmu.sbi.is_none());
+        emu.run(1000);
+        let address = emu.harts[0].get_registers()[S1 as usize];
+        assert_eq!(emu.harts[0].mmu.read_raw_from_ram(address), Some(0xd0));
+    }
+
+    #[test]
+    fn test_given_dtb() {
+        let mut boot = LinuxBoot::new(kernel());
+        boot.dtb = Some(FdtNode::new("").with_string("model", "custom").to_blob(0));
+        boot.bootargs = Some("quiet".to_string());
+        let (mmu, _audio) = MMU::new();
+        let mut emu = boot.build(mmu).unwrap();
+        assert_eq!(emu.run(1000), StopReason::PowerOff(5));
+        let address = emu.harts[0].get_registers()[S1 as usize];
+        let blob: Vec<u8> = (0..256).map(|i| emu.harts[0].mmu.read_raw_from_ram(address + i).unwrap_or(0)).collect();
+        let tree = FdtNode::from_blob(&blob).unwrap();
+        assert_eq!(tree.property("model").unwrap(), b"custom\0");
+        assert_eq!(tree.child("chosen").unwrap().property("bootargs").unwrap(), b"quiet\0");
+
+        let mut boot = LinuxBoot::new(kernel());
+        boot.dtb = Some(vec![1, 2, 3]);
+        assert_eq!(boot.build(MMU::new().0).err(), Some(BootError::InvalidDtb));
+        let mut boot = LinuxBoot::new(kernel());
+        boot.initrd = Some(vec![0; 40 * 1024 * 1024]);
+        assert_eq!(boot.build(MMU::new().0).err(), Some(BootError::TooLarge));
+    }
+
+    // Kernel linked at entry with code section and `bss` bytes of NOBITS section after it
+    fn elf_kernel(bss: u32) -> Vec<u8> {
+        let code = kernel();
+        let entry = RAM_ADDRESS + KERNEL_OFFSET;
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1];
+        elf.resize(24, 0);
+        elf.extend([entry, 0, 52, 0].iter().flat_map(|v| v.to_le_bytes()));
+        elf.extend([52u16, 0, 0, 40, 2, 0].iter().flat_map(|v| v.to_le_bytes()));
+        let code_end = entry + code.len() as u32;
+        for header in [[0, 1, 6, entry, 132, code.len() as u32], [0, 8, 3, code_end, 0, bss]] {
+            elf.extend(header.iter().chain(&[0; 4]).flat_map(|v| v.to_le_bytes()));
+        }
+        elf.extend(code);
+        elf
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let mut emu = LinuxBoot::new(elf_kernel(4096)).build(MMU::new().0).unwrap();
+        assert_eq!(emu.run(1000), StopReason::PowerOff(5));
+        // Bss runs into initrd or device tree, while file itself is small
+        let mut boot = LinuxBoot::new(elf_kernel(40 * 1024 * 1024));
+        boot.initrd = Some(vec![0; 4096]);
+        assert_eq!(boot.build(MMU::new().0).err(), Some(BootError::TooLarge));
+        let boot = LinuxBoot::new(elf_kernel(RAM_SIZE as u32 - KERNEL_OFFSET - 4096));
+        assert_eq!(boot.build(MMU::new().0).err(), Some(BootError::TooLarge));
+        // Raw image that fits into RAM but not below device tree
+        let mut boot = LinuxBoot::new(vec![0; RAM_SIZE - 100]);
+        boot.nommu = true;
+        assert_eq!(boot.build(MMU::new().0).err(), Some(BootError::TooLarge));
+    }
+}
@@ -0,0 +1,252 @@
+// Command line of emulator. Process exits with exit code of guest when it powers off (test
+// finisher, HTIF, SBI, semihosting or syscall exit), failures of emulator itself get their own
+// codes so CI can tell them apart.
+
+use std::{path::PathBuf, time::Duration};
+
+// Bad command line
+pub const EXIT_USAGE: i32 = 120;
+// Program, kernel or other given file can't be loaded
+pub const EXIT_LOAD: i32 = 121;
+// Emulator error, like trap without trap handler
+pub const EXIT_FAULT: i32 = 122;
+// Every hart waits for interrupt which can't come
+pub const EXIT_DEADLOCK: i32 = 123;
+// --timeout elapsed, same code as coreutils timeout uses
+pub const EXIT_TIMEOUT: i32 = 124;
+// --max-instructions were executed
+pub const EXIT_INSTRUCTION_LIMIT: i32 = 125;
+
+pub const USAGE: &str = "\
+Usage: rv-emu-rs [options] <program.elf>
+       rv-emu-rs [options] --kernel <Image or ELF> [--initrd <file>] [--append <bootargs>] [--dtb <file>] [--nommu]
+       rv-emu-rs [options] --user <static Linux ELF> [arguments]
+       rv-emu-rs --compliance <directory with tests> [directory for signatures]
+
+Options:
+  --max-instructions <n>  stop after n instructions of all harts, or of each --compliance test
+  --timeout <seconds>     stop after this much wall clock time, not with --compliance
+  --no-audio              don't open audio output
+  --wav <file>            write audio of guest to WAV file instead of playing it
+  --uart <target>         UART output goes to stdout (default), stderr, none or file
+  --newlib                serve newlib syscalls of bare-metal program
+  --semihosting           serve RISC-V semihosting calls of bare-metal program
+  --root <directory>      files of --newlib and --semihosting are opened inside of it
+  --cosim <log>           check execution against reference commit log
+  --step                  execute instruction per key press, showing registers
+
+Exit code is exit code of guest, or one of emulator's own:
+  120 bad command line, 121 loading failed, 122 emulator fault, 123 harts wait forever,
+  124 timeout, 125 instruction limit";
+
+// Files and settings for Linux boot, see boot.rs
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct BootFiles {
+    pub kernel: PathBuf,
+    pub initrd: Option<PathBuf>,
+    pub append: Option<String>,
+    pub dtb: Option<PathBuf>,
+    pub nommu: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Mode {
+    Help,
+    // Firmware or bare-metal program
+    Elf(PathBuf),
+    Kernel(BootFiles),
+    // Program followed by its arguments
+    User(Vec<String>),
+    Compliance { tests: PathBuf, signatures: Option<PathBuf> },
+}
+
+// Syscall layer for bare-metal programs
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Runtime {
+    None,
+    Newlib,
+    Semihosting,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UartOutput {
+    Stdout,
+    Stderr,
+    Discard,
+    File(PathBuf),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub mode: Mode,
+    pub max_instructions: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub audio: bool,
+    pub wav: Option<PathBuf>,
+    pub uart: UartOutput,
+    pub runtime: Runtime,
+    pub root: Option<PathBuf>,
+    pub cosim: Option<PathBuf>,
+    pub step: bool,
+}
+
+// Error is message for user, usage is printed after it
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        mode: Mode::Help,
+        max_instructions: None,
+        timeout: None,
+        audio: true,
+        wav: None,
+        uart: UartOutput::Stdout,
+        runtime: Runtime::None,
+        root: None,
+        cosim: None,
+        step: false,
+    };
+    let mut program = None;
+    let mut boot: Option<BootFiles> = None;
+    let mut mode = None;
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("Expected value after {arg}"));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(options),
+            "--max-instructions" => {
+                let value = value()?;
+                let count = value.parse().map_err(|_| format!("Invalid instruction count \"{value}\""))?;
+                options.max_instructions = Some(count);
+            }
+            "--timeout" => {
+                let value = value()?;
+                let seconds = value.parse::<f64>().ok().and_then(|s| Duration::try_from_secs_f64(s).ok());
+                options.timeout = Some(seconds.ok_or_else(|| format!("Invalid timeout \"{value}\""))?);
+            }
+            "--no-audio" => options.audio = false,
+            "--wav" => options.wav = Some(value()?.into()),
+            "--uart" => {
+                options.uart = match value()?.as_str() {
+                    "stdout" => UartOutput::Stdout,
+                    "stderr" => UartOutput::Stderr,
+                    "none" => UartOutput::Discard,
+                    path => UartOutput::File(path.into()),
+                }
+            }
+            "--newlib" | "--semihosting" => {
+                if options.runtime != Runtime::None {
+                    return Err("Only one of --newlib and --semihosting can be used".to_string());
+                }
+                options.runtime = if arg == "--newlib" { Runtime::Newlib } else { Runtime::Semihosting };
+            }
+            "--root" => options.root = Some(value()?.into()),
+            "--cosim" => options.cosim = Some(value()?.into()),
+            "--step" => options.step = true,
+            "--kernel" => boot.get_or_insert_with(Default::default).kernel = value()?.into(),
+            "--initrd" => boot.get_or_insert_with(Default::default).initrd = Some(value()?.into()),
+            "--append" => boot.get_or_insert_with(Default::default).append = Some(value()?),
+            "--dtb" => boot.get_or_insert_with(Default::default).dtb = Some(value()?.into()),
+            "--nommu" => boot.get_or_insert_with(Default::default).nommu = true,
+            // Everything after program belongs to it
+            "--user" => {
+                let argv: Vec<String> = args.by_ref().collect();
+                if argv.is_empty() {
+                    return Err("Expected program after --user".to_string());
+                }
+                mode = Some(Mode::User(argv));
+            }
+            "--compliance" => {
+                let tests = value()?.into();
+                let signatures = args.next_if(|a| !a.starts_with('-')).map(PathBuf::from);
+                mode = Some(Mode::Compliance { tests, signatures });
+            }
+            option if option.starts_with('-') => return Err(format!("Unknown option {option}")),
+            path => {
+                if program.is_some() {
+                    return Err(format!("Unexpected argument \"{path}\""));
+                }
+                program = Some(PathBuf::from(path));
+            }
+        }
+    }
+
+    options.mode = match (mode, boot, program) {
+        (Some(mode), None, None) => mode,
+        (None, Some(boot), None) if boot.kernel.as_os_str().is_empty() => {
+            return Err("Expected --kernel".to_string());
+        }
+        (None, Some(boot), None) => Mode::Kernel(boot),
+        (None, None, Some(path)) => Mode::Elf(path),
+        (None, None, None) => return Err("Expected program".to_string()),
+        _ => return Err("Only one program can be run".to_string()),
+    };
+    let elf = matches!(options.mode, Mode::Elf(_));
+    if !elf && (options.runtime != Runtime::None || options.cosim.is_some() || options.step) {
+        return Err("--newlib, --semihosting, --cosim and --step need ELF program".to_string());
+    }
+    // Compliance tests run on virtual time, so only instruction limit makes sense for them
+    if matches!(options.mode, Mode::Compliance { .. }) && options.timeout.is_some() {
+        return Err("--timeout can't be used with --compliance".to_string());
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::{parse, BootFiles, Mode, Runtime, UartOutput};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let options = parse(args("--no-audio prog.elf --max-instructions 1000 --timeout 2.5 --uart none --wav out.wav")).unwrap();
+        assert_eq!(options.mode, Mode::Elf("prog.elf".into()));
+        assert_eq!(options.max_instructions, Some(1000));
+        assert_eq!(options.timeout, Some(Duration::from_millis(2500)));
+        assert!(!options.audio);
+        assert_eq!(options.wav, Some(PathBuf::from("out.wav")));
+        assert_eq!(options.uart, UartOutput::Discard);
+
+        let options = parse(args("--semihosting --root files prog.elf --uart out.txt")).unwrap();
+        assert_eq!(options.runtime, Runtime::Semihosting);
+        assert_eq!(options.root, Some(PathBuf::from("files")));
+        assert_eq!(options.uart, UartOutput::File("out.txt".into()));
+        assert!(options.audio);
+
+        let options = parse(args("--kernel Image --append console=hvc0 --nommu")).unwrap();
+        let boot = BootFiles { kernel: "Image".into(), append: Some("console=hvc0".into()), nommu: true, ..Default::default() };
+        assert_eq!(options.mode, Mode::Kernel(boot));
+
+        // Options of program aren't parsed
+        let options = parse(args("--timeout 1 --user ls --timeout -l")).unwrap();
+        assert_eq!(options.mode, Mode::User(args("ls --timeout -l")));
+        assert_eq!(parse(args("--compliance tests sigs")).unwrap().mode,
+            Mode::Compliance { tests: "tests".into(), signatures: Some("sigs".into()) });
+        assert_eq!(parse(args("--compliance tests --no-audio")).unwrap().mode,
+            Mode::Compliance { tests: "tests".into(), signatures: None });
+        assert_eq!(parse(args("prog.elf --help")).unwrap().mode, Mode::Help);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for line in [
+            "",
+            "prog.elf other.elf",
+            "prog.elf --kernel Image",
+            "--initrd rootfs.cpio",
+            "--kernel Image --newlib",
+            "prog.elf --newlib --semihosting",
+            "prog.elf --max-instructions lots",
+            "prog.elf --timeout -1",
+            "prog.elf --timeout",
+            "prog.elf --fast",
+            "--user",
+            "--compliance tests --timeout 5",
+        ] {
+            assert!(parse(args(line)).is_err(), "{line}");
+        }
+    }
+}
@@ -0,0 +1,312 @@
+// Core-local interruptor with SiFive layout: msip of hart N at 4 * N, mtimecmp at
+// 0x4000 + 8 * N and shared mtime. Timer interrupt is raised when mtime >= mtimecmp,
+// mtime is the same value as `time` CSR. State lives on bus, so every hart can access it.
+
+use std::time::Duration;
+
+use crate::{
+    clock::{Clock, TIMEBASE_FREQUENCY},
+    cpu::CPU,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+};
+
+pub const CLINT_ADDRESS: u32 = 0x0200_0000;
+pub const CLINT_SIZE: u32 = 0x10000;
+pub const CLINT_ADDRESS_END: u32 = CLINT_ADDRESS + CLINT_SIZE - 1;
+pub const MSIP_OFFSET: u32 = 0;
+pub const MTIMECMP_OFFSET: u32 = 0x4000;
+pub const MTIME_OFFSET: u32 = 0xBFF8;
+
+pub const MIP_MSIP: u32 = 1 << 3;
+pub const MIP_MTIP: u32 = 1 << 7;
+// Timer interrupt is forwarded here when SBI firmware is built in
+pub const MIP_STIP: u32 = 1 << 5;
+
+// Wall clock doesn't follow instret, so deadline is polled every this many instructions
+pub const TIMER_POLL_INTERVAL: u64 = 1024;
+// Longest host sleep in one WFI wait, so host stop is still noticed
+pub const MAX_IDLE_SLEEP: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+pub struct Clint {
+    // Source of mtime, shared by all harts
+    pub clock: Clock,
+    // Added to clock, set by mtime writes and skipped idle time
+    pub time_offset: u64,
+    // Indexed by hartid
+    pub msip: Vec<bool>,
+    pub mtimecmp: Vec<u64>,
+    // Retired instructions of all harts, hart keeps it current before accessing bus, so
+    // mtime matches its `time` CSR
+    pub instret: u64,
+    // Pending bits or deadline were written, hart that wrote them has to check them
+    pub written: bool,
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl Clint {
+    pub fn new(harts: usize) -> Self {
+        Clint {
+            clock: Clock::default(),
+            time_offset: 0,
+            msip: vec![false; harts],
+            mtimecmp: vec![u64::MAX; harts],
+            instret: 0,
+            written: false,
+        }
+    }
+    pub fn time(&self) -> u64 {
+        self.clock.time(self.instret).wrapping_add(self.time_offset)
+    }
+    pub fn set_harts(&mut self, harts: usize) {
+        self.msip.resize(harts, false);
+        self.mtimecmp.resize(harts, u64::MAX);
+    }
+    pub fn save(&self, w: &mut SnapshotWriter) {
+        self.clock.save(w);
+        w.u64(self.time_offset);
+        w.u32(self.msip.len() as u32);
+        for (msip, mtimecmp) in self.msip.iter().zip(&self.mtimecmp) {
+            w.bool(*msip);
+            w.u64(*mtimecmp);
+        }
+    }
+    pub fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.clock = Clock::restore(r)?;
+        self.time_offset = r.u64()?;
+        let harts = r.u32()? as usize;
+        self.msip.clear();
+        self.mtimecmp.clear();
+        for _ in 0..harts {
+            self.msip.push(r.bool()?);
+            self.mtimecmp.push(r.u64()?);
+        }
+        Ok(())
+    }
+}
+
+fn half(value: u64, offset: u32) -> u32 {
+    if offset & 0b100 == 0 { value as u32 } else { (value >> 32) as u32 }
+}
+
+fn with_half(old: u64, offset: u32, value: u32) -> u64 {
+    if offset & 0b100 == 0 {
+        old & !(u32::MAX as u64) | value as u64
+    } else {
+        old & u32::MAX as u64 | (value as u64) << 32
+    }
+}
+
+enum Register {
+    Msip(usize),
+    Mtimecmp(usize),
+    Mtime,
+}
+
+fn register(clint: &Clint, offset: u32) -> Option<Register> {
+    let harts = clint.msip.len() as u32;
+    if offset.wrapping_sub(MTIME_OFFSET) < 8 {
+        Some(Register::Mtime)
+    } else if offset >= MTIMECMP_OFFSET && (offset - MTIMECMP_OFFSET) / 8 < harts {
+        Some(Register::Mtimecmp(((offset - MTIMECMP_OFFSET) / 8) as usize))
+    } else if offset / 4 < harts {
+        Some(Register::Msip((offset / 4) as usize))
+    } else {
+        None
+    }
+}
+
+// Word access only, MMU maps None to access fault
+pub fn read(clint: &Clint, address: u32) -> Option<u32> {
+    let offset = address - CLINT_ADDRESS;
+    Some(match register(clint, offset)? {
+        Register::Msip(hart) => clint.msip[hart] as u32,
+        Register::Mtimecmp(hart) => half(clint.mtimecmp[hart], offset),
+        Register::Mtime => half(clint.time(), offset),
+    })
+}
+
+pub fn write(clint: &mut Clint, address: u32, value: u32) -> Option<()> {
+    let offset = address - CLINT_ADDRESS;
+    match register(clint, offset)? {
+        Register::Msip(hart) => clint.msip[hart] = value & 1 != 0,
+        Register::Mtimecmp(hart) => clint.mtimecmp[hart] = with_half(clint.mtimecmp[hart], offset, value),
+        Register::Mtime => {
+            let time = with_half(clint.time(), offset, value);
+            clint.time_offset = time.wrapping_sub(clint.clock.time(clint.instret));
+        }
+    }
+    // Other harts check bus when they are scheduled
+    clint.written = true;
+    Some(())
+}
+
+// Sets MSIP and MTIP (STIP with SBI) of hart from bus and plans next check
+pub fn update(cpu: &mut CPU) {
+    let hart = cpu.hartid as usize;
+    let now = cpu.time();
+    let timer = if cpu.mmu.sbi.is_some() { MIP_STIP } else { MIP_MTIP };
+    let clint = &cpu.mmu.clint;
+    let mtimecmp = clint.mtimecmp[hart];
+    let mut mip = cpu.mip & !(MIP_MSIP | timer);
+    if clint.msip[hart] {
+        mip |= MIP_MSIP;
+    }
+    if now >= mtimecmp {
+        mip |= timer;
+        // Stays pending until mtimecmp or mtime is written
+        cpu.next_timer_check = u64::MAX;
+    } else {
+        let instret = cpu.instret + cpu.other_instret;
+        let deadline = clint.clock.time(instret).saturating_add(mtimecmp - now);
+        cpu.next_timer_check = match clint.clock.instret_at(deadline) {
+            Some(at) => at.saturating_sub(cpu.other_instret),
+            None => cpu.instret + TIMER_POLL_INTERVAL,
+        };
+    }
+    cpu.mip = mip;
+}
+
+// Earliest deadline of harts that wait for timer interrupt
+fn next_deadline(clint: &Clint, harts: &[CPU]) -> Option<u64> {
+    harts
+        .iter()
+        .filter(|hart| !hart.stopped && hart.mie & (MIP_MTIP | MIP_STIP) != 0)
+        .map(|hart| clint.mtimecmp[hart.hartid as usize])
+        .filter(|&mtimecmp| mtimecmp != u64::MAX)
+        .min()
+}
+
+// Called when all harts are idle, `bus` is index of hart that holds bus. Virtual time jumps
+// to nearest timer deadline, wall clock sleeps towards it. Returns false if no interrupt
+// can wake any hart.
+pub fn wait_for_interrupt(harts: &mut [CPU], bus: usize) -> bool {
+    let clint = &harts[bus].mmu.clint;
+    let Some(deadline) = next_deadline(clint, harts) else {
+        return false;
+    };
+    let remaining = deadline.saturating_sub(harts[bus].time());
+    let clint = &mut harts[bus].mmu.clint;
+    match clint.clock {
+        Clock::Virtual { .. } => clint.time_offset = clint.time_offset.wrapping_add(remaining),
+        Clock::WallClock(_) => {
+            let micros = remaining as u128 * 1_000_000 / TIMEBASE_FREQUENCY as u128;
+            let micros = micros.min(u64::MAX as u128) as u64;
+            std::thread::sleep(Duration::from_micros(micros).min(MAX_IDLE_SLEEP));
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::{
+        assembler::*,
+        clock::Clock,
+        cpu::EXECUTION_MODES,
+        emulator::{Emulator, StopReason},
+        errors::EmulatorError,
+    };
+
+    use super::{CLINT_ADDRESS, MTIMECMP_OFFSET, MTIME_OFFSET};
+
+    // Sets mtimecmp to mtime + `delay` and enables timer interrupt, handler stores mcause
+    // and time to A1, A2 and ends program.
+    fn timer_program(delay: u32, body: impl FnOnce(&mut Assembler)) -> Assembler {
+        let mut asm = Assembler::new();
+        asm.la(T0, "handler").csrw(0x305, T0).li(T0, 1 << 7).csrw(0x304, T0);
+        asm.li(T1, CLINT_ADDRESS + MTIME_OFFSET).lw(T4, 0, T1).li(T3, delay).add(T4, T4, T3);
+        asm.li(T2, CLINT_ADDRESS + MTIMECMP_OFFSET).sw(T4, 0, T2).sw(ZERO, 4, T2);
+        asm.li(T0, 8).csrw(0x300, T0);
+        body(&mut asm);
+        asm.label("handler").csrr(A1, 0x342).csrr(A2, 0xC01).mv(A3, T4).csrw(0x305, ZERO).ecall();
+        asm
+    }
+
+    fn emulator(asm: &Assembler, clock: Clock) -> Emulator {
+        let mut cpu = asm.cpu();
+        cpu.mmu.clint.clock = clock;
+        Emulator::new(cpu)
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let asm = timer_program(50, |asm| {
+            asm.label("spin").addi(A0, A0, 1).j("spin");
+        });
+        for &mode in EXECUTION_MODES {
+            let mut emu = emulator(&asm, Clock::virtual_time(10));
+            emu.harts[0].execution_mode = mode;
+            let reason = emu.run(100_000);
+            assert_eq!(reason, StopReason::Fatal(EmulatorError::UnsetTrapHandler), "{mode:?}");
+            let regs = emu.harts[0].get_registers();
+            assert_eq!(regs[A1 as usize], 0x80000007, "{mode:?}");
+            assert!(regs[A2 as usize] >= regs[A3 as usize], "{mode:?}");
+            // Tick is 100 instructions, loop iteration 2
+            assert!(regs[A0 as usize] > 2000, "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn test_wfi_skips_virtual_time() {
+        // Whole second of guest time
+        let asm = timer_program(1_000_000, |asm| {
+            asm.wfi();
+        });
+        let mut emu = emulator(&asm, Clock::virtual_time(10));
+        assert_eq!(emu.run(1000), StopReason::Fatal(EmulatorError::UnsetTrapHandler));
+        let regs = emu.harts[0].get_registers();
+        assert_eq!(regs[A1 as usize], 0x80000007);
+        assert!(regs[A2 as usize] >= regs[A3 as usize]);
+        assert!(emu.executed < 100);
+    }
+
+    #[test]
+    fn test_wfi_sleeps_with_wall_clock() {
+        let asm = timer_program(2000, |asm| {
+            asm.label("wait").wfi().j("wait");
+        });
+        let mut emu = emulator(&asm, Clock::wall_clock());
+        let start = Instant::now();
+        assert_eq!(emu.run(1000), StopReason::Fatal(EmulatorError::UnsetTrapHandler));
+        assert!(start.elapsed().as_micros() >= 2000);
+        assert!(emu.executed < 100);
+    }
+
+    #[test]
+    fn test_word_access_only() {
+        let mut asm = Assembler::new();
+        asm.la(T0, "handler").csrw(0x305, T0).li(T1, CLINT_ADDRESS + MTIME_OFFSET);
+        asm.li(T0, 1000).sw(T0, 0, T1).sw(ZERO, 4, T1).lw(A0, 0, T1).amoadd_w(A1, T0, T1);
+        asm.lb(A2, 0, T1).sh(T0, 0, T1).csrw(0x305, ZERO).ecall();
+        asm.skip_faults_handler("handler");
+        for &mode in EXECUTION_MODES {
+            let mut emu = emulator(&asm, Clock::virtual_time(10));
+            emu.harts[0].execution_mode = mode;
+            assert_eq!(emu.run(1000), StopReason::Fatal(EmulatorError::UnsetTrapHandler));
+            let regs = emu.harts[0].get_registers();
+            assert_eq!(regs[A0 as usize], 1000, "{mode:?}");
+            assert_eq!(regs[A1 as usize], 1000, "{mode:?}");
+            assert_eq!(regs[A2 as usize], 0, "{mode:?}");
+            assert_eq!(regs[S11 as usize], 2, "{mode:?}");
+            // Store access fault
+            assert_eq!(regs[S10 as usize], 7, "{mode:?}");
+            assert!(emu.harts[0].time() >= 2000, "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn test_wfi_without_wakeup() {
+        let mut asm = Assembler::new();
+        asm.li(T0, 1 << 7).csrw(0x304, T0).wfi();
+        let mut emu = emulator(&asm, Clock::virtual_time(10));
+        assert_eq!(emu.run(1000), StopReason::WaitForInterrupt);
+    }
+}
@@ -0,0 +1,158 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+// Frequency of `time` CSR, one tick is a microsecond
+pub const TIMEBASE_FREQUENCY: u64 = 1_000_000;
+// Used for virtual time when nothing else is configured, 100 MIPS
+pub const DEFAULT_NANOS_PER_INSTRUCTION: u64 = 10;
+// Time of day guest services report with virtual clock, 2024-01-01 00:00 UTC
+pub const VIRTUAL_EPOCH: Duration = Duration::from_secs(1_704_067_200);
+
+// Source of `time` CSR.
+#[derive(Debug, Clone, Copy)]
+pub enum Clock {
+    // Host time since creation, guest timing depends on host speed
+    WallClock(Instant),
+    // Time is function of retired instructions, so every run is the same
+    Virtual { nanos_per_instruction: u64 },
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::wall_clock()
+    }
+}
+
+impl Clock {
+    pub fn wall_clock() -> Self {
+        Clock::WallClock(Instant::now())
+    }
+    pub fn virtual_time(nanos_per_instruction: u64) -> Self {
+        Clock::Virtual {
+            nanos_per_instruction,
+        }
+    }
+    // Value of `time` in ticks after `instret` retired instructions
+    pub fn time(&self, instret: u64) -> u64 {
+        match self {
+            Clock::WallClock(start) => {
+                (start.elapsed().as_nanos() * TIMEBASE_FREQUENCY as u128 / 1_000_000_000) as u64
+            }
+            Clock::Virtual {
+                nanos_per_instruction,
+            } => {
+                (instret as u128 * *nanos_per_instruction as u128 * TIMEBASE_FREQUENCY as u128
+                    / 1_000_000_000) as u64
+            }
+        }
+    }
+    pub fn save(&self, w: &mut SnapshotWriter) {
+        match self {
+            // Only elapsed time is kept, clock continues from it after restore
+            Clock::WallClock(start) => {
+                w.u8(0);
+                w.u64(start.elapsed().as_nanos() as u64);
+            }
+            Clock::Virtual {
+                nanos_per_instruction,
+            } => {
+                w.u8(1);
+                w.u64(*nanos_per_instruction);
+            }
+        }
+    }
+    pub fn restore(r: &mut SnapshotReader) -> Result<Self, SnapshotError> {
+        match r.u8()? {
+            0 => {
+                let elapsed = Duration::from_nanos(r.u64()?);
+                let now = Instant::now();
+                Ok(Clock::WallClock(now.checked_sub(elapsed).unwrap_or(now)))
+            }
+            1 => Ok(Clock::virtual_time(r.u64()?)),
+            _ => Err(SnapshotError::Corrupt),
+        }
+    }
+    // Time of day told to guest by syscalls when `time` is `ticks`. Virtual clock doesn't look
+    // at host, so runs stay the same.
+    pub fn realtime(&self, ticks: u64) -> Duration {
+        match self {
+            Clock::WallClock(_) => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            Clock::Virtual { .. } => VIRTUAL_EPOCH.saturating_add(ticks_duration(ticks)),
+        }
+    }
+    // Time since guest service was created at `start`, virtual clock counts from zero `time`
+    pub fn since(&self, start: Instant, ticks: u64) -> Duration {
+        match self {
+            Clock::WallClock(_) => start.elapsed(),
+            Clock::Virtual { .. } => ticks_duration(ticks),
+        }
+    }
+    // First instret at which time reaches `ticks`, None if it doesn't depend on instret
+    pub fn instret_at(&self, ticks: u64) -> Option<u64> {
+        match self {
+            Clock::WallClock(_) => None,
+            Clock::Virtual {
+                nanos_per_instruction: 0,
+            } => Some(u64::MAX),
+            Clock::Virtual {
+                nanos_per_instruction,
+            } => {
+                let nanos = ticks as u128 * 1_000_000_000;
+                let per_instruction = *nanos_per_instruction as u128 * TIMEBASE_FREQUENCY as u128;
+                Some(nanos.div_ceil(per_instruction).min(u64::MAX as u128) as u64)
+            }
+        }
+    }
+}
+
+fn ticks_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * 1_000_000_000 / TIMEBASE_FREQUENCY as u128;
+    Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        cpu::EXECUTION_MODES,
+    };
+
+    use std::time::{Duration, Instant};
+
+    use super::{Clock, VIRTUAL_EPOCH};
+
+    #[test]
+    fn test_virtual_time() {
+        let clock = Clock::virtual_time(10);
+        assert_eq!(clock.time(99), 0);
+        assert_eq!(clock.time(100), 1);
+        assert_eq!(clock.time(250_000), 2500);
+        assert_eq!(clock.instret_at(1), Some(100));
+        assert_eq!(clock.time(clock.instret_at(2501).unwrap()), 2501);
+        // Guest services don't see host time
+        assert_eq!(clock.realtime(2_500_000), VIRTUAL_EPOCH + Duration::from_millis(2500));
+        assert_eq!(clock.since(Instant::now(), 2500), Duration::from_micros(2500));
+    }
+
+    #[test]
+    fn test_deterministic_time() {
+        let mut asm = Assembler::new();
+        asm.csrr(A0, 0xC01);
+        for _ in 0..10 {
+            asm.nop();
+        }
+        asm.csrr(A1, 0xC01).csrr(A2, 0xC81).ecall();
+        for &mode in EXECUTION_MODES {
+            let mut cpu = asm.cpu();
+            cpu.execution_mode = mode;
+            cpu.mmu.clint.clock = Clock::virtual_time(1000);
+            cpu.instret = (1 << 32) - 5;
+            let _ = cpu.run(100, &mut 0);
+            let regs = cpu.get_registers();
+            assert_eq!(regs[A0 as usize], u32::MAX - 4, "{mode:?}");
+            assert_eq!(regs[A1 as usize], 6, "{mode:?}");
+            assert_eq!(regs[A2 as usize], 1, "{mode:?}");
+        }
+    }
+}
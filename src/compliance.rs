@@ -0,0 +1,155 @@
+use std::{
+    fmt::Display,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    clock::{Clock, DEFAULT_NANOS_PER_INSTRUCTION},
+    elf_analyzer::ElfInfo,
+    emulator::{Emulator, StopReason},
+    mmu::MMU,
+};
+
+pub const DEFAULT_INSTRUCTION_LIMIT: u64 = 10_000_000;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TestOutcome {
+    Pass,
+    // Number of failed test case from tohost
+    Fail(u32),
+    Timeout,
+    Error(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: TestOutcome,
+    pub instructions: u64,
+}
+
+impl Display for TestResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.outcome {
+            TestOutcome::Pass => write!(f, "PASS    {}", self.name)?,
+            TestOutcome::Fail(test) => write!(f, "FAIL    {} (test {test})", self.name)?,
+            TestOutcome::Timeout => write!(f, "TIMEOUT {}", self.name)?,
+            TestOutcome::Error(e) => write!(f, "ERROR   {} ({e})", self.name)?,
+        }
+        write!(f, ", {} instructions", self.instructions)
+    }
+}
+
+// Runs riscv-tests and riscv-arch-test ELFs, which report result through HTIF tohost.
+pub struct ComplianceRunner {
+    instruction_limit: u64,
+    signature_dir: Option<PathBuf>,
+}
+
+impl Default for ComplianceRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ComplianceRunner {
+    pub fn new() -> Self {
+        ComplianceRunner {
+            instruction_limit: DEFAULT_INSTRUCTION_LIMIT,
+            signature_dir: None,
+        }
+    }
+    pub fn with_instruction_limit(mut self, limit: u64) -> Self {
+        self.instruction_limit = limit;
+        self
+    }
+    // Region between begin_signature and end_signature is dumped to `<dir>/<test>.signature`
+    pub fn with_signature_dir(mut self, dir: PathBuf) -> Self {
+        self.signature_dir = Some(dir);
+        self
+    }
+    fn execute(&self, emu: &mut Emulator) -> TestOutcome {
+        match emu.run(self.instruction_limit) {
+            StopReason::PowerOff(0) => TestOutcome::Pass,
+            StopReason::PowerOff(test) => TestOutcome::Fail(test),
+            StopReason::BudgetExhausted => TestOutcome::Timeout,
+            StopReason::Fatal(e) => TestOutcome::Error(format!("{e:?} at 0x{:08x}", emu.harts[0].pc)),
+            reason => TestOutcome::Error(format!("{reason:?} at 0x{:08x}", emu.harts[0].pc)),
+        }
+    }
+    pub fn run_elf(&self, name: &str, elf: Vec<u8>) -> io::Result<TestResult> {
+        let (mmu, _audio) = MMU::new();
+        let (mut emu, info) = match Emulator::from_elf_info(elf, mmu) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                let outcome = TestOutcome::Error(format!("can't load: {e:?}"));
+                return Ok(TestResult { name: name.to_string(), outcome, instructions: 0 });
+            }
+        };
+        // Results mustn't depend on speed of host
+        emu.harts[0].mmu.clint.clock = Clock::virtual_time(DEFAULT_NANOS_PER_INSTRUCTION);
+        let outcome = if info.tohost.is_none() {
+            TestOutcome::Error("no tohost symbol".to_string())
+        } else {
+            self.execute(&mut emu)
+        };
+        if let Some(dir) = &self.signature_dir {
+            let path = dir.join(format!("{}.signature", name.replace(['/', '\\'], "_")));
+            dump_signature(&emu, &info, &mut fs::File::create(path)?)?;
+        }
+        Ok(TestResult {
+            name: name.to_string(),
+            outcome,
+            instructions: emu.executed,
+        })
+    }
+    // Runs every ELF file inside of directory and its subdirectories.
+    pub fn run_directory(&self, dir: &Path) -> io::Result<Vec<TestResult>> {
+        let mut files = vec![];
+        collect_elfs(dir, &mut files)?;
+        files.sort();
+        let mut results = vec![];
+        for file in files {
+            let name = file.strip_prefix(dir).unwrap_or(&file).to_string_lossy().to_string();
+            results.push(self.run_elf(&name, fs::read(&file)?)?);
+        }
+        Ok(results)
+    }
+}
+
+fn collect_elfs(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_elfs(&path, files)?;
+        } else {
+            let mut magic = [0u8; 4];
+            let is_elf = fs::File::open(&path)
+                .and_then(|mut f| io::Read::read_exact(&mut f, &mut magic))
+                .is_ok()
+                && magic == *b"\x7fELF";
+            if is_elf {
+                files.push(path);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Writes signature in format of riscv-arch-test reference files, one word per line.
+pub fn dump_signature(emu: &Emulator, info: &ElfInfo, out: &mut dyn Write) -> io::Result<()> {
+    let (Some(begin), Some(end)) = (info.symbol("begin_signature"), info.symbol("end_signature"))
+    else {
+        return Ok(());
+    };
+    for address in (begin..end).step_by(4) {
+        let mut word = [0u8; 4];
+        for (i, byte) in word.iter_mut().enumerate() {
+            *byte = emu.harts[0].mmu.read_raw_from_ram(address + i as u32).unwrap_or(0);
+        }
+        writeln!(out, "{:08x}", u32::from_le_bytes(word))?;
+    }
+    Ok(())
+}
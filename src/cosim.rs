@@ -0,0 +1,343 @@
+use std::{
+    fmt::Display,
+    io::{self, BufRead},
+};
+
+use crate::{
+    cpu::PrivilegeMode,
+    disassembler::{csr_name, disassemble, REGISTER_NAMES},
+    emulator::Emulator,
+    errors::EmulatorError,
+    tracer::{CommitRecord, MemoryWrite},
+};
+
+fn parse_hex(token: &str) -> Option<u32> {
+    let digits = token.strip_prefix("0x")?;
+    // Spike prints values with xlen width, rv32 ones are sign-extended in rv64 logs
+    u64::from_str_radix(digits, 16).ok().map(|v| v as u32)
+}
+
+fn parse_privilege(token: &str) -> Option<PrivilegeMode> {
+    Some(match token {
+        "0" => PrivilegeMode::User,
+        "1" => PrivilegeMode::Supervisor,
+        "3" => PrivilegeMode::Machine,
+        _ => return None,
+    })
+}
+
+// Parses one commit line of `spike --log-commits` output.
+// Disassembly lines, exceptions and everything else yield None.
+pub fn parse_commit_line(line: &str) -> Option<CommitRecord> {
+    let rest = line.trim_start().strip_prefix("core")?;
+    let (hart, rest) = rest.split_once(':')?;
+    let hart = hart.trim().parse().ok()?;
+    let mut tokens = rest.split_whitespace().peekable();
+    let privilege = parse_privilege(tokens.next()?)?;
+    let pc = parse_hex(tokens.next()?)?;
+    let instr = tokens.next()?.strip_prefix('(')?.strip_suffix(')')?;
+    let instr = parse_hex(instr)?;
+    let mut record = CommitRecord::new(hart, privilege, pc, instr);
+    while let Some(token) = tokens.next() {
+        if token == "mem" {
+            let address = parse_hex(tokens.next()?)?;
+            match tokens.peek().filter(|t| t.starts_with("0x")) {
+                Some(value) => {
+                    let size = ((value.len() - 2) / 2) as u8;
+                    let value = parse_hex(value)?;
+                    tokens.next();
+                    record.stores.push(MemoryWrite { address, value, size });
+                }
+                None => record.loads.push(address),
+            }
+        } else if let Some(reg) = token.strip_prefix('x') {
+            let value = parse_hex(tokens.next()?)?;
+            record.reg_write = Some((reg.parse().ok()?, value));
+        } else if let Some(csr) = token.strip_prefix('c') {
+            let (num, _name) = csr.split_once('_')?;
+            let value = parse_hex(tokens.next()?)?;
+            record.csr_writes.push((num.parse().ok()?, value));
+        } else {
+            // Floating point and vector writes, emulator doesn't have them
+            tokens.next();
+        }
+    }
+    Some(record)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MismatchKind {
+    Pc,
+    Instruction,
+    Register(u8),
+    Csr(u16),
+}
+
+// Emulator state right after diverging instruction
+#[derive(Debug, Clone)]
+pub struct StateDump {
+    pub pc: u32,
+    pub privilege: PrivilegeMode,
+    pub registers: [u32; 32],
+    pub mstatus: u64,
+    pub mtvec: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
+    pub mie: u32,
+    pub mip: u32,
+    pub mscratch: u32,
+}
+
+impl StateDump {
+    pub fn capture(emu: &Emulator) -> Self {
+        let cpu = &emu.harts[0];
+        StateDump {
+            pc: cpu.pc,
+            privilege: cpu.privilege,
+            registers: cpu.get_registers(),
+            mstatus: cpu.mstatus,
+            mtvec: cpu.mtvec,
+            mepc: cpu.mepc,
+            mcause: cpu.mcause,
+            mtval: cpu.mtval,
+            mie: cpu.mie,
+            mip: cpu.mip,
+            mscratch: cpu.mscratch,
+        }
+    }
+}
+
+impl Display for StateDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "pc: 0x{:08x} privilege: {:?}", self.pc, self.privilege)?;
+        for (ind, x) in self.registers.iter().enumerate() {
+            write!(f, "{:<4}: {x:08x}", REGISTER_NAMES[ind])?;
+            if ind % 4 == 3 {
+                writeln!(f)?;
+            } else {
+                write!(f, "  ")?;
+            }
+        }
+        writeln!(
+            f,
+            "mstatus: {:08x} mtvec: {:08x} mepc: {:08x} mcause: {:08x}",
+            self.mstatus as u32, self.mtvec, self.mepc, self.mcause
+        )?;
+        writeln!(
+            f,
+            "mtval: {:08x} mie: {:08x} mip: {:08x} mscratch: {:08x}",
+            self.mtval, self.mie, self.mip, self.mscratch
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    // Number of instructions that matched before this one
+    pub index: u64,
+    pub kind: MismatchKind,
+    pub expected: CommitRecord,
+    pub actual: CommitRecord,
+    pub state: StateDump,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let what = match self.kind {
+            MismatchKind::Pc => "pc".to_string(),
+            MismatchKind::Instruction => "instruction".to_string(),
+            MismatchKind::Register(rd) => format!("register {}", REGISTER_NAMES[rd as usize]),
+            MismatchKind::Csr(csr) => format!("csr {}", csr_name(csr)),
+        };
+        writeln!(f, "Mismatch in {what} at instruction {}", self.index)?;
+        writeln!(f, "expected: {}", self.expected)?;
+        writeln!(f, "actual:   {}", self.actual)?;
+        writeln!(f, "executed: {}", disassemble(self.actual.instr))?;
+        write!(f, "{}", self.state)
+    }
+}
+
+#[derive(Debug)]
+pub enum CosimError {
+    Mismatch(Box<Mismatch>),
+    // Emulator can't continue, reference log still has records
+    Emulator(EmulatorError),
+    Io(io::Error),
+}
+
+impl From<io::Error> for CosimError {
+    fn from(value: io::Error) -> Self {
+        CosimError::Io(value)
+    }
+}
+
+fn compare(expected: &CommitRecord, actual: &CommitRecord, registers: &[u32; 32]) -> Option<MismatchKind> {
+    if expected.pc != actual.pc {
+        return Some(MismatchKind::Pc);
+    }
+    if expected.instr != actual.instr {
+        return Some(MismatchKind::Instruction);
+    }
+    // Compressed instructions don't fill their records, so rd is checked against register file
+    let expected_rd = expected.reg_write.filter(|(rd, _)| *rd != 0);
+    if let Some((rd, value)) = expected_rd {
+        if registers[rd as usize] != value {
+            return Some(MismatchKind::Register(rd));
+        }
+    }
+    if let Some((rd, _)) = actual.reg_write {
+        if expected_rd.is_none_or(|(expected_rd, _)| expected_rd != rd) {
+            return Some(MismatchKind::Register(rd));
+        }
+    }
+    for (csr, value) in &expected.csr_writes {
+        let actual_value = actual.csr_writes.iter().find(|(c, _)| c == csr);
+        if actual_value.is_none_or(|(_, v)| v != value) {
+            return Some(MismatchKind::Csr(*csr));
+        }
+    }
+    for (csr, _) in &actual.csr_writes {
+        if !expected.csr_writes.iter().any(|(c, _)| c == csr) {
+            return Some(MismatchKind::Csr(*csr));
+        }
+    }
+    None
+}
+
+// Runs emulator in lock-step with reference commit log, checking every retired instruction.
+pub struct CoSimulator<R: BufRead> {
+    reference: R,
+    checked: u64,
+}
+
+impl<R: BufRead> CoSimulator<R> {
+    pub fn new(reference: R) -> Self {
+        CoSimulator {
+            reference,
+            checked: 0,
+        }
+    }
+    pub fn checked(&self) -> u64 {
+        self.checked
+    }
+    fn next_record(&mut self) -> io::Result<Option<CommitRecord>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reference.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            if let Some(record) = parse_commit_line(&line) {
+                return Ok(Some(record));
+            }
+        }
+    }
+    // Checks next instruction, returns false when reference log is over.
+    pub fn step(&mut self, emu: &mut Emulator) -> Result<bool, CosimError> {
+        let Some(mut expected) = self.next_record()? else {
+            return Ok(false);
+        };
+        if self.checked == 0 {
+            // Reference usually starts in boot rom, emulator starts right at entry point
+            while expected.pc != emu.harts[0].pc {
+                match self.next_record()? {
+                    Some(record) => expected = record,
+                    None => return Ok(false),
+                }
+            }
+        }
+        // Traps are not commits in Spike, so they don't consume reference records
+        let actual = loop {
+            match emu.harts[0].execute_committed() {
+                Ok(record) => break record,
+                Err(trap) => emu.harts[0].process_trap(trap).map_err(CosimError::Emulator)?,
+            }
+        };
+        if let Some(kind) = compare(&expected, &actual, &emu.harts[0].get_registers()) {
+            return Err(CosimError::Mismatch(Box::new(Mismatch {
+                index: self.checked,
+                kind,
+                expected,
+                actual,
+                state: StateDump::capture(emu),
+            })));
+        }
+        self.checked += 1;
+        Ok(true)
+    }
+    // Runs until reference log ends or at most `limit` instructions are checked.
+    pub fn run(&mut self, emu: &mut Emulator, limit: Option<u64>) -> Result<u64, CosimError> {
+        while limit.is_none_or(|l| self.checked < l) {
+            if !self.step(emu)? {
+                break;
+            }
+        }
+        Ok(self.checked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::Assembler, emulator::Emulator};
+
+    use super::{parse_commit_line, CoSimulator, CosimError, MismatchKind};
+
+    fn emulator(program: &[u32]) -> Emulator {
+        let mut asm = Assembler::new();
+        for &instr in program {
+            asm.instr(instr);
+        }
+        asm.emulator()
+    }
+
+    #[test]
+    fn test_parse_commit_line() {
+        let line = "core   0: 3 0x80000000 (0x00000297) x5  0x80000000";
+        let record = parse_commit_line(line).unwrap();
+        assert_eq!(record.pc, 0x80000000);
+        assert_eq!(record.reg_write, Some((5, 0x80000000)));
+        assert_eq!(record.to_string(), line);
+
+        let line = "core   0: 3 0x80000014 (0x00a581a3) mem 0x80001003 0x05";
+        assert_eq!(parse_commit_line(line).unwrap().to_string(), line);
+        let line = "core   0: 3 0x8000000c (0x0005a603) x12 0x00000005 mem 0x80001000";
+        assert_eq!(parse_commit_line(line).unwrap().to_string(), line);
+        let line = "core   0: 3 0x80000010 (0x34051073) c832_mscratch 0x00000005";
+        assert_eq!(parse_commit_line(line).unwrap().csr_writes, vec![(832, 5)]);
+
+        assert!(parse_commit_line("core   0: 0x80000000 (0x00000297) auipc   t0, 0x0").is_none());
+        assert!(parse_commit_line("core   0: exception trap_machine_ecall, epc 0x80000018").is_none());
+    }
+
+    #[test]
+    fn test_cosim() {
+        let program = [
+            0x00500513, // li a0, 5
+            0x800015b7, // lui a1, 0x80001
+            0x34051073, // csrw mscratch, a0
+        ];
+        let reference = "\
+core   0: 0x00001000 (0x00000297) auipc   t0, 0x0
+core   0: 3 0x00001000 (0x00000297) x5  0x00001000
+core   0: 3 0x80000000 (0x00500513) x10 0x00000005
+core   0: 3 0x80000004 (0x800015b7) x11 0x80001000
+core   0: 3 0x80000008 (0x34051073) c832_mscratch 0x00000005
+";
+        let mut emu = emulator(&program);
+        let mut cosim = CoSimulator::new(reference.as_bytes());
+        assert_eq!(cosim.run(&mut emu, None).unwrap(), 3);
+
+        let reference = reference.replace("x11 0x80001000", "x11 0x80002000");
+        let mut emu = emulator(&program);
+        let mut cosim = CoSimulator::new(reference.as_bytes());
+        match cosim.run(&mut emu, None) {
+            Err(CosimError::Mismatch(m)) => {
+                assert_eq!(m.index, 1);
+                assert_eq!(m.kind, MismatchKind::Register(11));
+                assert_eq!(m.state.registers[11], 0x80001000);
+            }
+            r => panic!("Expected mismatch, got {r:?}"),
+        }
+    }
+}
@@ -0,0 +1,249 @@
+// Zicntr and Zihpm counters. Counters are computed from raw totals kept by cpu
+// (retired instructions and event tallies), so hot loops only increment totals.
+// Index of counter is its CSR number & 0x1F: 0 cycle, 1 time, 2 instret, 3..=31 hpmcounters.
+
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+// Values of mhpmevent
+pub const EVENT_NONE: u32 = 0;
+pub const EVENT_LOADS: u32 = 1;
+pub const EVENT_STORES: u32 = 2;
+pub const EVENT_TAKEN_BRANCHES: u32 = 3;
+pub const EVENT_TRAPS: u32 = 4;
+pub const EVENT_COUNT: usize = 5;
+
+pub const COUNTER_CYCLE: usize = 0;
+pub const COUNTER_TIME: usize = 1;
+pub const COUNTER_INSTRET: usize = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Counter {
+    value: u64,
+    // Raw total when value was set, None while counter is inhibited
+    base: Option<u64>,
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Counter {
+            value: 0,
+            base: Some(0),
+        }
+    }
+}
+
+impl Counter {
+    fn read(&self, raw: u64) -> u64 {
+        match self.base {
+            Some(base) => self.value.wrapping_add(raw.wrapping_sub(base)),
+            None => self.value,
+        }
+    }
+    fn write(&mut self, value: u64, raw: u64) {
+        self.value = value;
+        if self.base.is_some() {
+            self.base = Some(raw);
+        }
+    }
+    fn set_inhibited(&mut self, inhibited: bool, raw: u64) {
+        if inhibited && self.base.is_some() {
+            self.value = self.read(raw);
+            self.base = None;
+        } else if !inhibited && self.base.is_none() {
+            self.base = Some(raw);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Counters {
+    counters: [Counter; 32],
+    // mhpmevent3..31, other entries stay EVENT_NONE
+    events: [u32; 32],
+    // How many times each event happened
+    pub event_totals: [u64; EVENT_COUNT],
+    pub inhibit: u32,
+    // Some hpmcounter counts loads, stores or taken branches
+    instruction_events: bool,
+    pub mcounteren: u32,
+    pub scounteren: u32,
+}
+
+impl Counters {
+    fn raw(&self, index: usize, instret: u64) -> u64 {
+        match index {
+            // One cycle per retired instruction and per trap
+            COUNTER_CYCLE => instret + self.event_totals[EVENT_TRAPS as usize],
+            COUNTER_INSTRET => instret,
+            _ => self
+                .event_totals
+                .get(self.events[index] as usize)
+                .copied()
+                .unwrap_or(0),
+        }
+    }
+    pub fn count(&mut self, event: u32) {
+        self.event_totals[event as usize] += 1;
+    }
+    pub fn read(&self, index: usize, instret: u64) -> u64 {
+        self.counters[index].read(self.raw(index, instret))
+    }
+    pub fn write(&mut self, index: usize, value: u64, instret: u64) {
+        let raw = self.raw(index, instret);
+        self.counters[index].write(value, raw);
+    }
+    pub fn event(&self, index: usize) -> u32 {
+        self.events[index]
+    }
+    // Unsupported events are legalized to EVENT_NONE
+    pub fn set_event(&mut self, index: usize, event: u32, instret: u64) {
+        let value = self.read(index, instret);
+        self.events[index] = if (event as usize) < EVENT_COUNT { event } else { EVENT_NONE };
+        self.write(index, value, instret);
+        self.update_instruction_events();
+    }
+    fn update_instruction_events(&mut self) {
+        self.instruction_events = self.events[3..]
+            .iter()
+            .any(|e| matches!(*e, EVENT_LOADS | EVENT_STORES | EVENT_TAKEN_BRANCHES));
+    }
+    pub fn set_inhibit(&mut self, inhibit: u32, instret: u64) {
+        // time can't be inhibited
+        self.inhibit = inhibit & !(1 << COUNTER_TIME);
+        for index in 0..32 {
+            let raw = self.raw(index, instret);
+            self.counters[index].set_inhibited(self.inhibit & (1 << index) != 0, raw);
+        }
+    }
+    pub fn save(&self, w: &mut SnapshotWriter) {
+        for counter in &self.counters {
+            w.u64(counter.value);
+            w.option_u64(counter.base);
+        }
+        for event in self.events {
+            w.u32(event);
+        }
+        for total in self.event_totals {
+            w.u64(total);
+        }
+        w.u32(self.inhibit);
+        w.u32(self.mcounteren);
+        w.u32(self.scounteren);
+    }
+    pub fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        for counter in &mut self.counters {
+            counter.value = r.u64()?;
+            counter.base = r.option_u64()?;
+        }
+        for event in &mut self.events {
+            // Legalized like mhpmevent writes
+            *event = match r.u32()? {
+                e if (e as usize) < EVENT_COUNT => e,
+                _ => EVENT_NONE,
+            };
+        }
+        for total in &mut self.event_totals {
+            *total = r.u64()?;
+        }
+        self.inhibit = r.u32()?;
+        self.mcounteren = r.u32()?;
+        self.scounteren = r.u32()?;
+        self.update_instruction_events();
+        Ok(())
+    }
+    // Events that only interpreter handlers count, not translated code
+    #[inline(always)]
+    pub fn counts_instruction_events(&self) -> bool {
+        self.instruction_events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        cpu::{CPU, EXECUTION_MODES},
+        errors::EmulatorError,
+    };
+
+    fn run(asm: &Assembler) -> Vec<CPU> {
+        EXECUTION_MODES
+            .iter()
+            .map(|&mode| {
+                let mut cpu = asm.cpu();
+                cpu.execution_mode = mode;
+                match cpu.run(10000, &mut 0) {
+                    Err(EmulatorError::UnsetTrapHandler) => cpu,
+                    r => panic!("Program didn't finish in {mode:?}: {r:?}"),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_instret_and_cycle() {
+        let mut asm = Assembler::new();
+        asm.la(T0, "handler").csrw(0x305, T0);
+        asm.csrr(A0, 0xC02).csrr(A1, 0xC02).ecall();
+        asm.csrr(A2, 0xC00).csrr(A3, 0xC02);
+        // Writable from machine mode, high half too
+        asm.li(T0, 1000).csrw(0xB02, T0).li(T0, 7).csrw(0xB82, T0).csrr(A4, 0xC02).csrr(A5, 0xC82);
+        asm.csrw(0x305, ZERO).ecall();
+        asm.label("handler").csrr(T1, 0x341).addi(T1, T1, 4).csrw(0x341, T1).mret();
+        for cpu in run(&asm) {
+            let regs = cpu.get_registers();
+            assert_eq!(regs[A1 as usize], regs[A0 as usize] + 1);
+            // cycle was read one instruction earlier, but ecall trap took a cycle too
+            assert_eq!(regs[A2 as usize], regs[A3 as usize]);
+            assert_eq!(regs[A4 as usize], 1001);
+            assert_eq!(regs[A5 as usize], 7);
+        }
+    }
+
+    #[test]
+    fn test_inhibit() {
+        let mut asm = Assembler::new();
+        asm.li(T0, 0b101).csrw(0x320, T0);
+        asm.csrr(A0, 0xB02).nop().csrr(A1, 0xB02).csrr(A2, 0xB00);
+        asm.csrw(0x320, ZERO).csrr(A3, 0xB02);
+        asm.ecall();
+        for cpu in run(&asm) {
+            let regs = cpu.get_registers();
+            assert_eq!(regs[A0 as usize], regs[A1 as usize]);
+            assert_eq!(regs[A3 as usize], regs[A1 as usize] + 1);
+            assert_eq!(regs[A2 as usize], regs[A1 as usize]);
+        }
+    }
+
+    #[test]
+    fn test_hpm_events() {
+        let mut asm = Assembler::new();
+        asm.li(T0, 1).csrw(0x323, T0).li(T0, 3).csrw(0x324, T0).li(T0, 99).csrw(0x325, T0);
+        asm.csrr(A3, 0x325).li(A2, 0x80002000).li(A4, 3);
+        asm.label("loop").lw(T1, 0, A2).lw(T1, 4, A2).addi(A4, A4, -1).bne(A4, ZERO, "loop");
+        asm.csrr(A0, 0xC03).csrr(A1, 0xC04).ecall();
+        for cpu in run(&asm) {
+            let regs = cpu.get_registers();
+            assert_eq!(regs[A0 as usize], 6, "{:?}", cpu.execution_mode);
+            assert_eq!(regs[A1 as usize], 2, "{:?}", cpu.execution_mode);
+            assert_eq!(regs[A3 as usize], 0);
+        }
+    }
+
+    #[test]
+    fn test_counteren() {
+        let mut asm = Assembler::new();
+        asm.la(T0, "handler").csrw(0x305, T0);
+        // Only cycle is available to user mode
+        asm.li(T0, 1).csrw(0x306, T0).csrw(0x106, T0).pmp_allow_all();
+        asm.li(T0, 0b11 << 11).csrrc(ZERO, 0x300, T0).la(T0, "user").csrw(0x341, T0).mret();
+        asm.label("user").csrr(A0, 0xC00).csrr(A1, 0xC02).li(A2, 1).ecall();
+        asm.label("handler").csrr(A3, 0x342).csrw(0x305, ZERO).ecall();
+        for cpu in run(&asm) {
+            let regs = cpu.get_registers();
+            assert_ne!(regs[A0 as usize], 0);
+            assert_eq!(regs[A2 as usize], 0);
+            assert_eq!(regs[A3 as usize], 2);
+        }
+    }
+}
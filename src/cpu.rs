@@ -1,18 +1,68 @@
-use std::{sync::{atomic::AtomicBool, Arc}, time::Instant};
+use std::sync::{atomic::AtomicBool, Arc};
 
 use crate::{
-    errors::EmulatorError, mmu::{MMU, RAM_ADDRESS_END}, ops_decode::{
-        get_compressed_func3, get_csr_num, get_funct3, get_funct7, get_imm_b_type, get_imm_i_type, get_imm_j_type, get_imm_s_type, get_imm_u_type, get_opcode, get_rd, get_rs1, get_rs2, get_rs3
-    }, traps::{Trap, TrapType}
+    block_engine::{run_blocks, BlockCache}, clint, linux_user, newlib, sbi, semihosting, csr::{self, CsrTable}, counters::{Counters, EVENT_LOADS, EVENT_STORES, EVENT_TAKEN_BRANCHES, EVENT_TRAPS}, decode_cache::{DecodeCache, DecodedInstruction, Handler}, errors::EmulatorError, manual_debugger::read_instruction, mmu::{MMU, RAM_ADDRESS_END}, pmp::{Access, Pmp}, ops_decode::{
+        get_csr_num, get_funct3, get_funct7, get_imm_b_type, get_imm_i_type, get_imm_j_type, get_imm_s_type, get_imm_u_type, get_opcode, get_rd, get_rs1, get_rs2, get_rs3
+    }, tracer::{CommitRecord, MemoryWrite, Tracer}, traps::{Trap, TrapType}
 };
+#[cfg(feature = "jit")]
+use crate::jit::{run_jit, JitCache};
 
-#[derive(Clone, Copy)]
+// Values are encodings used by mstatus.MPP and Spike's commit log
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum PrivilegeMode {
-    Machine = 0,
+    User = 0,
     Supervisor = 1,
     Reserved = 2,
-    User = 3,
+    Machine = 3,
 }
+impl PrivilegeMode {
+    pub fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => PrivilegeMode::User,
+            1 => PrivilegeMode::Supervisor,
+            2 => PrivilegeMode::Reserved,
+            _ => PrivilegeMode::Machine,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ExecutionMode {
+    // One instruction per dispatch
+    #[default]
+    Interpreter,
+    // Whole basic blocks per dispatch, see block_engine
+    BasicBlock,
+    // Basic blocks translated to native code, see jit
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+// Every mode of this build, differential tests compare them against each other
+pub const EXECUTION_MODES: &[ExecutionMode] = &[
+    ExecutionMode::Interpreter,
+    ExecutionMode::BasicBlock,
+    #[cfg(feature = "jit")]
+    ExecutionMode::Jit,
+];
+
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+
+// Interrupts in order of priority
+const INTERRUPT_PRIORITY: [(u32, TrapType); 6] = [
+    (11, TrapType::MachineExternalInterrupt),
+    (3, TrapType::MachineSoftwareInterrupt),
+    (7, TrapType::MachineTimerInterrupt),
+    (9, TrapType::SupervisorExternalInterrupt),
+    (1, TrapType::SupervisorSoftwareInterrupt),
+    (5, TrapType::SupervisorTimerInterrupt),
+];
 
 // RV32IMA
 #[allow(dead_code)]
@@ -24,10 +74,18 @@ pub struct CPU {
     //CSRs
     //pub CSRs: [u32; 4096],
     pub mstatus: u64,
-    pub cycle: u64,
-    pub mtimecmp: u64,
-
-    pub time_crs_start: Instant,
+    // Index of hart, also its CLINT registers
+    pub hartid: u32,
+    // instret at which CLINT state is checked again
+    pub next_timer_check: u64,
+    // Retired by other harts, virtual time follows all of them
+    pub other_instret: u64,
+    // Instructions that completed without trap
+    pub instret: u64,
+    pub counters: Counters,
+    // Implemented CSRs, entries can be replaced to add or override registers
+    pub csrs: CsrTable,
+    pub pmp: Pmp,
 
 
     pub mscratch: u32,
@@ -38,12 +96,28 @@ pub struct CPU {
     pub mepc: u32,
     pub mtval: u32,
     pub mcause: u32,
+    // Traps and interrupts with these bits set are taken in S mode when they come from below M
+    pub medeleg: u32,
+    pub mideleg: u32,
+
+    pub sscratch: u32,
+    pub stvec: u32,
+    pub sepc: u32,
+    pub stval: u32,
+    pub scause: u32,
     pub privilege: PrivilegeMode,
     pub wfi: bool,
-    pub reservation_slot: Option<u32>,
+    // Stopped through SBI HSM, only hart_start of another hart resumes it
+    pub stopped: bool,
     pub stopflag: Option<Arc<AtomicBool>>,
-    
-    
+    pub tracer: Option<Tracer>,
+    pub satp: u32,
+    pub decode_cache: DecodeCache,
+    pub execution_mode: ExecutionMode,
+    pub blocks: BlockCache,
+    // Created on first run in jit mode
+    #[cfg(feature = "jit")]
+    pub jit: Option<JitCache>,
 }
 
 #[allow(dead_code)]
@@ -57,8 +131,9 @@ impl CPU {
             pc: 0,
             mmu,
             mstatus: 0,
-            cycle: 0,
-            mtimecmp: 0,
+            hartid: 0,
+            next_timer_check: 0,
+            other_instret: 0,
             mscratch: 0,
             mtvec: 0,
             mie: 0,
@@ -66,11 +141,28 @@ impl CPU {
             mepc: 0,
             mtval: 0,
             mcause: 0,
+            medeleg: 0,
+            mideleg: 0,
+            sscratch: 0,
+            stvec: 0,
+            sepc: 0,
+            stval: 0,
+            scause: 0,
             privilege: PrivilegeMode::Machine,
             wfi: false,
-            reservation_slot: None,
+            stopped: false,
             stopflag: None,
-            time_crs_start: Instant::now(),
+            tracer: None,
+            satp: 0,
+            decode_cache: DecodeCache::new(),
+            execution_mode: ExecutionMode::Interpreter,
+            blocks: BlockCache::default(),
+            #[cfg(feature = "jit")]
+            jit: None,
+            instret: 0,
+            counters: Counters::default(),
+            csrs: CsrTable::new(),
+            pmp: Pmp::default(),
         }
     }
     pub fn get_registers(&self) -> [u32; 32] {
@@ -80,6 +172,10 @@ impl CPU {
         regs[0] = 0;
         self.x = regs
     }
+    // Used by jit, which keeps x0 zero by never writing it
+    pub fn registers_ptr(&mut self) -> *mut u32 {
+        self.x.as_mut_ptr()
+    }
     #[inline(always)]
     fn set_x(&mut self, x: u8, val: u32) {
         if x != 0 {
@@ -92,12 +188,249 @@ impl CPU {
     }
     //pub fn run_debugger(&mut self) {}
     pub fn execute_instruction(&mut self) -> Result<u32, Trap> {
-        let instr = self.fetch()?;
-        let instr_len = self.execute(instr)? as u32;
-        self.pc += instr_len;
-        Ok(instr)
+        if let Some(mut tracer) = self.tracer.take() {
+            let res = self.execute_traced(&mut tracer);
+            self.tracer = Some(tracer);
+            return res;
+        }
+        let decoded = self.fetch_decoded()?;
+        (decoded.handler)(self, &decoded)?;
+        self.pc += decoded.len as u32;
+        self.instret += 1;
+        Ok(decoded.instr)
+    }
+    // Drops cached code from pages written since last call, returns true if there were any.
+    #[inline(always)]
+    pub fn invalidate_written_code(&mut self) -> bool {
+        if self.mmu.invalidated_code_pages.is_empty() {
+            return false;
+        }
+        for page in std::mem::take(&mut self.mmu.invalidated_code_pages) {
+            self.decode_cache.invalidate_page(page);
+        }
+        true
+    }
+    #[inline(always)]
+    fn fetch_decoded(&mut self) -> Result<DecodedInstruction, Trap> {
+        self.invalidate_written_code();
+        let decoded = self.decode_at(self.pc)?;
+        self.check_pmp(self.pc, decoded.len as u32, Access::Execute)?;
+        Ok(decoded)
+    }
+    // Access fault if PMP denies access from current privilege
+    #[inline(always)]
+    pub fn check_pmp(&self, address: u32, size: u32, access: Access) -> Result<(), Trap> {
+        if self.pmp.applies(self.privilege) && !self.pmp.allows(address, size, access, self.privilege) {
+            return Err(access.fault(address));
+        }
+        Ok(())
+    }
+    pub fn decode_at(&mut self, pc: u32) -> Result<DecodedInstruction, Trap> {
+        if let Some(decoded) = self.decode_cache.get(pc) {
+            return Ok(*decoded);
+        }
+        let decoded = Self::decode(self.mmu.fetch_word(pc)?);
+        self.mmu.mark_code_page(pc);
+        self.mmu.mark_code_page(pc.wrapping_add(2));
+        self.decode_cache.insert(pc, decoded);
+        Ok(decoded)
+    }
+    fn execute_traced(&mut self, tracer: &mut Tracer) -> Result<u32, Trap> {
+        let pc = self.pc;
+        match self.execute_committed() {
+            Ok(record) => {
+                tracer.commit(&record);
+                Ok(record.instr)
+            }
+            Err(trap) => {
+                tracer.exception(self.hartid, pc, read_instruction(&self.mmu, pc), &trap);
+                Err(trap)
+            }
+        }
+    }
+    // Executes instruction like execute_instruction, but also reports what it changed.
+    pub fn execute_committed(&mut self) -> Result<CommitRecord, Trap> {
+        let pc = self.pc;
+        let decoded = self.fetch_decoded()?;
+        let mut record = CommitRecord::new(self.hartid, self.privilege, pc, decoded.instr);
+        let operands = self.memory_operands(decoded.instr);
+        (decoded.handler)(self, &decoded)?;
+        self.pc += decoded.len as u32;
+        self.instret += 1;
+        self.complete_commit(&mut record, operands);
+        Ok(record)
+    }
+    // Address and rs2 value of memory instruction, captured before it executes
+    fn memory_operands(&self, instr: u32) -> Option<(u32, u32)> {
+        let base = self.get_x(get_rs1(instr));
+        let address = match get_opcode(instr) {
+            0b0000011 => base.wrapping_add(get_imm_i_type(instr)),
+            0b0100011 => base.wrapping_add(get_imm_s_type(instr)),
+            0b0101111 => base,
+            _ => return None,
+        };
+        Some((address, self.get_x(get_rs2(instr))))
+    }
+    fn complete_commit(&self, record: &mut CommitRecord, operands: Option<(u32, u32)>) {
+        let instr = record.instr;
+        if instr & 0b11 != 0b11 {
+            return;
+        }
+        let opcode = get_opcode(instr);
+        let funct3 = get_funct3(instr);
+        let rd = get_rd(instr);
+        let writes_rd = match opcode {
+            0b0110111 | 0b0010111 | 0b1101111 | 0b1100111 | 0b0000011 | 0b0010011
+            | 0b0110011 | 0b0101111 => true,
+            0b1110011 => funct3 != 0,
+            _ => false,
+        };
+        if writes_rd && rd != 0 {
+            record.reg_write = Some((rd, self.get_x(rd)));
+        }
+        match (opcode, operands) {
+            (0b0000011, Some((address, _))) => record.loads.push(address),
+            (0b0100011, Some((address, value))) => {
+                let size = 1 << funct3;
+                let mask = u32::MAX >> (32 - size * 8);
+                record.stores.push(MemoryWrite {
+                    address,
+                    value: value & mask,
+                    size,
+                })
+            }
+            (0b0101111, Some((address, value))) => match get_rs3(instr) {
+                0b00010 => record.loads.push(address),
+                0b00011 => {
+                    let succeeded = if rd != 0 {
+                        self.get_x(rd) == 0
+                    } else {
+                        self.mmu.read_word(address) == Ok(value)
+                    };
+                    if succeeded {
+                        record.stores.push(MemoryWrite {
+                            address,
+                            value,
+                            size: 4,
+                        })
+                    }
+                }
+                _ => {
+                    record.loads.push(address);
+                    record.stores.push(MemoryWrite {
+                        address,
+                        value: self.mmu.read_word(address).unwrap_or(0),
+                        size: 4,
+                    })
+                }
+            },
+            (0b1110011, _) if funct3 != 0 => {
+                // csrrw(i) always writes, set/clear variants only with non-zero rs1/uimm
+                let csr = get_csr_num(instr);
+                if funct3 & 0b11 == 0b01 || get_rs1(instr) != 0 {
+                    record.csr_writes.push((csr, self.read_csr(csr).unwrap_or(0)));
+                }
+            }
+            _ => {}
+        }
     }
+    // Runs `budget` instructions with selected execution mode, adding them to `executed`.
+    // Instructions that trapped are counted too, like step does. Stops early when hart is idle.
+    pub fn run(&mut self, budget: u64, executed: &mut u64) -> Result<(), EmulatorError> {
+        let end = *executed + budget;
+        #[cfg(feature = "jit")]
+        if self.execution_mode == ExecutionMode::Jit && self.tracer.is_none() && self.can_run_translated() {
+            let mut jit = self.jit.take().unwrap_or_default();
+            let res = run_jit(self, &mut jit, budget, executed);
+            self.jit = Some(jit);
+            if res.is_err() || self.can_run_translated() {
+                return res;
+            }
+        }
+        // Jit mode uses blocks while translated code can't run
+        if self.execution_mode != ExecutionMode::Interpreter && self.tracer.is_none() {
+            let mut blocks = std::mem::take(&mut self.blocks);
+            let res = run_blocks(self, &mut blocks, end - *executed, executed);
+            self.blocks = blocks;
+            return res;
+        }
+        for _ in 0..budget {
+            if self.is_idle() {
+                break;
+            }
+            self.step()?;
+            *executed += 1;
+        }
+        Ok(())
+    }
+    // Value of `time` CSR and mtime
+    pub fn time(&self) -> u64 {
+        let clint = &self.mmu.clint;
+        clint.clock.time(self.instret + self.other_instret).wrapping_add(clint.time_offset)
+    }
+    // Translated code doesn't count instruction events, its stores don't drop reservations
+    // and its memory accesses aren't checked by PMP
+    #[cfg(feature = "jit")]
+    #[inline(always)]
+    pub fn can_run_translated(&self) -> bool {
+        !self.counters.counts_instruction_events()
+            && !self.mmu.has_reservations()
+            && !self.pmp.applies(self.privilege)
+    }
+    // Devices can't reach hart that accessed them, so stop and timer changes they ask for
+    // are taken after store
+    #[inline(always)]
+    fn take_bus_requests(&mut self) {
+        if self.mmu.stop_requested {
+            self.mmu.stop_requested = false;
+            self.stopped = true;
+        }
+        if self.mmu.clint.written {
+            self.mmu.clint.written = false;
+            self.next_timer_check = 0;
+        }
+    }
+    // CLINT derives mtime from instret of hart that accesses it, syscall layers read it too
+    #[inline(always)]
+    pub fn sync_bus_time(&mut self) {
+        self.mmu.clint.instret = self.instret + self.other_instret;
+    }
+    // Hart executed WFI and no enabled interrupt is pending, so only outside event can wake it
+    #[inline(always)]
+    pub fn is_idle(&self) -> bool {
+        self.stopped || self.wfi && self.mip & self.mie == 0
+    }
+    // Takes pending enabled interrupt, returns true if it did.
+    pub fn check_interrupts(&mut self) -> Result<bool, EmulatorError> {
+        if self.instret >= self.next_timer_check {
+            clint::update(self);
+        }
+        let pending = self.mip & self.mie;
+        if pending == 0 {
+            return Ok(false);
+        }
+        // WFI is woken by pending interrupt even if it isn't taken
+        self.wfi = false;
+        // Interrupts of a level are always enabled below it and at it only with its global enable
+        let machine = pending & !self.mideleg;
+        let supervisor = pending & self.mideleg;
+        let enabled = match self.privilege {
+            PrivilegeMode::Machine if self.mstatus & MSTATUS_MIE == 0 => 0,
+            PrivilegeMode::Machine => machine,
+            PrivilegeMode::Supervisor if self.mstatus & MSTATUS_SIE == 0 => machine,
+            _ => machine | supervisor,
+        };
+        for (bit, tcause) in INTERRUPT_PRIORITY {
+            if enabled & (1 << bit) != 0 {
+                self.process_trap(Trap { tcause, tval: 0 })?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+    #[allow(clippy::needless_return)]
     pub fn step(&mut self) -> Result<u32, EmulatorError> {
+        self.check_interrupts()?;
         let res = self.execute_instruction();
         match res {
             Ok(v) => {
@@ -111,26 +444,36 @@ impl CPU {
     }
     pub fn process_trap(&mut self, trap: Trap) -> Result<(), EmulatorError> {
         // println!("TRAP!\nTRAP!\nTRAP!\nTRAP!\n{}", trap);
+        if trap.tcause == TrapType::EnvironmentCallFromSMode && self.mmu.sbi.is_some() {
+            sbi::handle_ecall(self);
+            return Ok(());
+        }
+        if trap.tcause == TrapType::EnvironmentCallFromUMode && self.mmu.linux_user.is_some() {
+            linux_user::handle_ecall(self);
+            return Ok(());
+        }
+        if trap.tcause == TrapType::EnvironmentCallFromMMode && self.mmu.newlib.is_some() {
+            newlib::handle_ecall(self);
+            return Ok(());
+        }
+        let delegated = if trap.is_interupt() { self.mideleg } else { self.medeleg };
+        if self.privilege != PrivilegeMode::Machine && delegated & (1 << (trap.tcause as u32 & 0x1F)) != 0 {
+            self.supervisor_trap(trap);
+            return Ok(());
+        }
         if self.mtvec == 0 {
             return Err(EmulatorError::UnsetTrapHandler);
         }
+        // Interrupts are disabled in handler, previous state is kept in MPIE and MPP
+        let mie = (self.mstatus & MSTATUS_MIE) >> 3;
+        self.mstatus &= !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP);
+        self.mstatus |= mie << 7 | (self.privilege as u64) << 11;
         self.privilege = PrivilegeMode::Machine;
+        self.counters.count(EVENT_TRAPS);
         self.mepc = self.pc;
         self.mcause = trap.tcause as u32;
         self.mtval = trap.tval;
-        let mode = self.mtvec & 0b11;
-
-        match mode {
-            0 => {
-                self.pc = self.mtvec & !0b11;
-            }
-            1 => {
-                todo!()
-            }
-            _ => {
-                unreachable!()
-            }
-        }
+        self.pc = Self::trap_vector(self.mtvec, &trap);
 
         //TODO
 
@@ -163,259 +506,175 @@ impl CPU {
         //}
     }
 
+    // Same as machine trap, but with S mode registers
+    fn supervisor_trap(&mut self, trap: Trap) {
+        let sie = (self.mstatus & MSTATUS_SIE) >> 1;
+        let spp = (self.privilege == PrivilegeMode::Supervisor) as u64;
+        self.mstatus &= !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP);
+        self.mstatus |= sie << 5 | spp << 8;
+        self.privilege = PrivilegeMode::Supervisor;
+        self.counters.count(EVENT_TRAPS);
+        self.sepc = self.pc;
+        self.scause = trap.tcause as u32;
+        self.stval = trap.tval;
+        self.pc = Self::trap_vector(self.stvec, &trap);
+    }
+    // Handler address from mtvec or stvec, vectored mode only applies to interrupts
+    fn trap_vector(tvec: u32, trap: &Trap) -> u32 {
+        let base = tvec & !0b11;
+        match tvec & 0b11 {
+            1 if trap.is_interupt() => base.wrapping_add(4 * (trap.get_trap_cause() & 0x7FFF_FFFF)),
+            _ => base,
+        }
+    }
+
     fn fetch(&mut self) -> Result<u32, Trap> {
         self.mmu.fetch_word(self.pc)
     }
-    fn execute(&mut self, instr: u32) -> Result<u8, Trap> {
+    // Selects handler and extracts operands, so executing cached instruction doesn't decode it again.
+    pub fn decode(instr: u32) -> DecodedInstruction {
         let opcode = get_opcode(instr);
-        let micro_opcode = opcode & 0b11;
-        if micro_opcode == 0b11 {
-            //Common instructions processing
-            match opcode {
-                0b0110111 => self.lui(instr),
-                0b0010111 => self.auipc(instr),
-                0b1101111 => self.jal(instr),
-                0b1100111 => match get_funct3(instr) {
-                    0 => self.jalr(instr),
-                    _ => Err(Trap {
-                        tcause: crate::traps::TrapType::IllegalInstruction,
-                        tval: instr,
-                    }),
-                },
-                0b1100011 => match get_funct3(instr) {
-                    0b000 => self.beq(instr),
-                    0b001 => self.bne(instr),
-                    0b100 => self.blt(instr),
-                    0b101 => self.bge(instr),
-                    0b110 => self.bltu(instr),
-                    0b111 => self.bgeu(instr),
-                    _ => Err(Trap {
-                        tcause: crate::traps::TrapType::IllegalInstruction,
-                        tval: instr,
-                    }),
-                },
-                0b0000011 => match get_funct3(instr) {
-                    0b000 => self.lb(instr),
-                    0b001 => self.lh(instr),
-                    0b010 => self.lw(instr),
-                    0b100 => self.lbu(instr),
-                    0b101 => self.lhu(instr),
-                    _ => Err(Trap {
-                        tcause: crate::traps::TrapType::IllegalInstruction,
-                        tval: instr,
-                    }),
-                },
-                0b0100011 => match get_funct3(instr) {
-                    0b000 => self.sb(instr),
-                    0b001 => self.sh(instr),
-                    0b010 => self.sw(instr),
-                    _ => Err(Trap {
-                        tcause: crate::traps::TrapType::IllegalInstruction,
-                        tval: instr,
-                    }),
-                },
-                0b0010011 => match get_funct3(instr) {
-                    0b000 => self.addi(instr),
-                    0b010 => self.slti(instr),
-                    0b011 => self.sltiu(instr),
-                    0b100 => self.xori(instr),
-                    0b110 => self.ori(instr),
-                    0b111 => self.andi(instr),
-                    0b001 => match get_funct7(instr) {
-                        0 => self.slli(instr),
-                        _ => Err(Trap {
-                            tcause: crate::traps::TrapType::IllegalInstruction,
-                            tval: instr,
-                        }),
-                    },
-                    0b101 => match get_funct7(instr) {
-                        0 => self.srli(instr),
-                        0b0100000 => self.srai(instr),
-                        _ => Err(Trap {
-                            tcause: crate::traps::TrapType::IllegalInstruction,
-                            tval: instr,
-                        }),
-                    },
-                    _ => unreachable!(),
-                },
-                0b0110011 => match get_funct3(instr) {
-                    0b000 => match get_funct7(instr) {
-                        0 => self.add(instr),
-                        1 => self.mul(instr),
-                        0b0100000 => self.sub(instr),
-                        _ => Err(Trap {
-                            tcause: crate::traps::TrapType::IllegalInstruction,
-                            tval: instr,
-                        }),
-                    },
-                    0b001 => match get_funct7(instr) {
-                        0 => self.sll(instr),
-                        1 => self.mulh(instr),
-                        _ => Err(Trap {
-                            tcause: crate::traps::TrapType::IllegalInstruction,
-                            tval: instr,
-                        }),
-                    },
-                    0b010 => match get_funct7(instr) {
-                        0 => self.slt(instr),
-                        1 => self.mulhsu(instr),
-                        _ => Err(Trap {
-                            tcause: crate::traps::TrapType::IllegalInstruction,
-                            tval: instr,
-                        }),
-                    },
-                    0b011 => match get_funct7(instr) {
-                        0 => self.sltu(instr),
-                        1 => self.mulhu(instr),
-                        _ => Err(Trap {
-                            tcause: crate::traps::TrapType::IllegalInstruction,
-                            tval: instr,
-                        }),
-                    },
-                    0b100 => match get_funct7(instr) {
-                        0 => self.xor(instr),
-                        1 => self.div(instr),
-                        _ => Err(Trap {
-                            tcause: crate::traps::TrapType::IllegalInstruction,
-                            tval: instr,
-                        }),
-                    },
-                    0b101 => match get_funct7(instr) {
-                        0 => self.srl(instr),
-                        1 => self.divu(instr),
-                        0b0100000 => self.sra(instr),
-                        _ => Err(Trap {
-                            tcause: crate::traps::TrapType::IllegalInstruction,
-                            tval: instr,
-                        }),
-                    },
-                    0b110 => match get_funct7(instr) {
-                        0 => self.or(instr),
-                        1 => self.rem(instr),
-                        _ => Err(Trap {
-                            tcause: crate::traps::TrapType::IllegalInstruction,
-                            tval: instr,
-                        }),
-                    },
-                    0b111 => match get_funct7(instr) {
-                        0 => self.and(instr),
-                        1 => self.rem(instr),
-                        _ => Err(Trap {
-                            tcause: crate::traps::TrapType::IllegalInstruction,
-                            tval: instr,
-                        }),
-                    },
-                    _ => unreachable!(),
-                },
-                0b0001111 => match get_funct3(instr) {
-                    0b000 => self.fence(instr),
-                    0b001 => self.fence_i(instr),
-                    _ => Err(Trap {
-                        tcause: crate::traps::TrapType::IllegalInstruction,
-                        tval: instr,
-                    }),
-                },
-                0b1110011 => match get_funct3(instr) {
-                    0b000 => match get_imm_i_type(instr) {
-                        0 => self.ecall(instr),
-                        1 => self.ebreak(instr),
-
-                        a @ _ => {
-                            if get_rs1(instr) == 0 && get_rd(instr) == 0 {
-                                match a {
-                                    0b000100000010 => self.sret(instr),
-                                    0b001100000010 => self.mret(instr),
-                                    0b000100000101 => self.wfi(instr),
-                                    _ => Err(Trap {
-                                        tcause: crate::traps::TrapType::IllegalInstruction,
-                                        tval: instr,
-                                    }),
-                                }
-                            } else {
-                                Err(Trap {
-                                    tcause: crate::traps::TrapType::IllegalInstruction,
-                                    tval: instr,
-                                })
-                            }
-                        }
-                    },
-                    0b001 => self.csrrw(instr),
-                    0b010 => self.csrrs(instr),
-                    0b011 => self.csrrc(instr),
-                    0b101 => self.csrrwi(instr),
-                    0b110 => self.csrrsi(instr),
-                    0b111 => self.csrrci(instr),
-                    _ => Err(Trap {
-                        tcause: crate::traps::TrapType::IllegalInstruction,
-                        tval: instr,
-                    }),
-                },
-                0b0101111 => match get_funct3(instr) {
-                    0b010 => match get_rs3(instr) {
-                        0b00010 => self.lr_w(instr),
-                        0b00011 => self.sc_w(instr),
-                        0b00001 => self.amoswap_w(instr),
-                        0b00000 => self.amoadd_w(instr),
-                        0b00100 => self.amoxor_w(instr),
-                        0b01100 => self.amoand_w(instr),
-                        0b01000 => self.amoor_w(instr),
-                        0b10000 => self.amomin_w(instr),
-                        0b10100 => self.amomax_w(instr),
-                        0b11000 => self.amominu_w(instr),
-                        0b11100 => self.amomaxu_w(instr),
-                        _ => Err(Trap {
-                            tcause: crate::traps::TrapType::IllegalInstruction,
-                            tval: instr,
-                        }),
-                    },
-                    _ => Err(Trap {
-                        tcause: crate::traps::TrapType::IllegalInstruction,
-                        tval: instr,
-                    }),
-                },
-                _ => Err(Trap {
-                    tcause: crate::traps::TrapType::IllegalInstruction,
-                    tval: instr,
-                }),
-            }
-            .map(|_| 4)
-        } else {
-            let compressed = instr as u16;
-            match micro_opcode {
-                0b00 => match get_compressed_func3(compressed) {
-                    0b000 => match compressed {
-                        0 => Err(Trap {
-                            tcause: crate::traps::TrapType::IllegalInstruction,
-                            tval: instr,
-                        }),
-                        _ => todo!(),
-                    },
-                    _ => todo!(),
+        if opcode & 0b11 != 0b11 {
+            return Self::decode_compressed(instr as u16);
+        }
+        let funct3 = get_funct3(instr);
+        let funct7 = get_funct7(instr);
+        let handler: Handler = match (opcode, funct3) {
+            (0b0110111, _) => Self::lui,
+            (0b0010111, _) => Self::auipc,
+            (0b1101111, _) => Self::jal,
+            (0b1100111, 0b000) => Self::jalr,
+            (0b1100011, 0b000) => Self::beq,
+            (0b1100011, 0b001) => Self::bne,
+            (0b1100011, 0b100) => Self::blt,
+            (0b1100011, 0b101) => Self::bge,
+            (0b1100011, 0b110) => Self::bltu,
+            (0b1100011, 0b111) => Self::bgeu,
+            (0b0000011, 0b000) => Self::lb,
+            (0b0000011, 0b001) => Self::lh,
+            (0b0000011, 0b010) => Self::lw,
+            (0b0000011, 0b100) => Self::lbu,
+            (0b0000011, 0b101) => Self::lhu,
+            (0b0100011, 0b000) => Self::sb,
+            (0b0100011, 0b001) => Self::sh,
+            (0b0100011, 0b010) => Self::sw,
+            (0b0010011, 0b000) => Self::addi,
+            (0b0010011, 0b010) => Self::slti,
+            (0b0010011, 0b011) => Self::sltiu,
+            (0b0010011, 0b100) => Self::xori,
+            (0b0010011, 0b110) => Self::ori,
+            (0b0010011, 0b111) => Self::andi,
+            (0b0010011, 0b001) if funct7 == 0 => Self::slli,
+            (0b0010011, 0b101) if funct7 == 0 => Self::srli,
+            (0b0010011, 0b101) if funct7 == 0b0100000 => Self::srai,
+            (0b0110011, _) => match (funct7, funct3) {
+                (0, 0b000) => Self::add,
+                (0b0100000, 0b000) => Self::sub,
+                (0, 0b001) => Self::sll,
+                (0, 0b010) => Self::slt,
+                (0, 0b011) => Self::sltu,
+                (0, 0b100) => Self::xor,
+                (0, 0b101) => Self::srl,
+                (0b0100000, 0b101) => Self::sra,
+                (0, 0b110) => Self::or,
+                (0, 0b111) => Self::and,
+                (1, 0b000) => Self::mul,
+                (1, 0b001) => Self::mulh,
+                (1, 0b010) => Self::mulhsu,
+                (1, 0b011) => Self::mulhu,
+                (1, 0b100) => Self::div,
+                (1, 0b101) => Self::divu,
+                (1, 0b110) => Self::rem,
+                (1, 0b111) => Self::remu,
+                _ => Self::illegal,
+            },
+            (0b0001111, 0b000) => Self::fence,
+            (0b0001111, 0b001) => Self::fence_i,
+            (0b1110011, 0b000) => match get_csr_num(instr) {
+                0 => Self::ecall,
+                1 => Self::ebreak,
+                _ if funct7 == 0b0001001 && get_rd(instr) == 0 => Self::sfence_vma,
+                a if get_rs1(instr) == 0 && get_rd(instr) == 0 => match a {
+                    0b000100000010 => Self::sret,
+                    0b001100000010 => Self::mret,
+                    0b000100000101 => Self::wfi,
+                    _ => Self::illegal,
                 },
-                0b01 => todo!("0b{compressed:00$b}| opcode {micro_opcode}", 16),
-                0b10 => todo!("0b{compressed:00$b}| opcode {micro_opcode}", 16),
-                _ => unreachable!(),
-            }
+                _ => Self::illegal,
+            },
+            (0b1110011, 0b001) => Self::csrrw,
+            (0b1110011, 0b010) => Self::csrrs,
+            (0b1110011, 0b011) => Self::csrrc,
+            (0b1110011, 0b101) => Self::csrrwi,
+            (0b1110011, 0b110) => Self::csrrsi,
+            (0b1110011, 0b111) => Self::csrrci,
+            (0b0101111, 0b010) => match get_rs3(instr) {
+                0b00010 => Self::lr_w,
+                0b00011 => Self::sc_w,
+                0b00001 => Self::amoswap_w,
+                0b00000 => Self::amoadd_w,
+                0b00100 => Self::amoxor_w,
+                0b01100 => Self::amoand_w,
+                0b01000 => Self::amoor_w,
+                0b10000 => Self::amomin_w,
+                0b10100 => Self::amomax_w,
+                0b11000 => Self::amominu_w,
+                0b11100 => Self::amomaxu_w,
+                _ => Self::illegal,
+            },
+            _ => Self::illegal,
+        };
+        let imm = match opcode {
+            0b0110111 | 0b0010111 => get_imm_u_type(instr),
+            0b1101111 => get_imm_j_type(instr),
+            0b1100011 => get_imm_b_type(instr),
+            0b0100011 => get_imm_s_type(instr),
+            0b1110011 => get_csr_num(instr) as u32,
+            _ => get_imm_i_type(instr),
+        };
+        DecodedInstruction {
+            handler,
+            instr,
+            imm,
+            rd: get_rd(instr),
+            rs1: get_rs1(instr),
+            rs2: get_rs2(instr),
+            len: 4,
+        }
+    }
+    // C extension isn't implemented, so every 16-bit encoding is illegal
+    fn decode_compressed(compressed: u16) -> DecodedInstruction {
+        DecodedInstruction {
+            handler: Self::illegal,
+            instr: compressed as u32,
+            imm: 0,
+            rd: 0,
+            rs1: 0,
+            rs2: 0,
+            len: 2,
         }
     }
-    fn timer_reader(&self) -> u64 {
-        self.time_crs_start.elapsed().as_micros() as u64
+    fn illegal(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        Err(Trap {
+            tcause: crate::traps::TrapType::IllegalInstruction,
+            tval: d.instr,
+        })
     }
-    fn lui(&mut self, instr: u32) -> Result<(), Trap> {
-        let rd = get_rd(instr);
-        let imm = get_imm_u_type(instr);
+    fn lui(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rd = d.rd;
+        let imm = d.imm;
         self.set_x(rd, imm);
         Ok(())
     }
-    fn auipc(&mut self, instr: u32) -> Result<(), Trap> {
-        let rd = get_rd(instr);
-        let imm = get_imm_u_type(instr);
+    fn auipc(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rd = d.rd;
+        let imm = d.imm;
         self.set_x(rd, imm.wrapping_add(self.pc));
         Ok(())
     }
-    fn jal(&mut self, instr: u32) -> Result<(), Trap> {
-        let rd = get_rd(instr);
-        let imm = get_imm_j_type(instr);
+    fn jal(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rd = d.rd;
+        let imm = d.imm;
         let t = self.pc.wrapping_add(imm);
         //if t & 0b11 == 0 {
         self.set_x(rd, self.pc.wrapping_add(4));
@@ -428,10 +687,10 @@ impl CPU {
         //    })
         //}
     }
-    fn jalr(&mut self, instr: u32) -> Result<(), Trap> {
-        let rd = get_rd(instr);
-        let rs1 = get_rs1(instr);
-        let imm = get_imm_i_type(instr);
+    fn jalr(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rd = d.rd;
+        let rs1 = d.rs1;
+        let imm = d.imm;
         let t = (self.get_x(rs1).wrapping_add(imm)) & !1;
         //if t & 0b11 == 0 {
         self.set_x(rd, self.pc + 4);
@@ -444,137 +703,167 @@ impl CPU {
         //    })
         //}
     }
-    fn beq(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let imm = get_imm_b_type(instr);
+    fn beq(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let imm = d.imm;
         if self.get_x(rs1) == self.get_x(rs2) {
             self.pc = self.pc.wrapping_sub(4).wrapping_add(imm);
+            self.counters.count(EVENT_TAKEN_BRANCHES);
         }
         Ok(())
     }
-    fn bne(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let imm = get_imm_b_type(instr);
+    fn bne(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let imm = d.imm;
         if self.get_x(rs1) != self.get_x(rs2) {
             self.pc = self.pc.wrapping_sub(4).wrapping_add(imm);
+            self.counters.count(EVENT_TAKEN_BRANCHES);
         }
         Ok(())
     }
-    fn blt(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let imm = get_imm_b_type(instr);
+    fn blt(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let imm = d.imm;
         if (self.get_x(rs1) as i32) < (self.get_x(rs2) as i32) {
             self.pc = self.pc.wrapping_sub(4).wrapping_add(imm);
+            self.counters.count(EVENT_TAKEN_BRANCHES);
         }
         Ok(())
     }
-    fn bge(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let imm = get_imm_b_type(instr);
+    fn bge(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let imm = d.imm;
         if (self.get_x(rs1) as i32) >= (self.get_x(rs2) as i32) {
             self.pc = self.pc.wrapping_sub(4).wrapping_add(imm);
+            self.counters.count(EVENT_TAKEN_BRANCHES);
         }
         Ok(())
     }
-    fn bltu(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let imm = get_imm_b_type(instr);
+    fn bltu(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let imm = d.imm;
         if self.get_x(rs1) < self.get_x(rs2) {
             self.pc = self.pc.wrapping_sub(4).wrapping_add(imm);
+            self.counters.count(EVENT_TAKEN_BRANCHES);
         }
         Ok(())
     }
-    fn bgeu(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let imm = get_imm_b_type(instr);
+    fn bgeu(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let imm = d.imm;
         if self.get_x(rs1) >= self.get_x(rs2) {
             self.pc = self.pc.wrapping_sub(4).wrapping_add(imm);
+            self.counters.count(EVENT_TAKEN_BRANCHES);
         }
         Ok(())
     }
-    fn lb(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn lb(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         let address = self.get_x(rs1).wrapping_add(imm);
+        self.check_pmp(address, 1, Access::Read)?;
         let res = self.mmu.read_byte(address)? as i8 as i32 as u32;
+        self.counters.count(EVENT_LOADS);
         self.set_x(rd, res);
         Ok(())
     }
-    fn lh(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn lh(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         let address = self.get_x(rs1).wrapping_add(imm);
+        self.check_pmp(address, 2, Access::Read)?;
         let res = self.mmu.read_halfword(address)? as i16 as i32 as u32;
+        self.counters.count(EVENT_LOADS);
         self.set_x(rd, res);
         Ok(())
     }
-    fn lw(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn lw(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         let address = self.get_x(rs1).wrapping_add(imm);
+        self.check_pmp(address, 4, Access::Read)?;
+        self.sync_bus_time();
         let res = self.mmu.read_word(address)?;
+        self.counters.count(EVENT_LOADS);
         self.set_x(rd, res);
         Ok(())
     }
-    fn lbu(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn lbu(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         let address = self.get_x(rs1).wrapping_add(imm);
+        self.check_pmp(address, 1, Access::Read)?;
         let res = self.mmu.read_byte(address)? as u32;
+        self.counters.count(EVENT_LOADS);
         self.set_x(rd, res);
         Ok(())
     }
-    fn lhu(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn lhu(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         let address = self.get_x(rs1).wrapping_add(imm);
+        self.check_pmp(address, 2, Access::Read)?;
         let res = self.mmu.read_halfword(address)? as u32;
+        self.counters.count(EVENT_LOADS);
         self.set_x(rd, res);
         Ok(())
     }
-    fn sb(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let imm = get_imm_s_type(instr);
+    fn sb(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let imm = d.imm;
         let address = self.get_x(rs1).wrapping_add(imm);
-        self.mmu.write_byte(address, self.get_x(rs2) as _)
+        self.check_pmp(address, 1, Access::Write)?;
+        self.mmu.write_byte(address, self.get_x(rs2) as _)?;
+        self.counters.count(EVENT_STORES);
+        self.take_bus_requests();
+        Ok(())
     }
-    fn sh(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let imm = get_imm_s_type(instr);
+    fn sh(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let imm = d.imm;
         let address = self.get_x(rs1).wrapping_add(imm);
-        self.mmu.write_halfword(address, self.get_x(rs2) as _)
+        self.check_pmp(address, 2, Access::Write)?;
+        self.mmu.write_halfword(address, self.get_x(rs2) as _)?;
+        self.counters.count(EVENT_STORES);
+        self.take_bus_requests();
+        Ok(())
     }
-    fn sw(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let imm = get_imm_s_type(instr);
+    fn sw(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let imm = d.imm;
         let address = self.get_x(rs1).wrapping_add(imm);
-        self.mmu.write_word(address, self.get_x(rs2) as _)
+        self.check_pmp(address, 4, Access::Write)?;
+        self.sync_bus_time();
+        self.mmu.write_word(address, self.get_x(rs2) as _)?;
+        self.counters.count(EVENT_STORES);
+        self.take_bus_requests();
+        Ok(())
     }
-    fn addi(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn addi(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         self.set_x(rd, self.get_x(rs1).wrapping_add(imm));
         Ok(())
     }
-    fn slti(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn slti(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         self.set_x(
             rd,
             if (self.get_x(rs1) as i32) < (imm as i32) {
@@ -585,77 +874,77 @@ impl CPU {
         );
         Ok(())
     }
-    fn sltiu(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn sltiu(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         self.set_x(rd, if self.get_x(rs1) < imm { 1 } else { 0 });
         Ok(())
     }
-    fn xori(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn xori(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         self.set_x(rd, self.get_x(rs1) ^ imm);
         Ok(())
     }
-    fn ori(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn ori(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         self.set_x(rd, self.get_x(rs1) | imm);
         Ok(())
     }
-    fn andi(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn andi(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         self.set_x(rd, self.get_x(rs1) & imm);
         Ok(())
     }
-    fn slli(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn slli(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         let shamt = imm & 0b11111;
         self.set_x(rd, self.get_x(rs1).wrapping_shl(shamt));
         Ok(())
     }
-    fn srli(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn srli(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         let shamt = imm & 0b11111;
         self.set_x(rd, self.get_x(rs1).wrapping_shr(shamt));
         Ok(())
     }
-    fn srai(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        let imm = get_imm_i_type(instr);
+    fn srai(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rd = d.rd;
+        let imm = d.imm;
         let shamt = imm & 0b11111;
         self.set_x(rd, (self.get_x(rs1) as i32).wrapping_shr(shamt) as u32);
         Ok(())
     }
-    fn add(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn add(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         self.set_x(rd, self.get_x(rs1).wrapping_add(self.get_x(rs2)));
         Ok(())
     }
-    fn sub(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn sub(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         self.set_x(rd, self.get_x(rs1).wrapping_sub(self.get_x(rs2)));
         Ok(())
     }
 
-    fn slt(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn slt(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         self.set_x(
             rd,
             if (self.get_x(rs1) as i32) < (self.get_x(rs2) as i32) {
@@ -666,10 +955,10 @@ impl CPU {
         );
         Ok(())
     }
-    fn sltu(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn sltu(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         self.set_x(
             rd,
             if self.get_x(rs1) < self.get_x(rs2) {
@@ -680,65 +969,80 @@ impl CPU {
         );
         Ok(())
     }
-    fn xor(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn xor(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         self.set_x(rd, self.get_x(rs1) ^ self.get_x(rs2));
         Ok(())
     }
-    fn sll(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn sll(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         self.set_x(rd, self.get_x(rs1).wrapping_shl(self.get_x(rs2) & 0b11111));
         Ok(())
     }
-    fn srl(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn srl(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         self.set_x(rd, self.get_x(rs1).wrapping_shr(self.get_x(rs2) & 0b11111));
         Ok(())
     }
-    fn sra(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn sra(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         self.set_x(
             rd,
             (self.get_x(rs1) as i32).wrapping_shr(self.get_x(rs2) & 0b11111) as u32,
         );
         Ok(())
     }
-    fn or(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn or(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         self.set_x(rd, self.get_x(rs1) | self.get_x(rs2));
         Ok(())
     }
-    fn and(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn and(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         self.set_x(rd, self.get_x(rs1) & self.get_x(rs2));
         Ok(())
     }
-    fn fence(&mut self, instr: u32) -> Result<(), Trap> {
+    fn fence(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
         Ok(())
     }
-    fn fence_i(&mut self, instr: u32) -> Result<(), Trap> {
+    fn fence_i(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.decode_cache.clear();
         Ok(())
     }
-    fn ecall(&mut self, instr: u32) -> Result<(), Trap> {
-        let exception_type = TrapType::EnvironmentCallFromMMode; //TODO: Expand when adding privileges
-        return Err(Trap {
+    fn ecall(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let exception_type = match self.privilege {
+            PrivilegeMode::User => TrapType::EnvironmentCallFromUMode,
+            PrivilegeMode::Supervisor => TrapType::EnvironmentCallFromSMode,
+            _ => TrapType::EnvironmentCallFromMMode,
+        };
+        // mtval is zero for ecall
+        Err(Trap {
             tcause: exception_type,
-            tval: self.pc,
-        });
+            tval: 0,
+        })
     }
-    fn ebreak(&mut self, instr: u32) -> Result<(), Trap> {
+    #[allow(clippy::needless_return)]
+    fn ebreak(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        // Host services are for M mode programs only, lower modes get normal breakpoint
+        if self.privilege == PrivilegeMode::Machine
+            && self.mmu.semihosting.is_some()
+            && semihosting::is_call(&self.mmu, self.pc)
+        {
+            semihosting::handle_call(self);
+            return Ok(());
+        }
         let exception_type = TrapType::Breakpoint;
         return Err(Trap {
             tcause: exception_type,
@@ -746,192 +1050,140 @@ impl CPU {
         });
     }
 
-    fn get_csr(&self, csr: u16) -> Result<u32, Trap> {
-        //TODO: privileges check
-        Ok(match csr {
-            0x340 => self.mscratch,
-            0x305 => self.mtvec,
-            0x304 => self.mie,
-
-            0x344 => self.mip,
-            0x341 => self.mepc,
-            0x342 => self.mcause,
-            0x343 => self.mtval,
-            0xf11 => 0xff0ff0ff,                              //vendorId
-            0xf12 => 0x0,                                     //marchid
-            0xf13 => 0x0,                                     //mimpid
-            0xf14 => 0x0,                                     //mhartid
-            0xf15 => 0x0,                                     //mconfigptr
-            0x301 => 0b01_0_10000_00000000_00010001_00000001, //misa, XLEN=32, IMA+X
-
-            0x300 => self.mstatus as u32, //mstatus
-            0x310 => (self.mstatus >> 32) as u32, //mstatus
-
-
-            0xC00 => self.cycle as u32,
-            0xC80 => (self.cycle >> 32) as u32,
-            
-
-
-            0xC01 => self.timer_reader() as u32, //time
-            0xC81 => (self.timer_reader() >> 32) as u32, //timeh
-            _ => todo!("CSR {csr:0x} not implemented"),
-        })
-    }
-    //Is register writable
-    fn set_csr(&mut self, csr: u16, new_val: u32) -> Result<bool, Trap> {
-        //TODO: privileges check
-        match csr {
-            0x340 => self.mscratch = new_val,
-            0x305 => self.mtvec = new_val,
-            0x304 => self.mie = new_val,
-            0xC00 => {
-                return Ok(false);
-            } //cyclel
-            0xC80 => {
-                return Ok(false);
-            } //cycleh
-            0x344 => self.mip = new_val,
-            0x341 => self.mepc = new_val,
-            0x300 => { self.mstatus &= !(u32::MAX as u64); self.mstatus |= new_val as u64 }, //mstatus
-            0x310 => { self.mstatus &= u32::MAX as u64; self.mstatus |= (new_val as u64) << 32 },
-            0x342 => self.mcause = new_val,
-            0x343 => self.mtval = new_val,
-            0xf11..=0xf15 => {
-                return Ok(false);
-            } //vendorId
-            0x301 => {
-                return Ok(false);
-            } //misa, XLEN=32, IMA+X
-            _ => todo!("CSR 0x{csr:0x} not implemented"),
-        };
-        Ok(true)
-    }
-    fn sret(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
+    // None if CSR isn't implemented or can't be read from current privilege
+    pub fn read_csr(&self, csr: u16) -> Option<u32> {
+        self.csrs.get(csr).and_then(|entry| (entry.read)(self, csr))
+    }
+    // Bits outside of WARL mask keep their value, access isn't checked
+    pub fn write_csr(&mut self, csr: u16, value: u32) {
+        if let Some(entry) = self.csrs.get(csr) {
+            let old = (entry.read)(self, csr).unwrap_or(0);
+            (entry.write)(self, csr, old & !entry.write_mask | value & entry.write_mask);
+        }
     }
-    fn mret(&mut self, instr: u32) -> Result<(), Trap> {
-        //TODO Do csr register shenenigans when implement multiple levels of priveleges.
-        self.pc = self.mepc.wrapping_sub(4);
+    fn sret(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        if self.privilege == PrivilegeMode::User {
+            return self.illegal(d);
+        }
+        let spie = (self.mstatus & MSTATUS_SPIE) >> 5;
+        let spp = (self.mstatus & MSTATUS_SPP) >> 8;
+        self.mstatus &= !(MSTATUS_SIE | MSTATUS_SPP);
+        self.mstatus |= spie << 1 | MSTATUS_SPIE;
+        self.privilege = if spp == 1 { PrivilegeMode::Supervisor } else { PrivilegeMode::User };
+        self.pc = self.sepc.wrapping_sub(4);
         Ok(())
     }
-    fn wfi(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
-    }
-    fn csrrw(&mut self, instr: u32) -> Result<(), Trap> {
-        let csr = get_csr_num(instr);
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        if rd != 0 {
-            let val = self.get_csr(csr)?;
-            if !self.set_csr(csr, self.get_x(rs1))? {
-                todo!("Handle write in read-only registers")
-            }
-            self.set_x(rd, val)
-        } else {
-            if !self.set_csr(csr, self.get_x(rs1))? {
-                todo!("Handle write in read-only registers")
-            }
+    // There is no address translation cache to flush
+    fn sfence_vma(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        if self.privilege == PrivilegeMode::User {
+            return self.illegal(d);
         }
         Ok(())
     }
-    fn csrrs(&mut self, instr: u32) -> Result<(), Trap> {
-        let csr = get_csr_num(instr);
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        if rs1 != 0 {
-            let val = self.get_csr(csr)?;
-            let set_bits = val | self.get_x(rs1);
-            if !self.set_csr(csr, set_bits)? {
-                todo!("Handle read in write-only registers")
-            }
-            self.set_x(rd, val)
-        } else {
-            let val = self.get_csr(csr)?;
-            self.set_x(rd, val)
+    fn mret(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        if self.privilege != PrivilegeMode::Machine {
+            return self.illegal(d);
         }
+        let mpie = (self.mstatus & MSTATUS_MPIE) >> 7;
+        let mpp = (self.mstatus & MSTATUS_MPP) >> 11;
+        // MIE is restored, MPP is set to least privileged mode
+        self.mstatus &= !(MSTATUS_MIE | MSTATUS_MPP);
+        self.mstatus |= mpie << 3 | MSTATUS_MPIE;
+        self.privilege = PrivilegeMode::from_bits(mpp as u8);
+        self.pc = self.mepc.wrapping_sub(4);
         Ok(())
     }
-    fn csrrc(&mut self, instr: u32) -> Result<(), Trap> {
-        let csr = get_csr_num(instr);
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        if rs1 != 0 {
-            let val = self.get_csr(csr)?;
-            let set_bits = val & !self.get_x(rs1);
-            if !self.set_csr(csr, set_bits)? {
-                todo!("Handle read in write-only registers")
-            }
-            self.set_x(rd, val)
-        } else {
-            let val = self.get_csr(csr)?;
-            self.set_x(rd, val)
-        }
+    fn wfi(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.wfi = true;
         Ok(())
     }
-    fn csrrwi(&mut self, instr: u32) -> Result<(), Trap> {
-        let csr = get_csr_num(instr);
-        let rs1 = get_rs1(instr);
-        let rd = get_rd(instr);
-        if rd != 0 {
-            let val = self.get_csr(csr)?;
-            if !self.set_csr(csr, rs1 as u32)? {
-                todo!("Handle write in read-only registers")
-            }
-            self.set_x(rd, val)
-        } else {
-            if !self.set_csr(csr, self.get_x(rs1))? {
-                todo!("Handle write in read-only registers")
-            }
+    // Shared by all Zicsr instructions, funct3 bit 2 selects immediate operand
+    fn csr_instruction(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let illegal = Trap {
+            tcause: TrapType::IllegalInstruction,
+            tval: d.instr,
+        };
+        let csr = d.imm as u16;
+        let funct3 = get_funct3(d.instr);
+        let operand = if funct3 & 0b100 == 0 { self.get_x(d.rs1) } else { d.rs1 as u32 };
+        // csrrw(i) always writes, set/clear variants only with non-zero rs1/uimm
+        let is_write = funct3 & 0b11 == 0b01;
+        let writes = is_write || d.rs1 != 0;
+        let entry = self.csrs.get(csr).ok_or(illegal)?;
+        if (self.privilege as u8) < csr::required_privilege(csr) as u8 || writes && csr::is_read_only(csr) {
+            return Err(illegal);
+        }
+        // csrrw with rd = x0 doesn't read, so read side effects and checks are skipped
+        let old = if is_write && d.rd == 0 { 0 } else { (entry.read)(self, csr).ok_or(illegal)? };
+        if writes {
+            let value = match funct3 & 0b11 {
+                0b01 => operand,
+                0b10 => old | operand,
+                _ => old & !operand,
+            };
+            let value = old & !entry.write_mask | value & entry.write_mask;
+            (entry.write)(self, csr, value);
         }
-        Ok(())  
+        self.set_x(d.rd, old);
+        Ok(())
     }
-    fn csrrsi(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
+    fn csrrw(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.csr_instruction(d)
     }
-    fn csrrci(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
-        // Ok(())
+    fn csrrs(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.csr_instruction(d)
     }
-    fn mul(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn csrrc(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.csr_instruction(d)
+    }
+    fn csrrwi(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.csr_instruction(d)
+    }
+    fn csrrsi(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.csr_instruction(d)
+    }
+    fn csrrci(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.csr_instruction(d)
+    }
+    fn mul(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         let m = (self.get_x(rs1) as i32).wrapping_mul(self.get_x(rs2) as i32);
         self.set_x(rd, m as u32);
         Ok(())
     }
-    fn mulh(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn mulh(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         let m = (self.get_x(rs1) as i32 as i64).wrapping_mul(self.get_x(rs2) as i32 as i64);
         let m = (m as u64) >> 32;
         self.set_x(rd, m as u32);
         Ok(())
     }
-    fn mulhsu(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn mulhsu(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         let m = (self.get_x(rs1) as i32 as i64).wrapping_mul(self.get_x(rs2) as u64 as i64);
         let m = m >> 32;
         self.set_x(rd, m as u32);
         Ok(())
     }
-    fn mulhu(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn mulhu(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         let m = (self.get_x(rs1) as u64).wrapping_mul(self.get_x(rs2) as u64);
         let m = m >> 32;
         self.set_x(rd, m as u32);
         Ok(())
     }
-    fn div(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn div(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         let rs1 = self.get_x(rs1);
         let rs2 = self.get_x(rs2);
 
@@ -943,10 +1195,10 @@ impl CPU {
         self.set_x(rd, m as u32);
         Ok(())
     }
-    fn divu(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn divu(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         let rs1 = self.get_x(rs1);
         let rs2 = self.get_x(rs2);
 
@@ -958,10 +1210,10 @@ impl CPU {
         self.set_x(rd, m);
         Ok(())
     }
-    fn rem(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn rem(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         let rs1 = self.get_x(rs1);
         let rs2 = self.get_x(rs2);
 
@@ -973,53 +1225,103 @@ impl CPU {
         self.set_x(rd, m as u32);
         Ok(())
     }
-    fn remu(&mut self, instr: u32) -> Result<(), Trap> {
-        let rs1 = get_rs1(instr);
-        let rs2 = get_rs2(instr);
-        let rd = get_rd(instr);
+    fn remu(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let rs1 = d.rs1;
+        let rs2 = d.rs2;
+        let rd = d.rd;
         let rs1 = self.get_x(rs1);
         let rs2 = self.get_x(rs2);
 
         let m = if rs2 != 0 {
             rs1.wrapping_rem(rs2)
         } else {
-            u32::MAX
+            rs1
         };
         self.set_x(rd, m);
         Ok(())
     }
-    fn lr_w(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
+    fn lr_w(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let address = self.get_x(d.rs1);
+        if !address.is_multiple_of(4) {
+            return Err(Trap {
+                tcause: TrapType::LoadAddressMisaligned,
+                tval: address,
+            });
+        }
+        self.check_pmp(address, 4, Access::Read)?;
+        self.sync_bus_time();
+        let value = self.mmu.read_word(address)?;
+        self.mmu.reserve(self.hartid, address);
+        self.set_x(d.rd, value);
+        Ok(())
     }
-    fn sc_w(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
+    fn sc_w(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        let address = self.get_x(d.rs1);
+        if !address.is_multiple_of(4) {
+            return Err(Trap {
+                tcause: TrapType::StoreAddressMisaligned,
+                tval: address,
+            });
+        }
+        // Reservation is dropped whether store happens or not
+        if self.mmu.take_reservation(self.hartid) == Some(address) {
+            self.check_pmp(address, 4, Access::Write)?;
+            self.sync_bus_time();
+            self.mmu.write_word(address, self.get_x(d.rs2))?;
+            self.take_bus_requests();
+            self.set_x(d.rd, 0);
+        } else {
+            self.set_x(d.rd, 1);
+        }
+        Ok(())
     }
-    fn amoswap_w(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
+    // Read-modify-write, faults are reported as store faults
+    fn amo(&mut self, d: &DecodedInstruction, op: fn(u32, u32) -> u32) -> Result<(), Trap> {
+        let address = self.get_x(d.rs1);
+        if !address.is_multiple_of(4) {
+            return Err(Trap {
+                tcause: TrapType::StoreAddressMisaligned,
+                tval: address,
+            });
+        }
+        self.check_pmp(address, 4, Access::Read).map_err(|_| Access::Write.fault(address))?;
+        self.check_pmp(address, 4, Access::Write)?;
+        self.sync_bus_time();
+        let old = self.mmu.read_word(address).map_err(|_| Trap {
+            tcause: TrapType::StoreAccessFault,
+            tval: address,
+        })?;
+        self.mmu.write_word(address, op(old, self.get_x(d.rs2)))?;
+        self.take_bus_requests();
+        self.set_x(d.rd, old);
+        Ok(())
     }
-    fn amoadd_w(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
+    fn amoswap_w(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.amo(d, |_, b| b)
     }
-    fn amoxor_w(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
+    fn amoadd_w(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.amo(d, u32::wrapping_add)
     }
-    fn amoand_w(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
+    fn amoxor_w(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.amo(d, |a, b| a ^ b)
     }
-    fn amoor_w(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
+    fn amoand_w(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.amo(d, |a, b| a & b)
     }
-    fn amomin_w(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
+    fn amoor_w(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.amo(d, |a, b| a | b)
     }
-    fn amomax_w(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
+    fn amomin_w(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.amo(d, |a, b| (a as i32).min(b as i32) as u32)
     }
-    fn amominu_w(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
+    fn amomax_w(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.amo(d, |a, b| (a as i32).max(b as i32) as u32)
     }
-    fn amomaxu_w(&mut self, instr: u32) -> Result<(), Trap> {
-        todo!()
+    fn amominu_w(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.amo(d, u32::min)
+    }
+    fn amomaxu_w(&mut self, d: &DecodedInstruction) -> Result<(), Trap> {
+        self.amo(d, u32::max)
     }
 
     fn c_addi4spn(&mut self, instr: u16) -> Result<(), Trap> {
@@ -0,0 +1,375 @@
+// Table of implemented CSRs. Access rules come from CSR address: bits 11:10 == 0b11 mark
+// read-only registers and bits 9:8 hold the lowest privilege that can access it.
+// CSRs missing from table raise illegal instruction.
+
+use crate::{
+    counters::COUNTER_TIME,
+    cpu::{PrivilegeMode, CPU, MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP, MSTATUS_SIE, MSTATUS_SPIE, MSTATUS_SPP},
+};
+
+// None makes access illegal, e.g. counter not enabled for current privilege
+pub type CsrRead = fn(&CPU, u16) -> Option<u32>;
+// Gets value where bits outside of write mask are already kept from old value
+pub type CsrWrite = fn(&mut CPU, u16, u32);
+
+pub const CSR_COUNT: usize = 4096;
+
+// RV32 IMA with supervisor and user modes
+pub const MISA: u32 = 1 << 30 | 1 << 20 | 1 << 18 | 1 << 12 | 1 << 8 | 1;
+// Machine software, timer and external interrupts
+pub const MACHINE_INTERRUPTS: u32 = 1 << 3 | 1 << 7 | 1 << 11;
+// Same for supervisor, these can be delegated
+pub const SUPERVISOR_INTERRUPTS: u32 = 1 << 1 | 1 << 5 | 1 << 9;
+// Exceptions that can be delegated, all except ecall from M mode
+pub const DELEGABLE_EXCEPTIONS: u32 = 0x3FF | 1 << 12 | 1 << 13 | 1 << 15;
+// Fields of mstatus visible through sstatus
+pub const SSTATUS_MASK: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP;
+// MODE field of satp, set is Sv32
+pub const SATP_MODE: u32 = 1 << 31;
+
+#[derive(Clone, Copy)]
+pub struct Csr {
+    pub read: CsrRead,
+    pub write: CsrWrite,
+    // WARL fields, writes to other bits are ignored
+    pub write_mask: u32,
+}
+
+impl Csr {
+    pub fn new(read: CsrRead, write: CsrWrite, write_mask: u32) -> Self {
+        Csr {
+            read,
+            write,
+            write_mask,
+        }
+    }
+    pub fn read_only(read: CsrRead) -> Self {
+        Self::new(read, |_, _, _| {}, 0)
+    }
+}
+
+// Read-only registers with fixed value
+fn read_constant(_: &CPU, csr: u16) -> Option<u32> {
+    Some(match csr {
+        0x301 => MISA,
+        0xF11 => 0xff0ff0ff, //vendorId
+        _ => 0,
+    })
+}
+
+pub fn is_read_only(csr: u16) -> bool {
+    csr >> 10 == 0b11
+}
+
+pub fn required_privilege(csr: u16) -> PrivilegeMode {
+    PrivilegeMode::from_bits(((csr >> 8) & 0b11) as u8)
+}
+
+// Lower privileges need permission from mcounteren, user mode from scounteren too
+fn counter_accessible(cpu: &CPU, index: usize) -> bool {
+    let bit = 1 << index;
+    match cpu.privilege {
+        PrivilegeMode::Machine => true,
+        PrivilegeMode::Supervisor => cpu.counters.mcounteren & bit != 0,
+        _ => cpu.counters.mcounteren & cpu.counters.scounteren & bit != 0,
+    }
+}
+
+fn read_counter(cpu: &CPU, csr: u16) -> Option<u32> {
+    let index = (csr & 0x1F) as usize;
+    // Unprivileged copies at 0xC00 are checked against counteren
+    if csr >> 8 == 0xC && !counter_accessible(cpu, index) {
+        return None;
+    }
+    let value = match index {
+        COUNTER_TIME => cpu.time(),
+        _ => cpu.counters.read(index, cpu.instret),
+    };
+    Some(if csr & 0x80 == 0 { value as u32 } else { (value >> 32) as u32 })
+}
+
+fn write_counter(cpu: &mut CPU, csr: u16, value: u32) {
+    let index = (csr & 0x1F) as usize;
+    let old = cpu.counters.read(index, cpu.instret);
+    let value = if csr & 0x80 == 0 {
+        old & !(u32::MAX as u64) | value as u64
+    } else {
+        old & u32::MAX as u64 | (value as u64) << 32
+    };
+    // Write takes effect after this instruction retires, so it isn't counted
+    cpu.counters.write(index, value, cpu.instret + 1);
+}
+
+fn write_mstatus(cpu: &mut CPU, _: u16, value: u32) {
+    let mut value = value as u64;
+    // MPP is WARL, only implemented modes can be written
+    let mpp = PrivilegeMode::from_bits(((value & MSTATUS_MPP) >> 11) as u8);
+    if mpp == PrivilegeMode::Reserved {
+        value = value & !MSTATUS_MPP | cpu.mstatus & MSTATUS_MPP;
+    }
+    cpu.mstatus = cpu.mstatus & !(u32::MAX as u64) | value;
+}
+
+// Each pmpcfg holds configuration bytes of 4 entries
+fn read_pmpcfg(cpu: &CPU, csr: u16) -> Option<u32> {
+    let first = (csr & 0x3) as usize * 4;
+    Some(u32::from_le_bytes(std::array::from_fn(|i| cpu.pmp.cfg(first + i))))
+}
+
+fn write_pmpcfg(cpu: &mut CPU, csr: u16, value: u32) {
+    let first = (csr & 0x3) as usize * 4;
+    for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+        cpu.pmp.set_cfg(first + i, byte);
+    }
+}
+
+pub struct CsrTable {
+    entries: Box<[Option<Csr>]>,
+}
+
+impl Default for CsrTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CsrTable {
+    pub fn empty() -> Self {
+        CsrTable {
+            entries: vec![None; CSR_COUNT].into(),
+        }
+    }
+    // Machine and supervisor mode registers, PMP and counters
+    pub fn new() -> Self {
+        let mut table = Self::empty();
+        for csr in [0x301, 0xF11, 0xF12, 0xF13, 0xF15] {
+            table.insert(csr, Csr::read_only(read_constant));
+        }
+        table.insert(0xF14, Csr::read_only(|cpu, _| Some(cpu.hartid)));
+        let mstatus_mask = (MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP | SSTATUS_MASK) as u32;
+        table.insert(0x300, Csr::new(|cpu, _| Some(cpu.mstatus as u32), write_mstatus, mstatus_mask));
+        table.insert(0x310, Csr::read_only(|cpu, _| Some((cpu.mstatus >> 32) as u32)));
+        let interrupts = MACHINE_INTERRUPTS | SUPERVISOR_INTERRUPTS;
+        table.insert(0x304, Csr::new(|cpu, _| Some(cpu.mie), |cpu, _, v| cpu.mie = v, interrupts));
+        // Vectored and direct modes only
+        table.insert(0x305, Csr::new(|cpu, _| Some(cpu.mtvec), |cpu, _, v| cpu.mtvec = v, !0b10));
+        table.insert(0x340, Csr::new(|cpu, _| Some(cpu.mscratch), |cpu, _, v| cpu.mscratch = v, u32::MAX));
+        table.insert(0x341, Csr::new(|cpu, _| Some(cpu.mepc), |cpu, _, v| cpu.mepc = v, !0b11));
+        table.insert(0x342, Csr::new(|cpu, _| Some(cpu.mcause), |cpu, _, v| cpu.mcause = v, u32::MAX));
+        table.insert(0x343, Csr::new(|cpu, _| Some(cpu.mtval), |cpu, _, v| cpu.mtval = v, u32::MAX));
+        // Machine pending bits come from CLINT
+        table.insert(0x344, Csr::new(|cpu, _| Some(cpu.mip), |cpu, _, v| cpu.mip = v, SUPERVISOR_INTERRUPTS));
+        table.insert(0x302, Csr::new(|cpu, _| Some(cpu.medeleg), |cpu, _, v| cpu.medeleg = v, DELEGABLE_EXCEPTIONS));
+        table.insert(0x303, Csr::new(|cpu, _| Some(cpu.mideleg), |cpu, _, v| cpu.mideleg = v, SUPERVISOR_INTERRUPTS));
+
+        // Supervisor views of mstatus, mie and mip only show delegated bits
+        table.insert(
+            0x100,
+            Csr::new(
+                |cpu, _| Some((cpu.mstatus & SSTATUS_MASK) as u32),
+                |cpu, _, v| cpu.mstatus = cpu.mstatus & !SSTATUS_MASK | v as u64,
+                SSTATUS_MASK as u32,
+            ),
+        );
+        table.insert(
+            0x104,
+            Csr::new(
+                |cpu, _| Some(cpu.mie & cpu.mideleg),
+                |cpu, _, v| cpu.mie = cpu.mie & !cpu.mideleg | v & cpu.mideleg,
+                SUPERVISOR_INTERRUPTS,
+            ),
+        );
+        // Only software interrupt can be raised or cleared from S mode
+        table.insert(
+            0x144,
+            Csr::new(
+                |cpu, _| Some(cpu.mip & cpu.mideleg),
+                |cpu, _, v| {
+                    let mask = 1 << 1 & cpu.mideleg;
+                    cpu.mip = cpu.mip & !mask | v & mask;
+                },
+                1 << 1,
+            ),
+        );
+        table.insert(0x105, Csr::new(|cpu, _| Some(cpu.stvec), |cpu, _, v| cpu.stvec = v, !0b10));
+        table.insert(0x140, Csr::new(|cpu, _| Some(cpu.sscratch), |cpu, _, v| cpu.sscratch = v, u32::MAX));
+        table.insert(0x141, Csr::new(|cpu, _| Some(cpu.sepc), |cpu, _, v| cpu.sepc = v, !0b11));
+        table.insert(0x142, Csr::new(|cpu, _| Some(cpu.scause), |cpu, _, v| cpu.scause = v, u32::MAX));
+        table.insert(0x143, Csr::new(|cpu, _| Some(cpu.stval), |cpu, _, v| cpu.stval = v, u32::MAX));
+        for csr in 0x3A0..=0x3A3 {
+            table.insert(csr, Csr::new(read_pmpcfg, write_pmpcfg, u32::MAX));
+        }
+        for csr in 0x3B0..=0x3BF {
+            table.insert(
+                csr,
+                Csr::new(
+                    |cpu, csr| Some(cpu.pmp.addr((csr & 0xF) as usize)),
+                    |cpu, csr, v| cpu.pmp.set_addr((csr & 0xF) as usize, v),
+                    u32::MAX,
+                ),
+            );
+        }
+        table.insert(
+            0x180,
+            Csr::new(
+                |cpu, _| Some(cpu.satp),
+                |cpu, _, v| {
+                    // Only Bare mode until Sv32 is implemented, write of other mode has no effect
+                    if v & SATP_MODE != 0 {
+                        return;
+                    }
+                    // Translation changes, so cached pcs may now be different code
+                    cpu.satp = v;
+                    cpu.decode_cache.clear();
+                },
+                u32::MAX,
+            ),
+        );
+
+        for csr in (0xC00..=0xC1F).chain(0xC80..=0xC9F) {
+            table.insert(csr, Csr::read_only(read_counter));
+        }
+        // There is no mtime CSR
+        for csr in (0xB00..=0xB1F).chain(0xB80..=0xB9F).filter(|csr| csr & 0x1F != 1) {
+            table.insert(csr, Csr::new(read_counter, write_counter, u32::MAX));
+        }
+        for csr in 0x323..=0x33F {
+            table.insert(
+                csr,
+                Csr::new(
+                    |cpu, csr| Some(cpu.counters.event((csr & 0x1F) as usize)),
+                    |cpu, csr, v| cpu.counters.set_event((csr & 0x1F) as usize, v, cpu.instret),
+                    u32::MAX,
+                ),
+            );
+        }
+        table.insert(
+            0x320,
+            Csr::new(
+                |cpu, _| Some(cpu.counters.inhibit),
+                |cpu, _, v| cpu.counters.set_inhibit(v, cpu.instret),
+                u32::MAX,
+            ),
+        );
+        table.insert(
+            0x306,
+            Csr::new(|cpu, _| Some(cpu.counters.mcounteren), |cpu, _, v| cpu.counters.mcounteren = v, u32::MAX),
+        );
+        table.insert(
+            0x106,
+            Csr::new(|cpu, _| Some(cpu.counters.scounteren), |cpu, _, v| cpu.counters.scounteren = v, u32::MAX),
+        );
+        table
+    }
+    pub fn insert(&mut self, csr: u16, entry: Csr) {
+        self.entries[csr as usize] = Some(entry);
+    }
+    pub fn remove(&mut self, csr: u16) {
+        self.entries[csr as usize] = None;
+    }
+    #[inline(always)]
+    pub fn get(&self, csr: u16) -> Option<Csr> {
+        self.entries[csr as usize & (CSR_COUNT - 1)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        cpu::CPU,
+        errors::EmulatorError,
+    };
+
+    use super::{MACHINE_INTERRUPTS, MISA, SUPERVISOR_INTERRUPTS};
+
+    // Traps are skipped by handler, which counts them in S11 and keeps mtval in S10.
+    // Ecall from user mode or machine mode without handler ends program.
+    fn run(body: impl FnOnce(&mut Assembler)) -> CPU {
+        let mut asm = Assembler::new();
+        asm.la(T6, "handler").csrw(0x305, T6);
+        body(&mut asm);
+        asm.label("stop").csrw(0x305, ZERO).ecall();
+        asm.label("handler").csrr(T6, 0x342).addi(T6, T6, -8).beq(T6, ZERO, "stop");
+        asm.addi(S11, S11, 1)
+            .csrr(S10, 0x343)
+            .csrr(T6, 0x341)
+            .addi(T6, T6, 4)
+            .csrw(0x341, T6)
+            .mret();
+        let mut cpu = asm.cpu();
+        match cpu.run(10000, &mut 0) {
+            Err(EmulatorError::UnsetTrapHandler) => cpu,
+            r => panic!("Program didn't finish: {r:?}"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_csr() {
+        let cpu = run(|asm| {
+            // tselect isn't implemented
+            asm.csrr(A0, 0x7A0).li(A1, 1);
+        });
+        let regs = cpu.get_registers();
+        assert_eq!(regs[S11 as usize], 1);
+        assert_eq!(regs[A1 as usize], 1);
+        // mtval is the instruction
+        assert_eq!(regs[S10 as usize], 0x7A002573);
+        assert_eq!(cpu.mcause, 2);
+    }
+
+    #[test]
+    fn test_read_only_and_warl() {
+        let regs = run(|asm| {
+            // Reading read-only CSR with csrrs x0 is fine, writing isn't
+            asm.csrr(A0, 0xF11).csrrs(A1, 0xF11, ZERO).csrw(0xF11, A0);
+            asm.li(T0, u32::MAX).csrw(0x301, T0).csrr(A2, 0x301);
+            asm.csrw(0x304, T0).csrr(A4, 0x304).csrrw(A3, 0x305, T0).csrrw(A3, 0x305, A3);
+        })
+        .get_registers();
+        assert_eq!(regs[A0 as usize], 0xff0ff0ff);
+        assert_eq!(regs[A1 as usize], 0xff0ff0ff);
+        assert_eq!(regs[S11 as usize], 1);
+        assert_eq!(regs[A2 as usize], MISA);
+        assert_eq!(regs[A3 as usize], !0b10);
+        assert_eq!(regs[A4 as usize], MACHINE_INTERRUPTS | SUPERVISOR_INTERRUPTS);
+    }
+
+    #[test]
+    fn test_satp_bare_only() {
+        let regs = run(|asm| {
+            asm.li(T0, 0x1234).csrw(0x180, T0).csrr(A0, 0x180);
+            asm.li(T0, 0x8000_5678).csrw(0x180, T0).csrr(A1, 0x180);
+        })
+        .get_registers();
+        assert_eq!(regs[A0 as usize], 0x1234);
+        // Sv32 write is ignored
+        assert_eq!(regs[A1 as usize], 0x1234);
+        assert_eq!(regs[S11 as usize], 0);
+    }
+
+    #[test]
+    fn test_immediate_variants() {
+        let regs = run(|asm| {
+            asm.csrrwi(ZERO, 0x340, 0b10110).csrrsi(A0, 0x340, 0b00001).csrrci(A1, 0x340, 0b00110);
+            asm.csrrsi(A2, 0x340, 0);
+        })
+        .get_registers();
+        assert_eq!(regs[A0 as usize], 0b10110);
+        assert_eq!(regs[A1 as usize], 0b10111);
+        assert_eq!(regs[A2 as usize], 0b10001);
+    }
+
+    #[test]
+    fn test_privilege() {
+        let cpu = run(|asm| {
+            asm.pmp_allow_all();
+            asm.li(T0, 0b11 << 11).csrrc(ZERO, 0x300, T0).la(T0, "user").csrw(0x341, T0).mret();
+            asm.label("user").csrr(A0, 0x340).li(A1, 1).ecall();
+        });
+        let regs = cpu.get_registers();
+        assert_eq!(regs[S11 as usize], 1);
+        assert_eq!(regs[A1 as usize], 1);
+        assert_eq!(cpu.mcause, 8);
+    }
+}
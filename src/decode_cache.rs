@@ -0,0 +1,122 @@
+use crate::{cpu::CPU, mmu::PAGE_SIZE, traps::Trap};
+
+pub type Handler = fn(&mut CPU, &DecodedInstruction) -> Result<(), Trap>;
+
+// Instruction with operands already extracted, `imm` is decoded according to instruction format.
+#[derive(Clone, Copy)]
+pub struct DecodedInstruction {
+    pub handler: Handler,
+    pub instr: u32,
+    pub imm: u32,
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    // In bytes
+    pub len: u8,
+}
+
+pub const DECODE_CACHE_SIZE: usize = 1 << 14;
+// Instructions are at least halfword aligned, so odd tag never matches
+const INVALID_TAG: u32 = 1;
+
+// Direct-mapped cache of decoded instructions keyed by physical pc.
+pub struct DecodeCache {
+    entries: Box<[(u32, DecodedInstruction)]>,
+    // Incremented on every clear, so translations made from entries know they are stale
+    generation: u64,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        let empty = (INVALID_TAG, CPU::decode(0));
+        DecodeCache {
+            entries: vec![empty; DECODE_CACHE_SIZE].into(),
+            generation: 0,
+        }
+    }
+    #[inline(always)]
+    fn index(pc: u32) -> usize {
+        (pc >> 1) as usize & (DECODE_CACHE_SIZE - 1)
+    }
+    #[inline(always)]
+    pub fn get(&self, pc: u32) -> Option<&DecodedInstruction> {
+        let (tag, decoded) = &self.entries[Self::index(pc)];
+        if *tag == pc {
+            Some(decoded)
+        } else {
+            None
+        }
+    }
+    pub fn insert(&mut self, pc: u32, decoded: DecodedInstruction) {
+        self.entries[Self::index(pc)] = (pc, decoded);
+    }
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+    pub fn clear(&mut self) {
+        self.generation += 1;
+        for (tag, _) in self.entries.iter_mut() {
+            *tag = INVALID_TAG;
+        }
+    }
+    // Drops every instruction that starts in page or runs into it from previous one.
+    // Bumps generation too, so translations of other harts sharing this cache see it.
+    pub fn invalidate_page(&mut self, page: u32) {
+        self.generation += 1;
+        let start = (page * PAGE_SIZE).wrapping_sub(2);
+        for pc in (0..PAGE_SIZE + 2).step_by(2).map(|offset| start.wrapping_add(offset)) {
+            let (tag, _) = &mut self.entries[Self::index(pc)];
+            if *tag == pc {
+                *tag = INVALID_TAG;
+            }
+        }
+    }
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        cpu::CPU,
+        mmu::RAM_ADDRESS,
+        ops_decode::encode_i_type,
+    };
+
+    fn run(asm: &Assembler) -> CPU {
+        let mut cpu = asm.cpu();
+        while cpu.execute_instruction().is_ok() {}
+        cpu
+    }
+
+    #[test]
+    fn test_store_invalidates() {
+        let mut asm = Assembler::new();
+        asm.la(A1, "target").li(A2, encode_i_type(0b0010011, A0, 0, A0, 100));
+        asm.label("target")
+            .addi(A0, A0, 1)
+            .addi(T0, T0, 1)
+            // Patch already executed instruction
+            .sw(A2, 0, A1)
+            .li(T1, 2)
+            .bne(T0, T1, "target")
+            .ecall();
+        let cpu = run(&asm);
+        assert_eq!(cpu.get_registers()[A0 as usize], 101);
+    }
+
+    #[test]
+    fn test_fence_i() {
+        let mut asm = Assembler::new();
+        asm.addi(A0, A0, 1).fence_i().ecall();
+        let cpu = run(&asm);
+        // Only instructions after fence.i stay cached
+        assert!(cpu.decode_cache.get(RAM_ADDRESS).is_none());
+        assert!(cpu.decode_cache.get(RAM_ADDRESS + 8).is_some());
+    }
+}
@@ -0,0 +1,547 @@
+use crate::ops_decode::{
+    get_compressed_cb_and_imm, get_compressed_cb_branch_imm, get_compressed_cb_shift_imm,
+    get_compressed_ci_addi16sp_imm, get_compressed_ci_li_addi_imm, get_compressed_ci_lui_imm,
+    get_compressed_ci_stack_load_32_imm, get_compressed_ciw_addi4spn_imm,
+    get_compressed_cj_jump_imm, get_compressed_cl_mem_load_32_imm,
+    get_compressed_cs_mem_store_32_imm, get_compressed_css_stack_write_32_imm,
+    get_compressed_func, get_compressed_func2, get_compressed_func3, get_compressed_rd,
+    get_compressed_rdc, get_compressed_rs1c, get_compressed_rs2, get_funct3, get_funct7,
+    get_imm_i_type, get_opcode, get_rd, get_rs1, get_rs2, parse_format_b, parse_format_csr,
+    parse_format_i, parse_format_j, parse_format_r, parse_format_r2, parse_format_s,
+    parse_format_u,
+};
+
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+pub fn register_name(reg: u8) -> &'static str {
+    REGISTER_NAMES[(reg & 0x1f) as usize]
+}
+
+pub fn instruction_length(instr: u32) -> u32 {
+    if instr & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+pub fn csr_name(csr: u16) -> String {
+    let name = match csr {
+        0x001 => "fflags",
+        0x002 => "frm",
+        0x003 => "fcsr",
+        0xc00 => "cycle",
+        0xc01 => "time",
+        0xc02 => "instret",
+        0xc80 => "cycleh",
+        0xc81 => "timeh",
+        0xc82 => "instreth",
+        0xc03..=0xc1f => return format!("hpmcounter{}", csr - 0xc00),
+        0xc83..=0xc9f => return format!("hpmcounter{}h", csr - 0xc80),
+
+        0x100 => "sstatus",
+        0x104 => "sie",
+        0x105 => "stvec",
+        0x106 => "scounteren",
+        0x10a => "senvcfg",
+        0x140 => "sscratch",
+        0x141 => "sepc",
+        0x142 => "scause",
+        0x143 => "stval",
+        0x144 => "sip",
+        0x180 => "satp",
+
+        0xf11 => "mvendorid",
+        0xf12 => "marchid",
+        0xf13 => "mimpid",
+        0xf14 => "mhartid",
+        0xf15 => "mconfigptr",
+        0x300 => "mstatus",
+        0x301 => "misa",
+        0x302 => "medeleg",
+        0x303 => "mideleg",
+        0x304 => "mie",
+        0x305 => "mtvec",
+        0x306 => "mcounteren",
+        0x30a => "menvcfg",
+        0x310 => "mstatush",
+        0x31a => "menvcfgh",
+        0x320 => "mcountinhibit",
+        0x323..=0x33f => return format!("mhpmevent{}", csr - 0x320),
+        0x340 => "mscratch",
+        0x341 => "mepc",
+        0x342 => "mcause",
+        0x343 => "mtval",
+        0x344 => "mip",
+        0x34a => "mtinst",
+        0x34b => "mtval2",
+        0x3a0..=0x3af => return format!("pmpcfg{}", csr - 0x3a0),
+        0x3b0..=0x3ef => return format!("pmpaddr{}", csr - 0x3b0),
+        0xb00 => "mcycle",
+        0xb02 => "minstret",
+        0xb80 => "mcycleh",
+        0xb82 => "minstreth",
+        0xb03..=0xb1f => return format!("mhpmcounter{}", csr - 0xb00),
+        0xb83..=0xb9f => return format!("mhpmcounter{}h", csr - 0xb80),
+
+        0x7a0 => "tselect",
+        0x7a1 => "tdata1",
+        0x7a2 => "tdata2",
+        0x7a3 => "tdata3",
+        0x7b0 => "dcsr",
+        0x7b1 => "dpc",
+        0x7b2 => "dscratch0",
+        0x7b3 => "dscratch1",
+        _ => return format!("0x{csr:03x}"),
+    };
+    name.to_string()
+}
+
+// Disassembles 16- or 32-bit instruction depending on its lowest bits.
+// Branch and jump targets are printed relative to pc, as Spike does.
+pub fn disassemble(instr: u32) -> String {
+    if instr & 0b11 == 0b11 {
+        disassemble_32(instr)
+    } else {
+        disassemble_16(instr as u16)
+    }
+}
+
+fn op(mnemonic: &str, operands: &str) -> String {
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{mnemonic:<7} {operands}")
+    }
+}
+
+fn pc_relative(offset: u32) -> String {
+    let offset = offset as i32;
+    if offset < 0 {
+        format!("pc - {}", offset.unsigned_abs())
+    } else {
+        format!("pc + {offset}")
+    }
+}
+
+fn unknown() -> String {
+    "unknown".to_string()
+}
+
+fn disassemble_32(instr: u32) -> String {
+    let r = |n: u8| register_name(n);
+    match get_opcode(instr) {
+        0b0110111 | 0b0010111 => {
+            let f = parse_format_u(instr);
+            let mnemonic = if get_opcode(instr) == 0b0110111 { "lui" } else { "auipc" };
+            op(mnemonic, &format!("{}, 0x{:x}", r(f.rd as u8), (f.imm as u32) >> 12))
+        }
+        0b1101111 => {
+            let f = parse_format_j(instr);
+            match f.rd {
+                0 => op("j", &pc_relative(f.imm)),
+                1 => op("jal", &pc_relative(f.imm)),
+                rd => op("jal", &format!("{}, {}", r(rd), pc_relative(f.imm))),
+            }
+        }
+        0b1100111 if get_funct3(instr) == 0 => {
+            let f = parse_format_i(instr);
+            match (f.rd, f.rs1, f.imm) {
+                (0, 1, 0) => "ret".to_string(),
+                (0, rs1, 0) => op("jr", r(rs1)),
+                (1, rs1, 0) => op("jalr", r(rs1)),
+                (rd, rs1, imm) => op("jalr", &format!("{}, {}({})", r(rd), imm as i32, r(rs1))),
+            }
+        }
+        0b1100011 => {
+            let f = parse_format_b(instr);
+            let target = pc_relative(f.imm);
+            let mnemonic = match get_funct3(instr) {
+                0b000 => "beq",
+                0b001 => "bne",
+                0b100 => "blt",
+                0b101 => "bge",
+                0b110 => "bltu",
+                0b111 => "bgeu",
+                _ => return unknown(),
+            };
+            match (mnemonic, f.rs1, f.rs2) {
+                ("beq", rs1, 0) => op("beqz", &format!("{}, {target}", r(rs1))),
+                ("bne", rs1, 0) => op("bnez", &format!("{}, {target}", r(rs1))),
+                ("blt", rs1, 0) => op("bltz", &format!("{}, {target}", r(rs1))),
+                ("bge", rs1, 0) => op("bgez", &format!("{}, {target}", r(rs1))),
+                ("blt", 0, rs2) => op("bgtz", &format!("{}, {target}", r(rs2))),
+                ("bge", 0, rs2) => op("blez", &format!("{}, {target}", r(rs2))),
+                (m, rs1, rs2) => op(m, &format!("{}, {}, {target}", r(rs1), r(rs2))),
+            }
+        }
+        0b0000011 => {
+            let f = parse_format_i(instr);
+            let mnemonic = match get_funct3(instr) {
+                0b000 => "lb",
+                0b001 => "lh",
+                0b010 => "lw",
+                0b100 => "lbu",
+                0b101 => "lhu",
+                _ => return unknown(),
+            };
+            op(mnemonic, &format!("{}, {}({})", r(f.rd), f.imm as i32, r(f.rs1)))
+        }
+        0b0100011 => {
+            let f = parse_format_s(instr);
+            let mnemonic = match get_funct3(instr) {
+                0b000 => "sb",
+                0b001 => "sh",
+                0b010 => "sw",
+                _ => return unknown(),
+            };
+            op(mnemonic, &format!("{}, {}({})", r(f.rs2), f.imm as i32, r(f.rs1)))
+        }
+        0b0010011 => disassemble_op_imm(instr),
+        0b0110011 => disassemble_op(instr),
+        0b0001111 => match get_funct3(instr) {
+            0b000 => {
+                let fm = instr >> 28;
+                let pred = (instr >> 24) & 0xf;
+                let succ = (instr >> 20) & 0xf;
+                if fm == 0b1000 && pred == 0b0011 && succ == 0b0011 {
+                    "fence.tso".to_string()
+                } else if pred == 0xf && succ == 0xf {
+                    "fence".to_string()
+                } else {
+                    op("fence", &format!("{}, {}", fence_set(pred), fence_set(succ)))
+                }
+            }
+            0b001 => "fence.i".to_string(),
+            _ => unknown(),
+        },
+        0b1110011 => disassemble_system(instr),
+        0b0101111 if get_funct3(instr) == 0b010 => {
+            let f = parse_format_r2(instr);
+            let mnemonic = match f.rs3 {
+                0b00010 if f.rs2 == 0 => "lr.w",
+                0b00011 => "sc.w",
+                0b00001 => "amoswap.w",
+                0b00000 => "amoadd.w",
+                0b00100 => "amoxor.w",
+                0b01100 => "amoand.w",
+                0b01000 => "amoor.w",
+                0b10000 => "amomin.w",
+                0b10100 => "amomax.w",
+                0b11000 => "amominu.w",
+                0b11100 => "amomaxu.w",
+                _ => return unknown(),
+            };
+            let ordering = match (instr >> 25) & 0b11 {
+                0b10 => ".aq",
+                0b01 => ".rl",
+                0b11 => ".aqrl",
+                _ => "",
+            };
+            let mnemonic = format!("{mnemonic}{ordering}");
+            if f.rs3 == 0b00010 {
+                op(&mnemonic, &format!("{}, ({})", r(f.rd), r(f.rs1)))
+            } else {
+                op(&mnemonic, &format!("{}, {}, ({})", r(f.rd), r(f.rs2), r(f.rs1)))
+            }
+        }
+        _ => unknown(),
+    }
+}
+
+fn fence_set(bits: u32) -> String {
+    let mut s = String::new();
+    for (bit, c) in [(8, 'i'), (4, 'o'), (2, 'r'), (1, 'w')] {
+        if bits & bit != 0 {
+            s.push(c)
+        }
+    }
+    if s.is_empty() {
+        s.push('0')
+    }
+    s
+}
+
+fn disassemble_op_imm(instr: u32) -> String {
+    let r = |n: u8| register_name(n);
+    let f = parse_format_i(instr);
+    let imm = f.imm as i32;
+    let shamt = f.imm & 0b11111;
+    match get_funct3(instr) {
+        0b000 => match (f.rd, f.rs1, imm) {
+            (0, 0, 0) => "nop".to_string(),
+            (rd, 0, imm) => op("li", &format!("{}, {imm}", r(rd))),
+            (rd, rs1, 0) => op("mv", &format!("{}, {}", r(rd), r(rs1))),
+            (rd, rs1, imm) => op("addi", &format!("{}, {}, {imm}", r(rd), r(rs1))),
+        },
+        0b010 => op("slti", &format!("{}, {}, {imm}", r(f.rd), r(f.rs1))),
+        0b011 if imm == 1 => op("seqz", &format!("{}, {}", r(f.rd), r(f.rs1))),
+        0b011 => op("sltiu", &format!("{}, {}, {imm}", r(f.rd), r(f.rs1))),
+        0b100 if imm == -1 => op("not", &format!("{}, {}", r(f.rd), r(f.rs1))),
+        0b100 => op("xori", &format!("{}, {}, {imm}", r(f.rd), r(f.rs1))),
+        0b110 => op("ori", &format!("{}, {}, {imm}", r(f.rd), r(f.rs1))),
+        0b111 => op("andi", &format!("{}, {}, {imm}", r(f.rd), r(f.rs1))),
+        0b001 if get_funct7(instr) == 0 => {
+            op("slli", &format!("{}, {}, {shamt}", r(f.rd), r(f.rs1)))
+        }
+        0b101 if get_funct7(instr) == 0 => {
+            op("srli", &format!("{}, {}, {shamt}", r(f.rd), r(f.rs1)))
+        }
+        0b101 if get_funct7(instr) == 0b0100000 => {
+            op("srai", &format!("{}, {}, {shamt}", r(f.rd), r(f.rs1)))
+        }
+        _ => unknown(),
+    }
+}
+
+fn disassemble_op(instr: u32) -> String {
+    let r = |n: u8| register_name(n);
+    let f = parse_format_r(instr);
+    let mnemonic = match (get_funct7(instr), get_funct3(instr)) {
+        (0, 0b000) => "add",
+        (0b0100000, 0b000) if f.rs1 == 0 => {
+            return op("neg", &format!("{}, {}", r(f.rd), r(f.rs2)))
+        }
+        (0b0100000, 0b000) => "sub",
+        (0, 0b001) => "sll",
+        (0, 0b010) if f.rs2 == 0 => return op("sltz", &format!("{}, {}", r(f.rd), r(f.rs1))),
+        (0, 0b010) if f.rs1 == 0 => return op("sgtz", &format!("{}, {}", r(f.rd), r(f.rs2))),
+        (0, 0b010) => "slt",
+        (0, 0b011) if f.rs1 == 0 => return op("snez", &format!("{}, {}", r(f.rd), r(f.rs2))),
+        (0, 0b011) => "sltu",
+        (0, 0b100) => "xor",
+        (0, 0b101) => "srl",
+        (0b0100000, 0b101) => "sra",
+        (0, 0b110) => "or",
+        (0, 0b111) => "and",
+        (1, 0b000) => "mul",
+        (1, 0b001) => "mulh",
+        (1, 0b010) => "mulhsu",
+        (1, 0b011) => "mulhu",
+        (1, 0b100) => "div",
+        (1, 0b101) => "divu",
+        (1, 0b110) => "rem",
+        (1, 0b111) => "remu",
+        _ => return unknown(),
+    };
+    op(mnemonic, &format!("{}, {}, {}", r(f.rd), r(f.rs1), r(f.rs2)))
+}
+
+fn disassemble_system(instr: u32) -> String {
+    let r = |n: u8| register_name(n);
+    let funct3 = get_funct3(instr);
+    if funct3 == 0 {
+        let (rd, rs1) = (get_rd(instr), get_rs1(instr));
+        if get_funct7(instr) == 0b0001001 && rd == 0 {
+            return match (rs1, get_rs2(instr)) {
+                (0, 0) => "sfence.vma".to_string(),
+                (rs1, 0) => op("sfence.vma", r(rs1)),
+                (rs1, rs2) => op("sfence.vma", &format!("{}, {}", r(rs1), r(rs2))),
+            };
+        }
+        if rd != 0 || rs1 != 0 {
+            return unknown();
+        }
+        return match get_imm_i_type(instr) {
+            0b000000000000 => "ecall",
+            0b000000000001 => "ebreak",
+            0b000100000010 => "sret",
+            0b001100000010 => "mret",
+            0b000100000101 => "wfi",
+            _ => return unknown(),
+        }
+        .to_string();
+    }
+    let f = parse_format_csr(instr);
+    let csr = csr_name(f.csr);
+    match funct3 {
+        0b001 if f.rd == 0 => op("csrw", &format!("{csr}, {}", r(f.rs))),
+        0b001 => op("csrrw", &format!("{}, {csr}, {}", r(f.rd), r(f.rs))),
+        0b010 if f.rs == 0 => match f.csr {
+            0xc00 | 0xc01 | 0xc02 | 0xc80 | 0xc81 | 0xc82 => {
+                op(&format!("rd{csr}"), r(f.rd))
+            }
+            _ => op("csrr", &format!("{}, {csr}", r(f.rd))),
+        },
+        0b010 if f.rd == 0 => op("csrs", &format!("{csr}, {}", r(f.rs))),
+        0b010 => op("csrrs", &format!("{}, {csr}, {}", r(f.rd), r(f.rs))),
+        0b011 if f.rd == 0 => op("csrc", &format!("{csr}, {}", r(f.rs))),
+        0b011 => op("csrrc", &format!("{}, {csr}, {}", r(f.rd), r(f.rs))),
+        0b101 if f.rd == 0 => op("csrwi", &format!("{csr}, {}", f.rs)),
+        0b101 => op("csrrwi", &format!("{}, {csr}, {}", r(f.rd), f.rs)),
+        0b110 if f.rd == 0 => op("csrsi", &format!("{csr}, {}", f.rs)),
+        0b110 => op("csrrsi", &format!("{}, {csr}, {}", r(f.rd), f.rs)),
+        0b111 if f.rd == 0 => op("csrci", &format!("{csr}, {}", f.rs)),
+        0b111 => op("csrrci", &format!("{}, {csr}, {}", r(f.rd), f.rs)),
+        _ => unknown(),
+    }
+}
+
+fn disassemble_16(instr: u16) -> String {
+    let r = |n: u8| register_name(n);
+    // rs1'/rs2'/rd' fields address x8-x15
+    let rs1c = get_compressed_rs1c(instr) + 8;
+    let rdc = get_compressed_rdc(instr) + 8;
+    let rd = get_compressed_rd(instr);
+    let rs2 = get_compressed_rs2(instr);
+    let bit12 = (instr >> 12) & 1;
+    match (instr & 0b11, get_compressed_func3(instr)) {
+        (0b00, 0b000) => match get_compressed_ciw_addi4spn_imm(instr) {
+            0 => unknown(),
+            imm => op("c.addi4spn", &format!("{}, sp, {imm}", r(rdc))),
+        },
+        (0b00, 0b010) => op(
+            "c.lw",
+            &format!("{}, {}({})", r(rdc), get_compressed_cl_mem_load_32_imm(instr), r(rs1c)),
+        ),
+        (0b00, 0b110) => op(
+            "c.sw",
+            &format!("{}, {}({})", r(rdc), get_compressed_cs_mem_store_32_imm(instr), r(rs1c)),
+        ),
+        (0b01, 0b000) => match (rd, get_compressed_ci_li_addi_imm(instr) as i32) {
+            (0, _) => "nop".to_string(),
+            (rd, imm) => op("c.addi", &format!("{}, {imm}", r(rd))),
+        },
+        (0b01, 0b001) => op("c.jal", &pc_relative(get_compressed_cj_jump_imm(instr))),
+        (0b01, 0b010) => op(
+            "c.li",
+            &format!("{}, {}", r(rd), get_compressed_ci_li_addi_imm(instr) as i32),
+        ),
+        (0b01, 0b011) if rd == 2 => match get_compressed_ci_addi16sp_imm(instr) as i32 {
+            0 => unknown(),
+            imm => op("c.addi16sp", &format!("sp, {imm}")),
+        },
+        (0b01, 0b011) => match get_compressed_ci_lui_imm(instr) {
+            0 => unknown(),
+            imm => op("c.lui", &format!("{}, 0x{:x}", r(rd), imm >> 12 & 0xfffff)),
+        },
+        (0b01, 0b100) => match get_compressed_func2(instr) {
+            0b00 if bit12 == 0 => op(
+                "c.srli",
+                &format!("{}, {}", r(rs1c), get_compressed_cb_shift_imm(instr)),
+            ),
+            0b01 if bit12 == 0 => op(
+                "c.srai",
+                &format!("{}, {}", r(rs1c), get_compressed_cb_shift_imm(instr)),
+            ),
+            0b10 => op(
+                "c.andi",
+                &format!("{}, {}", r(rs1c), get_compressed_cb_and_imm(instr) as i32),
+            ),
+            0b11 if bit12 == 0 => {
+                let mnemonic = match get_compressed_func(instr) {
+                    0b00 => "c.sub",
+                    0b01 => "c.xor",
+                    0b10 => "c.or",
+                    _ => "c.and",
+                };
+                op(mnemonic, &format!("{}, {}", r(rs1c), r(rdc)))
+            }
+            _ => unknown(),
+        },
+        (0b01, 0b101) => op("c.j", &pc_relative(get_compressed_cj_jump_imm(instr))),
+        (0b01, 0b110) => op(
+            "c.beqz",
+            &format!("{}, {}", r(rs1c), pc_relative(get_compressed_cb_branch_imm(instr))),
+        ),
+        (0b01, 0b111) => op(
+            "c.bnez",
+            &format!("{}, {}", r(rs1c), pc_relative(get_compressed_cb_branch_imm(instr))),
+        ),
+        (0b10, 0b000) if bit12 == 0 => op(
+            "c.slli",
+            &format!("{}, {}", r(rd), get_compressed_cb_shift_imm(instr)),
+        ),
+        (0b10, 0b010) if rd != 0 => op(
+            "c.lwsp",
+            &format!("{}, {}(sp)", r(rd), get_compressed_ci_stack_load_32_imm(instr)),
+        ),
+        (0b10, 0b100) => match (bit12, rd, rs2) {
+            (0, 0, 0) => unknown(),
+            (0, 1, 0) => "ret".to_string(),
+            (0, rs1, 0) => op("c.jr", r(rs1)),
+            (0, rd, rs2) => op("c.mv", &format!("{}, {}", r(rd), r(rs2))),
+            (_, 0, 0) => "c.ebreak".to_string(),
+            (_, rs1, 0) => op("c.jalr", r(rs1)),
+            (_, rd, rs2) => op("c.add", &format!("{}, {}", r(rd), r(rs2))),
+        },
+        (0b10, 0b110) => op(
+            "c.swsp",
+            &format!("{}, {}(sp)", r(rs2), get_compressed_css_stack_write_32_imm(instr)),
+        ),
+        _ => unknown(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csr_name, disassemble};
+    use crate::ops_decode::{encode_i_type, encode_r_type, encode_u_type};
+
+    #[test]
+    fn test_base_instructions() {
+        assert_eq!(disassemble(0x00000297), "auipc   t0, 0x0");
+        assert_eq!(disassemble(encode_u_type(0b0110111, 10, 0x12345000)), "lui     a0, 0x12345");
+        assert_eq!(disassemble(0xff010113), "addi    sp, sp, -16");
+        assert_eq!(disassemble(0x00c58533), "add     a0, a1, a2");
+        assert_eq!(disassemble(0x40c58533), "sub     a0, a1, a2");
+        assert_eq!(disassemble(0x00c12083), "lw      ra, 12(sp)");
+        assert_eq!(disassemble(0x00112623), "sw      ra, 12(sp)");
+        assert_eq!(disassemble(0x00b50463), "beq     a0, a1, pc + 8");
+        assert_eq!(disassemble(0xfeb51ee3), "bne     a0, a1, pc - 4");
+        assert_eq!(disassemble(0x02c5c533), "div     a0, a1, a2");
+        assert_eq!(disassemble(0x00351513), "slli    a0, a0, 3");
+        assert_eq!(disassemble(0x40355513), "srai    a0, a0, 3");
+    }
+    #[test]
+    fn test_pseudo_instructions() {
+        assert_eq!(disassemble(0x00000013), "nop");
+        assert_eq!(disassemble(encode_i_type(0b0010011, 10, 0, 0, -5)), "li      a0, -5");
+        assert_eq!(disassemble(encode_i_type(0b0010011, 10, 0, 11, 0)), "mv      a0, a1");
+        assert_eq!(disassemble(0x00008067), "ret");
+        assert_eq!(disassemble(0x00050067), "jr      a0");
+        assert_eq!(disassemble(0x0100006f), "j       pc + 16");
+        assert_eq!(disassemble(0xff5ff0ef), "jal     pc - 12");
+        assert_eq!(disassemble(0x00050463), "beqz    a0, pc + 8");
+        assert_eq!(disassemble(encode_r_type(0b0110011, 10, 0, 0, 11, 0b0100000)), "neg     a0, a1");
+        assert_eq!(disassemble(0x0ff0000f), "fence");
+    }
+    #[test]
+    fn test_system_instructions() {
+        assert_eq!(disassemble(0x00000073), "ecall");
+        assert_eq!(disassemble(0x00100073), "ebreak");
+        assert_eq!(disassemble(0x30200073), "mret");
+        assert_eq!(disassemble(0x10500073), "wfi");
+        assert_eq!(disassemble(0x30529073), "csrw    mtvec, t0");
+        assert_eq!(disassemble(0x30002573), "csrr    a0, mstatus");
+        assert_eq!(disassemble(0x30046073), "csrsi   mstatus, 8");
+        assert_eq!(disassemble(0xc0102573), "rdtime  a0");
+        assert_eq!(disassemble(0x100525af), "lr.w    a1, (a0)");
+        assert_eq!(disassemble(0x06c5a52f), "amoadd.w.aqrl a0, a2, (a1)");
+    }
+    #[test]
+    fn test_compressed_instructions() {
+        assert_eq!(disassemble(0x0001), "nop");
+        assert_eq!(disassemble(0x1141), "c.addi  sp, -16");
+        assert_eq!(disassemble(0x8082), "ret");
+        assert_eq!(disassemble(0x4108), "c.lw    a0, 0(a0)");
+        assert_eq!(disassemble(0xc14c), "c.sw    a1, 4(a0)");
+        assert_eq!(disassemble(0x4532), "c.lwsp  a0, 12(sp)");
+        assert_eq!(disassemble(0xc62a), "c.swsp  a0, 12(sp)");
+        assert_eq!(disassemble(0x852e), "c.mv    a0, a1");
+        assert_eq!(disassemble(0x9002), "c.ebreak");
+        assert_eq!(disassemble(0xa001), "c.j     pc + 0");
+        assert_eq!(disassemble(0x0000), "unknown");
+    }
+    #[test]
+    fn test_csr_names() {
+        assert_eq!(csr_name(0x300), "mstatus");
+        assert_eq!(csr_name(0xb03), "mhpmcounter3");
+        assert_eq!(csr_name(0x3b5), "pmpaddr5");
+        assert_eq!(csr_name(0x7ff), "0x7ff");
+    }
+}
@@ -1,4 +1,6 @@
 //From https://github.com/takahirox/riscv-rust/blob/master/src/elf_analyzer.rs
+// Style lints aren't applied to keep it close to original
+#![allow(clippy::redundant_field_names, clippy::ptr_arg, clippy::needless_range_loop, clippy::len_zero, clippy::needless_borrow, clippy::useless_vec)]
 
 use std::collections::HashMap;
 
@@ -13,29 +15,29 @@ pub struct Header {
     _e_osabi: u8,
     _e_abi_version: u8,
     _e_type: u16,
-    _e_machine: u16,
+    pub e_machine: u16,
     _e_version: u32,
     pub e_entry: u64,
-    _e_phoff: u64,
+    pub e_phoff: u64,
     e_shoff: u64,
     _e_flags: u32,
     _e_ehsize: u16,
-    _e_phentsize: u16,
-    _e_phnum: u16,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
     _e_shentsize: u16,
     e_shnum: u16,
     _e_shstrndx: u16,
 }
 
 /// ELF program header
-pub struct _ProgramHeader {
-    _p_type: u32,
+pub struct ProgramHeader {
+    pub p_type: u32,
     _p_flags: u32,
-    _p_offset: u64,
-    _p_vaddr: u64,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
     _p_paddr: u64,
-    _p_filesz: u64,
-    _p_memsz: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
     _p_align: u64,
 }
 
@@ -207,15 +209,15 @@ impl ElfAnalyzer {
             _e_osabi: e_osabi,
             _e_abi_version: e_abi_version,
             _e_type: e_type,
-            _e_machine: e_machine,
+            e_machine: e_machine,
             _e_version: e_version,
             e_entry: e_entry,
-            _e_phoff: e_phoff,
+            e_phoff: e_phoff,
             e_shoff: e_shoff,
             _e_flags: e_flags,
             _e_ehsize: e_ehsize,
-            _e_phentsize: e_phentsize,
-            _e_phnum: e_phnum,
+            e_phentsize: e_phentsize,
+            e_phnum: e_phnum,
             _e_shentsize: e_shentsize,
             e_shnum: e_shnum,
             _e_shstrndx: e_shstrndx,
@@ -226,10 +228,10 @@ impl ElfAnalyzer {
     ///
     /// # Arguments
     /// * `header`
-    pub fn _read_program_headers(&self, header: &Header) -> Vec<_ProgramHeader> {
+    pub fn read_program_headers(&self, header: &Header) -> Vec<ProgramHeader> {
         let mut headers = Vec::new();
-        let mut offset = header._e_phoff as usize;
-        for _i in 0..header._e_phnum {
+        let mut offset = header.e_phoff as usize;
+        for _i in 0..header.e_phnum {
             let p_type = self.read_word(offset);
             offset += 4;
 
@@ -342,14 +344,14 @@ impl ElfAnalyzer {
             println!("p_align:{:X}", p_align);
             */
 
-            headers.push(_ProgramHeader {
-                _p_type: p_type,
+            headers.push(ProgramHeader {
+                p_type: p_type,
                 _p_flags: p_flags,
-                _p_offset: p_offset,
-                _p_vaddr: p_vaddr,
+                p_offset: p_offset,
+                p_vaddr: p_vaddr,
                 _p_paddr: p_paddr,
-                _p_filesz: p_filesz,
-                _p_memsz: p_memsz,
+                p_filesz: p_filesz,
+                p_memsz: p_memsz,
                 _p_align: p_align,
             });
         }
@@ -731,17 +733,64 @@ impl ElfAnalyzer {
     }
 }
 
-pub fn elf_setup_mmu(elf: Vec<u8>, mmu: &mut MMU) -> u32 {
+/// Why ELF file can't be loaded
+#[derive(Debug, PartialEq, Eq)]
+pub enum ElfError {
+    NotElf,
+    /// Header or section is past end of file
+    Truncated,
+    /// Section at this address isn't in RAM
+    OutsideRam(u32),
+}
+
+/// Things about loaded ELF that emulator needs besides memory contents
+pub struct ElfInfo {
+    pub entry: u32,
+    /// Address of riscv-tests `tohost`, if ELF has one
+    pub tohost: Option<u32>,
+    pub symbols: HashMap<String, u64>,
+    /// End of program and bss sections in RAM, 0 if there are none
+    pub image_end: u64,
+}
+
+impl ElfInfo {
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).map(|v| *v as u32)
+    }
+}
+
+pub fn elf_setup_mmu(elf: Vec<u8>, mmu: &mut MMU) -> Result<ElfInfo, ElfError> {
+    let len = elf.len() as u64;
     let analyzer = ElfAnalyzer::new(elf);
     let mut symbol_map: HashMap<String, u64> = HashMap::new();
-    if !analyzer.validate() {
-        panic!("This file does not seem ELF file");
+    if !analyzer.validate() || len < 5 {
+        return Err(ElfError::NotElf);
+    }
+    // Header and section header sizes of 32 and 64-bit ELF
+    let (header_size, section_header_size) = match analyzer.read_byte(4) {
+        1 => (52, 40),
+        2 => (64, 64),
+        _ => return Err(ElfError::NotElf),
+    };
+    if len < header_size {
+        return Err(ElfError::Truncated);
     }
 
     let header = analyzer.read_header();
-    //let program_headers = analyzer._read_program_headers(&header);
+    if header.e_shoff.checked_add(header.e_shnum as u64 * section_header_size).is_none_or(|end| end > len) {
+        return Err(ElfError::Truncated);
+    }
+    //let program_headers = analyzer.read_program_headers(&header);
     let section_headers = analyzer.read_section_headers(&header);
 
+    // Bss (NOBITS) isn't loaded, but program still takes that memory
+    let image_end = section_headers
+        .iter()
+        .filter(|h| (h.sh_type == 1 || h.sh_type == 8) && h.sh_addr >= 0x80000000)
+        .map(|h| h.sh_addr.saturating_add(h.sh_size))
+        .max()
+        .unwrap_or(0);
+
     let mut program_data_section_headers = vec![];
     let mut symbol_table_section_headers = vec![];
     let mut string_table_section_headers = vec![];
@@ -751,11 +800,17 @@ pub fn elf_setup_mmu(elf: Vec<u8>, mmu: &mut MMU) -> u32 {
             1 => program_data_section_headers.push(&section_headers[i]),
             2 => symbol_table_section_headers.push(&section_headers[i]),
             3 => string_table_section_headers.push(&section_headers[i]),
-            _ => {}
+            _ => continue,
         };
+        // Contents of these are read
+        let end = section_headers[i].sh_offset.checked_add(section_headers[i].sh_size);
+        if end.is_none_or(|end| end > len) {
+            return Err(ElfError::Truncated);
+        }
     }
 
     // Find program data section named .tohost to detect if the elf file is riscv-tests
+    let tohost = analyzer.find_tohost_addr(&program_data_section_headers, &string_table_section_headers);
 
     // Creates symbol - virtual address mapping
     if string_table_section_headers.len() > 0 {
@@ -778,13 +833,20 @@ pub fn elf_setup_mmu(elf: Vec<u8>, mmu: &mut MMU) -> u32 {
         //println!("{:x}", program_data_section_headers[i].sh_addr);
         if sh_addr >= 0x80000000 && sh_size > 0 {
             for j in 0..sh_size {
-                assert!(mmu.write_raw_to_ram(
+                if !mmu.write_raw_to_ram(
                     (sh_addr + j as u64) as u32,
                     analyzer.read_byte(sh_offset + j)
-                ),);
+                ) {
+                    return Err(ElfError::OutsideRam(sh_addr as u32));
+                }
             }
         }
     }
 
-    header.e_entry as u32
+    Ok(ElfInfo {
+        entry: header.e_entry as u32,
+        tohost: tohost.or_else(|| symbol_map.get("tohost").copied()).map(|v| v as u32),
+        symbols: symbol_map,
+        image_end,
+    })
 }
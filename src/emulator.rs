@@ -1,15 +1,337 @@
-use crate::{cpu::CPU, elf_analyzer::elf_setup_mmu, mmu::MMU};
+use std::{collections::HashSet, sync::atomic::Ordering};
 
+use crate::{clint, cpu::CPU, sbi, elf_analyzer::{elf_setup_mmu, ElfError, ElfInfo}, errors::EmulatorError, htif::Htif, mmu::MMU, test_finisher::FinisherRequest};
+
+// Host stop and poweroff are checked between batches of this many instructions
+pub const RUN_BATCH: u64 = 4096;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StopReason {
+    // Whole instruction limit was executed
+    BudgetExhausted,
+    // pc reached breakpoint, instruction at it wasn't executed yet
+    Breakpoint(u32),
+    // Hart executed WFI and no interrupt is pending or can come from timer
+    WaitForInterrupt,
+    Fatal(EmulatorError),
+    // `stopflag` of cpu was set by host
+    HostStop,
+    // Guest powered off with exit code
+    PowerOff(u32),
+    // Guest asked for reset through test finisher, host decides what to do
+    Reset,
+}
+
+// Instructions a hart runs before next one is scheduled, when there is more than one
+pub const DEFAULT_QUANTUM: u64 = 1000;
+
+// Harts share bus (MMU with RAM, devices and reservations) and decode cache, which are moved
+// to hart that runs. Between runs they are held by hart 0.
 pub struct Emulator {
-    pub cpu: CPU,
+    pub harts: Vec<CPU>,
+    // Harts are scheduled round-robin, each runs this many instructions at once
+    pub quantum: u64,
+    // Hart that ran last, stop reason refers to it
+    pub current: usize,
+    pub breakpoints: HashSet<u32>,
+    // Instructions executed by run since creation, all harts together
+    pub executed: u64,
 }
 
 impl Emulator {
-    //TODO: make fallable interface
-    pub fn from_elf(elf: Vec<u8>, mut mmu: MMU) -> Self {
-        let pc = elf_setup_mmu(elf, &mut mmu);
+    pub fn new(cpu: CPU) -> Self {
+        Emulator {
+            harts: vec![cpu],
+            quantum: DEFAULT_QUANTUM,
+            current: 0,
+            breakpoints: HashSet::new(),
+            executed: 0,
+        }
+    }
+    pub fn with_harts(mut self, count: usize) -> Self {
+        self.set_harts(count);
+        self
+    }
+    pub fn with_quantum(mut self, quantum: u64) -> Self {
+        self.quantum = quantum.max(1);
+        self
+    }
+    // Added harts start at pc of hart 0 with the same execution mode
+    pub fn set_harts(&mut self, count: usize) {
+        let count = count.max(1);
+        self.move_bus(self.bus_holder(), 0);
+        self.harts.truncate(count);
+        while self.harts.len() < count {
+            let mut hart = CPU::new(MMU::detached());
+            hart.hartid = self.harts.len() as u32;
+            hart.pc = self.harts[0].pc;
+            hart.execution_mode = self.harts[0].execution_mode;
+            self.harts.push(hart);
+        }
+        self.harts[0].mmu.clint.set_harts(count);
+        self.current = self.current.min(count - 1);
+    }
+    pub fn from_elf(elf: Vec<u8>, mmu: MMU) -> Result<Self, ElfError> {
+        Ok(Self::from_elf_info(elf, mmu)?.0)
+    }
+    pub fn from_elf_info(elf: Vec<u8>, mut mmu: MMU) -> Result<(Self, ElfInfo), ElfError> {
+        let info = elf_setup_mmu(elf, &mut mmu)?;
+        if let Some(tohost) = info.tohost {
+            mmu.htif = Some(Htif::new(tohost, info.symbol("fromhost")));
+        }
         let mut cpu = CPU::new(mmu);
-        cpu.pc = pc;
-        Emulator { cpu }
+        cpu.pc = info.entry;
+        Ok((Emulator::new(cpu), info))
+    }
+    // Exit code written by program through HTIF tohost or test finisher, given to SBI shutdown,
+    // exit syscall or semihosting
+    pub fn exit_code(&self) -> Option<u32> {
+        let mmu = &self.harts[self.bus_holder()].mmu;
+        let htif = mmu.htif.as_ref().and_then(|h| h.exit_code);
+        htif.or(mmu.sbi.as_ref().and_then(|s| s.exit_code))
+            .or(mmu.linux_user.as_ref().and_then(|u| u.exit_code))
+            .or(mmu.newlib.as_ref().and_then(|n| n.exit_code))
+            .or(mmu.semihosting.as_ref().and_then(|s| s.exit_code))
+            .or(match mmu.finisher {
+                Some(FinisherRequest::Exit(code)) => Some(code),
+                _ => None,
+            })
+    }
+    // Only inside of run bus can be somewhere else than in hart 0
+    fn bus_holder(&self) -> usize {
+        self.harts
+            .iter()
+            .position(|hart| hart.mmu.has_ram())
+            .unwrap_or(0)
+    }
+    fn move_bus(&mut self, from: usize, to: usize) {
+        if from != to {
+            let (a, b) = if from < to {
+                let (left, right) = self.harts.split_at_mut(to);
+                (&mut left[from], &mut right[0])
+            } else {
+                let (left, right) = self.harts.split_at_mut(from);
+                (&mut right[0], &mut left[to])
+            };
+            std::mem::swap(&mut a.mmu, &mut b.mmu);
+            std::mem::swap(&mut a.decode_cache, &mut b.decode_cache);
+        }
+        // Others were parked, so bus state may have changed
+        let total: u64 = self.harts.iter().map(|hart| hart.instret).sum();
+        let hart = &mut self.harts[to];
+        hart.other_instret = total - hart.instret;
+        hart.next_timer_check = 0;
+    }
+    // Moves bus to next hart that isn't idle, starting after current one. Returns false if
+    // all are idle.
+    fn schedule(&mut self, holder: &mut usize) -> bool {
+        for i in 1..=self.harts.len() {
+            let next = (self.current + i) % self.harts.len();
+            self.move_bus(*holder, next);
+            *holder = next;
+            sbi::update(&mut self.harts[next]);
+            clint::update(&mut self.harts[next]);
+            if !self.harts[next].is_idle() {
+                self.current = next;
+                return true;
+            }
+        }
+        false
+    }
+    fn stop_requested(&self) -> bool {
+        self.harts
+            .iter()
+            .filter_map(|hart| hart.stopflag.as_ref())
+            .any(|flag| flag.swap(false, Ordering::Relaxed))
+    }
+    // Runs at most `limit` instructions on all harts together. Breakpoint at pc where run
+    // starts doesn't stop it, so execution can be resumed from breakpoint. Host stop clears
+    // `stopflag`.
+    pub fn run(&mut self, limit: u64) -> StopReason {
+        let mut holder = self.bus_holder();
+        self.move_bus(holder, self.current);
+        holder = self.current;
+        let reason = self.run_harts(limit, &mut holder);
+        self.move_bus(holder, 0);
+        reason
+    }
+    fn run_harts(&mut self, limit: u64, holder: &mut usize) -> StopReason {
+        let mut executed = 0;
+        let mut resumed = true;
+        // Instructions current hart ran in its quantum
+        let mut used = 0;
+        let quantum = if self.harts.len() == 1 { u64::MAX } else { self.quantum };
+        loop {
+            if let Some(code) = self.exit_code() {
+                return StopReason::PowerOff(code);
+            }
+            // Reported once, machine is reset by host
+            let mmu = &mut self.harts[*holder].mmu;
+            if mmu.finisher == Some(FinisherRequest::Reset) {
+                mmu.finisher = None;
+                return StopReason::Reset;
+            }
+            if self.stop_requested() {
+                return StopReason::HostStop;
+            }
+            if used >= quantum || self.harts[self.current].is_idle() {
+                used = 0;
+                if !self.schedule(holder) {
+                    // Idle time is skipped or slept through until timer wakes some hart
+                    if !clint::wait_for_interrupt(&mut self.harts, *holder) {
+                        return StopReason::WaitForInterrupt;
+                    }
+                    continue;
+                }
+            }
+            let cpu = &mut self.harts[self.current];
+            if !resumed && self.breakpoints.contains(&cpu.pc) {
+                return StopReason::Breakpoint(cpu.pc);
+            }
+            if executed >= limit {
+                return StopReason::BudgetExhausted;
+            }
+            resumed = false;
+            // Breakpoints need pc of every instruction
+            let batch = if self.breakpoints.is_empty() { RUN_BATCH } else { 1 };
+            let before = executed;
+            let res = cpu.run(batch.min(limit - executed).min(quantum - used), &mut executed);
+            used += executed - before;
+            self.executed += executed - before;
+            if let Err(e) = res {
+                return StopReason::Fatal(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    use crate::{
+        assembler::*,
+        clint::CLINT_ADDRESS,
+        cpu::EXECUTION_MODES,
+        elf_analyzer::ElfError,
+        errors::EmulatorError,
+        htif::Htif,
+        mmu::{MMU, RAM_ADDRESS},
+    };
+
+    use super::{Emulator, StopReason};
+
+    fn emulator(asm: &Assembler) -> Emulator {
+        asm.emulator()
+    }
+
+    #[test]
+    fn test_budget_and_breakpoint() {
+        let mut asm = Assembler::new();
+        asm.label("loop").addi(A0, A0, 1).addi(A1, A1, 1).j("loop");
+        for &mode in EXECUTION_MODES {
+            let mut emu = emulator(&asm);
+            emu.harts[0].execution_mode = mode;
+            assert_eq!(emu.run(10000), StopReason::BudgetExhausted);
+            assert_eq!(emu.executed, 10000);
+            emu.breakpoints.insert(RAM_ADDRESS + 4);
+            let reason = emu.run(10000);
+            assert_eq!(reason, StopReason::Breakpoint(RAM_ADDRESS + 4), "{mode:?}");
+            // Resuming from breakpoint executes whole loop once
+            assert_eq!(emu.run(10000), StopReason::Breakpoint(RAM_ADDRESS + 4));
+            assert_eq!(emu.harts[0].get_registers()[A1 as usize], 3335);
+        }
+    }
+
+    #[test]
+    fn test_invalid_elf() {
+        let load = |elf: &[u8]| Emulator::from_elf(elf.to_vec(), MMU::new().0).err();
+        assert_eq!(load(b"#!/bin/sh\n"), Some(ElfError::NotElf));
+        assert_eq!(load(b"\x7fELF\x01\x01\x01"), Some(ElfError::Truncated));
+        // Section header table is past end of file
+        let mut header = vec![0; 52];
+        header[..5].copy_from_slice(b"\x7fELF\x01");
+        header[32..36].copy_from_slice(&1000u32.to_le_bytes());
+        header[48..50].copy_from_slice(&1u16.to_le_bytes());
+        assert_eq!(load(&header), Some(ElfError::Truncated));
+    }
+
+    #[test]
+    fn test_wait_for_interrupt() {
+        let mut asm = Assembler::new();
+        asm.li(T0, 8).csrw(0x304, T0).wfi().li(A0, 1).ecall();
+        let mut emu = emulator(&asm);
+        assert_eq!(emu.run(100), StopReason::WaitForInterrupt);
+        assert_eq!(emu.harts[0].get_registers()[A0 as usize], 0);
+        // Pending software interrupt wakes hart, but isn't taken with MIE clear
+        emu.harts[0].mmu.clint.msip[0] = true;
+        assert_eq!(emu.run(100), StopReason::Fatal(EmulatorError::UnsetTrapHandler));
+        assert_eq!(emu.harts[0].get_registers()[A0 as usize], 1);
+    }
+
+    #[test]
+    fn test_host_stop() {
+        let mut asm = Assembler::new();
+        asm.label("loop").j("loop");
+        let mut emu = emulator(&asm);
+        let flag = Arc::new(AtomicBool::new(true));
+        emu.harts[0].stopflag = Some(flag.clone());
+        assert_eq!(emu.run(100), StopReason::HostStop);
+        assert_eq!(emu.executed, 0);
+        assert_eq!(emu.run(100), StopReason::BudgetExhausted);
+    }
+
+    #[test]
+    fn test_power_off() {
+        let mut asm = Assembler::new();
+        asm.li(A0, 0x80003000).li(A1, (3 << 1) | 1).sw(A1, 0, A0).sw(ZERO, 4, A0);
+        asm.label("loop").j("loop");
+        let mut emu = emulator(&asm);
+        emu.harts[0].mmu.htif = Some(Htif::new(0x80003000, None));
+        assert_eq!(emu.run(100000), StopReason::PowerOff(3));
+    }
+
+    #[test]
+    fn test_smp_lr_sc() {
+        // Every hart increments shared counter with LR/SC, small quantum splits pairs often
+        let mut asm = Assembler::new();
+        asm.csrr(A0, 0xF14).li(A1, 0x80100000).li(A2, 500).li(A3, 0x80100004);
+        asm.label("loop").lr_w(T0, A1).addi(T0, T0, 1).sc_w(T1, T0, A1).bne(T1, ZERO, "loop");
+        asm.addi(S0, S0, 1).addi(A2, A2, -1).bne(A2, ZERO, "loop");
+        asm.li(T0, 1).amoadd_w(ZERO, T0, A3).bne(A0, ZERO, "park");
+        asm.label("wait").lw(T0, 0, A3).li(T1, 3).bne(T0, T1, "wait").ecall();
+        asm.label("park").wfi().j("park");
+        for &mode in EXECUTION_MODES {
+            for quantum in [3, 7, 1000] {
+                let mut emu = emulator(&asm).with_harts(3).with_quantum(quantum);
+                emu.harts.iter_mut().for_each(|hart| hart.execution_mode = mode);
+                let reason = emu.run(1_000_000);
+                assert_eq!(reason, StopReason::Fatal(EmulatorError::UnsetTrapHandler), "{mode:?}");
+                assert_eq!(emu.current, 0);
+                assert_eq!(emu.harts[0].mmu.read_word(0x80100000), Ok(1500), "{mode:?} {quantum}");
+                for (hartid, hart) in emu.harts.iter().enumerate() {
+                    assert_eq!(hart.get_registers()[A0 as usize], hartid as u32);
+                    assert_eq!(hart.get_registers()[S0 as usize], 500);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_smp_software_interrupt() {
+        // Hart 0 wakes hart 1 through its msip, hart 1 reports back through memory
+        let mut asm = Assembler::new();
+        asm.li(A1, CLINT_ADDRESS).li(A2, 0x80100000).csrr(A0, 0xF14).bne(A0, ZERO, "hart1");
+        asm.li(T0, 1).sw(T0, 4, A1);
+        asm.label("wait").lw(T0, 0, A2).beq(T0, ZERO, "wait").ecall();
+        asm.label("hart1").la(T0, "handler").csrw(0x305, T0).li(T0, 8).csrw(0x304, T0);
+        asm.csrw(0x300, T0).label("idle").wfi().j("idle");
+        asm.label("handler").sw(ZERO, 4, A1).csrr(T0, 0x342).sw(T0, 0, A2).mret();
+        let mut emu = emulator(&asm).with_harts(2).with_quantum(10);
+        let reason = emu.run(10_000);
+        assert_eq!(reason, StopReason::Fatal(EmulatorError::UnsetTrapHandler));
+        assert_eq!(emu.harts[0].mmu.read_word(0x80100000), Ok(0x80000003));
+        assert_eq!(emu.harts[0].mmu.clint.msip, [false, false]);
+        assert!(emu.harts[1].is_idle());
     }
 }
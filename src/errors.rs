@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum EmulatorError {
     UnsetTrapHandler
-}
\ No newline at end of file
+}
@@ -0,0 +1,432 @@
+// Flattened device tree (DTB) describing machine to guest. Tree is built from FdtNode values
+// and serialized to version 17 blob, all integers big-endian. Only devices this emulator
+// has are described, so there is no PLIC or virtio node.
+
+use std::collections::HashMap;
+
+use crate::{
+    clock::TIMEBASE_FREQUENCY,
+    clint::{CLINT_ADDRESS, CLINT_SIZE},
+    csr::MISA,
+    emulator::Emulator,
+    mmu::{PAGE_SIZE, PRIMITIVE_AUDIO_ADDRESS, RAM_ADDRESS, RAM_ADDRESS_END, RAM_SIZE, UART_ADDRESS, UART_REGION_SIZE},
+    test_finisher::{FINISHER_PASS, FINISHER_RESET, TEST_FINISHER_ADDRESS, TEST_FINISHER_SIZE},
+};
+
+pub const FDT_MAGIC: u32 = 0xd00dfeed;
+pub const FDT_VERSION: u32 = 17;
+pub const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// Extensions beyond single letter ones in misa
+pub const ISA_EXTENSIONS: [&str; 4] = ["zicntr", "zicsr", "zifencei", "zihpm"];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FdtNode {
+    // With unit address, e.g. "memory@80000000", root node has empty name
+    pub name: String,
+    pub properties: Vec<(String, Vec<u8>)>,
+    pub children: Vec<FdtNode>,
+}
+
+impl FdtNode {
+    pub fn new(name: &str) -> Self {
+        FdtNode {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+    pub fn with_property(mut self, name: &str, value: Vec<u8>) -> Self {
+        self.set_property(name, value);
+        self
+    }
+    pub fn with_empty(self, name: &str) -> Self {
+        self.with_property(name, vec![])
+    }
+    pub fn with_cells(self, name: &str, cells: &[u32]) -> Self {
+        self.with_property(name, cells.iter().flat_map(|c| c.to_be_bytes()).collect())
+    }
+    pub fn with_u32(self, name: &str, value: u32) -> Self {
+        self.with_cells(name, &[value])
+    }
+    pub fn with_string(self, name: &str, value: &str) -> Self {
+        self.with_strings(name, &[value])
+    }
+    // String list, each one nul-terminated
+    pub fn with_strings(self, name: &str, values: &[&str]) -> Self {
+        let value = values.iter().flat_map(|s| s.bytes().chain([0])).collect();
+        self.with_property(name, value)
+    }
+    pub fn with_child(mut self, child: FdtNode) -> Self {
+        self.children.push(child);
+        self
+    }
+    // Replaces value of existing property
+    pub fn set_property(&mut self, name: &str, value: Vec<u8>) {
+        match self.properties.iter_mut().find(|(n, _)| n == name) {
+            Some((_, old)) => *old = value,
+            None => self.properties.push((name.to_string(), value)),
+        }
+    }
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_slice())
+    }
+    pub fn child(&self, name: &str) -> Option<&FdtNode> {
+        self.children.iter().find(|c| c.name == name)
+    }
+    // Adds empty child if there is none with this name
+    pub fn child_mut(&mut self, name: &str) -> &mut FdtNode {
+        match self.children.iter().position(|c| c.name == name) {
+            Some(index) => &mut self.children[index],
+            None => {
+                self.children.push(FdtNode::new(name));
+                self.children.last_mut().unwrap()
+            }
+        }
+    }
+    pub fn to_blob(&self, boot_cpuid: u32) -> Vec<u8> {
+        let mut writer = FdtWriter::default();
+        writer.node(self);
+        writer.u32(FDT_END);
+        writer.finish(boot_cpuid)
+    }
+    // None if blob isn't valid device tree
+    pub fn from_blob(blob: &[u8]) -> Option<FdtNode> {
+        let header = |index: usize| -> Option<u32> {
+            Some(u32::from_be_bytes(blob.get(index * 4..index * 4 + 4)?.try_into().unwrap()))
+        };
+        if header(0)? != FDT_MAGIC || header(6)? > FDT_VERSION {
+            return None;
+        }
+        let total = (header(1)? as usize).min(blob.len());
+        let structure = blob.get(header(2)? as usize..total)?;
+        let strings = blob.get(header(3)? as usize..total)?;
+        let mut reader = FdtReader {
+            structure,
+            strings,
+            offset: 0,
+        };
+        while reader.u32()? == FDT_NOP {}
+        reader.offset -= 4;
+        reader.node()
+    }
+}
+
+#[derive(Default)]
+struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+}
+
+impl FdtWriter {
+    fn u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+    // Structure block is aligned to 4 bytes after names and values
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.structure.extend_from_slice(bytes);
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+    // Property names are stored once in strings block
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend(name.bytes().chain([0]));
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+    fn node(&mut self, node: &FdtNode) {
+        self.u32(FDT_BEGIN_NODE);
+        self.bytes(&node.name.bytes().chain([0]).collect::<Vec<_>>());
+        for (name, value) in &node.properties {
+            let offset = self.string_offset(name);
+            self.u32(FDT_PROP);
+            self.u32(value.len() as u32);
+            self.u32(offset);
+            self.bytes(value);
+        }
+        for child in &node.children {
+            self.node(child);
+        }
+        self.u32(FDT_END_NODE);
+    }
+    // Header, empty memory reservation map, structure and strings
+    fn finish(self, boot_cpuid: u32) -> Vec<u8> {
+        let reservations = FDT_HEADER_SIZE;
+        let structure = reservations + 16;
+        let strings = structure + self.structure.len();
+        let total = strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            total as u32,
+            structure as u32,
+            strings as u32,
+            reservations as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            boot_cpuid,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob: Vec<u8> = header.iter().flat_map(|v| v.to_be_bytes()).collect();
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+struct FdtReader<'a> {
+    structure: &'a [u8],
+    strings: &'a [u8],
+    offset: usize,
+}
+
+impl FdtReader<'_> {
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.structure.get(self.offset..self.offset + 4)?;
+        self.offset += 4;
+        Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+    fn bytes(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.structure.get(self.offset..self.offset + len)?;
+        self.offset = (self.offset + len).next_multiple_of(4);
+        Some(bytes)
+    }
+    fn string(bytes: &[u8]) -> Option<String> {
+        let end = bytes.iter().position(|b| *b == 0)?;
+        String::from_utf8(bytes[..end].to_vec()).ok()
+    }
+    fn node(&mut self) -> Option<FdtNode> {
+        if self.u32()? != FDT_BEGIN_NODE {
+            return None;
+        }
+        let rest = self.structure.get(self.offset..)?;
+        let name = Self::string(rest)?;
+        self.bytes(name.len() + 1)?;
+        let mut node = FdtNode::new(&name);
+        loop {
+            match self.u32()? {
+                FDT_PROP => {
+                    let len = self.u32()? as usize;
+                    let name = Self::string(self.strings.get(self.u32()? as usize..)?)?;
+                    let value = self.bytes(len)?.to_vec();
+                    node.properties.push((name, value));
+                }
+                FDT_BEGIN_NODE => {
+                    self.offset -= 4;
+                    node.children.push(self.node()?);
+                }
+                FDT_NOP => {}
+                FDT_END_NODE => return Some(node),
+                _ => return None,
+            }
+        }
+    }
+}
+
+// Single letter extensions from misa in canonical order
+fn misa_extensions() -> Vec<String> {
+    "imafdqlcbkjtpvh"
+        .chars()
+        .filter(|c| MISA & (1 << (*c as u8 - b'a')) != 0)
+        .map(String::from)
+        .collect()
+}
+
+// ISA string like "rv32ima_zicsr"
+pub fn isa_string() -> String {
+    format!("rv32{}_{}", misa_extensions().concat(), ISA_EXTENSIONS.join("_"))
+}
+
+fn cpu_node(hartid: u32) -> FdtNode {
+    let mut extensions = misa_extensions();
+    extensions.extend(ISA_EXTENSIONS.map(String::from));
+    let extensions: Vec<&str> = extensions.iter().map(String::as_str).collect();
+    FdtNode::new(&format!("cpu@{hartid:x}"))
+        .with_string("device_type", "cpu")
+        .with_u32("reg", hartid)
+        .with_string("status", "okay")
+        .with_string("compatible", "riscv")
+        .with_string("riscv,isa", &isa_string())
+        .with_string("riscv,isa-base", "rv32i")
+        .with_strings("riscv,isa-extensions", &extensions)
+        .with_child(
+            FdtNode::new("interrupt-controller")
+                .with_u32("#interrupt-cells", 1)
+                .with_empty("interrupt-controller")
+                .with_string("compatible", "riscv,cpu-intc")
+                .with_u32("phandle", intc_phandle(hartid)),
+        )
+}
+
+// Interrupt controller of each hart, other phandles come after them
+fn intc_phandle(hartid: u32) -> u32 {
+    hartid + 1
+}
+
+// Describes harts, memory and devices of emulator
+pub fn generate(emu: &Emulator) -> FdtNode {
+    let harts = emu.harts.len() as u32;
+    let mut cpus = FdtNode::new("cpus")
+        .with_u32("#address-cells", 1)
+        .with_u32("#size-cells", 0)
+        .with_u32("timebase-frequency", TIMEBASE_FREQUENCY as u32);
+    for hartid in 0..harts {
+        cpus = cpus.with_child(cpu_node(hartid));
+    }
+    // Phandles after interrupt controllers of harts
+    let finisher_phandle = intc_phandle(harts);
+    let clint_interrupts: Vec<u32> = (0..harts)
+        .flat_map(|hartid| [intc_phandle(hartid), 3, intc_phandle(hartid), 7])
+        .collect();
+    let soc = FdtNode::new("soc")
+        .with_u32("#address-cells", 1)
+        .with_u32("#size-cells", 1)
+        .with_string("compatible", "simple-bus")
+        .with_empty("ranges")
+        .with_child(
+            FdtNode::new(&format!("clint@{CLINT_ADDRESS:x}"))
+                .with_strings("compatible", &["sifive,clint0", "riscv,clint0"])
+                .with_cells("reg", &[CLINT_ADDRESS, CLINT_SIZE])
+                .with_cells("interrupts-extended", &clint_interrupts),
+        )
+        // Write-only byte at base, not 16550 compatible
+        .with_child(
+            FdtNode::new(&format!("serial@{UART_ADDRESS:x}"))
+                .with_string("compatible", "rv-emu-rs,uart")
+                .with_cells("reg", &[UART_ADDRESS, UART_REGION_SIZE as u32]),
+        )
+        // Samples are written as halfwords, reading word gives buffer fill level
+        .with_child(
+            FdtNode::new(&format!("audio@{PRIMITIVE_AUDIO_ADDRESS:x}"))
+                .with_string("compatible", "rv-emu-rs,primitive-audio")
+                .with_cells("reg", &[PRIMITIVE_AUDIO_ADDRESS, 4]),
+        )
+        .with_child(
+            FdtNode::new(&format!("test@{TEST_FINISHER_ADDRESS:x}"))
+                .with_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"])
+                .with_cells("reg", &[TEST_FINISHER_ADDRESS, TEST_FINISHER_SIZE])
+                .with_u32("phandle", finisher_phandle),
+        );
+    let mut root = FdtNode::new("")
+        .with_u32("#address-cells", 1)
+        .with_u32("#size-cells", 1)
+        .with_string("compatible", "rv-emu-rs")
+        .with_string("model", "rv-emu-rs")
+        .with_child(FdtNode::new("chosen").with_string("stdout-path", &format!("/soc/serial@{UART_ADDRESS:x}")))
+        .with_child(cpus)
+        .with_child(
+            FdtNode::new(&format!("memory@{RAM_ADDRESS:x}"))
+                .with_string("device_type", "memory")
+                .with_cells("reg", &[RAM_ADDRESS, RAM_SIZE as u32]),
+        )
+        .with_child(soc)
+        .with_child(
+            FdtNode::new("poweroff")
+                .with_string("compatible", "syscon-poweroff")
+                .with_u32("regmap", finisher_phandle)
+                .with_u32("offset", 0)
+                .with_u32("value", FINISHER_PASS),
+        )
+        .with_child(
+            FdtNode::new("reboot")
+                .with_string("compatible", "syscon-reboot")
+                .with_u32("regmap", finisher_phandle)
+                .with_u32("offset", 0)
+                .with_u32("value", FINISHER_RESET),
+        );
+    if emu.harts[0].mmu.htif.is_some() {
+        root = root.with_child(FdtNode::new("htif").with_string("compatible", "ucb,htif0"));
+    }
+    root
+}
+
+// Highest page aligned address where blob fits at end of RAM
+pub fn default_address(len: usize) -> u32 {
+    (RAM_ADDRESS_END + 1 - len as u32) & !(PAGE_SIZE - 1)
+}
+
+impl Emulator {
+    // Copies blob to end of RAM and passes it to every hart: hartid in a0 and address in a1.
+    // Stack starts below it. Returns address of blob.
+    pub fn load_fdt(&mut self, blob: &[u8]) -> u32 {
+        let address = default_address(blob.len());
+        for (i, byte) in blob.iter().enumerate() {
+            self.harts[0].mmu.write_raw_to_ram(address + i as u32, *byte);
+        }
+        for hart in &mut self.harts {
+            let mut regs = hart.get_registers();
+            regs[10] = hart.hartid;
+            regs[11] = address;
+            regs[2] = address - 16;
+            hart.set_registers(regs);
+        }
+        address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::Assembler,
+        htif::Htif,
+        mmu::RAM_ADDRESS,
+    };
+
+    use super::{generate, isa_string, FdtNode, FDT_MAGIC};
+
+    #[test]
+    fn test_round_trip() {
+        let node = FdtNode::new("")
+            .with_u32("#size-cells", 1)
+            .with_strings("compatible", &["a", "bc"])
+            .with_child(FdtNode::new("child@1").with_empty("flag").with_cells("reg", &[1, 2]))
+            .with_child(FdtNode::new("other").with_u32("#size-cells", 2));
+        let blob = node.to_blob(0);
+        assert_eq!(blob[..4], FDT_MAGIC.to_be_bytes());
+        assert_eq!(u32::from_be_bytes(blob[4..8].try_into().unwrap()) as usize, blob.len());
+        assert_eq!(FdtNode::from_blob(&blob), Some(node));
+        assert_eq!(FdtNode::from_blob(&blob[..blob.len() - 8]), None);
+    }
+
+    #[test]
+    fn test_machine_tree() {
+        let mut emu = Assembler::new().emulator().with_harts(2);
+        emu.harts[0].mmu.htif = Some(Htif::new(0x80001000, None));
+        let blob = generate(&emu).to_blob(0);
+        let root = FdtNode::from_blob(&blob).unwrap();
+        let memory = root.child("memory@80000000").unwrap();
+        assert_eq!(memory.property("reg").unwrap(), [0x80, 0, 0, 0, 0x04, 0, 0, 0]);
+        let cpus = root.child("cpus").unwrap();
+        assert_eq!(cpus.children.len(), 2);
+        assert_eq!(cpus.property("timebase-frequency").unwrap(), 1_000_000u32.to_be_bytes());
+        assert_eq!(isa_string(), "rv32ima_zicntr_zicsr_zifencei_zihpm");
+        let cpu = cpus.child("cpu@1").unwrap();
+        assert_eq!(cpu.property("riscv,isa").unwrap(), b"rv32ima_zicntr_zicsr_zifencei_zihpm\0");
+        assert!(cpu.property("riscv,isa-extensions").unwrap().starts_with(b"i\0m\0a\0zicntr\0"));
+        let clint = root.child("soc").unwrap().child("clint@2000000").unwrap();
+        assert_eq!(clint.property("interrupts-extended").unwrap().len(), 2 * 4 * 4);
+        assert!(root.child("htif").is_some());
+        let finisher = root.child("soc").unwrap().child("test@100000").unwrap();
+        assert_eq!(finisher.property("phandle").unwrap(), 3u32.to_be_bytes());
+        assert_eq!(root.child("poweroff").unwrap().property("regmap").unwrap(), 3u32.to_be_bytes());
+
+        let address = emu.load_fdt(&blob);
+        assert_eq!(address % 4096, 0);
+        assert_eq!(emu.harts[0].mmu.read_word(address), Ok(FDT_MAGIC.swap_bytes()));
+        assert_eq!(emu.harts[1].get_registers()[10..12], [1, address]);
+        assert!(RAM_ADDRESS < address);
+    }
+}
@@ -142,8 +142,8 @@ impl SingleThreadBase for Emulator {
         &mut self,
         regs: &mut <Self::Arch as gdbstub::arch::Arch>::Registers,
     ) -> gdbstub::target::TargetResult<(), Self> {
-        regs.x = self.cpu.get_registers();
-        regs.pc = self.cpu.pc;
+        regs.x = self.harts[0].get_registers();
+        regs.pc = self.harts[0].pc;
         Ok(())
     }
 
@@ -151,8 +151,8 @@ impl SingleThreadBase for Emulator {
         &mut self,
         regs: &<Self::Arch as gdbstub::arch::Arch>::Registers,
     ) -> gdbstub::target::TargetResult<(), Self> {
-        self.cpu.set_registers(regs.x);
-        self.cpu.pc = regs.pc;
+        self.harts[0].set_registers(regs.x);
+        self.harts[0].pc = regs.pc;
         Ok(())
     }
 
@@ -162,7 +162,7 @@ impl SingleThreadBase for Emulator {
         data: &mut [u8],
     ) -> gdbstub::target::TargetResult<(), Self> {
         for (address, value) in (start_addr..).zip(data.iter_mut()) {
-            *value = match self.cpu.mmu.read_raw_from_ram(address) {
+            *value = match self.harts[0].mmu.read_raw_from_ram(address) {
                 Some(v) => v,
                 None => {
                     return Err(TargetError::NonFatal);
@@ -178,7 +178,7 @@ impl SingleThreadBase for Emulator {
         data: &[u8],
     ) -> gdbstub::target::TargetResult<(), Self> {
         for (address, value) in (start_addr..).zip(data.iter().copied()) {
-            if !self.cpu.mmu.write_raw_to_ram(address, value){
+            if !self.harts[0].mmu.write_raw_to_ram(address, value){
                 return Err(TargetError::NonFatal);
             };
         }
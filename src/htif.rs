@@ -0,0 +1,139 @@
+// Host-target interface used by riscv-tests, riscv-arch-test and proxy kernel.
+// Target writes 64-bit command to `tohost`, host answers through `fromhost`.
+// On rv32 command is written as two words, so device 0 commands are executed
+// on write of lower half and other devices on write of upper half.
+
+pub const SYS_WRITE: u64 = 64;
+pub const SYS_EXIT: u64 = 93;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HtifCommand {
+    // Test finished, 0 is pass, otherwise number of failed test
+    Exit(u32),
+    // Address of `magic_mem` with syscall number and arguments
+    Syscall(u32),
+    Putchar(u8),
+}
+
+#[derive(Debug, Clone)]
+pub struct Htif {
+    pub tohost: u32,
+    pub fromhost: Option<u32>,
+    pub exit_code: Option<u32>,
+}
+
+impl Htif {
+    pub fn new(tohost: u32, fromhost: Option<u32>) -> Self {
+        Htif {
+            tohost,
+            fromhost,
+            exit_code: None,
+        }
+    }
+    pub fn is_tohost(&self, address: u32) -> bool {
+        address & !0b111 == self.tohost
+    }
+    // Decodes command after write of `word` to `address` inside of tohost.
+    pub fn decode(&self, address: u32, word: u32, other_half: u32) -> Option<HtifCommand> {
+        let (low, high) = if address == self.tohost {
+            if other_half != 0 {
+                return None;
+            }
+            (word, 0)
+        } else {
+            (other_half, word)
+        };
+        let device = high >> 24;
+        let cmd = (high >> 16) & 0xff;
+        match (device, cmd) {
+            (0, 0) if low & 1 != 0 => Some(HtifCommand::Exit(low >> 1)),
+            (0, 0) if low != 0 => Some(HtifCommand::Syscall(low)),
+            (1, 1) => Some(HtifCommand::Putchar(low as u8)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        cpu::EXECUTION_MODES,
+        mmu::{MMU, RAM_ADDRESS_END},
+    };
+
+    use super::{Htif, HtifCommand, SYS_WRITE};
+
+    #[test]
+    fn test_decode() {
+        let htif = Htif::new(0x80001000, None);
+        assert!(htif.is_tohost(0x80001004));
+        assert!(!htif.is_tohost(0x80001008));
+        assert_eq!(htif.decode(0x80001000, 1, 0), Some(HtifCommand::Exit(0)));
+        assert_eq!(htif.decode(0x80001000, 7, 0), Some(HtifCommand::Exit(3)));
+        assert_eq!(htif.decode(0x80001000, 0x80002000, 0), Some(HtifCommand::Syscall(0x80002000)));
+        assert_eq!(htif.decode(0x80001004, 0, 1), Some(HtifCommand::Exit(0)));
+        assert_eq!(htif.decode(0x80001004, 0x01010000, b'a' as u32), Some(HtifCommand::Putchar(b'a')));
+        assert_eq!(htif.decode(0x80001000, b'a' as u32, 0x01010000), None);
+    }
+
+    #[test]
+    fn test_mmu_tohost() {
+        let (mut mmu, _audio) = MMU::new();
+        mmu.htif = Some(Htif::new(0x80001000, Some(0x80001008)));
+        // Syscall write(1, "hi", 2) through magic_mem
+        mmu.write_word(0x80002000, b'h' as u32 | (b'i' as u32) << 8).unwrap();
+        for (i, arg) in [SYS_WRITE as u32, 1, 0x80002000, 2].into_iter().enumerate() {
+            mmu.write_word(0x80003000 + i as u32 * 8, arg).unwrap();
+        }
+        mmu.write_word(0x80001000, 0x80003000).unwrap();
+        mmu.write_word(0x80001004, 0).unwrap();
+        assert_eq!(mmu.uart.try_get_byte(), Some(b'h'));
+        assert_eq!(mmu.uart.try_get_byte(), Some(b'i'));
+        assert_eq!(mmu.read_word(0x80003000), Ok(2));
+        assert_eq!(mmu.read_word(0x80001000), Ok(0));
+        assert_eq!(mmu.read_word(0x80001008), Ok(1));
+        assert_eq!(mmu.htif.as_ref().unwrap().exit_code, None);
+
+        mmu.write_word(0x80001000, (5 << 1) | 1).unwrap();
+        mmu.write_word(0x80001004, 0).unwrap();
+        assert_eq!(mmu.htif.as_ref().unwrap().exit_code, Some(5));
+    }
+
+    #[test]
+    fn test_write_bounds() {
+        let (mut mmu, _audio) = MMU::new();
+        mmu.htif = Some(Htif::new(0x80001000, None));
+        let write = |mmu: &mut MMU, buffer: u32, len: u32| {
+            for (i, arg) in [SYS_WRITE as u32, 1, buffer, len].into_iter().enumerate() {
+                mmu.write_word(0x80003000 + i as u32 * 8, arg).unwrap();
+                mmu.write_word(0x80003004 + i as u32 * 8, 0).unwrap();
+            }
+            mmu.write_word(0x80001000, 0x80003000).unwrap();
+            mmu.write_word(0x80001004, 0).unwrap();
+            mmu.read_word(0x80003000).unwrap()
+        };
+        // Outside of RAM
+        assert_eq!(write(&mut mmu, 0xFFFF_FFF0, 0x100), -14i32 as u32);
+        assert_eq!(mmu.uart.try_get_byte(), None);
+        // Cut at end of RAM
+        mmu.write_word(RAM_ADDRESS_END - 3, u32::from_le_bytes(*b"abcd")).unwrap();
+        assert_eq!(write(&mut mmu, RAM_ADDRESS_END - 1, u32::MAX), 2);
+        assert_eq!(std::iter::from_fn(|| mmu.uart.try_get_byte()).collect::<Vec<_>>(), b"cd");
+    }
+
+    #[test]
+    fn test_exit_stops_hart() {
+        let mut asm = Assembler::new();
+        asm.li(A0, 0x80003000).li(A1, (7 << 1) | 1).sw(A1, 0, A0).li(A2, 1).ecall();
+        for &mode in EXECUTION_MODES {
+            let mut cpu = asm.cpu();
+            cpu.mmu.htif = Some(Htif::new(0x80003000, None));
+            cpu.execution_mode = mode;
+            cpu.run(100, &mut 0).unwrap();
+            assert!(cpu.stopped, "{mode:?}");
+            assert_eq!(cpu.get_registers()[A2 as usize], 0, "{mode:?}");
+            assert_eq!(cpu.mmu.htif.as_ref().unwrap().exit_code, Some(7));
+        }
+    }
+}
@@ -0,0 +1,654 @@
+// Translates hot RV32 basic blocks into x86-64 code, see x86_emitter for register usage.
+// Guest registers stay in CPU, loads and stores to RAM are done inline, everything else
+// (devices, pages with code, tohost, unsupported instructions) exits at that instruction
+// and is executed by interpreter, which also takes care of traps.
+
+use std::{collections::HashMap, ptr};
+
+use crate::{
+    block_engine::MAX_BLOCK_LENGTH,
+    cpu::CPU,
+    decode_cache::DecodedInstruction,
+    errors::EmulatorError,
+    mmu::{PAGE_SIZE, RAM_ADDRESS, RAM_SIZE},
+    x86_emitter::{Alu, Cond, Emitter, Label, Reg, Shift, Width},
+};
+
+#[cfg(not(all(target_arch = "x86_64", unix)))]
+compile_error!("jit feature is only supported on x86-64 unix hosts");
+
+pub const CODE_BUFFER_SIZE: usize = 32 * 1024 * 1024;
+
+// Returned by block: pc is start of next block
+const EXIT_NEXT: u32 = 0;
+// Returned by block: instruction at pc has to be executed by interpreter
+const EXIT_INTERPRET: u32 = 1;
+
+// Shared with generated code, offsets below have to match layout
+#[repr(C)]
+pub struct JitContext {
+    ram: *mut u8,
+    code_pages: *const u64,
+    pc: u32,
+    // Page of RAM where stores always exit, used for HTIF tohost
+    slow_page: u32,
+    retired: u64,
+}
+
+const CONTEXT_PC: u8 = 16;
+const CONTEXT_SLOW_PAGE: u8 = 20;
+const CONTEXT_RETIRED: u8 = 24;
+
+type BlockFn = unsafe extern "sysv64" fn(regs: *mut u32, context: *mut JitContext) -> u32;
+
+// Anonymous mapping that is writable only while code is copied into it
+struct CodeBuffer {
+    ptr: *mut u8,
+    used: usize,
+}
+
+impl CodeBuffer {
+    fn new() -> Self {
+        // SAFETY: fresh private anonymous mapping, result is checked
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                CODE_BUFFER_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert!(ptr != libc::MAP_FAILED, "Can't map JIT code buffer");
+        CodeBuffer {
+            ptr: ptr as *mut u8,
+            used: 0,
+        }
+    }
+    // Copies code in and returns its entry, None when buffer is full
+    fn push(&mut self, code: &[u8]) -> Option<BlockFn> {
+        if self.used + code.len() > CODE_BUFFER_SIZE {
+            return None;
+        }
+        let page = PAGE_SIZE as usize;
+        let start = self.used / page * page;
+        let end = (self.used + code.len()).div_ceil(page) * page;
+        // SAFETY: range is inside of mapping and none of its code is running right now
+        unsafe {
+            let pages = self.ptr.add(start) as *mut libc::c_void;
+            assert_eq!(libc::mprotect(pages, end - start, libc::PROT_READ | libc::PROT_WRITE), 0);
+            ptr::copy_nonoverlapping(code.as_ptr(), self.ptr.add(self.used), code.len());
+            assert_eq!(libc::mprotect(pages, end - start, libc::PROT_READ | libc::PROT_EXEC), 0);
+        }
+        // SAFETY: code was generated by translate for BlockFn calling convention
+        let entry = unsafe { std::mem::transmute::<*mut u8, BlockFn>(self.ptr.add(self.used)) };
+        self.used = (self.used + code.len()).next_multiple_of(16);
+        Some(entry)
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        // SAFETY: mapping was created in new and no block can run after cache is dropped
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, CODE_BUFFER_SIZE);
+        }
+    }
+}
+
+pub struct JitBlock {
+    pub start: u32,
+    // Number of guest instructions, when all of them execute
+    pub len: usize,
+    entry: BlockFn,
+    links: [Option<(u32, usize)>; 2],
+}
+
+pub struct JitCache {
+    code: CodeBuffer,
+    blocks: Vec<JitBlock>,
+    // None for pc that starts with instruction, which can't be translated
+    lookup: HashMap<u32, Option<usize>>,
+    // Decode cache generation that blocks were translated for
+    generation: u64,
+}
+
+impl Default for JitCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JitCache {
+    pub fn new() -> Self {
+        JitCache {
+            code: CodeBuffer::new(),
+            blocks: vec![],
+            lookup: HashMap::new(),
+            generation: 0,
+        }
+    }
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.lookup.clear();
+        self.code.used = 0;
+    }
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+    fn find_or_translate(&mut self, cpu: &mut CPU, pc: u32) -> Option<usize> {
+        if let Some(index) = self.lookup.get(&pc) {
+            return *index;
+        }
+        let index = translate(cpu, pc).map(|(code, len)| {
+            let entry = match self.code.push(&code) {
+                Some(entry) => entry,
+                None => {
+                    self.clear();
+                    self.code.push(&code).expect("Block is larger than JIT code buffer")
+                }
+            };
+            self.blocks.push(JitBlock {
+                start: pc,
+                len,
+                entry,
+                links: [None, None],
+            });
+            self.blocks.len() - 1
+        });
+        self.lookup.insert(pc, index);
+        index
+    }
+    fn successor(&mut self, cpu: &mut CPU, from: usize, pc: u32) -> Option<usize> {
+        for (target, index) in self.blocks[from].links.iter().flatten() {
+            if *target == pc {
+                return Some(*index);
+            }
+        }
+        let count = self.blocks.len();
+        let index = self.find_or_translate(cpu, pc)?;
+        // Translation could have flushed full buffer together with `from`
+        if self.blocks.len() >= count {
+            let links = &mut self.blocks[from].links;
+            links[1] = links[0];
+            links[0] = Some((pc, index));
+        }
+        Some(index)
+    }
+    // Runs block, returns exit code and number of retired instructions
+    fn execute(&self, cpu: &mut CPU, index: usize) -> (u32, u64) {
+        let slow_page = match &cpu.mmu.htif {
+            Some(htif) => htif.tohost.wrapping_sub(RAM_ADDRESS) / PAGE_SIZE,
+            None => u32::MAX,
+        };
+        let mut context = JitContext {
+            ram: cpu.mmu.ram_ptr(),
+            code_pages: cpu.mmu.code_pages_ptr(),
+            pc: 0,
+            slow_page,
+            retired: 0,
+        };
+        let regs = cpu.registers_ptr();
+        // SAFETY: pointers stay valid during call, nothing else accesses cpu meanwhile
+        let exit = unsafe { (self.blocks[index].entry)(regs, &mut context) };
+        cpu.pc = context.pc;
+        cpu.instret += context.retired;
+        (exit, context.retired)
+    }
+}
+
+fn exit(e: &mut Emitter, pc: u32, retired: usize, code: u32) {
+    e.store_context_imm(CONTEXT_PC, pc);
+    if retired > 0 {
+        e.add_context_u64(CONTEXT_RETIRED, retired as i8);
+    }
+    e.mov_imm(Reg::Eax, code);
+    e.ret();
+}
+
+enum Translated {
+    Continue,
+    EndOfBlock,
+    Unsupported,
+}
+
+// Exit to interpreter for instruction that was reached after `retired` others
+struct SlowPath {
+    label: Label,
+    pc: u32,
+    retired: usize,
+}
+
+// eax = offset of accessed address in RAM, jumps to slow path when access isn't inside of it
+fn emit_ram_offset(e: &mut Emitter, d: &DecodedInstruction, size: u32, slow: &SlowPath) {
+    e.load_guest(Reg::Eax, d.rs1);
+    e.alu_imm(Alu::Add, Reg::Eax, d.imm);
+    e.alu_imm(Alu::Sub, Reg::Eax, RAM_ADDRESS);
+    e.alu_imm(Alu::Cmp, Reg::Eax, RAM_SIZE as u32 - size);
+    e.jcc(Cond::Above, slow.label);
+}
+
+fn translate_instruction(
+    e: &mut Emitter,
+    d: &DecodedInstruction,
+    pc: u32,
+    retired: usize,
+    slow_paths: &mut Vec<SlowPath>,
+) -> Translated {
+    let opcode = d.instr & 0x7F;
+    let funct3 = (d.instr >> 12) & 0b111;
+    let funct7 = d.instr >> 25;
+    let rr = |e: &mut Emitter| {
+        e.load_guest(Reg::Eax, d.rs1);
+        e.load_guest(Reg::Ecx, d.rs2);
+    };
+    match (opcode, funct3) {
+        (0b0110111, _) => {
+            e.store_guest_imm(d.rd, d.imm);
+        }
+        (0b0010111, _) => {
+            e.store_guest_imm(d.rd, pc.wrapping_add(d.imm));
+        }
+        (0b0010011, _) => {
+            let op = match (funct3, funct7) {
+                (0b000, _) => Ok(Alu::Add),
+                (0b100, _) => Ok(Alu::Xor),
+                (0b110, _) => Ok(Alu::Or),
+                (0b111, _) => Ok(Alu::And),
+                (0b010, _) => Err(Ok(Cond::Less)),
+                (0b011, _) => Err(Ok(Cond::Below)),
+                (0b001, 0) => Err(Err(Shift::Shl)),
+                (0b101, 0) => Err(Err(Shift::Shr)),
+                (0b101, 0b0100000) => Err(Err(Shift::Sar)),
+                _ => return Translated::Unsupported,
+            };
+            e.load_guest(Reg::Eax, d.rs1);
+            match op {
+                Ok(op) => e.alu_imm(op, Reg::Eax, d.imm),
+                Err(Ok(cond)) => {
+                    e.alu_imm(Alu::Cmp, Reg::Eax, d.imm);
+                    e.set_eax(cond);
+                }
+                Err(Err(shift)) => e.shift_imm(shift, Reg::Eax, d.imm as u8),
+            }
+            e.store_guest(d.rd, Reg::Eax);
+        }
+        (0b0110011, _) => {
+            match (funct7, funct3) {
+                (0, 0b000) | (0b0100000, 0b000) | (0, 0b100) | (0, 0b110) | (0, 0b111) => {
+                    let op = match (funct7, funct3) {
+                        (0, 0b000) => Alu::Add,
+                        (0b0100000, _) => Alu::Sub,
+                        (_, 0b100) => Alu::Xor,
+                        (_, 0b110) => Alu::Or,
+                        _ => Alu::And,
+                    };
+                    rr(e);
+                    e.alu(op, Reg::Eax, Reg::Ecx);
+                }
+                (0, 0b010) | (0, 0b011) => {
+                    rr(e);
+                    e.alu(Alu::Cmp, Reg::Eax, Reg::Ecx);
+                    e.set_eax(if funct3 == 0b010 { Cond::Less } else { Cond::Below });
+                }
+                (0, 0b001) | (0, 0b101) | (0b0100000, 0b101) => {
+                    let shift = match (funct7, funct3) {
+                        (_, 0b001) => Shift::Shl,
+                        (0, _) => Shift::Shr,
+                        _ => Shift::Sar,
+                    };
+                    rr(e);
+                    e.shift_cl(shift, Reg::Eax);
+                }
+                (1, 0b000) => {
+                    rr(e);
+                    e.imul(Reg::Eax, Reg::Ecx);
+                }
+                (1, 0b001) | (1, 0b011) => {
+                    rr(e);
+                    e.mul_wide(funct3 == 0b001, Reg::Ecx);
+                    e.mov(Reg::Eax, Reg::Edx);
+                }
+                // mulhsu and division are left to interpreter
+                _ => return Translated::Unsupported,
+            }
+            e.store_guest(d.rd, Reg::Eax);
+        }
+        (0b0000011, 0b000 | 0b001 | 0b010 | 0b100 | 0b101) => {
+            let width = match funct3 & 0b11 {
+                0b00 => Width::Byte,
+                0b01 => Width::Half,
+                _ => Width::Word,
+            };
+            let slow = SlowPath {
+                label: e.label(),
+                pc,
+                retired,
+            };
+            emit_ram_offset(e, d, 1 << (funct3 & 0b11), &slow);
+            e.load_ram(width, funct3 & 0b100 == 0);
+            e.store_guest(d.rd, Reg::Ecx);
+            slow_paths.push(slow);
+        }
+        (0b0100011, 0b000..=0b010) => {
+            let width = [Width::Byte, Width::Half, Width::Word][funct3 as usize];
+            let slow = SlowPath {
+                label: e.label(),
+                pc,
+                retired,
+            };
+            emit_ram_offset(e, d, 1 << funct3, &slow);
+            // Misaligned store can end in next page, only page of first byte is tested below
+            if funct3 != 0 {
+                e.mov(Reg::Edx, Reg::Eax);
+                e.alu_imm(Alu::And, Reg::Edx, (1 << funct3) - 1);
+                e.jcc(Cond::NotEqual, slow.label);
+            }
+            // Stores to pages with code and to tohost have side effects
+            e.mov(Reg::Edx, Reg::Eax);
+            e.shift_imm(Shift::Shr, Reg::Edx, PAGE_SIZE.trailing_zeros() as u8);
+            e.test_code_page();
+            e.jcc(Cond::Below, slow.label);
+            e.cmp_edx_context(CONTEXT_SLOW_PAGE);
+            e.jcc(Cond::Equal, slow.label);
+            e.load_guest(Reg::Ecx, d.rs2);
+            e.store_ram(width);
+            slow_paths.push(slow);
+        }
+        (0b1100011, 0b000 | 0b001 | 0b100..=0b111) => {
+            let cond = match funct3 {
+                0b000 => Cond::Equal,
+                0b001 => Cond::NotEqual,
+                0b100 => Cond::Less,
+                0b101 => Cond::GreaterOrEqual,
+                0b110 => Cond::Below,
+                _ => Cond::AboveOrEqual,
+            };
+            let taken = e.label();
+            rr(e);
+            e.alu(Alu::Cmp, Reg::Eax, Reg::Ecx);
+            e.jcc(cond, taken);
+            exit(e, pc.wrapping_add(4), retired + 1, EXIT_NEXT);
+            e.bind(taken);
+            exit(e, pc.wrapping_add(d.imm), retired + 1, EXIT_NEXT);
+            return Translated::EndOfBlock;
+        }
+        (0b1101111, _) => {
+            e.store_guest_imm(d.rd, pc.wrapping_add(4));
+            exit(e, pc.wrapping_add(d.imm), retired + 1, EXIT_NEXT);
+            return Translated::EndOfBlock;
+        }
+        (0b1100111, 0b000) => {
+            // Target is computed before rd is written, rd can be rs1
+            e.load_guest(Reg::Eax, d.rs1);
+            e.alu_imm(Alu::Add, Reg::Eax, d.imm);
+            e.alu_imm(Alu::And, Reg::Eax, !1);
+            e.store_guest_imm(d.rd, pc.wrapping_add(4));
+            e.store_context(CONTEXT_PC, Reg::Eax);
+            e.add_context_u64(CONTEXT_RETIRED, (retired + 1) as i8);
+            e.mov_imm(Reg::Eax, EXIT_NEXT);
+            e.ret();
+            return Translated::EndOfBlock;
+        }
+        _ => return Translated::Unsupported,
+    }
+    Translated::Continue
+}
+
+// Generates code for block at pc, returns it with number of instructions.
+// None if first instruction can't be translated.
+fn translate(cpu: &mut CPU, start: u32) -> Option<(Vec<u8>, usize)> {
+    let mut e = Emitter::new();
+    let mut slow_paths = vec![];
+    e.prologue();
+    let mut pc = start;
+    let mut count = 0;
+    loop {
+        let boundary = count == MAX_BLOCK_LENGTH || (count > 0 && pc.is_multiple_of(PAGE_SIZE));
+        // Fetch faults and compressed instructions are left to interpreter
+        let decoded = match cpu.mmu.fetch_word(pc) {
+            Ok(instr) if instr & 0b11 == 0b11 && !boundary => cpu.decode_at(pc).ok(),
+            _ => None,
+        };
+        let translated = match &decoded {
+            Some(d) => translate_instruction(&mut e, d, pc, count, &mut slow_paths),
+            None => Translated::Unsupported,
+        };
+        match translated {
+            Translated::Continue => {
+                count += 1;
+                pc = pc.wrapping_add(4);
+            }
+            Translated::EndOfBlock => {
+                count += 1;
+                break;
+            }
+            Translated::Unsupported if count == 0 => return None,
+            Translated::Unsupported => {
+                exit(&mut e, pc, count, EXIT_NEXT);
+                break;
+            }
+        }
+    }
+    for slow in slow_paths {
+        e.bind(slow.label);
+        exit(&mut e, slow.pc, slow.retired, EXIT_INTERPRET);
+    }
+    Some((e.finish(), count))
+}
+
+// Executes `budget` instructions with translated blocks, adding them to `executed`.
+// Instructions that weren't translated are executed by interpreter one by one.
+// Returns early when hart idles or translated code can't be used, see CPU::can_run_translated.
+pub fn run_jit(
+    cpu: &mut CPU,
+    cache: &mut JitCache,
+    budget: u64,
+    executed: &mut u64,
+) -> Result<(), EmulatorError> {
+    let end = *executed + budget;
+    let mut previous = None;
+    let mut interpret = false;
+    while *executed < end && !cpu.is_idle() && cpu.can_run_translated() {
+        if cpu.invalidate_written_code() || cache.generation != cpu.decode_cache.generation() {
+            cache.clear();
+            cache.generation = cpu.decode_cache.generation();
+            previous = None;
+        }
+        if cpu.check_interrupts()? {
+            previous = None;
+            interpret = false;
+        }
+        if !interpret {
+            let pc = cpu.pc;
+            let block = match previous.take() {
+                Some(from) => cache.successor(cpu, from, pc),
+                None => cache.find_or_translate(cpu, pc),
+            };
+            if let Some(index) = block.filter(|i| cache.blocks[*i].len as u64 <= end - *executed) {
+                let (exit, retired) = cache.execute(cpu, index);
+                *executed += retired;
+                match exit {
+                    EXIT_NEXT => previous = Some(index),
+                    _ => interpret = true,
+                }
+                continue;
+            }
+        }
+        interpret = false;
+        if let Err(trap) = cpu.execute_instruction() {
+            cpu.process_trap(trap)?;
+        }
+        *executed += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        cpu::{ExecutionMode, CPU},
+        errors::EmulatorError,
+        htif::Htif,
+        mmu::RAM_ADDRESS,
+    };
+
+    fn run(asm: &Assembler, mode: ExecutionMode, setup: impl Fn(&mut CPU)) -> (CPU, u64) {
+        let mut cpu = asm.cpu();
+        cpu.execution_mode = mode;
+        setup(&mut cpu);
+        let mut executed = 0;
+        match cpu.run(1_000_000, &mut executed) {
+            Err(EmulatorError::UnsetTrapHandler) => (cpu, executed),
+            r => panic!("Program didn't finish: {r:?}"),
+        }
+    }
+
+    // Small xorshift generator, so failures are reproducible
+    struct Random(u32);
+
+    impl Random {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+        fn reg(&mut self) -> u8 {
+            // x0 included, so writes to it are covered
+            [ZERO, A0, A1, A2, A3, A4, A5, T0][self.next() as usize % 8]
+        }
+    }
+
+    #[test]
+    fn test_random_alu() {
+        let mut random = Random(0x12345678);
+        for _ in 0..20 {
+            let mut asm = Assembler::new();
+            for reg in [A0, A1, A2, A3, A4, A5, T0] {
+                // Mix of small and edge values
+                let value = match random.next() % 4 {
+                    0 => random.next() % 64,
+                    1 => [0, 1, 0x7FFFFFFF, 0x80000000, u32::MAX][random.next() as usize % 5],
+                    _ => random.next(),
+                };
+                asm.li(reg, value);
+            }
+            for _ in 0..40 {
+                let (rd, rs1, rs2) = (random.reg(), random.reg(), random.reg());
+                let imm = (random.next() % 4096) as i16 - 2048;
+                let shamt = (random.next() % 32) as u8;
+                match random.next() % 22 {
+                    0 => asm.add(rd, rs1, rs2),
+                    1 => asm.sub(rd, rs1, rs2),
+                    2 => asm.sll(rd, rs1, rs2),
+                    3 => asm.slt(rd, rs1, rs2),
+                    4 => asm.sltu(rd, rs1, rs2),
+                    5 => asm.xor(rd, rs1, rs2),
+                    6 => asm.srl(rd, rs1, rs2),
+                    7 => asm.sra(rd, rs1, rs2),
+                    8 => asm.or(rd, rs1, rs2),
+                    9 => asm.and(rd, rs1, rs2),
+                    10 => asm.mul(rd, rs1, rs2),
+                    11 => asm.mulh(rd, rs1, rs2),
+                    12 => asm.mulhu(rd, rs1, rs2),
+                    13 => asm.addi(rd, rs1, imm),
+                    14 => asm.slti(rd, rs1, imm),
+                    15 => asm.sltiu(rd, rs1, imm),
+                    16 => asm.xori(rd, rs1, imm),
+                    17 => asm.ori(rd, rs1, imm),
+                    18 => asm.andi(rd, rs1, imm),
+                    19 => asm.slli(rd, rs1, shamt),
+                    20 => asm.srli(rd, rs1, shamt),
+                    _ => asm.srai(rd, rs1, shamt),
+                };
+            }
+            asm.ecall();
+            let (interpreted, _) = run(&asm, ExecutionMode::Interpreter, |_| {});
+            let (cpu, _) = run(&asm, ExecutionMode::Jit, |_| {});
+            assert_eq!(cpu.get_registers(), interpreted.get_registers());
+            assert!(cpu.jit.as_ref().is_some_and(|jit| !jit.is_empty()));
+        }
+    }
+
+    #[test]
+    fn test_loads_and_stores() {
+        let mut asm = Assembler::new();
+        asm.li(A0, 0x80002000).li(A1, 0x8badf00d);
+        asm.sw(A1, 0, A0).sh(A1, 6, A0).sb(A1, 9, A0);
+        asm.lb(A2, 0, A0).lbu(A3, 3, A0).lh(A4, 2, A0).lhu(A5, 6, A0).lw(T0, 8, A0);
+        // Last byte of RAM is still on fast path
+        asm.li(T1, 0x83FFFFFF).sb(A1, 0, T1).lbu(T2, 0, T1);
+        asm.ecall();
+        let (interpreted, _) = run(&asm, ExecutionMode::Interpreter, |_| {});
+        let (cpu, count) = run(&asm, ExecutionMode::Jit, |_| {});
+        assert_eq!(cpu.get_registers(), interpreted.get_registers());
+        assert_eq!(cpu.get_registers()[A2 as usize], 0x0d);
+        assert_eq!(cpu.get_registers()[A5 as usize], 0xf00d);
+        assert_eq!(cpu.get_registers()[T2 as usize], 0x0d);
+        // Everything but final ecall
+        assert_eq!(count, asm.assemble().len() as u64 - 1);
+    }
+
+    #[test]
+    fn test_store_across_code_page() {
+        // Page 0 holds only data, `target` starts page 1 and program page 2
+        let mut asm = Assembler::new();
+        for _ in 0..1024 {
+            asm.instr(0);
+        }
+        asm.label("target").addi(A0, A0, 1).ret();
+        for _ in 0..1022 {
+            asm.instr(0);
+        }
+        // Misaligned store from page 0 turns addi a0, a0, 1 into addi a1, a0, 1
+        asm.call("target").li(T1, 0x80000FFE).li(T2, 0x0593_0000).sw(T2, 0, T1);
+        asm.call("target").ecall();
+        let setup = |cpu: &mut CPU| cpu.pc = RAM_ADDRESS + 0x2000;
+        let (interpreted, _) = run(&asm, ExecutionMode::Interpreter, setup);
+        let (cpu, _) = run(&asm, ExecutionMode::Jit, setup);
+        assert_eq!(cpu.get_registers(), interpreted.get_registers());
+        assert_eq!(cpu.get_registers()[A1 as usize], 2);
+    }
+
+    #[test]
+    fn test_device_access() {
+        let mut asm = Assembler::new();
+        asm.li(A0, 0x10000000);
+        asm.li(A1, b'o' as u32).sb(A1, 0, A0).li(A1, b'k' as u32).sb(A1, 0, A0).ecall();
+        let (mut cpu, _) = run(&asm, ExecutionMode::Jit, |_| {});
+        assert_eq!(cpu.mmu.uart.try_get_byte(), Some(b'o'));
+        assert_eq!(cpu.mmu.uart.try_get_byte(), Some(b'k'));
+    }
+
+    #[test]
+    fn test_tohost_store() {
+        let mut asm = Assembler::new();
+        // Putchar of 0, exit would stop hart before ecall
+        asm.li(A0, 0x80003000).li(A1, 0x0101_0000).sw(A1, 4, A0).ecall();
+        let (mut cpu, _) = run(&asm, ExecutionMode::Jit, |cpu| {
+            cpu.mmu.htif = Some(Htif::new(0x80003000, None));
+        });
+        assert_eq!(cpu.mmu.uart.try_get_byte(), Some(0));
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut asm = Assembler::new();
+        asm.label("loop").addi(A0, A0, 1).addi(A1, A1, 2).j("loop");
+        let mut cpu = asm.cpu();
+        cpu.execution_mode = ExecutionMode::Jit;
+        let mut executed = 0;
+        cpu.run(1000, &mut executed).unwrap();
+        cpu.run(2, &mut executed).unwrap();
+        assert_eq!(executed, 1002);
+        assert_eq!(cpu.get_registers()[A0 as usize], 334);
+        assert_eq!(cpu.get_registers()[A1 as usize], 668);
+        assert_eq!(cpu.pc, RAM_ADDRESS);
+    }
+}
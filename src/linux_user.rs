@@ -0,0 +1,666 @@
+// Linux user-mode emulation like qemu-user: static RV32 ELF runs in U mode without kernel and
+// its ecalls are translated to host calls. Guest addresses are physical until Sv32 is
+// implemented, so program has to be linked into RAM, e.g. with -Wl,-Ttext-segment=0x80000000.
+// Console fds go through UART. There is one thread and process state isn't saved in snapshots.
+
+use std::{
+    collections::hash_map::RandomState,
+    fs::{self, File, Metadata, OpenOptions},
+    hash::BuildHasher,
+    io::{self, Read, Seek, SeekFrom, Write},
+    time::{Instant, UNIX_EPOCH},
+};
+
+use crate::{
+    cpu::{PrivilegeMode, CPU},
+    elf_analyzer::ElfAnalyzer,
+    emulator::Emulator,
+    mmu::{MMU, PAGE_SIZE, RAM_ADDRESS, RAM_ADDRESS_END},
+};
+
+// Syscall numbers of riscv32. It has only 64-bit time variants, so there is statx instead of
+// fstat and _llseek instead of lseek.
+pub const SYS_IOCTL: u32 = 29;
+pub const SYS_OPENAT: u32 = 56;
+pub const SYS_CLOSE: u32 = 57;
+pub const SYS_LLSEEK: u32 = 62;
+pub const SYS_READ: u32 = 63;
+pub const SYS_WRITE: u32 = 64;
+pub const SYS_READV: u32 = 65;
+pub const SYS_WRITEV: u32 = 66;
+pub const SYS_EXIT: u32 = 93;
+pub const SYS_EXIT_GROUP: u32 = 94;
+pub const SYS_SET_TID_ADDRESS: u32 = 96;
+pub const SYS_SET_ROBUST_LIST: u32 = 99;
+pub const SYS_RT_SIGACTION: u32 = 134;
+pub const SYS_RT_SIGPROCMASK: u32 = 135;
+pub const SYS_UNAME: u32 = 160;
+pub const SYS_GETPID: u32 = 172;
+pub const SYS_GETPPID: u32 = 173;
+pub const SYS_GETUID: u32 = 174;
+pub const SYS_GETEUID: u32 = 175;
+pub const SYS_GETGID: u32 = 176;
+pub const SYS_GETEGID: u32 = 177;
+pub const SYS_GETTID: u32 = 178;
+pub const SYS_BRK: u32 = 214;
+pub const SYS_MUNMAP: u32 = 215;
+// mmap2, offset is in pages
+pub const SYS_MMAP: u32 = 222;
+pub const SYS_MPROTECT: u32 = 226;
+pub const SYS_GETRANDOM: u32 = 278;
+pub const SYS_STATX: u32 = 291;
+pub const SYS_CLOCK_GETTIME64: u32 = 403;
+
+pub const EIO: i32 = 5;
+pub const EBADF: i32 = 9;
+pub const ENOMEM: i32 = 12;
+pub const EACCES: i32 = 13;
+pub const EFAULT: i32 = 14;
+pub const EINVAL: i32 = 22;
+pub const ENOTTY: i32 = 25;
+pub const ESPIPE: i32 = 29;
+pub const ENOSYS: i32 = 38;
+
+const AT_FDCWD: u32 = -100i32 as u32;
+const AT_SYMLINK_NOFOLLOW: u32 = 0x100;
+const AT_EMPTY_PATH: u32 = 0x1000;
+const O_ACCMODE: u32 = 3;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+const CLOCK_REALTIME: u32 = 0;
+
+// Auxiliary vector entries
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_UID: u32 = 11;
+const AT_EUID: u32 = 12;
+const AT_GID: u32 = 13;
+const AT_EGID: u32 = 14;
+const AT_HWCAP: u32 = 16;
+const AT_CLKTCK: u32 = 17;
+const AT_SECURE: u32 = 23;
+const AT_RANDOM: u32 = 25;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+const EM_RISCV: u16 = 243;
+
+// Stack takes end of RAM, anonymous mappings grow down below it
+pub const STACK_SIZE: u32 = 1024 * 1024;
+
+const A0: usize = 10;
+const A7: usize = 17;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UserError {
+    NotElf,
+    // Not static RV32 executable
+    Unsupported,
+    // Segment at this address isn't in RAM, it needs virtual memory
+    OutsideRam(u32),
+    // Program, arguments and stack don't fit into RAM
+    TooLarge,
+}
+
+enum Fd {
+    // stdin, stdout and stderr, read from and written to UART
+    Console,
+    File(File),
+}
+
+pub struct LinuxUser {
+    fds: Vec<Option<Fd>>,
+    // Program break, heap grows up from end of loaded image
+    brk_start: u32,
+    brk: u32,
+    // Lowest mapped address, memory isn't reused after munmap
+    mmap_bottom: u32,
+    start: Instant,
+    pub exit_code: Option<u32>,
+}
+
+impl LinuxUser {
+    pub fn new(image_end: u32) -> Self {
+        let brk = image_end.next_multiple_of(PAGE_SIZE);
+        LinuxUser {
+            fds: vec![Some(Fd::Console), Some(Fd::Console), Some(Fd::Console)],
+            brk_start: brk,
+            brk,
+            mmap_bottom: RAM_ADDRESS_END + 1 - STACK_SIZE,
+            start: Instant::now(),
+            exit_code: None,
+        }
+    }
+    fn fd(&mut self, fd: u32) -> Result<&mut Fd, i32> {
+        self.fds.get_mut(fd as usize).and_then(|fd| fd.as_mut()).ok_or(EBADF)
+    }
+    fn syscall(&mut self, mmu: &mut MMU, number: u32, args: [u32; 6]) -> Result<u32, i32> {
+        match number {
+            SYS_OPENAT => self.openat(mmu, args),
+            SYS_CLOSE => {
+                self.fd(args[0])?;
+                self.fds[args[0] as usize] = None;
+                Ok(0)
+            }
+            SYS_LLSEEK => {
+                let Fd::File(file) = self.fd(args[0])? else {
+                    return Err(ESPIPE);
+                };
+                let offset = (args[1] as u64) << 32 | args[2] as u64;
+                let position = match args[4] {
+                    0 => SeekFrom::Start(offset),
+                    1 => SeekFrom::Current(offset as i64),
+                    2 => SeekFrom::End(offset as i64),
+                    _ => return Err(EINVAL),
+                };
+                let position = file.seek(position).map_err(errno)?;
+                write_guest(mmu, args[3], &position.to_le_bytes())?;
+                Ok(0)
+            }
+            SYS_READ => self.read(mmu, args[0], args[1], args[2]),
+            SYS_WRITE => self.write(mmu, args[0], args[1], args[2]),
+            SYS_READV | SYS_WRITEV => {
+                let iov = read_guest(mmu, args[1], args[2].min(1024) * 8)?;
+                let mut total = 0;
+                for entry in iov.chunks_exact(8) {
+                    let base = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+                    let len = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+                    let done = if number == SYS_READV {
+                        self.read(mmu, args[0], base, len)?
+                    } else {
+                        self.write(mmu, args[0], base, len)?
+                    };
+                    total += done;
+                    if done < len {
+                        break;
+                    }
+                }
+                Ok(total)
+            }
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit_code = Some(args[0] & 0xFF);
+                Ok(0)
+            }
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(1),
+            SYS_GETPPID | SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+            SYS_SET_ROBUST_LIST | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK | SYS_MUNMAP | SYS_MPROTECT => Ok(0),
+            // Console isn't terminal, so programs buffer output fully
+            SYS_IOCTL => self.fd(args[0]).and(Err(ENOTTY)),
+            SYS_UNAME => {
+                let mut uts = vec![0; 65 * 6];
+                let fields = ["Linux", "rv-emu-rs", "6.1.0", "#1", "riscv32", "(none)"];
+                for (i, field) in fields.iter().enumerate() {
+                    uts[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+                }
+                write_guest(mmu, args[0], &uts)?;
+                Ok(0)
+            }
+            SYS_BRK => {
+                let requested = args[0];
+                if requested >= self.brk_start && requested <= self.mmap_bottom {
+                    if requested > self.brk {
+                        write_guest(mmu, self.brk, &vec![0; (requested - self.brk) as usize])?;
+                    }
+                    self.brk = requested;
+                }
+                Ok(self.brk)
+            }
+            SYS_MMAP => self.mmap(mmu, args),
+            SYS_GETRANDOM => {
+                if !in_ram(args[0], args[1]) {
+                    return Err(EFAULT);
+                }
+                write_guest(mmu, args[0], &random_bytes(args[1] as usize))?;
+                Ok(args[1])
+            }
+            SYS_STATX => self.statx(mmu, args),
+            SYS_CLOCK_GETTIME64 => {
+                let (clock, ticks) = (mmu.clint.clock, mmu.clint.time());
+                let time = if args[0] == CLOCK_REALTIME {
+                    clock.realtime(ticks)
+                } else {
+                    clock.since(self.start, ticks)
+                };
+                let mut ts = time.as_secs().to_le_bytes().to_vec();
+                ts.extend_from_slice(&(time.subsec_nanos() as u64).to_le_bytes());
+                write_guest(mmu, args[1], &ts)?;
+                Ok(0)
+            }
+            _ => Err(ENOSYS),
+        }
+    }
+    fn read(&mut self, mmu: &mut MMU, fd: u32, address: u32, len: u32) -> Result<u32, i32> {
+        let fd = self.fd(fd)?;
+        // Checked before buffer is allocated and file is read
+        if !in_ram(address, len) {
+            return Err(EFAULT);
+        }
+        let data = match fd {
+            // Returns what UART has, nothing means end of file
+            Fd::Console => std::iter::from_fn(|| mmu.uart.emu_try_get_byte()).take(len as usize).collect(),
+            Fd::File(file) => {
+                let mut data = vec![0; len as usize];
+                let read = file.read(&mut data).map_err(errno)?;
+                data.truncate(read);
+                data
+            }
+        };
+        write_guest(mmu, address, &data)?;
+        Ok(data.len() as u32)
+    }
+    fn write(&mut self, mmu: &mut MMU, fd: u32, address: u32, len: u32) -> Result<u32, i32> {
+        let data = read_guest(mmu, address, len)?;
+        match self.fd(fd)? {
+            Fd::Console => data.iter().for_each(|b| mmu.uart.emu_push(*b)),
+            Fd::File(file) => file.write_all(&data).map_err(errno)?,
+        }
+        Ok(len)
+    }
+    // Mode of created files is host default
+    fn openat(&mut self, mmu: &MMU, args: [u32; 6]) -> Result<u32, i32> {
+        let path = read_string(mmu, args[1])?;
+        if args[0] != AT_FDCWD && !path.starts_with('/') {
+            return Err(ENOSYS);
+        }
+        let flags = args[2];
+        let file = OpenOptions::new()
+            .read(flags & O_ACCMODE != 1)
+            .write(flags & O_ACCMODE != 0)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .create(flags & O_CREAT != 0 && flags & O_EXCL == 0)
+            .create_new(flags & O_CREAT != 0 && flags & O_EXCL != 0)
+            .open(path)
+            .map_err(errno)?;
+        let fd = match self.fds.iter().position(|fd| fd.is_none()) {
+            Some(free) => free,
+            None => {
+                self.fds.push(None);
+                self.fds.len() - 1
+            }
+        };
+        self.fds[fd] = Some(Fd::File(file));
+        Ok(fd as u32)
+    }
+    fn mmap(&mut self, mmu: &mut MMU, args: [u32; 6]) -> Result<u32, i32> {
+        let [address, len, _prot, flags, fd, offset] = args;
+        if len == 0 {
+            return Err(EINVAL);
+        }
+        let size = len.checked_next_multiple_of(PAGE_SIZE).ok_or(ENOMEM)?;
+        let address = if flags & MAP_FIXED != 0 {
+            if !in_ram(address, size) {
+                return Err(EFAULT);
+            }
+            address
+        } else {
+            let bottom = self.mmap_bottom.checked_sub(size).filter(|b| *b >= self.brk).ok_or(ENOMEM)?;
+            self.mmap_bottom = bottom;
+            bottom
+        };
+        let mut data = vec![0; size as usize];
+        if flags & MAP_ANONYMOUS == 0 {
+            let Fd::File(file) = self.fd(fd)? else {
+                return Err(EINVAL);
+            };
+            let position = file.stream_position().map_err(errno)?;
+            file.seek(SeekFrom::Start(offset as u64 * PAGE_SIZE as u64)).map_err(errno)?;
+            let mut read = 0;
+            while read < len as usize {
+                match file.read(&mut data[read..len as usize]).map_err(errno)? {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+            file.seek(SeekFrom::Start(position)).map_err(errno)?;
+        }
+        write_guest(mmu, address, &data)?;
+        Ok(address)
+    }
+    fn statx(&mut self, mmu: &mut MMU, args: [u32; 6]) -> Result<u32, i32> {
+        let [dirfd, path, flags, _mask, buffer, _] = args;
+        let path = read_string(mmu, path)?;
+        let metadata = if path.is_empty() && flags & AT_EMPTY_PATH != 0 {
+            match self.fd(dirfd)? {
+                Fd::Console => None,
+                Fd::File(file) => Some(file.metadata().map_err(errno)?),
+            }
+        } else if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Err(ENOSYS);
+        } else if flags & AT_SYMLINK_NOFOLLOW != 0 {
+            Some(fs::symlink_metadata(path).map_err(errno)?)
+        } else {
+            Some(fs::metadata(path).map_err(errno)?)
+        };
+        write_guest(mmu, buffer, &statx_buffer(metadata.as_ref()))?;
+        Ok(0)
+    }
+}
+
+// struct statx with basic fields, console is character device
+fn statx_buffer(metadata: Option<&Metadata>) -> Vec<u8> {
+    let mut buffer = vec![0; 256];
+    let mut put = |offset: usize, bytes: &[u8]| buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
+    let mode: u16 = match metadata {
+        None => 0o020620,
+        Some(m) if m.is_dir() => 0o040755,
+        Some(m) if m.is_symlink() => 0o120777,
+        Some(m) if m.permissions().readonly() => 0o100444,
+        Some(_) => 0o100644,
+    };
+    // STATX_BASIC_STATS
+    put(0, &0x7FFu32.to_le_bytes());
+    put(4, &PAGE_SIZE.to_le_bytes());
+    put(16, &1u32.to_le_bytes());
+    put(28, &mode.to_le_bytes());
+    if let Some(metadata) = metadata {
+        put(40, &metadata.len().to_le_bytes());
+        put(48, &metadata.len().div_ceil(512).to_le_bytes());
+        let modified = metadata.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+        // atime, ctime and mtime
+        for offset in [64, 96, 112] {
+            put(offset, &modified.as_secs().to_le_bytes());
+            put(offset + 8, &modified.subsec_nanos().to_le_bytes());
+        }
+    }
+    buffer
+}
+
+pub fn errno(e: io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+// Not cryptographically secure, but differs between runs
+fn random_bytes(len: usize) -> Vec<u8> {
+    let state = RandomState::new();
+    (0..len.div_ceil(8) as u64).flat_map(|i| state.hash_one(i).to_le_bytes()).take(len).collect()
+}
+
+pub fn in_ram(address: u32, len: u32) -> bool {
+    address >= RAM_ADDRESS && address.checked_add(len).is_some_and(|end| end - 1 <= RAM_ADDRESS_END || len == 0)
+}
+
+pub fn read_guest(mmu: &MMU, address: u32, len: u32) -> Result<Vec<u8>, i32> {
+    if !in_ram(address, len) {
+        return Err(EFAULT);
+    }
+    Ok((0..len).map(|i| mmu.read_raw_from_ram(address + i).unwrap()).collect())
+}
+
+pub fn write_guest(mmu: &mut MMU, address: u32, bytes: &[u8]) -> Result<(), i32> {
+    if !in_ram(address, bytes.len() as u32) {
+        return Err(EFAULT);
+    }
+    for (i, byte) in bytes.iter().enumerate() {
+        mmu.write_raw_to_ram(address + i as u32, *byte);
+    }
+    Ok(())
+}
+
+// Paths longer than page are rejected
+pub fn read_string(mmu: &MMU, address: u32) -> Result<String, i32> {
+    let mut bytes = vec![];
+    loop {
+        match mmu.read_raw_from_ram(address.wrapping_add(bytes.len() as u32)).ok_or(EFAULT)? {
+            0 => return String::from_utf8(bytes).map_err(|_| EINVAL),
+            _ if bytes.len() >= PAGE_SIZE as usize => return Err(EINVAL),
+            byte => bytes.push(byte),
+        }
+    }
+}
+
+// Services ecall from U mode, pc still points to ecall
+pub fn handle_ecall(cpu: &mut CPU) {
+    let mut regs = cpu.get_registers();
+    let args: [u32; 6] = regs[A0..A0 + 6].try_into().unwrap();
+    let mut user = cpu.mmu.linux_user.take().unwrap();
+    cpu.sync_bus_time();
+    regs[A0] = user.syscall(&mut cpu.mmu, regs[A7], args).unwrap_or_else(|e| -e as u32);
+    cpu.stopped = user.exit_code.is_some();
+    cpu.mmu.linux_user = Some(user);
+    cpu.set_registers(regs);
+    cpu.pc = cpu.pc.wrapping_add(4);
+}
+
+impl Emulator {
+    // Loads static ELF and puts argc, argv, envp and auxv on stack at end of RAM like Linux
+    // does. Hart runs it in U mode.
+    pub fn from_linux_elf(elf: Vec<u8>, argv: &[String], envp: &[String], mut mmu: MMU) -> Result<Self, UserError> {
+        let len = elf.len();
+        let analyzer = ElfAnalyzer::new(elf);
+        if !analyzer.validate() || len < 52 || analyzer.read_byte(4) != 1 {
+            return Err(UserError::NotElf);
+        }
+        let header = analyzer.read_header();
+        if header.e_machine != EM_RISCV {
+            return Err(UserError::Unsupported);
+        }
+        if header.e_phentsize != 32 || header.e_phoff as usize + header.e_phnum as usize * 32 > len {
+            return Err(UserError::NotElf);
+        }
+        let mut image_end = RAM_ADDRESS;
+        let mut phdr = 0;
+        for segment in analyzer.read_program_headers(&header) {
+            let (address, offset) = (segment.p_vaddr as u32, segment.p_offset as usize);
+            match segment.p_type {
+                PT_LOAD => {
+                    if !in_ram(address, segment.p_memsz as u32) {
+                        return Err(UserError::OutsideRam(address));
+                    }
+                    if offset + segment.p_filesz as usize > len {
+                        return Err(UserError::NotElf);
+                    }
+                    for i in 0..segment.p_filesz as usize {
+                        mmu.write_raw_to_ram(address + i as u32, analyzer.read_byte(offset + i));
+                    }
+                    if (offset..offset + segment.p_filesz as usize).contains(&(header.e_phoff as usize)) && phdr == 0 {
+                        phdr = address + (header.e_phoff as usize - offset) as u32;
+                    }
+                    image_end = image_end.max(address + segment.p_memsz as u32);
+                }
+                PT_INTERP => return Err(UserError::Unsupported),
+                PT_PHDR => phdr = address,
+                _ => {}
+            }
+        }
+
+        let stack_top = RAM_ADDRESS_END + 1;
+        let stack_bottom = stack_top - STACK_SIZE;
+        if image_end > stack_bottom {
+            return Err(UserError::TooLarge);
+        }
+        let mut position = stack_top;
+        let mut push = |mmu: &mut MMU, bytes: &[u8]| {
+            position = position.checked_sub(bytes.len() as u32).filter(|p| *p >= stack_bottom + PAGE_SIZE)?;
+            write_guest(mmu, position, bytes).ok()?;
+            Some(position)
+        };
+        let random = push(&mut mmu, &random_bytes(16)).unwrap();
+        let mut strings = |mmu: &mut MMU, list: &[String]| -> Option<Vec<u32>> {
+            list.iter().map(|s| push(mmu, &[s.as_bytes(), &[0]].concat())).collect()
+        };
+        let envp = strings(&mut mmu, envp).ok_or(UserError::TooLarge)?;
+        let argv = strings(&mut mmu, argv).ok_or(UserError::TooLarge)?;
+
+        let mut words = vec![argv.len() as u32];
+        words.extend(argv);
+        words.push(0);
+        words.extend(envp);
+        words.push(0);
+        let auxv = [
+            (AT_PHDR, phdr),
+            (AT_PHENT, 32),
+            (AT_PHNUM, header.e_phnum as u32),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, header.e_entry as u32),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            // Single letter extensions like in misa
+            (AT_HWCAP, 1 << 8 | 1 << 12 | 1),
+            (AT_CLKTCK, 100),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random),
+            (AT_NULL, 0),
+        ];
+        words.extend(auxv.iter().flat_map(|&(key, value)| [key, value]));
+        let sp = (position & !15).checked_sub(words.len() as u32 * 4).ok_or(UserError::TooLarge)? & !15;
+        if sp < stack_bottom + PAGE_SIZE {
+            return Err(UserError::TooLarge);
+        }
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        write_guest(&mut mmu, sp, &bytes).unwrap();
+
+        mmu.linux_user = Some(LinuxUser::new(image_end));
+        let mut cpu = CPU::new(mmu);
+        cpu.pc = header.e_entry as u32;
+        cpu.privilege = PrivilegeMode::User;
+        cpu.pmp.allow_all();
+        cpu.counters.mcounteren = u32::MAX;
+        cpu.counters.scounteren = u32::MAX;
+        let mut regs = cpu.get_registers();
+        regs[2] = sp;
+        cpu.set_registers(regs);
+        Ok(Emulator::new(cpu))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        emulator::{Emulator, StopReason},
+        mmu::{MMU, PAGE_SIZE, RAM_ADDRESS},
+    };
+
+    use super::{
+        read_string, UserError, AT_FDCWD, EBADF, EFAULT, EM_RISCV, ENOSYS, MAP_ANONYMOUS, MAP_FIXED, O_CREAT,
+        O_TRUNC, PT_LOAD, SYS_BRK, SYS_CLOSE, SYS_EXIT, SYS_EXIT_GROUP, SYS_GETRANDOM, SYS_LLSEEK, SYS_MMAP,
+        SYS_OPENAT, SYS_READ, SYS_STATX, SYS_WRITE,
+    };
+
+    // Static executable with one segment holding headers and code, followed by page of bss
+    fn elf(asm: &Assembler, address: u32) -> Vec<u8> {
+        let code = asm.assemble_bytes();
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1];
+        elf.resize(16, 0);
+        elf.extend([2u16, EM_RISCV].iter().flat_map(|v| v.to_le_bytes()));
+        elf.extend([1, address + 84, 52, 0, 0].iter().flat_map(|v| v.to_le_bytes()));
+        elf.extend([52u16, 32, 1, 40, 0, 0].iter().flat_map(|v| v.to_le_bytes()));
+        let size = 84 + code.len() as u32;
+        let phdr = [PT_LOAD, 0, address, address, size, size + PAGE_SIZE, 7, PAGE_SIZE];
+        elf.extend(phdr.iter().flat_map(|v| v.to_le_bytes()));
+        elf.extend(code);
+        elf
+    }
+
+    fn string(asm: &mut Assembler, label: &str, s: &str) {
+        asm.label(label);
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.resize(bytes.len() / 4 * 4 + 4, 0);
+        for word in bytes.chunks_exact(4) {
+            asm.instr(u32::from_le_bytes(word.try_into().unwrap()));
+        }
+    }
+
+    fn run(asm: &Assembler, argv: &[&str]) -> (Emulator, StopReason, String) {
+        let argv: Vec<String> = argv.iter().map(|s| s.to_string()).collect();
+        let envp = vec!["HOME=/".to_string()];
+        let mut emu = Emulator::from_linux_elf(elf(asm, RAM_ADDRESS), &argv, &envp, MMU::new().0).unwrap();
+        let reason = emu.run(10000);
+        let output = std::iter::from_fn(|| emu.harts[0].mmu.uart.try_get_byte()).map(|b| b as char).collect();
+        (emu, reason, output)
+    }
+
+    #[test]
+    fn test_console_brk_and_exit() {
+        let mut asm = Assembler::new();
+        asm.lw(S0, 0, SP).lw(S1, 8, SP).lw(S5, 16, SP);
+        asm.li(A0, 1).mv(A1, S1).li(A2, 2).li(A7, SYS_WRITE).ecall();
+        asm.li(A0, 0).li(A7, SYS_BRK).ecall().mv(S2, A0);
+        asm.addi(A0, S2, 16).li(A7, SYS_BRK).ecall().mv(S3, A0);
+        asm.li(A7, 9999).ecall().mv(S4, A0);
+        asm.li(A0, 1).la(A1, "message").li(A2, 3).li(A7, SYS_WRITE).ecall();
+        asm.addi(A0, S0, 40).li(A7, SYS_EXIT_GROUP).ecall();
+        string(&mut asm, "message", "ok\n");
+        let (emu, reason, output) = run(&asm, &["prog", "hi"]);
+        assert_eq!(reason, StopReason::PowerOff(42));
+        assert_eq!(output, "hiok\n");
+        let regs = emu.harts[0].get_registers();
+        let image_end = RAM_ADDRESS + 84 + asm.offset() + PAGE_SIZE;
+        assert_eq!(regs[S2 as usize], image_end.next_multiple_of(PAGE_SIZE));
+        assert_eq!(regs[S3 as usize], regs[S2 as usize] + 16);
+        assert_eq!(regs[S4 as usize], -ENOSYS as u32);
+        // envp follows argv and its terminator
+        assert_eq!(read_string(&emu.harts[0].mmu, regs[S5 as usize]).unwrap(), "HOME=/");
+    }
+
+    #[test]
+    fn test_files() {
+        let path = std::env::temp_dir().join(format!("rv-emu-rs-user-{}", std::process::id()));
+        let mut asm = Assembler::new();
+        asm.li(A0, AT_FDCWD).la(A1, "path").li(A2, 2 | O_CREAT | O_TRUNC).li(A3, 0o644);
+        asm.li(A7, SYS_OPENAT).ecall().mv(S0, A0);
+        asm.mv(A0, S0).la(A1, "message").li(A2, 5).li(A7, SYS_WRITE).ecall();
+        asm.mv(A0, S0).li(A1, 0).li(A2, 1).addi(A3, SP, -8).li(A4, 0).li(A7, SYS_LLSEEK).ecall();
+        asm.mv(A0, S0).addi(A1, SP, -64).li(A2, 100).li(A7, SYS_READ).ecall().mv(S1, A0);
+        asm.mv(A0, S0).li(A7, SYS_CLOSE).ecall().mv(S2, A0);
+        asm.li(A0, 1).addi(A1, SP, -64).mv(A2, S1).li(A7, SYS_WRITE).ecall();
+        asm.li(A0, AT_FDCWD).la(A1, "path").li(A2, 0).li(A3, 0x7FF).addi(A4, SP, -512);
+        asm.li(A7, SYS_STATX).ecall().lw(S3, -512 + 40, SP);
+        asm.mv(A0, S0).li(A7, SYS_CLOSE).ecall().mv(S4, A0);
+        asm.li(A0, 0).li(A7, SYS_EXIT).ecall();
+        string(&mut asm, "message", "file\n");
+        string(&mut asm, "path", path.to_str().unwrap());
+        let (emu, reason, output) = run(&asm, &["prog"]);
+        assert_eq!(std::fs::read(&path).unwrap(), b"file\n");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reason, StopReason::PowerOff(0));
+        // Seek to offset 1
+        assert_eq!(output, "ile\n");
+        let regs = emu.harts[0].get_registers();
+        assert_eq!(regs[S0 as usize], 3);
+        assert_eq!(regs[S2 as usize], 0);
+        assert_eq!(regs[S3 as usize], 5);
+        assert_eq!(regs[S4 as usize], -EBADF as u32);
+    }
+
+    #[test]
+    fn test_huge_buffers() {
+        // Lengths are rejected before buffer for them is allocated
+        let mut asm = Assembler::new();
+        asm.li(A0, 0x1000).li(A1, u32::MAX).li(A2, 0).li(A7, SYS_GETRANDOM).ecall().mv(S0, A0);
+        asm.li(A0, 0).li(A1, 0x1000).li(A2, u32::MAX).li(A7, SYS_READ).ecall().mv(S1, A0);
+        asm.li(A0, 0x1000).li(A1, 0xFFFF_0000).li(A2, 3).li(A3, MAP_FIXED | MAP_ANONYMOUS).li(A4, u32::MAX);
+        asm.li(A5, 0).li(A7, SYS_MMAP).ecall().mv(S2, A0);
+        asm.li(A0, 0).li(A7, SYS_EXIT).ecall();
+        let (emu, reason, _) = run(&asm, &["prog"]);
+        assert_eq!(reason, StopReason::PowerOff(0));
+        let regs = emu.harts[0].get_registers();
+        for reg in [S0, S1, S2] {
+            assert_eq!(regs[reg as usize], -EFAULT as u32);
+        }
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let mut asm = Assembler::new();
+        asm.ecall();
+        let load = |elf| Emulator::from_linux_elf(elf, &[], &[], MMU::new().0).err();
+        assert_eq!(load(elf(&asm, 0x10000)), Some(UserError::OutsideRam(0x10000)));
+        assert_eq!(load(b"\x7fELF".to_vec()), Some(UserError::NotElf));
+        let mut elf = elf(&asm, RAM_ADDRESS);
+        elf[18] = 62;
+        assert_eq!(load(elf), Some(UserError::Unsupported));
+    }
+}
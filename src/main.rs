@@ -1,124 +1,375 @@
 use std::{
-    env, fs::File, io::{self, Read}
+    env, fs::{self, File}, io::{self, BufReader, Read, Write}, mem, path::Path, process, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread
 };
 
-use mmu::MMU;
-use rodio::{OutputStream, Source};
+use boot::LinuxBoot;
+use cli::{Mode, Options, Runtime, UartOutput, USAGE, EXIT_DEADLOCK, EXIT_FAULT, EXIT_INSTRUCTION_LIMIT, EXIT_LOAD, EXIT_TIMEOUT, EXIT_USAGE};
 
+use compliance::{ComplianceRunner, TestOutcome};
+use cosim::{CoSimulator, CosimError};
+
+use disassembler::{disassemble, REGISTER_NAMES};
+use emulator::{Emulator, StopReason, RUN_BATCH};
+use manual_debugger::{read_instruction, CodeDisplay};
+use mmu::{MMU, RAM_ADDRESS, RAM_SIZE};
+use newlib::{Console, Newlib};
+use primitive_audio::{create_primitive_audio_pair, AudioSink, NullSink, WavSink};
+use semihosting::Semihosting;
+use rodio::{OutputStream, OutputStreamHandle, Source};
+
+pub mod assembler;
+pub mod block_engine;
+pub mod boot;
+pub mod cli;
+pub mod clint;
+pub mod clock;
+pub mod compliance;
+pub mod counters;
+pub mod csr;
+pub mod cosim;
 pub mod cpu;
+pub mod decode_cache;
+pub mod disassembler;
 pub mod elf_analyzer;
 pub mod emulator;
+pub mod fdt;
 pub mod mmu;
+pub mod newlib;
 pub mod ops_decode;
+pub mod pmp;
+pub mod sbi;
+pub mod semihosting;
+pub mod snapshot;
+pub mod test_finisher;
+pub mod tracer;
 pub mod traps;
 pub mod uart;
 pub mod errors;
+pub mod htif;
+pub mod linux_user;
+#[cfg(feature = "jit")]
+pub mod jit;
+#[cfg(feature = "jit")]
+pub mod x86_emitter;
 pub mod manual_debugger;
 pub mod primitive_audio;
 //pub mod gdb;
 
 fn main() {
-    //let mut m = mmu::MMU::default();
-    let mut path = "./bad_apple/target/riscv32i-unknown-none-elf/release/bad_apple".to_string();
-    //let mut path = "./bad_apple/target/riscv32i-unknown-none-elf/debug/bad_apple".to_string();
-    
-    //let path = "./test_asm/target/testtraps.s.elf";
-    //let mut path: String = "./test_asm/target/testadd.s.elf".to_string();
-    if let Some(p) = env::args().skip(1).next() {
-        path = p
-    }
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    println!("Loading elf \"{}\"", path);
-    let mut elf_file = File::open(path).unwrap();
-    let mut elf_contents = vec![];
-    elf_file.read_to_end(&mut elf_contents).unwrap();
-
-    let (mmu, audio) = MMU::new();
-    stream_handle.play_raw(audio.convert_samples()).unwrap();
-    let mut emu = emulator::Emulator::from_elf(elf_contents, mmu);
+    let options = cli::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{e}\n\n{USAGE}");
+        process::exit(EXIT_USAGE)
+    });
+    let code = match &options.mode {
+        Mode::Help => {
+            println!("{USAGE}");
+            0
+        }
+        Mode::Compliance { tests, signatures } => run_compliance(tests, signatures.as_deref(), options.max_instructions),
+        _ => run(&options),
+    };
+    process::exit(code)
+}
+
+// Instruction limit applies to each test
+fn run_compliance(tests: &Path, signatures: Option<&Path>, max_instructions: Option<u64>) -> i32 {
+    let mut runner = ComplianceRunner::new();
+    if let Some(limit) = max_instructions {
+        runner = runner.with_instruction_limit(limit);
+    }
+    if let Some(signatures) = signatures {
+        if let Err(e) = fs::create_dir_all(signatures) {
+            eprintln!("Can't create {}: {e}", signatures.display());
+            return EXIT_LOAD;
+        }
+        runner = runner.with_signature_dir(signatures.into());
+    }
+    let results = match runner.run_directory(tests) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("Can't read tests in {}: {e}", tests.display());
+            return EXIT_LOAD;
+        }
+    };
+    for result in &results {
+        println!("{result}");
+    }
+    let passed = results.iter().filter(|r| r.outcome == TestOutcome::Pass).count();
+    println!("Passed {passed}/{}", results.len());
+    if passed == results.len() { 0 } else { 1 }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("Can't read {}: {e}", path.display()))
+}
+
+// Builds machine again for every reset, audio sink is kept
+fn load(options: &Options, audio: Box<dyn AudioSink + Send>) -> Result<Emulator, String> {
+    let (mut mmu, _audio) = MMU::new();
+    mmu.audio = audio;
+    let emu = match &options.mode {
+        Mode::Elf(path) => {
+            let (emu, info) = Emulator::from_elf_info(read(path)?, mmu)
+                .map_err(|e| format!("Can't load {}: {e:?}", path.display()))?;
+            let root = options.root.clone();
+            match options.runtime {
+                Runtime::None => emu,
+                Runtime::Newlib => {
+                    let heap = info.symbol("_end").unwrap_or(RAM_ADDRESS + RAM_SIZE as u32 / 2);
+                    let mut newlib = Newlib::new(heap).with_console(Console::Host);
+                    if let Some(root) = root {
+                        newlib = newlib.with_root(root);
+                    }
+                    emu.with_newlib(newlib)
+                }
+                Runtime::Semihosting => {
+                    let cmdline = path.display().to_string();
+                    let mut semihosting = Semihosting::new().with_console(Console::Host).with_cmdline(cmdline);
+                    if let Some(root) = root {
+                        semihosting = semihosting.with_root(root);
+                    }
+                    emu.with_semihosting(semihosting)
+                }
+            }
+        }
+        Mode::Kernel(files) => {
+            let mut boot = LinuxBoot::new(read(&files.kernel)?);
+            boot.initrd = files.initrd.as_deref().map(read).transpose()?;
+            boot.bootargs = files.append.clone();
+            boot.dtb = files.dtb.as_deref().map(read).transpose()?;
+            boot.nommu = files.nommu;
+            boot.build(mmu).map_err(|e| format!("Can't boot kernel: {e:?}"))?
+        }
+        Mode::User(argv) => {
+            let envp: Vec<String> = env::vars().map(|(key, value)| format!("{key}={value}")).collect();
+            let elf = read(Path::new(&argv[0]))?;
+            Emulator::from_linux_elf(elf, argv, &envp, mmu).map_err(|e| format!("Can't load {}: {e:?}", argv[0]))?
+        }
+        Mode::Help | Mode::Compliance { .. } => unreachable!(),
+    };
+    Ok(emu)
+}
+
+// Rodio plays audio when there is sound device, otherwise it is discarded
+fn audio_sink(options: &Options, output: Option<&OutputStreamHandle>) -> Result<Box<dyn AudioSink + Send>, String> {
+    if let Some(path) = &options.wav {
+        let sink = WavSink::create(path).map_err(|e| format!("Can't create {}: {e}", path.display()))?;
+        return Ok(Box::new(sink));
+    }
+    let Some(handle) = output else {
+        return Ok(Box::new(NullSink));
+    };
+    let (sink, source) = create_primitive_audio_pair();
+    match handle.play_raw(source.convert_samples()) {
+        Ok(()) => Ok(Box::new(sink)),
+        Err(e) => {
+            eprintln!("Can't play audio: {e}");
+            Ok(Box::new(NullSink))
+        }
+    }
+}
+
+fn run(options: &Options) -> i32 {
+    let output = (options.audio && options.wav.is_none()).then(OutputStream::try_default).and_then(|stream| {
+        stream.map_err(|e| eprintln!("No audio output: {e}")).ok()
+    });
+    if matches!(&options.mode, Mode::Kernel(files) if !files.nommu) {
+        eprintln!("Warning: Sv32 paging isn't implemented, only --nommu kernels can boot");
+    }
+    let loaded = audio_sink(options, output.as_ref().map(|(_stream, handle)| handle))
+        .and_then(|audio| load(options, audio));
+    let mut emu = match loaded {
+        Ok(emu) => emu,
+        Err(e) => {
+            eprintln!("{e}");
+            return EXIT_LOAD;
+        }
+    };
+    let code = if let Some(reference) = &options.cosim {
+        cosimulate(&mut emu, reference)
+    } else if options.step {
+        step(&mut emu)
+    } else {
+        execute(&mut emu, options)
+    };
+    if let Err(e) = emu.harts[0].mmu.audio.flush() {
+        eprintln!("Can't write audio: {e}");
+    }
+    code
+}
+
+// Runs until guest exits or limit is reached, reset builds machine again
+fn execute(emu: &mut Emulator, options: &Options) -> i32 {
+    let mut uart: Box<dyn Write> = match &options.uart {
+        UartOutput::Stdout => Box::new(io::stdout()),
+        UartOutput::Stderr => Box::new(io::stderr()),
+        UartOutput::Discard => Box::new(io::sink()),
+        UartOutput::File(path) => match File::create(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("Can't create {}: {e}", path.display());
+                return EXIT_LOAD;
+            }
+        },
+    };
+    // Harts can sleep in WFI for long, so host stops them when time is up
+    let stopflag = options.timeout.map(|timeout| {
+        let flag = Arc::new(AtomicBool::new(false));
+        let timer = flag.clone();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            timer.store(true, Ordering::Relaxed);
+        });
+        flag
+    });
+    emu.harts[0].stopflag = stopflag.clone();
+    // Instructions of machines before resets
+    let mut executed = 0;
+    let code = loop {
+        let remaining = options.max_instructions.map_or(u64::MAX, |max| max.saturating_sub(executed + emu.executed));
+        let reason = if remaining == 0 {
+            StopReason::BudgetExhausted
+        } else {
+            emu.run(remaining.min(RUN_BATCH * 256))
+        };
+        let output: Vec<u8> = std::iter::from_fn(|| emu.harts[0].mmu.uart.try_get_byte()).collect();
+        // Reader of pipe can go away, like with | head
+        if let Err(e) = uart.write_all(&output) {
+            eprintln!("Can't write UART output: {e}");
+            break EXIT_FAULT;
+        }
+        let pc = emu.harts[emu.current].pc;
+        let code = match reason {
+            StopReason::BudgetExhausted if remaining == 0 => {
+                eprintln!("Instruction limit reached at 0x{pc:0>8x}");
+                EXIT_INSTRUCTION_LIMIT
+            }
+            StopReason::BudgetExhausted => continue,
+            // Only stop host asks for is timeout
+            StopReason::HostStop if stopflag.is_some() => {
+                eprintln!("Timed out at 0x{pc:0>8x}");
+                EXIT_TIMEOUT
+            }
+            // Exit codes above 255 would be truncated, maybe to 0
+            StopReason::PowerOff(code) => code.min(255) as i32,
+            StopReason::Reset => {
+                executed += emu.executed;
+                let audio = mem::replace(&mut emu.harts[0].mmu.audio, Box::new(NullSink));
+                match load(options, audio) {
+                    Ok(new) => {
+                        *emu = new;
+                        emu.harts[0].stopflag = stopflag.clone();
+                    }
+                    Err(e) => {
+                        eprintln!("{e}");
+                        break EXIT_LOAD;
+                    }
+                }
+                continue;
+            }
+            StopReason::WaitForInterrupt => {
+                eprintln!("All harts wait for interrupt at 0x{pc:0>8x}");
+                EXIT_DEADLOCK
+            }
+            StopReason::Fatal(e) => {
+                match read_instruction(&emu.harts[emu.current].mmu, pc) {
+                    Some(instr) => eprintln!("Emulator error {e:?} at 0x{pc:0>8x}: {}", disassemble(instr)),
+                    None => eprintln!("Emulator error {e:?} at 0x{pc:0>8x}"),
+                }
+                EXIT_FAULT
+            }
+            reason @ (StopReason::Breakpoint(_) | StopReason::HostStop) => {
+                eprintln!("Stopped: {reason:?} at 0x{pc:0>8x}");
+                EXIT_FAULT
+            }
+        };
+        break code;
+    };
+    if let Err(e) = uart.flush() {
+        eprintln!("Can't write UART output: {e}");
+        return EXIT_FAULT;
+    }
+    code
+}
+
+// Exits with 0 when whole reference log matched
+fn cosimulate(emu: &mut Emulator, reference: &Path) -> i32 {
+    let reference = match File::open(reference) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => {
+            eprintln!("Can't open {}: {e}", reference.display());
+            return EXIT_LOAD;
+        }
+    };
+    let mut cosim = CoSimulator::new(reference);
+    match cosim.run(emu, None) {
+        Ok(checked) => {
+            println!("Reference log matched, {checked} instructions checked");
+            0
+        }
+        Err(CosimError::Mismatch(m)) => {
+            println!("{m}");
+            1
+        }
+        Err(e) => {
+            println!("Co-simulation stopped after {} instructions: {e:?}", cosim.checked());
+            1
+        }
+    }
+}
+
+// Executes instruction per line of stdin
+fn step(emu: &mut Emulator) -> i32 {
     let mut stdin = io::stdin();
-    println!("Start executing...");
-    let reg_names = [
-        "zo", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
-        "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
-        "t5", "t6",
-    ];
-
-    let display = false;
     let mut counter: u128 = 1;
     loop {
-        
-        //let display = true;
-        let pc = emu.cpu.pc;
-        if display {
-            println!("pc: {:0>8x}", pc);
-            let _ = stdin.read(&mut [0u8]).unwrap();
-        }
-        let res = emu.cpu.step();
-        while let Some(x) = emu.cpu.mmu.uart.try_get_byte() {
+        let pc = emu.harts[0].pc;
+        println!("pc: {:0>8x}", pc);
+        print!("{}", CodeDisplay::new(&emu.harts[0].mmu, pc, pc, 4));
+        let _ = stdin.read(&mut [0u8]).unwrap();
+        let res = emu.harts[0].step();
+        while let Some(x) = emu.harts[0].mmu.uart.try_get_byte() {
             print!("{}", x as char)
         }
         match res {
             Ok(instr) => {
-                if display {
-                    println!("Executed instruction 0x{:0>8x} on address {:x}; instruction no {}", instr, pc, counter);
-                    for (ind, x) in emu.cpu.get_registers().into_iter().enumerate() {
-                        let reg_name = reg_names[ind];
-                        print!("{reg_name:<3}: {:^10x}; ", x);
-                        if ind % 8 == 7 && ind != 0 {
-                            println!()
-                        }
+                println!("Executed instruction 0x{:0>8x} ({}) on address {:x}; instruction no {}", instr, disassemble(instr), pc, counter);
+                for (ind, x) in emu.harts[0].get_registers().into_iter().enumerate() {
+                    let reg_name = REGISTER_NAMES[ind];
+                    print!("{reg_name:<4}: {:^10x}; ", x);
+                    if ind % 8 == 7 && ind != 0 {
+                        println!()
                     }
-                    println!()
                 }
+                println!()
             }
             Err(trap) => {
-                //println!("Achieved trap: {} on address {:x}", trap, emu.cpu.pc);
-                println!("Encountered error! {trap:?}");
-                break;
+                println!("Encountered error! {trap:?} at 0x{:0>8x}", emu.harts[0].pc);
+                return EXIT_FAULT;
             }
         }
-        
-        // if counter%100000000 == 0 {
-        //    emu.cpu.process_trap(traps::Trap { tcause: traps::TrapType::EnvironmentCallFromMMode, tval: 0 }).unwrap();
-        // }
-        
         counter += 1;
     }
-    println!("UART output buffer:");
-    let mut v = vec![];
-    while let Some(x) = emu.cpu.mmu.uart.try_get_byte() {
-        //print!("{}", x as char)
-        v.push(x)
-    }
-
-    for i in v.chunks_exact(2) {
-        println!("Test {}: {}", i[1], i[0] as char)
-    }
-    println!();
 }
 
 #[cfg(test)]
 mod test {
-    use std::{fs::File, io::Read, path::Path};
+    use std::{fs::File, io::Read, path::Path, time::{Duration, Instant}};
 
-    use crate::{emulator, mmu::MMU};
+    use crate::{
+        assembler::*, cli::{self, EXIT_FAULT, EXIT_LOAD, EXIT_TIMEOUT}, clint::{CLINT_ADDRESS, MTIMECMP_OFFSET}, cpu::{PrivilegeMode, CPU, EXECUTION_MODES}, emulator::{self, StopReason}, errors::EmulatorError, mmu::{MMU, PRIMITIVE_AUDIO_ADDRESS, RAM_ADDRESS, RAM_ADDRESS_END, UART_ADDRESS}, test_finisher::{FINISHER_PASS, TEST_FINISHER_ADDRESS}, traps::{Trap, TrapType}
+    };
 
     pub fn run_arch_tests(path: &Path) {
         let mut elf_file = File::open(path).unwrap();
         let mut elf_contents = vec![];
         elf_file.read_to_end(&mut elf_contents).unwrap();
         let mmu = MMU::new();
-        let mut emu = emulator::Emulator::from_elf(elf_contents, mmu.0);
-        loop {
-            let res = emu.cpu.execute_instruction();
-            match res {
-                Ok(_) => (),
-                Err(_) => break,
-            }
-        }
+        let mut emu = emulator::Emulator::from_elf(elf_contents, mmu.0).unwrap();
+        // Tests end through test finisher after reporting results
+        assert_eq!(emu.run(10_000_000), StopReason::PowerOff(0));
         let mut v = vec![];
-        while let Some(x) = emu.cpu.mmu.uart.try_get_byte() {
+        while let Some(x) = emu.harts[0].mmu.uart.try_get_byte() {
             //print!("{}", x as char)
             v.push(x)
         }
@@ -134,23 +385,305 @@ mod test {
         }
     }
     #[test]
-    pub fn test_add() {
+    #[ignore = "needs test_asm/target built by test_asm/build.sh"]
+    pub fn test_add_elf() {
         let path = "./test_asm/target/testadd.s.elf";
         run_arch_tests(Path::new(path));
     }
     #[test]
-    pub fn test_addi() {
+    #[ignore = "needs test_asm/target built by test_asm/build.sh"]
+    pub fn test_addi_elf() {
         let path = "./test_asm/target/testaddi.s.elf";
         run_arch_tests(Path::new(path));
     }
     #[test]
-    pub fn test_sll() {
+    #[ignore = "needs test_asm/target built by test_asm/build.sh"]
+    pub fn test_sll_elf() {
         let path = "./test_asm/target/testsll.s.elf";
         run_arch_tests(Path::new(path));
     }
     #[test]
-    pub fn test_bltu() {
+    #[ignore = "needs test_asm/target built by test_asm/build.sh"]
+    pub fn test_bltu_elf() {
         let path = "./test_asm/target/testbltu.s.elf";
         run_arch_tests(Path::new(path));
     }
+
+    // Runs program from start of RAM, traps are handled until trap handler is unset.
+    // Every execution mode has to end in same state, cpu of interpreter is returned.
+    fn run(asm: &Assembler) -> CPU {
+        let mut results: Vec<CPU> = vec![];
+        for &mode in EXECUTION_MODES {
+            let mut cpu = asm.cpu();
+            cpu.execution_mode = mode;
+            match cpu.run(10000, &mut 0) {
+                Err(EmulatorError::UnsetTrapHandler) => {}
+                r => panic!("Program didn't finish in {mode:?}: {r:?}"),
+            }
+            if let Some(first) = results.first() {
+                assert_eq!(cpu.get_registers(), first.get_registers(), "{mode:?}");
+                assert_eq!(cpu.pc, first.pc, "{mode:?}");
+            }
+            results.push(cpu);
+        }
+        results.swap_remove(0)
+    }
+    fn test_rr_op(op: fn(&mut Assembler, u8, u8, u8) -> &mut Assembler, tests: &[(u32, u64, u64, u64)]) {
+        for &(test_num, result, first, second) in tests {
+            let mut asm = Assembler::new();
+            asm.li(A1, first as u32).li(A2, second as u32);
+            op(&mut asm, T0, A1, A2).ecall();
+            assert_eq!(run(&asm).get_registers()[T0 as usize], result as u32, "Test {test_num} failed");
+        }
+    }
+    fn test_imm_op(op: fn(&mut Assembler, u8, u8, i16) -> &mut Assembler, tests: &[(u32, u64, u64, i16)]) {
+        for &(test_num, result, first, imm) in tests {
+            let mut asm = Assembler::new();
+            asm.li(A1, first as u32);
+            // Immediates are written as 12-bit values like in assembly
+            op(&mut asm, T0, A1, imm << 4 >> 4).ecall();
+            assert_eq!(run(&asm).get_registers()[T0 as usize], result as u32, "Test {test_num} failed");
+        }
+    }
+    fn test_br2_op(op: for<'a> fn(&'a mut Assembler, u8, u8, &str) -> &'a mut Assembler, tests: &[(u32, bool, u32, u32)]) {
+        for &(test_num, taken, first, second) in tests {
+            let mut asm = Assembler::new();
+            asm.li(A1, first).li(A2, second);
+            op(&mut asm, A1, A2, "taken").li(T0, 0).ecall();
+            asm.label("taken").li(T0, 1).ecall();
+            assert_eq!(run(&asm).get_registers()[T0 as usize], taken as u32, "Test {test_num} failed");
+        }
+    }
+    #[test]
+    pub fn test_add() {
+        test_rr_op(Assembler::add, &[
+            (2, 0x00000000, 0x00000000, 0x00000000),
+            (3, 0x00000002, 0x00000001, 0x00000001),
+            (4, 0x0000000a, 0x00000003, 0x00000007),
+            (5, 0xffffffffffff8000, 0x0000000000000000, 0xffffffffffff8000),
+            (6, 0xffffffff80000000, 0xffffffff80000000, 0x00000000),
+            (7, 0xffffffff7fff8000, 0xffffffff80000000, 0xffffffffffff8000),
+            (8, 0x0000000000007fff, 0x0000000000000000, 0x0000000000007fff),
+            (9, 0x000000007fffffff, 0x000000007fffffff, 0x0000000000000000),
+            (10, 0x0000000080007ffe, 0x000000007fffffff, 0x0000000000007fff),
+            (11, 0xffffffff80007fff, 0xffffffff80000000, 0x0000000000007fff),
+            (12, 0x000000007fff7fff, 0x000000007fffffff, 0xffffffffffff8000),
+            (13, 0xffffffffffffffff, 0x0000000000000000, 0xffffffffffffffff),
+            (14, 0x0000000000000000, 0xffffffffffffffff, 0x0000000000000001),
+            (15, 0xfffffffffffffffe, 0xffffffffffffffff, 0xffffffffffffffff),
+            (16, 0x0000000080000000, 0x0000000000000001, 0x000000007fffffff),
+        ]);
+    }
+    #[test]
+    pub fn test_addi() {
+        test_imm_op(Assembler::addi, &[
+            (2, 0x00000000, 0x00000000, 0x000),
+            (3, 0x00000002, 0x00000001, 0x001),
+            (4, 0x0000000a, 0x00000003, 0x007),
+            (5, 0xfffffffffffff800, 0x0000000000000000, 0x800),
+            (6, 0xffffffff80000000, 0xffffffff80000000, 0x000),
+            (7, 0xffffffff7ffff800, 0xffffffff80000000, 0x800),
+            (8, 0x00000000000007ff, 0x00000000, 0x7ff),
+            (9, 0x000000007fffffff, 0x7fffffff, 0x000),
+            (10, 0x00000000800007fe, 0x7fffffff, 0x7ff),
+            (11, 0xffffffff800007ff, 0xffffffff80000000, 0x7ff),
+            (12, 0x000000007ffff7ff, 0x000000007fffffff, 0x800),
+            (13, 0xffffffffffffffff, 0x0000000000000000, 0xfff),
+            (14, 0x0000000000000000, 0xffffffffffffffff, 0x001),
+            (15, 0xfffffffffffffffe, 0xffffffffffffffff, 0xfff),
+            (16, 0x0000000080000000, 0x7fffffff, 0x001),
+        ]);
+    }
+    #[test]
+    pub fn test_sll() {
+        test_rr_op(Assembler::sll, &[
+            (2, 0x0000000000000001, 0x0000000000000001, 0),
+            (3, 0x0000000000000002, 0x0000000000000001, 1),
+            (4, 0x0000000000000080, 0x0000000000000001, 7),
+            (5, 0x0000000000004000, 0x0000000000000001, 14),
+            (6, 0x0000000080000000, 0x0000000000000001, 31),
+            (7, 0xffffffffffffffff, 0xffffffffffffffff, 0),
+            (8, 0xfffffffffffffffe, 0xffffffffffffffff, 1),
+            (9, 0xffffffffffffff80, 0xffffffffffffffff, 7),
+            (10, 0xffffffffffffc000, 0xffffffffffffffff, 14),
+            (11, 0xffffffff80000000, 0xffffffffffffffff, 31),
+            (12, 0x0000000021212121, 0x0000000021212121, 0),
+            (13, 0x0000000042424242, 0x0000000021212121, 1),
+            (14, 0x0000001090909080, 0x0000000021212121, 7),
+            (15, 0x0000084848484000, 0x0000000021212121, 14),
+            (16, 0x1090909080000000, 0x0000000021212121, 31),
+            // Verify that shifts only use bottom five bits
+            (17, 0x0000000021212121, 0x0000000021212121, 0xffffffffffffffc0),
+            (18, 0x0000000042424242, 0x0000000021212121, 0xffffffffffffffc1),
+            (19, 0x0000001090909080, 0x0000000021212121, 0xffffffffffffffc7),
+            (20, 0x0000084848484000, 0x0000000021212121, 0xffffffffffffffce),
+        ]);
+    }
+    #[test]
+    pub fn test_remu() {
+        test_rr_op(Assembler::remu, &[
+            (2, 2, 20, 6),
+            (3, 0x00000001, 0xffffffff, 2),
+            (4, 20, 20, 0),
+        ]);
+    }
+    #[test]
+    pub fn test_bltu() {
+        test_br2_op(Assembler::bltu, &[
+            (2, true, 0x00000000, 0x00000001),
+            (3, true, 0xfffffffe, 0xffffffff),
+            (4, true, 0x00000000, 0xffffffff),
+            (5, false, 0x00000001, 0x00000000),
+            (6, false, 0xffffffff, 0xfffffffe),
+            (7, false, 0xffffffff, 0x00000000),
+            (8, false, 0x80000000, 0x7fffffff),
+        ]);
+    }
+    #[test]
+    pub fn test_traps() {
+        let mut asm = Assembler::new();
+        asm.la(A0, "table").csrw(0x305, A0).ecall().nop().nop().nop();
+        asm.li(A0, 0).csrrw(A0, 0x305, A0).ecall().nop().nop();
+        asm.label("table")
+            .li(A0, 'I' as u32)
+            .call("sent_to_uart")
+            .li(A0, '\n' as u32)
+            .call("sent_to_uart")
+            .li(A0, 0)
+            .csrrw(A0, 0x341, A0)
+            .addi(A0, A0, 4)
+            .csrrw(A0, 0x341, A0)
+            .mret();
+        asm.label("sent_to_uart")
+            .addi(SP, SP, -4)
+            .sw(RA, 4, SP)
+            .li(RA, 0x10000000)
+            .sb(A0, 0, RA)
+            .lw(RA, 4, SP)
+            .addi(SP, SP, 4)
+            .ret();
+        let mut cpu = run(&asm);
+        // Second ecall happens with unset mtvec, so it stops execution
+        assert_eq!(cpu.pc, RAM_ADDRESS + 9 * 4);
+        // Handler skipped first ecall
+        assert_eq!(cpu.mcause, 11);
+        assert_eq!(cpu.mepc, RAM_ADDRESS + 4 * 4);
+        assert_eq!(cpu.mtvec, 0);
+        assert_eq!(cpu.mmu.uart.try_get_byte(), Some(b'I'));
+        assert_eq!(cpu.mmu.uart.try_get_byte(), Some(b'\n'));
+        assert_eq!(cpu.mmu.uart.try_get_byte(), None);
+    }
+    #[test]
+    pub fn test_compressed_illegal() {
+        for parcel in [0x0000, 0x0001, 0x4502, 0x8082] {
+            let mut asm = Assembler::new();
+            asm.la(A0, "handler").csrw(0x305, A0).instr(parcel);
+            asm.label("handler").csrr(S0, 0x342).csrr(S1, 0x343).csrw(0x305, ZERO).ecall();
+            let regs = run(&asm).get_registers();
+            assert_eq!(regs[S0 as usize], 2, "{parcel:04x}");
+            assert_eq!(regs[S1 as usize], parcel, "{parcel:04x}");
+        }
+    }
+    #[test]
+    pub fn test_trap_mstatus() {
+        let mut asm = Assembler::new();
+        asm.la(A0, "handler").csrw(0x305, A0).li(A0, 8).csrrs(ZERO, 0x300, A0).ecall();
+        // Back from handler in M mode, then mret goes to U mode with MPP of 0
+        asm.csrr(S1, 0x300).la(S4, "user").csrw(0x341, S4).mret();
+        asm.label("user").ecall();
+        asm.label("handler").bne(S2, ZERO, "done").csrr(S0, 0x300).li(S2, 1);
+        asm.csrr(A0, 0x341).addi(A0, A0, 4).csrw(0x341, A0).mret();
+        asm.label("done").csrr(S3, 0x300).csrw(0x305, ZERO).ecall();
+        let cpu = run(&asm);
+        let regs = cpu.get_registers();
+        // Trap from M mode with MIE set: MIE cleared, MPIE set, MPP is M
+        assert_eq!(regs[S0 as usize] & 0x1888, 0x1880);
+        // mret restored MIE, set MPIE and MPP to U
+        assert_eq!(regs[S1 as usize] & 0x1888, 0x88);
+        // Trap from U mode with MIE set: MPP is U
+        assert_eq!(regs[S3 as usize] & 0x1888, 0x80);
+        assert_eq!(cpu.privilege, PrivilegeMode::Machine);
+        assert_eq!(cpu.mepc, regs[S4 as usize]);
+    }
+    #[test]
+    pub fn test_timeout_during_wfi() {
+        // Timer interrupt is enabled, but over an hour away
+        let mut asm = Assembler::new();
+        asm.li(T0, CLINT_ADDRESS + MTIMECMP_OFFSET).li(T1, 1).sw(T1, 4, T0).sw(ZERO, 0, T0);
+        asm.li(T0, 1 << 7).csrw(0x304, T0).label("idle").wfi().j("idle");
+        let args = ["--no-audio", "--uart", "none", "--timeout", "0.1", "prog.elf"];
+        let options = cli::parse(args.map(String::from)).unwrap();
+        let start = Instant::now();
+        assert_eq!(super::execute(&mut asm.emulator(), &options), EXIT_TIMEOUT);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+    #[test]
+    pub fn test_load_non_elf() {
+        let path = std::env::temp_dir().join(format!("rv-emu-rs-script-{}", std::process::id()));
+        std::fs::write(&path, b"#!/bin/sh\n").unwrap();
+        let options = cli::parse(["--no-audio".to_string(), path.display().to_string()]).unwrap();
+        assert_eq!(super::run(&options), EXIT_LOAD);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    pub fn test_device_and_ram_end_faults() {
+        let programs: [fn(&mut Assembler); 5] = [
+            |asm| { asm.li(T0, UART_ADDRESS).lw(A0, 0, T0); },
+            |asm| { asm.li(T0, UART_ADDRESS).sw(A0, 0, T0); },
+            |asm| { asm.li(T0, PRIMITIVE_AUDIO_ADDRESS).lh(A0, 0, T0); },
+            |asm| { asm.li(T0, UART_ADDRESS).jalr(ZERO, T0, 0); },
+            |asm| { asm.li(T0, RAM_ADDRESS_END - 1).lw(A0, 0, T0); },
+        ];
+        let options = cli::parse(["--no-audio", "--uart", "none", "prog.elf"].map(String::from)).unwrap();
+        for (i, program) in programs.into_iter().enumerate() {
+            let mut asm = Assembler::new();
+            program(&mut asm);
+            for &mode in EXECUTION_MODES {
+                let mut emu = asm.emulator();
+                emu.harts[0].execution_mode = mode;
+                // Access faults trap to unset handler instead of panicking
+                assert_eq!(super::execute(&mut emu, &options), EXIT_FAULT, "{i} {mode:?}");
+            }
+        }
+    }
+    #[test]
+    pub fn test_output_errors() {
+        // Guest prints and passes, but its output can't be written
+        let mut asm = Assembler::new();
+        asm.li(T0, UART_ADDRESS).li(T1, b'x' as u32).sb(T1, 0, T0);
+        asm.li(T0, TEST_FINISHER_ADDRESS).li(T1, FINISHER_PASS).sw(T1, 0, T0);
+        let options = cli::parse(["--no-audio", "--uart", "/dev/full", "prog.elf"].map(String::from)).unwrap();
+        assert_eq!(super::execute(&mut asm.emulator(), &options), EXIT_FAULT);
+
+        let file = std::env::temp_dir().join(format!("rv-emu-rs-signatures-{}", std::process::id()));
+        std::fs::write(&file, b"").unwrap();
+        assert_eq!(super::run_compliance(&file, Some(&file.join("sub")), None), EXIT_LOAD);
+        std::fs::remove_file(&file).unwrap();
+    }
+    #[test]
+    pub fn test_mret_below_machine_mode() {
+        for mode in [PrivilegeMode::User, PrivilegeMode::Supervisor] {
+            let mut asm = Assembler::new();
+            asm.la(A0, "handler").csrw(0x305, A0).li(A0, 0b11 << 11).csrrc(ZERO, 0x300, A0);
+            asm.pmp_allow_all().li(A0, (mode as u32) << 11).csrrs(ZERO, 0x300, A0);
+            asm.la(A0, "lower").csrw(0x341, A0).mret();
+            asm.label("lower").mret();
+            asm.label("handler").csrr(S0, 0x342).csrr(S1, 0x300).csrw(0x305, ZERO).ecall();
+            let regs = run(&asm).get_registers();
+            assert_eq!(regs[S0 as usize], 2, "{mode:?}");
+            // Trap came from that mode
+            assert_eq!(regs[S1 as usize] >> 11 & 0b11, mode as u32, "{mode:?}");
+        }
+    }
+    #[test]
+    pub fn test_vectored_mtvec() {
+        let mut cpu = Assembler::new().ecall().cpu();
+        cpu.mtvec = (RAM_ADDRESS + 0x100) | 1;
+        // Exceptions go to base, interrupts to base + 4 * cause
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, RAM_ADDRESS + 0x100);
+        cpu.process_trap(Trap { tcause: TrapType::MachineTimerInterrupt, tval: 0 }).unwrap();
+        assert_eq!(cpu.pc, RAM_ADDRESS + 0x100 + 4 * 7);
+        assert_eq!(cpu.mcause, 0x8000_0007);
+    }
 }
@@ -1,3 +1,63 @@
+use std::fmt::Display;
+
+use crate::{
+    disassembler::{disassemble, instruction_length},
+    mmu::MMU,
+};
+
 pub struct CodeDisplay {
     lines: Vec<String>
-}
\ No newline at end of file
+}
+
+impl CodeDisplay {
+    // Disassembles `count` instructions starting from `address`, marking the one at `pc`.
+    // Reads memory without touching devices, so displaying code has no side effects.
+    pub fn new(mmu: &MMU, pc: u32, address: u32, count: usize) -> Self {
+        let mut lines = vec![];
+        let mut address = address;
+        for _ in 0..count {
+            let marker = if address == pc { "=>" } else { "  " };
+            match read_instruction(mmu, address) {
+                Some(instr) => {
+                    let len = instruction_length(instr);
+                    let raw = if len == 2 {
+                        format!("    {:04x}", instr as u16)
+                    } else {
+                        format!("{instr:08x}")
+                    };
+                    lines.push(format!("{marker} {address:08x}: {raw}  {}", disassemble(instr)));
+                    address = address.wrapping_add(len);
+                }
+                None => {
+                    lines.push(format!("{marker} {address:08x}: <inaccessible>"));
+                    break;
+                }
+            }
+        }
+        CodeDisplay { lines }
+    }
+}
+
+impl Display for CodeDisplay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn read_instruction(mmu: &MMU, address: u32) -> Option<u32> {
+    let low = u16::from_le_bytes([
+        mmu.read_raw_from_ram(address)?,
+        mmu.read_raw_from_ram(address.wrapping_add(1))?,
+    ]);
+    if instruction_length(low as u32) == 2 {
+        return Some(low as u32);
+    }
+    let high = u16::from_le_bytes([
+        mmu.read_raw_from_ram(address.wrapping_add(2))?,
+        mmu.read_raw_from_ram(address.wrapping_add(3))?,
+    ]);
+    Some((high as u32) << 16 | low as u32)
+}
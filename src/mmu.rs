@@ -1,4 +1,4 @@
-use crate::{primitive_audio::{self, PrimitiveAudioProducer, PrimitiveAudioReciever}, traps::Trap, uart::UART};
+use crate::{clint::{self, Clint, CLINT_ADDRESS, CLINT_ADDRESS_END}, htif::{Htif, HtifCommand, SYS_EXIT, SYS_WRITE}, linux_user::LinuxUser, newlib::Newlib, primitive_audio::{self, AudioSink, NullSink, PrimitiveAudioProducer}, sbi::Sbi, semihosting::Semihosting, snapshot::{SnapshotError, SnapshotReader, SnapshotWriter}, test_finisher::{self, FinisherRequest, TEST_FINISHER_ADDRESS, TEST_FINISHER_ADDRESS_END}, traps::Trap, uart::UART};
 
 pub const RAM_SIZE: usize = 64 * 1024 * 1024;
 
@@ -10,11 +10,35 @@ pub const UART_ADDRESS: u32 = 0x10000000;
 pub const UART_ADDRESS_END: u32 = 0x10000000 + UART_REGION_SIZE as u32 - 1;
 pub const PRIMITIVE_AUDIO_ADDRESS: u32 = 0x10000200;
 
+pub const PAGE_SIZE: u32 = 4096;
+const RAM_PAGES: usize = RAM_SIZE / PAGE_SIZE as usize;
+
 
 pub struct MMU {
     memory: Box<[u8]>,
     pub uart: UART,
-    pub audio: PrimitiveAudioReciever
+    // Rodio playback unless changed with with_audio
+    pub audio: Box<dyn AudioSink + Send>,
+    pub htif: Option<Htif>,
+    // Bitmap of RAM pages with decoded instructions in them
+    code_pages: Box<[u64]>,
+    // Pages that had code and were written since last check
+    pub invalidated_code_pages: Vec<u32>,
+    pub clint: Clint,
+    // LR reservations as (hartid, address), any write to reserved word drops them
+    reservations: Vec<(u32, u32)>,
+    // Built-in firmware state, see sbi
+    pub sbi: Option<Sbi>,
+    // Process state when running Linux program without kernel, see linux_user
+    pub linux_user: Option<LinuxUser>,
+    // Bare-metal syscalls served by emulator, see newlib
+    pub newlib: Option<Newlib>,
+    // Host services requested through ebreak, see semihosting
+    pub semihosting: Option<Semihosting>,
+    // Last request written to test finisher
+    pub finisher: Option<FinisherRequest>,
+    // Device asked hart that accessed it to stop, hart takes it after store
+    pub stop_requested: bool,
 }
 
 
@@ -25,9 +49,59 @@ impl MMU {
         (MMU {
             memory: v.into(),
             uart: UART::new(),
-            audio: audio_res,
+            audio: Box::new(audio_res),
+            htif: None,
+            code_pages: vec![0; RAM_PAGES / 64].into(),
+            invalidated_code_pages: vec![],
+            clint: Clint::default(),
+            reservations: vec![],
+            sbi: None,
+            linux_user: None,
+            newlib: None,
+            semihosting: None,
+            finisher: None,
+            stop_requested: false,
         }, audio_prod)
     }
+    // Without RAM, stands in for bus in harts that aren't running
+    pub fn detached() -> Self {
+        MMU {
+            memory: Box::new([]),
+            uart: UART::new(),
+            audio: Box::new(NullSink),
+            htif: None,
+            code_pages: Box::new([]),
+            invalidated_code_pages: vec![],
+            clint: Clint::default(),
+            reservations: vec![],
+            sbi: None,
+            linux_user: None,
+            newlib: None,
+            semihosting: None,
+            finisher: None,
+            stop_requested: false,
+        }
+    }
+    // Producer returned by new isn't needed then
+    pub fn with_audio(mut self, sink: impl AudioSink + Send + 'static) -> Self {
+        self.audio = Box::new(sink);
+        self
+    }
+    pub fn has_ram(&self) -> bool {
+        !self.memory.is_empty()
+    }
+    pub fn reserve(&mut self, hartid: u32, address: u32) {
+        self.take_reservation(hartid);
+        self.reservations.push((hartid, address));
+    }
+    pub fn take_reservation(&mut self, hartid: u32) -> Option<u32> {
+        let index = self.reservations.iter().position(|(hart, _)| *hart == hartid)?;
+        Some(self.reservations.swap_remove(index).1)
+    }
+    #[inline(always)]
+    pub fn has_reservations(&self) -> bool {
+        !self.reservations.is_empty()
+    }
     pub fn fetch_word(&self, address: u32) -> Result<u32, Trap> {
         //if address % 2 != 0 {
         //    return Err(Trap {
@@ -36,18 +110,12 @@ impl MMU {
         //    });
         //}
         match address {
-            RAM_ADDRESS..=RAM_ADDRESS_END => {
+            RAM_ADDRESS..=RAM_ADDRESS_END if fits_ram(address, 4) => {
                 let mem_adr = (address - RAM_ADDRESS) as usize;
                 Ok(u32::from_le_bytes(
                     self.memory[mem_adr..mem_adr + 4].try_into().unwrap(),
                 ))
             }
-            UART_ADDRESS..=UART_ADDRESS_END => {
-                todo!()
-            }
-            PRIMITIVE_AUDIO_ADDRESS => {
-                todo!()
-            }
             _ => Err(Trap {
                 tcause: crate::traps::TrapType::InstructionAccessFault,
                 tval: address,
@@ -56,18 +124,23 @@ impl MMU {
     }
     pub fn read_word(&self, address: u32) -> Result<u32, Trap> {
         match address {
-            RAM_ADDRESS..=RAM_ADDRESS_END => {
+            RAM_ADDRESS..=RAM_ADDRESS_END if fits_ram(address, 4) => {
                 let mem_adr = (address - RAM_ADDRESS) as usize;
                 Ok(u32::from_le_bytes(
                     self.memory[mem_adr..mem_adr + 4].try_into().unwrap(),
                 ))
             }
-            UART_ADDRESS..=UART_ADDRESS_END => {
-                todo!()
-            }
             PRIMITIVE_AUDIO_ADDRESS => {
                 Ok(self.audio.get_size())
             }
+            CLINT_ADDRESS..=CLINT_ADDRESS_END => clint::read(&self.clint, address).ok_or(Trap {
+                tcause: crate::traps::TrapType::LoadAccessFault,
+                tval: address,
+            }),
+            TEST_FINISHER_ADDRESS..=TEST_FINISHER_ADDRESS_END => test_finisher::read(address).ok_or(Trap {
+                tcause: crate::traps::TrapType::LoadAccessFault,
+                tval: address,
+            }),
             _ => Err(Trap {
                 tcause: crate::traps::TrapType::LoadAccessFault,
                 tval: address,
@@ -76,18 +149,12 @@ impl MMU {
     }
     pub fn read_halfword(&self, address: u32) -> Result<u16, Trap> {
         match address {
-            RAM_ADDRESS..=RAM_ADDRESS_END => {
+            RAM_ADDRESS..=RAM_ADDRESS_END if fits_ram(address, 2) => {
                 let mem_adr = (address - RAM_ADDRESS) as usize;
                 Ok(u16::from_le_bytes(
                     self.memory[mem_adr..mem_adr + 2].try_into().unwrap(),
                 ))
             }
-            UART_ADDRESS..=UART_ADDRESS_END => {
-                todo!()
-            }
-            PRIMITIVE_AUDIO_ADDRESS => {
-                todo!()
-            }
             _ => Err(Trap {
                 tcause: crate::traps::TrapType::LoadAccessFault,
                 tval: address,
@@ -97,12 +164,6 @@ impl MMU {
     pub fn read_byte(&self, address: u32) -> Result<u8, Trap> {
         match address {
             RAM_ADDRESS..=RAM_ADDRESS_END => Ok(self.memory[(address - RAM_ADDRESS) as usize]),
-            UART_ADDRESS..=UART_ADDRESS_END => {
-                todo!()
-            }
-            PRIMITIVE_AUDIO_ADDRESS => {
-                todo!()
-            }
             _ => Err(Trap {
                 tcause: crate::traps::TrapType::LoadAccessFault,
                 tval: address,
@@ -111,35 +172,40 @@ impl MMU {
     }
     pub fn write_word(&mut self, address: u32, word: u32) -> Result<(), Trap> {
         match address {
-            RAM_ADDRESS..=RAM_ADDRESS_END => {
-                let word = word.to_le_bytes();
+            RAM_ADDRESS..=RAM_ADDRESS_END if fits_ram(address, 4) => {
+                let bytes = word.to_le_bytes();
                 let mem_adr = (address - RAM_ADDRESS) as usize;
-                self.memory[mem_adr..mem_adr + 4].copy_from_slice(&word);
+                self.memory[mem_adr..mem_adr + 4].copy_from_slice(&bytes);
+                self.note_ram_write(mem_adr, 4);
+                if self.htif.as_ref().is_some_and(|h| h.is_tohost(address)) {
+                    self.htif_write(address, word);
+                }
                 Ok(())
             }
-            UART_ADDRESS..=UART_ADDRESS_END => {
-                todo!()
-            }
-            PRIMITIVE_AUDIO_ADDRESS => {
-                todo!()
-            }
+            CLINT_ADDRESS..=CLINT_ADDRESS_END => clint::write(&mut self.clint, address, word).ok_or(Trap {
+                tcause: crate::traps::TrapType::StoreAccessFault,
+                tval: address,
+            }),
+            TEST_FINISHER_ADDRESS..=TEST_FINISHER_ADDRESS_END => test_finisher::write(self, address, word).ok_or(Trap {
+                tcause: crate::traps::TrapType::StoreAccessFault,
+                tval: address,
+            }),
             _ => Err(Trap {
                 tcause: crate::traps::TrapType::StoreAccessFault,
                 tval: address,
             }),
         }
     }
+    #[allow(clippy::unit_arg)]
     pub fn write_halfword(&mut self, address: u32, halfword: u16) -> Result<(), Trap> {
         match address {
-            RAM_ADDRESS..=RAM_ADDRESS_END => {
+            RAM_ADDRESS..=RAM_ADDRESS_END if fits_ram(address, 2) => {
                 let word = halfword.to_le_bytes();
                 let mem_adr = (address - RAM_ADDRESS) as usize;
                 self.memory[mem_adr..mem_adr + 2].copy_from_slice(&word);
+                self.note_ram_write(mem_adr, 2);
                 Ok(())
             }
-            UART_ADDRESS..=UART_ADDRESS_END => {
-                todo!()
-            }
             PRIMITIVE_AUDIO_ADDRESS => {
                 Ok(self.audio.write(halfword as i16))
             }
@@ -153,6 +219,7 @@ impl MMU {
         match address {
             RAM_ADDRESS..=RAM_ADDRESS_END => {
                 self.memory[(address - RAM_ADDRESS) as usize] = byte;
+                self.note_ram_write((address - RAM_ADDRESS) as usize, 1);
                 Ok(())
             }
             UART_ADDRESS => {
@@ -160,12 +227,6 @@ impl MMU {
                 self.uart.emu_push(byte);
                 Ok(())
             }
-            UART_ADDRESS..=UART_ADDRESS_END => {
-                todo!()
-            }
-            PRIMITIVE_AUDIO_ADDRESS => {
-                todo!()
-            }
             _ => Err(Trap {
                 tcause: crate::traps::TrapType::StoreAccessFault,
                 tval: address,
@@ -176,6 +237,7 @@ impl MMU {
         match address {
             RAM_ADDRESS..=RAM_ADDRESS_END => {
                 self.memory[(address - RAM_ADDRESS) as usize] = byte;
+                self.note_ram_write((address - RAM_ADDRESS) as usize, 1);
                 true
             }
             _ => false,
@@ -187,4 +249,176 @@ impl MMU {
             _ => None,
         }
     }
+    // RAM is sparse, only pages with non-zero bytes are stored
+    pub fn save(&self, w: &mut SnapshotWriter) {
+        let pages: Vec<_> = self
+            .memory
+            .chunks_exact(PAGE_SIZE as usize)
+            .enumerate()
+            .filter(|(_, page)| page.iter().any(|b| *b != 0))
+            .collect();
+        w.u32(RAM_SIZE as u32);
+        w.u32(pages.len() as u32);
+        for (index, page) in pages {
+            w.u32(index as u32);
+            w.data.extend_from_slice(page);
+        }
+        self.uart.save(w);
+        self.audio.save(w);
+        self.clint.save(w);
+        w.u32(self.reservations.len() as u32);
+        for (hart, address) in &self.reservations {
+            w.u32(*hart);
+            w.u32(*address);
+        }
+        w.bool(self.htif.is_some());
+        if let Some(htif) = &self.htif {
+            w.u32(htif.tohost);
+            w.option_u64(htif.fromhost.map(|a| a as u64));
+            w.option_u64(htif.exit_code.map(|c| c as u64));
+        }
+        w.bool(self.sbi.is_some());
+        if let Some(sbi) = &self.sbi {
+            sbi.save(w);
+        }
+        FinisherRequest::save(self.finisher, w);
+    }
+    pub fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        if r.u32()? != RAM_SIZE as u32 {
+            return Err(SnapshotError::Corrupt);
+        }
+        self.memory.fill(0);
+        for _ in 0..r.u32()? {
+            let index = r.u32()? as usize;
+            let page = r.take(PAGE_SIZE as usize)?;
+            let start = index * PAGE_SIZE as usize;
+            self.memory
+                .get_mut(start..start + PAGE_SIZE as usize)
+                .ok_or(SnapshotError::Corrupt)?
+                .copy_from_slice(page);
+        }
+        // Caller drops all cached code
+        self.code_pages.fill(0);
+        self.invalidated_code_pages.clear();
+        self.uart.restore(r)?;
+        self.audio.restore(r)?;
+        self.clint.restore(r)?;
+        self.reservations.clear();
+        for _ in 0..r.u32()? {
+            self.reservations.push((r.u32()?, r.u32()?));
+        }
+        self.htif = match r.bool()? {
+            true => {
+                let mut htif = Htif::new(r.u32()?, r.option_u64()?.map(|a| a as u32));
+                htif.exit_code = r.option_u64()?.map(|c| c as u32);
+                Some(htif)
+            }
+            false => None,
+        };
+        self.sbi = match r.bool()? {
+            true => Some(Sbi::restore(r)?),
+            false => None,
+        };
+        self.finisher = FinisherRequest::restore(r)?;
+        Ok(())
+    }
+    // Used by jit for direct access to RAM
+    pub fn ram_ptr(&mut self) -> *mut u8 {
+        self.memory.as_mut_ptr()
+    }
+    pub fn code_pages_ptr(&self) -> *const u64 {
+        self.code_pages.as_ptr()
+    }
+    // Remembers that page contains instructions, so writes to it are reported.
+    pub fn mark_code_page(&mut self, address: u32) {
+        if let RAM_ADDRESS..=RAM_ADDRESS_END = address {
+            let page = ((address - RAM_ADDRESS) / PAGE_SIZE) as usize;
+            self.code_pages[page / 64] |= 1 << (page % 64);
+        }
+    }
+    #[inline(always)]
+    fn note_ram_write(&mut self, mem_adr: usize, size: usize) {
+        if !self.reservations.is_empty() {
+            let address = RAM_ADDRESS + mem_adr as u32;
+            self.reservations
+                .retain(|&(_, reserved)| address >= reserved + 4 || reserved >= address + size as u32);
+        }
+        for page in [mem_adr / PAGE_SIZE as usize, (mem_adr + size - 1) / PAGE_SIZE as usize] {
+            let bit = 1 << (page % 64);
+            if self.code_pages[page / 64] & bit != 0 {
+                self.code_pages[page / 64] &= !bit;
+                self.invalidated_code_pages.push(RAM_ADDRESS / PAGE_SIZE + page as u32);
+            }
+        }
+    }
+    fn read_doubleword_from_ram(&self, address: u32) -> u64 {
+        let low = self.read_word(address).unwrap_or(0) as u64;
+        let high = self.read_word(address.wrapping_add(4)).unwrap_or(0) as u64;
+        high << 32 | low
+    }
+    fn write_doubleword_to_ram(&mut self, address: u32, value: u64) {
+        for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+            self.write_raw_to_ram(address.wrapping_add(i as u32), byte);
+        }
+    }
+    fn htif_write(&mut self, address: u32, word: u32) {
+        let Some(htif) = &self.htif else {
+            return;
+        };
+        let other_half = self.read_word(address ^ 0b100).unwrap_or(0);
+        let Some(command) = htif.decode(address, word, other_half) else {
+            return;
+        };
+        let tohost = htif.tohost;
+        let fromhost = htif.fromhost;
+        let response = match command {
+            HtifCommand::Exit(code) => {
+                self.htif.as_mut().unwrap().exit_code = Some(code);
+                self.stop_requested = true;
+                None
+            }
+            HtifCommand::Putchar(byte) => {
+                self.uart.emu_push(byte);
+                Some(0x0101_0000_0000_0100 | byte as u64)
+            }
+            HtifCommand::Syscall(magic_mem) => {
+                let args: Vec<u64> = (0..8)
+                    .map(|i| self.read_doubleword_from_ram(magic_mem + i * 8))
+                    .collect();
+                let ret = match args[0] {
+                    SYS_WRITE if args[1] == 1 || args[1] == 2 => {
+                        // Buffer has to start in RAM, write is cut at its end
+                        match u32::try_from(args[2]).ok().filter(|a| (RAM_ADDRESS..=RAM_ADDRESS_END).contains(a)) {
+                            Some(buffer) => {
+                                let len = args[3].min((RAM_ADDRESS_END - buffer) as u64 + 1);
+                                for i in 0..len as u32 {
+                                    let byte = self.read_raw_from_ram(buffer.wrapping_add(i)).unwrap_or(0);
+                                    self.uart.emu_push(byte);
+                                }
+                                len
+                            }
+                            None => -14i64 as u64, // EFAULT
+                        }
+                    }
+                    SYS_EXIT => {
+                        self.htif.as_mut().unwrap().exit_code = Some(args[1] as u32);
+                        self.stop_requested = true;
+                        0
+                    }
+                    _ => -38i64 as u64, // ENOSYS
+                };
+                self.write_doubleword_to_ram(magic_mem, ret);
+                Some(1)
+            }
+        };
+        self.write_doubleword_to_ram(tohost, 0);
+        if let (Some(fromhost), Some(response)) = (fromhost, response) {
+            self.write_doubleword_to_ram(fromhost, response);
+        }
+    }
+}
+
+// Access of `size` bytes at `address` in RAM doesn't run past its end
+fn fits_ram(address: u32, size: u32) -> bool {
+    address - RAM_ADDRESS + size <= RAM_SIZE as u32
 }
@@ -0,0 +1,356 @@
+// Syscalls of newlib and picolibc libgloss for bare-metal programs. M mode ecall is served by
+// emulator instead of trapping to mtvec, so printf and exit work without own runtime.
+// Numbers follow proxy kernel, open flags are newlib ones. Open files aren't saved in snapshots.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    cpu::CPU,
+    emulator::Emulator,
+    linux_user::{errno, in_ram, read_guest, read_string, write_guest, EACCES, EBADF, EFAULT, EINVAL, ENOSYS, STACK_SIZE},
+    mmu::{MMU, RAM_ADDRESS_END},
+};
+
+pub const SYS_OPENAT: u32 = 56;
+pub const SYS_CLOSE: u32 = 57;
+pub const SYS_LSEEK: u32 = 62;
+pub const SYS_READ: u32 = 63;
+pub const SYS_WRITE: u32 = 64;
+pub const SYS_FSTAT: u32 = 80;
+pub const SYS_EXIT: u32 = 93;
+pub const SYS_GETTIMEOFDAY: u32 = 169;
+pub const SYS_BRK: u32 = 214;
+pub const SYS_CLOCK_GETTIME64: u32 = 403;
+// Older libgloss opens through this instead of openat
+pub const SYS_OPEN: u32 = 1024;
+
+const O_ACCMODE: u32 = 3;
+const O_APPEND: u32 = 0x0008;
+const O_CREAT: u32 = 0x0200;
+const O_TRUNC: u32 = 0x0400;
+const O_EXCL: u32 = 0x0800;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+const A0: usize = 10;
+const A7: usize = 17;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Console {
+    // stdout and stderr go to UART, stdin is read from it
+    Uart,
+    // Standard streams of emulator process
+    Host,
+}
+
+enum Fd {
+    Console,
+    File(File),
+}
+
+pub struct Newlib {
+    console: Console,
+    // Directory guest files are opened in, without it they can't be opened
+    root: Option<PathBuf>,
+    fds: Vec<Option<Fd>>,
+    // Program break, heap can grow until stack at end of RAM
+    brk: u32,
+    pub exit_code: Option<u32>,
+}
+
+impl Newlib {
+    pub fn new(heap_start: u32) -> Self {
+        Newlib {
+            console: Console::Uart,
+            root: None,
+            fds: vec![Some(Fd::Console), Some(Fd::Console), Some(Fd::Console)],
+            brk: heap_start,
+            exit_code: None,
+        }
+    }
+    pub fn with_console(mut self, console: Console) -> Self {
+        self.console = console;
+        self
+    }
+    pub fn with_root(mut self, root: PathBuf) -> Self {
+        self.root = Some(root);
+        self
+    }
+    fn fd(&mut self, fd: u32) -> Result<&mut Fd, i32> {
+        self.fds.get_mut(fd as usize).and_then(|fd| fd.as_mut()).ok_or(EBADF)
+    }
+    fn syscall(&mut self, mmu: &mut MMU, number: u32, args: [u32; 6]) -> Result<u32, i32> {
+        match number {
+            SYS_OPEN => self.open(mmu, args[0], args[1]),
+            // Directory fd is ignored, all paths are relative to root
+            SYS_OPENAT => self.open(mmu, args[1], args[2]),
+            SYS_CLOSE => {
+                self.fd(args[0])?;
+                self.fds[args[0] as usize] = None;
+                Ok(0)
+            }
+            SYS_LSEEK => {
+                let Fd::File(file) = self.fd(args[0])? else {
+                    return Err(EINVAL);
+                };
+                let offset = args[1] as i32 as i64;
+                let position = match args[2] {
+                    0 => SeekFrom::Start(u64::try_from(offset).map_err(|_| EINVAL)?),
+                    1 => SeekFrom::Current(offset),
+                    2 => SeekFrom::End(offset),
+                    _ => return Err(EINVAL),
+                };
+                let position = file.seek(position).map_err(errno)?;
+                u32::try_from(position).map_err(|_| EINVAL)
+            }
+            SYS_READ => {
+                let console = self.console;
+                let fd = self.fd(args[0])?;
+                if !in_ram(args[1], args[2]) {
+                    return Err(EFAULT);
+                }
+                let mut data = vec![0; args[2] as usize];
+                let read = match (console, fd) {
+                    (Console::Uart, Fd::Console) => {
+                        let bytes = std::iter::from_fn(|| mmu.uart.emu_try_get_byte()).take(data.len());
+                        bytes.zip(data.iter_mut()).map(|(byte, slot)| *slot = byte).count()
+                    }
+                    (Console::Host, Fd::Console) => io::stdin().read(&mut data).map_err(errno)?,
+                    (_, Fd::File(file)) => file.read(&mut data).map_err(errno)?,
+                };
+                write_guest(mmu, args[1], &data[..read])?;
+                Ok(read as u32)
+            }
+            SYS_WRITE => {
+                let data = read_guest(mmu, args[1], args[2])?;
+                match (self.console, self.fd(args[0])?) {
+                    (Console::Uart, Fd::Console) => data.iter().for_each(|b| mmu.uart.emu_push(*b)),
+                    (Console::Host, Fd::Console) if args[0] == 2 => io::stderr().write_all(&data).map_err(errno)?,
+                    (Console::Host, Fd::Console) => io::stdout().write_all(&data).map_err(errno)?,
+                    (_, Fd::File(file)) => file.write_all(&data).map_err(errno)?,
+                }
+                Ok(args[2])
+            }
+            SYS_FSTAT => {
+                let (mode, size) = match self.fd(args[0])? {
+                    Fd::Console => (S_IFCHR | 0o620, 0),
+                    Fd::File(file) => {
+                        let metadata = file.metadata().map_err(errno)?;
+                        let kind = if metadata.is_dir() { S_IFDIR } else { S_IFREG };
+                        (kind | 0o644, metadata.len())
+                    }
+                };
+                // struct kernel_stat of libgloss
+                let mut stat = vec![0; 128];
+                stat[16..20].copy_from_slice(&mode.to_le_bytes());
+                stat[20..24].copy_from_slice(&1u32.to_le_bytes());
+                stat[48..56].copy_from_slice(&size.to_le_bytes());
+                stat[56..60].copy_from_slice(&4096u32.to_le_bytes());
+                write_guest(mmu, args[1], &stat)?;
+                Ok(0)
+            }
+            SYS_EXIT => {
+                self.exit_code = Some(args[0]);
+                Ok(0)
+            }
+            // timeval and timespec have 64-bit seconds
+            SYS_GETTIMEOFDAY | SYS_CLOCK_GETTIME64 => {
+                let time = mmu.clint.clock.realtime(mmu.clint.time());
+                let (address, fraction) = if number == SYS_GETTIMEOFDAY {
+                    (args[0], time.subsec_micros())
+                } else {
+                    (args[1], time.subsec_nanos())
+                };
+                let mut value = time.as_secs().to_le_bytes().to_vec();
+                value.extend_from_slice(&(fraction as u64).to_le_bytes());
+                write_guest(mmu, address, &value)?;
+                Ok(0)
+            }
+            // Returns new break, or old one if request can't be done
+            SYS_BRK => {
+                if args[0] != 0 && args[0] <= RAM_ADDRESS_END + 1 - STACK_SIZE {
+                    self.brk = args[0];
+                }
+                Ok(self.brk)
+            }
+            _ => Err(ENOSYS),
+        }
+    }
+    fn open(&mut self, mmu: &MMU, path: u32, flags: u32) -> Result<u32, i32> {
+        let path = sandboxed_path(self.root.as_deref(), &read_string(mmu, path)?)?;
+        let file = OpenOptions::new()
+            .read(flags & O_ACCMODE != 1)
+            .write(flags & O_ACCMODE != 0)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .create(flags & O_CREAT != 0 && flags & O_EXCL == 0)
+            .create_new(flags & O_CREAT != 0 && flags & O_EXCL != 0)
+            .open(path)
+            .map_err(errno)?;
+        let fd = match self.fds.iter().position(|fd| fd.is_none()) {
+            Some(free) => free,
+            None => {
+                self.fds.push(None);
+                self.fds.len() - 1
+            }
+        };
+        self.fds[fd] = Some(Fd::File(file));
+        Ok(fd as u32)
+    }
+}
+
+// Guest path is relative to root, even if it starts with /, and ".." can't leave root.
+// Symlinks are resolved and have to end inside of root too. File that doesn't exist yet is
+// checked through its parent, so it can be created, unless it is dangling symlink.
+pub fn sandboxed_path(root: Option<&Path>, path: &str) -> Result<PathBuf, i32> {
+    let root = root.ok_or(EACCES)?.canonicalize().map_err(errno)?;
+    let mut relative = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::ParentDir if !relative.pop() => return Err(EACCES),
+            _ => {}
+        }
+    }
+    let joined = root.join(relative);
+    let resolved = match joined.canonicalize() {
+        Ok(resolved) => resolved,
+        Err(e) if e.kind() == ErrorKind::NotFound && fs::symlink_metadata(&joined).is_err() => {
+            let (Some(parent), Some(name)) = (joined.parent(), joined.file_name()) else {
+                return Err(errno(e));
+            };
+            parent.canonicalize().map_err(errno)?.join(name)
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(EACCES),
+        Err(e) => return Err(errno(e)),
+    };
+    if !resolved.starts_with(&root) {
+        return Err(EACCES);
+    }
+    Ok(resolved)
+}
+
+// Services ecall from M mode, pc still points to ecall. Errors are returned as -errno.
+pub fn handle_ecall(cpu: &mut CPU) {
+    let mut regs = cpu.get_registers();
+    let args: [u32; 6] = regs[A0..A0 + 6].try_into().unwrap();
+    let mut newlib = cpu.mmu.newlib.take().unwrap();
+    cpu.sync_bus_time();
+    regs[A0] = newlib.syscall(&mut cpu.mmu, regs[A7], args).unwrap_or_else(|e| -e as u32);
+    cpu.stopped = newlib.exit_code.is_some();
+    cpu.mmu.newlib = Some(newlib);
+    cpu.set_registers(regs);
+    cpu.pc = cpu.pc.wrapping_add(4);
+}
+
+impl Emulator {
+    // crt0 takes argc and argv from stack, so if sp isn't set it points to argc of 0 at end
+    // of RAM.
+    pub fn with_newlib(mut self, newlib: Newlib) -> Self {
+        let cpu = &mut self.harts[0];
+        let mut regs = cpu.get_registers();
+        if regs[2] == 0 {
+            regs[2] = RAM_ADDRESS_END + 1 - 16;
+            cpu.set_registers(regs);
+        }
+        cpu.mmu.newlib = Some(newlib);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        clock::{Clock, VIRTUAL_EPOCH},
+        emulator::StopReason,
+        linux_user::{EACCES, ENOSYS},
+    };
+
+    use super::{
+        sandboxed_path, Newlib, O_CREAT, S_IFCHR, SYS_BRK, SYS_CLOSE, SYS_EXIT, SYS_FSTAT, SYS_GETTIMEOFDAY, SYS_OPEN,
+        SYS_WRITE,
+    };
+
+    #[test]
+    fn test_syscalls() {
+        let root = std::env::temp_dir().join(format!("rv-emu-rs-newlib-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut asm = Assembler::new();
+        asm.li(A0, 1).la(A1, "message").li(A2, 3).li(A7, SYS_WRITE).ecall();
+        asm.li(A0, 1).addi(A1, SP, -128).li(A7, SYS_FSTAT).ecall().lw(S0, -128 + 16, SP);
+        asm.li(A0, 0).li(A7, SYS_BRK).ecall().mv(S1, A0);
+        asm.la(A0, "path").li(A1, 1 | O_CREAT).li(A7, SYS_OPEN).ecall().mv(S2, A0);
+        asm.mv(A0, S2).la(A1, "message").li(A2, 3).li(A7, SYS_WRITE).ecall();
+        asm.mv(A0, S2).li(A7, SYS_CLOSE).ecall();
+        asm.la(A0, "escape").li(A1, 0).li(A7, SYS_OPEN).ecall().mv(S3, A0);
+        asm.addi(A0, SP, -16).li(A7, SYS_GETTIMEOFDAY).ecall().lw(S4, -16, SP);
+        asm.li(A7, 12345).ecall().mv(S5, A0);
+        asm.li(A0, 3).li(A7, SYS_EXIT).ecall();
+        asm.label("message").instr(u32::from_le_bytes(*b"hi\n\0"));
+        asm.label("path").instr(u32::from_le_bytes(*b"/a/.")).instr(u32::from_le_bytes(*b"./f\0"));
+        asm.label("escape").instr(u32::from_le_bytes(*b"../f")).instr(0);
+
+        let newlib = Newlib::new(0x80100000).with_root(root.clone());
+        let mut emu = asm.emulator().with_newlib(newlib);
+        assert_eq!(emu.run(1000), StopReason::PowerOff(3));
+        assert_eq!(emu.exit_code(), Some(3));
+
+        let output: Vec<u8> = std::iter::from_fn(|| emu.harts[0].mmu.uart.try_get_byte()).collect();
+        assert_eq!(output, b"hi\n");
+        assert_eq!(std::fs::read(root.join("f")).unwrap(), b"hi\n");
+        std::fs::remove_dir_all(&root).unwrap();
+        let regs = emu.harts[0].get_registers();
+        assert_eq!(regs[S0 as usize] & S_IFCHR, S_IFCHR);
+        assert_eq!(regs[S1 as usize], 0x80100000);
+        assert_eq!(regs[S2 as usize], 3);
+        assert_eq!(regs[S3 as usize], -EACCES as u32);
+        assert!(regs[S4 as usize] > 0);
+        assert_eq!(regs[S5 as usize], -ENOSYS as u32);
+    }
+
+    #[test]
+    fn test_virtual_time_of_day() {
+        let mut asm = Assembler::new();
+        asm.addi(A0, SP, -16)
+            .li(A7, SYS_GETTIMEOFDAY)
+            .ecall()
+            .lw(S0, -16, SP)
+            .lw(S1, -8, SP);
+        asm.li(A0, 0).li(A7, SYS_EXIT).ecall();
+        let mut emu = asm.emulator().with_newlib(Newlib::new(0x80100000));
+        emu.harts[0].mmu.clint.clock = Clock::virtual_time(10);
+        assert_eq!(emu.run(1000), StopReason::PowerOff(0));
+        let regs = emu.harts[0].get_registers();
+        // Few instructions at 10 ns each are still within first microsecond
+        assert_eq!(regs[S0 as usize], VIRTUAL_EPOCH.as_secs() as u32);
+        assert_eq!(regs[S1 as usize], 0);
+    }
+
+    #[test]
+    fn test_sandboxed_symlinks() {
+        let base = std::env::temp_dir().join(format!("rv-emu-rs-sandbox-{}", std::process::id()));
+        let root = base.join("root");
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::write(base.join("secret"), b"").unwrap();
+        std::os::unix::fs::symlink(&base, root.join("out")).unwrap();
+        std::os::unix::fs::symlink(base.join("missing"), root.join("dangling")).unwrap();
+        std::os::unix::fs::symlink("dir", root.join("inside")).unwrap();
+        let resolve = |path| sandboxed_path(Some(&root), path);
+        let real = root.canonicalize().unwrap();
+        assert_eq!(resolve("/out/secret"), Err(EACCES));
+        assert_eq!(resolve("out"), Err(EACCES));
+        assert_eq!(resolve("out/new"), Err(EACCES));
+        assert_eq!(resolve("dangling"), Err(EACCES));
+        assert_eq!(resolve("inside/new"), Ok(real.join("dir/new")));
+        assert_eq!(resolve("/dir/../new"), Ok(real.join("new")));
+        assert_eq!(resolve(""), Ok(real.clone()));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
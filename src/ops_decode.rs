@@ -28,11 +28,6 @@ fn sign_extend32(data: u32, size: u32) -> u32 {
     debug_assert!(size > 0 && size <= 32);
     (((data << (32 - size)) as i32) >> (32 - size)) as u32
 }
-#[inline]
-fn sign_extend16(data: u16, size: u16) -> u16 {
-    debug_assert!(size > 0 && size <= 16);
-    (((data << (16 - size)) as i16) >> (16 - size)) as u16
-}
 
 #[inline]
 pub fn get_opcode(instruction: u32) -> u8 {
@@ -167,20 +162,20 @@ pub fn get_compressed_ci_stack_load_32_imm(instruction: u16) -> u32 {
 }
 //Don't appliable for C.LDSP, C.LQSP, C.FLDSP, C.SDSP, C.SQSP, C.FSDSP
 pub fn get_compressed_css_stack_write_32_imm(instruction: u16) -> u32 {
-    let off7_6: u32 = shift_and_trim16!(instruction, 6, 2);
-    let off5_2: u32 = shift_and_trim16!(instruction, 8, 4);
+    let off7_6: u32 = shift_and_trim16!(instruction, 7, 2);
+    let off5_2: u32 = shift_and_trim16!(instruction, 9, 4);
     off5_2 << 2 | off7_6 << 6
 }
 pub fn get_compressed_cl_mem_load_32_imm(instruction: u16) -> u32 {
     let off5_3: u32 = shift_and_trim16!(instruction, 10, 3);
-    let off6: u32 = shift_and_trim16!(instruction, 2, 2);
-    let off2: u32 = shift_and_trim16!(instruction, 4, 3);
+    let off6: u32 = shift_and_trim16!(instruction, 5, 1);
+    let off2: u32 = shift_and_trim16!(instruction, 6, 1);
     off6 << 6 | off5_3 << 3 | off2 << 2
 }
 pub fn get_compressed_cs_mem_store_32_imm(instruction: u16) -> u32 {
     let off5_3: u32 = shift_and_trim16!(instruction, 10, 3);
-    let off6: u32 = shift_and_trim16!(instruction, 2, 2);
-    let off2: u32 = shift_and_trim16!(instruction, 4, 3);
+    let off6: u32 = shift_and_trim16!(instruction, 5, 1);
+    let off2: u32 = shift_and_trim16!(instruction, 6, 1);
     off6 << 6 | off5_3 << 3 | off2 << 2
 }
 pub fn get_compressed_cb_shift_imm(instruction: u16) -> u32 {
@@ -256,14 +251,46 @@ pub fn encode_u_type(opcode: u8, rd: u8, imm: i32) -> u32 {
     let imm = imm as u32 & !0x0FFF;
     opcode | rd | imm
 }
+pub fn encode_s_type(opcode: u8, funct3: u8, rs1: u8, rs2: u8, imm: i16) -> u32 {
+    let opcode = opcode as u32;
+    let imm = imm as u32;
+    let imm4_0 = (imm & 0x1F) << 7;
+    let funct3 = (funct3 as u32) << 12;
+    let rs1 = (rs1 as u32) << 15;
+    let rs2 = (rs2 as u32) << 20;
+    let imm11_5 = ((imm >> 5) & 0x7F) << 25;
+    opcode | imm4_0 | funct3 | rs1 | rs2 | imm11_5
+}
+pub fn encode_b_type(opcode: u8, funct3: u8, rs1: u8, rs2: u8, imm: i16) -> u32 {
+    let opcode = opcode as u32;
+    let imm = imm as u32;
+    let imm11 = ((imm >> 11) & 0x1) << 7;
+    let imm4_1 = ((imm >> 1) & 0xF) << 8;
+    let funct3 = (funct3 as u32) << 12;
+    let rs1 = (rs1 as u32) << 15;
+    let rs2 = (rs2 as u32) << 20;
+    let imm10_5 = ((imm >> 5) & 0x3F) << 25;
+    let imm12 = ((imm >> 12) & 0x1) << 31;
+    opcode | imm11 | imm4_1 | funct3 | rs1 | rs2 | imm10_5 | imm12
+}
+pub fn encode_j_type(opcode: u8, rd: u8, imm: i32) -> u32 {
+    let opcode = opcode as u32;
+    let rd = (rd as u32) << 7;
+    let imm = imm as u32;
+    let imm19_12 = ((imm >> 12) & 0xFF) << 12;
+    let imm11 = ((imm >> 11) & 0x1) << 20;
+    let imm10_1 = ((imm >> 1) & 0x3FF) << 21;
+    let imm20 = ((imm >> 20) & 0x1) << 31;
+    opcode | rd | imm19_12 | imm11 | imm10_1 | imm20
+}
 
-struct FormatB {
-    rs1: u8,
-    rs2: u8,
-    imm: u32,
+pub struct FormatB {
+    pub rs1: u8,
+    pub rs2: u8,
+    pub imm: u32,
 }
 
-fn parse_format_b(word: u32) -> FormatB {
+pub fn parse_format_b(word: u32) -> FormatB {
     FormatB {
         rs1: ((word >> 15) & 0x1f) as u8, // [19:15]
         rs2: ((word >> 20) & 0x1f) as u8, // [24:20]
@@ -280,13 +307,13 @@ fn parse_format_b(word: u32) -> FormatB {
     }
 }
 
-struct FormatCSR {
-    csr: u16,
-    rs: u8,
-    rd: u8,
+pub struct FormatCSR {
+    pub csr: u16,
+    pub rs: u8,
+    pub rd: u8,
 }
 
-fn parse_format_csr(word: u32) -> FormatCSR {
+pub fn parse_format_csr(word: u32) -> FormatCSR {
     FormatCSR {
         csr: ((word >> 20) & 0xfff) as u16, // [31:20]
         rs: ((word >> 15) & 0x1f) as u8,    // [19:15], also uimm
@@ -294,13 +321,13 @@ fn parse_format_csr(word: u32) -> FormatCSR {
     }
 }
 
-struct FormatI {
-    rd: u8,
-    rs1: u8,
-    imm: u32,
+pub struct FormatI {
+    pub rd: u8,
+    pub rs1: u8,
+    pub imm: u32,
 }
 
-fn parse_format_i(word: u32) -> FormatI {
+pub fn parse_format_i(word: u32) -> FormatI {
     FormatI {
         rd: ((word >> 7) & 0x1f) as u8,   // [11:7]
         rs1: ((word >> 15) & 0x1f) as u8, // [19:15]
@@ -315,12 +342,12 @@ fn parse_format_i(word: u32) -> FormatI {
     }
 }
 
-struct FormatJ {
-    rd: u8,
-    imm: u32,
+pub struct FormatJ {
+    pub rd: u8,
+    pub imm: u32,
 }
 
-fn parse_format_j(word: u32) -> FormatJ {
+pub fn parse_format_j(word: u32) -> FormatJ {
     FormatJ {
         rd: ((word >> 7) & 0x1f) as u8, // [11:7]
         imm: (
@@ -336,13 +363,13 @@ fn parse_format_j(word: u32) -> FormatJ {
     }
 }
 
-struct FormatR {
-    rd: u8,
-    rs1: u8,
-    rs2: u8,
+pub struct FormatR {
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
 }
 
-fn parse_format_r(word: u32) -> FormatR {
+pub fn parse_format_r(word: u32) -> FormatR {
     FormatR {
         rd: ((word >> 7) & 0x1f) as u8,   // [11:7]
         rs1: ((word >> 15) & 0x1f) as u8, // [19:15]
@@ -351,14 +378,14 @@ fn parse_format_r(word: u32) -> FormatR {
 }
 
 // has rs3
-struct FormatR2 {
-    rd: u8,
-    rs1: u8,
-    rs2: u8,
-    rs3: u8,
+pub struct FormatR2 {
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    pub rs3: u8,
 }
 
-fn parse_format_r2(word: u32) -> FormatR2 {
+pub fn parse_format_r2(word: u32) -> FormatR2 {
     FormatR2 {
         rd: ((word >> 7) & 0x1f) as u8,   // [11:7]
         rs1: ((word >> 15) & 0x1f) as u8, // [19:15]
@@ -367,13 +394,13 @@ fn parse_format_r2(word: u32) -> FormatR2 {
     }
 }
 
-struct FormatS {
-    rs1: u8,
-    rs2: u8,
-    imm: u32,
+pub struct FormatS {
+    pub rs1: u8,
+    pub rs2: u8,
+    pub imm: u32,
 }
 
-fn parse_format_s(word: u32) -> FormatS {
+pub fn parse_format_s(word: u32) -> FormatS {
     FormatS {
         rs1: ((word >> 15) & 0x1f) as u8, // [19:15]
         rs2: ((word >> 20) & 0x1f) as u8, // [24:20]
@@ -389,12 +416,12 @@ fn parse_format_s(word: u32) -> FormatS {
     }
 }
 
-struct FormatU {
-    rd: usize,
-    imm: u64,
+pub struct FormatU {
+    pub rd: usize,
+    pub imm: u64,
 }
 
-fn parse_format_u(word: u32) -> FormatU {
+pub fn parse_format_u(word: u32) -> FormatU {
     FormatU {
         rd: ((word >> 7) & 0x1f) as usize, // [11:7]
         imm: (
@@ -404,19 +431,20 @@ fn parse_format_u(word: u32) -> FormatU {
 			} | // imm[63:32] = [31]
 			((word as u64) & 0xfffff000)
             // imm[31:12] = [31:12]
-        ) as u64,
+        ),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::ops_decode::{
-        get_compressed_cj_jump_imm, get_funct3, get_funct7, get_imm_b_type, get_imm_i_type,
+        get_compressed_cj_jump_imm, get_compressed_cl_mem_load_32_imm, get_compressed_cs_mem_store_32_imm,
+        get_compressed_css_stack_write_32_imm, get_funct3, get_funct7, get_imm_b_type, get_imm_i_type,
         get_imm_s_type, get_opcode, get_rd, get_rs1, get_rs2,
     };
 
     use super::{
-        encode_i_type, get_imm_j_type, parse_format_b, parse_format_i, parse_format_j,
+        encode_b_type, encode_i_type, encode_j_type, encode_s_type, get_imm_j_type, parse_format_b, parse_format_i, parse_format_j,
         parse_format_s,
     };
     #[test]
@@ -504,6 +532,22 @@ mod tests {
         assert_eq!(left, right)
     }
     #[test]
+    #[allow(clippy::unusual_byte_groupings)]
+    fn test_compressed_mem_imm() {
+        // c.swsp: uimm[5:2|7:6] in [12:7]
+        assert_eq!(get_compressed_css_stack_write_32_imm(0b110_111111_00000_10), 252);
+        assert_eq!(get_compressed_css_stack_write_32_imm(0b110_111100_00000_10), 0b111100);
+        assert_eq!(get_compressed_css_stack_write_32_imm(0b110_000011_00000_10), 0b11000000);
+        // c.lw and c.sw: uimm[5:3] in [12:10], uimm[2] in [6], uimm[6] in [5]
+        for (bits, imm) in [(0b111_000_11, 124), (0b101_000_00, 0b101000), (0b000_000_10, 0b100), (0b000_000_01, 0b1000000)] {
+            let load: u16 = 0b010 << 13 | bits << 5;
+            let store: u16 = 0b110 << 13 | bits << 5;
+            assert_eq!(get_compressed_cl_mem_load_32_imm(load), imm, "{load:016b}");
+            assert_eq!(get_compressed_cs_mem_store_32_imm(store), imm, "{store:016b}");
+        }
+    }
+    #[test]
+    #[allow(clippy::unusual_byte_groupings)]
     fn test_cj_imm() {
         let n: u16 = 0b000_10000000000_00;
         let left = get_compressed_cj_jump_imm(n);
@@ -555,4 +599,19 @@ mod tests {
         assert_eq!(get_rs1(n), rs1);
         assert_eq!(get_imm_i_type(n), imm as i32 as u32);
     }
+    #[test]
+    fn test_s_b_j_encoding() {
+        let n = encode_s_type(0b0100011, 0b010, 2, 10, -4);
+        assert_eq!(n, 0xfea12e23); // sw a0, -4(sp)
+        assert_eq!(get_imm_s_type(n), -4i32 as u32);
+        let n = encode_b_type(0b1100011, 0b001, 10, 11, -4096);
+        assert_eq!(n, 0x80b51063); // bne a0, a1, -4096
+        assert_eq!(get_imm_b_type(n), -4096i32 as u32);
+        let n = encode_b_type(0b1100011, 0b111, 5, 6, 4094);
+        assert_eq!(n, 0x7e62ffe3); // bgeu t0, t1, 4094
+        let n = encode_j_type(0b1101111, 1, 1048574);
+        assert_eq!(n, 0x7ffff0ef); // jal ra, 1048574
+        assert_eq!(get_imm_j_type(n), 1048574);
+        assert_eq!(encode_j_type(0b1101111, 0, -8), 0xff9ff06f); // j -8
+    }
 }
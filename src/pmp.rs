@@ -0,0 +1,296 @@
+// Physical memory protection with 16 entries and 4 byte granularity. Entry i is configured by
+// byte i % 4 of pmpcfg(i / 4) and pmpaddr(i), which holds bits 33:2 of address.
+// Lowest matching entry decides, unmatched S and U accesses fail, also when no entry is
+// enabled. Locked entries apply to M mode too and can't be changed until reset. When emulator
+// stands in for M mode firmware, it opens memory to S and U with last entry like OpenSBI does.
+
+use crate::{
+    cpu::PrivilegeMode,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+    traps::{Trap, TrapType},
+};
+
+pub const PMP_ENTRIES: usize = 16;
+
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+pub const PMP_A: u8 = 0b11 << 3;
+pub const PMP_L: u8 = 1 << 7;
+
+// Values of A field
+pub const PMP_OFF: u8 = 0;
+pub const PMP_TOR: u8 = 1 << 3;
+pub const PMP_NA4: u8 = 2 << 3;
+pub const PMP_NAPOT: u8 = 3 << 3;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    fn permission(self) -> u8 {
+        match self {
+            Access::Read => PMP_R,
+            Access::Write => PMP_W,
+            Access::Execute => PMP_X,
+        }
+    }
+    pub fn fault(self, address: u32) -> Trap {
+        let tcause = match self {
+            Access::Read => TrapType::LoadAccessFault,
+            Access::Write => TrapType::StoreAccessFault,
+            Access::Execute => TrapType::InstructionAccessFault,
+        };
+        Trap {
+            tcause,
+            tval: address,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    addr: [u32; PMP_ENTRIES],
+    // Lowest enabled entry gives S and U full access, so they don't need checks
+    open: bool,
+    // Some entry is locked, so M mode is checked too
+    locked: bool,
+}
+
+impl Pmp {
+    pub fn cfg(&self, index: usize) -> u8 {
+        self.cfg[index]
+    }
+    pub fn addr(&self, index: usize) -> u32 {
+        self.addr[index]
+    }
+    // Reserved bits read as zero and W without R is legalized to no access
+    pub fn set_cfg(&mut self, index: usize, value: u8) {
+        if self.cfg[index] & PMP_L != 0 {
+            return;
+        }
+        let mut value = value & (PMP_L | PMP_A | PMP_X | PMP_W | PMP_R);
+        if value & (PMP_R | PMP_W) == PMP_W {
+            value &= !PMP_W;
+        }
+        self.cfg[index] = value;
+        self.update();
+    }
+    // Address is locked by own lock and by lock of next TOR entry, which uses it as start
+    pub fn set_addr(&mut self, index: usize, value: u32) {
+        let next_tor_locked = self
+            .cfg
+            .get(index + 1)
+            .is_some_and(|cfg| cfg & PMP_L != 0 && cfg & PMP_A == PMP_TOR);
+        if self.cfg[index] & PMP_L == 0 && !next_tor_locked {
+            self.addr[index] = value;
+            self.update();
+        }
+    }
+    // Unlocked RWX entry over whole address space in last slot, like firmware sets up
+    pub fn allow_all(&mut self) {
+        self.set_addr(PMP_ENTRIES - 1, u32::MAX);
+        self.set_cfg(PMP_ENTRIES - 1, PMP_NAPOT | PMP_R | PMP_W | PMP_X);
+    }
+    fn update(&mut self) {
+        let lowest = (0..PMP_ENTRIES).find(|&index| self.cfg[index] & PMP_A != PMP_OFF);
+        self.open = lowest.is_some_and(|index| {
+            self.cfg[index] == PMP_NAPOT | PMP_R | PMP_W | PMP_X && self.addr[index] == u32::MAX
+        });
+        self.locked = self.cfg.iter().any(|cfg| cfg & PMP_L != 0 && cfg & PMP_A != PMP_OFF);
+    }
+    // Accesses from this privilege have to be checked
+    #[inline(always)]
+    pub fn applies(&self, privilege: PrivilegeMode) -> bool {
+        self.locked || !self.open && privilege != PrivilegeMode::Machine
+    }
+    // Byte range [start, end) of entry, None if entry is off
+    fn range(&self, index: usize) -> Option<(u64, u64)> {
+        let addr = self.addr[index] as u64;
+        match self.cfg[index] & PMP_A {
+            PMP_TOR => {
+                let start = if index == 0 { 0 } else { self.addr[index - 1] as u64 };
+                Some((start << 2, addr << 2))
+            }
+            PMP_NA4 => Some((addr << 2, (addr + 1) << 2)),
+            PMP_NAPOT => {
+                // Trailing ones encode size, mask covers them and following zero
+                let mask = addr ^ (addr + 1);
+                Some(((addr & !mask) << 2, ((addr & !mask) + mask + 1) << 2))
+            }
+            _ => None,
+        }
+    }
+    pub fn allows(&self, address: u32, size: u32, access: Access, privilege: PrivilegeMode) -> bool {
+        let start = address as u64;
+        let end = start + size as u64;
+        for index in 0..PMP_ENTRIES {
+            let Some((low, high)) = self.range(index) else {
+                continue;
+            };
+            if end <= low || start >= high {
+                continue;
+            }
+            // Access that matches only some bytes of entry fails
+            if start < low || end > high {
+                return false;
+            }
+            let cfg = self.cfg[index];
+            if privilege == PrivilegeMode::Machine && cfg & PMP_L == 0 {
+                return true;
+            }
+            return cfg & access.permission() != 0;
+        }
+        privilege == PrivilegeMode::Machine
+    }
+    pub fn save(&self, w: &mut SnapshotWriter) {
+        for (cfg, addr) in self.cfg.iter().zip(self.addr) {
+            w.u8(*cfg);
+            w.u32(addr);
+        }
+    }
+    pub fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        for index in 0..PMP_ENTRIES {
+            self.cfg[index] = r.u8()?;
+            self.addr[index] = r.u32()?;
+        }
+        self.update();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        cpu::{PrivilegeMode, CPU, EXECUTION_MODES},
+        errors::EmulatorError,
+    };
+
+    use super::{Access, Pmp, PMP_L, PMP_NA4, PMP_NAPOT, PMP_R, PMP_TOR, PMP_W, PMP_X};
+
+    #[test]
+    fn test_matching() {
+        let mut pmp = Pmp::default();
+        // Everything below 0x80000000, 4KB at 0x80100000 and 4 bytes at 0x80200000
+        pmp.set_addr(0, 0x20000000);
+        pmp.set_cfg(0, PMP_TOR | PMP_X);
+        pmp.set_addr(1, 0x200401FF);
+        pmp.set_cfg(1, PMP_NAPOT | PMP_R);
+        pmp.set_addr(2, 0x20080000);
+        pmp.set_cfg(2, PMP_NA4 | PMP_R | PMP_W);
+        let user = PrivilegeMode::User;
+        assert!(pmp.allows(0x1000, 4, Access::Execute, user));
+        assert!(!pmp.allows(0x7FFFFFFE, 4, Access::Execute, user));
+        assert!(pmp.allows(0x80100000, 4, Access::Read, user));
+        assert!(pmp.allows(0x80100FFC, 4, Access::Read, user));
+        assert!(!pmp.allows(0x80100FFE, 4, Access::Read, user));
+        assert!(!pmp.allows(0x80100000, 1, Access::Write, user));
+        assert!(pmp.allows(0x80200000, 4, Access::Write, user));
+        assert!(!pmp.allows(0x80200004, 1, Access::Read, user));
+        assert!(!pmp.allows(0x90000000, 4, Access::Read, user));
+        // M mode isn't checked by unlocked entries
+        assert!(!pmp.applies(PrivilegeMode::Machine));
+        assert!(pmp.allows(0x80100000, 4, Access::Write, PrivilegeMode::Machine));
+        // W without R is reserved
+        pmp.set_cfg(4, PMP_NAPOT | PMP_W);
+        assert_eq!(pmp.cfg(4), PMP_NAPOT);
+        // Lock of TOR entry protects address of previous entry too
+        pmp.set_addr(3, 0x200C0000);
+        pmp.set_cfg(3, PMP_TOR | PMP_L);
+        pmp.set_addr(2, 0);
+        pmp.set_cfg(3, 0);
+        assert_eq!(pmp.addr(2), 0x20080000);
+        assert_eq!(pmp.cfg(3), PMP_TOR | PMP_L);
+        assert!(pmp.applies(PrivilegeMode::Machine));
+        assert!(!pmp.allows(0x80200100, 4, Access::Read, PrivilegeMode::Machine));
+        assert!(pmp.allows(0x80400000, 4, Access::Read, PrivilegeMode::Machine));
+    }
+
+    #[test]
+    fn test_unconfigured() {
+        let mut pmp = Pmp::default();
+        // Without enabled entries S and U can't access anything
+        assert!(pmp.applies(PrivilegeMode::Supervisor));
+        assert!(!pmp.allows(0x80000000, 4, Access::Execute, PrivilegeMode::User));
+        assert!(!pmp.applies(PrivilegeMode::Machine));
+        pmp.allow_all();
+        assert!(!pmp.applies(PrivilegeMode::User));
+        assert!(pmp.allows(0xFFFFFFFC, 4, Access::Write, PrivilegeMode::User));
+        // Lower entry has to be checked again
+        pmp.set_cfg(0, PMP_NA4 | PMP_R);
+        assert!(pmp.applies(PrivilegeMode::User));
+        assert!(!pmp.allows(0, 4, Access::Write, PrivilegeMode::User));
+    }
+
+    // Handler counts faults in S11 and sets bit of each cause in S9. Instruction fault
+    // returns to ra, other faults skip instruction. Ecall from U mode ends program.
+    fn run(body: impl Fn(&mut Assembler)) -> Vec<CPU> {
+        let mut asm = Assembler::new();
+        asm.la(T6, "handler").csrw(0x305, T6);
+        body(&mut asm);
+        asm.label("stop").csrw(0x305, ZERO).ecall();
+        asm.label("handler").csrr(T6, 0x342).addi(T5, T6, -8).beq(T5, ZERO, "stop");
+        asm.li(T5, 1).sll(T5, T5, T6).or(S9, S9, T5);
+        asm.addi(T5, T6, -1).bne(T5, ZERO, "skip").addi(S11, S11, 1).csrw(0x341, RA).mret();
+        asm.skip_faults_handler("skip");
+        EXECUTION_MODES
+            .iter()
+            .map(|&mode| {
+                let mut cpu = asm.cpu();
+                cpu.execution_mode = mode;
+                match cpu.run(10000, &mut 0) {
+                    Err(EmulatorError::UnsetTrapHandler) => cpu,
+                    r => panic!("Program didn't finish in {mode:?}: {r:?}"),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_user_mode() {
+        let cpus = run(|asm| {
+            // Data page is read-only, code is RWX, rest of memory isn't accessible
+            asm.li(T0, 0x200401FF).csrw(0x3B0, T0).li(T0, 0x20001FFF).csrw(0x3B1, T0);
+            asm.li(T0, ((PMP_NAPOT | PMP_R | PMP_W | PMP_X) as u32) << 8 | (PMP_NAPOT | PMP_R) as u32);
+            asm.csrw(0x3A0, T0).csrr(A2, 0x3A0);
+            asm.li(T0, 0b11 << 11).csrrc(ZERO, 0x300, T0).la(T0, "user").csrw(0x341, T0).mret();
+            asm.label("user").li(T1, 0x80100000).lw(A0, 0, T1).sw(A0, 0, T1);
+            asm.li(T2, 0x80200000).lw(A1, 0, T2).jalr(RA, T1, 0).li(A3, 1).ecall();
+        });
+        for cpu in cpus {
+            let regs = cpu.get_registers();
+            assert_eq!(regs[S11 as usize], 3, "{:?}", cpu.execution_mode);
+            assert_eq!(regs[S9 as usize], 1 << 1 | 1 << 5 | 1 << 7);
+            assert_eq!(regs[A2 as usize], 0x1F19);
+            assert_eq!(regs[A3 as usize], 1);
+        }
+    }
+
+    #[test]
+    fn test_locked_entry() {
+        let cpus = run(|asm| {
+            // Read-only page that machine mode can't unlock
+            asm.li(T0, 0x200401FF).csrw(0x3B0, T0).li(T0, (PMP_L | PMP_NAPOT | PMP_R) as u32);
+            asm.csrw(0x3A0, T0).csrw(0x3A0, ZERO).csrw(0x3B0, ZERO).csrr(A3, 0x3B0);
+            // User code needs its own entry
+            asm.li(T0, 0x20001FFF).csrw(0x3B1, T0).li(T0, ((PMP_NAPOT | PMP_R | PMP_X) as u32) << 8);
+            asm.csrrs(ZERO, 0x3A0, T0).csrr(A2, 0x3A0);
+            asm.li(T1, 0x80100000).lw(A0, 0, T1).sw(A0, 0, T1).li(T2, 0x80200000).sw(A0, 0, T2);
+            asm.li(T0, 0b11 << 11).csrrc(ZERO, 0x300, T0).la(T0, "user").csrw(0x341, T0).mret();
+            asm.label("user").lw(A0, 0, T1).lw(A1, 0, T2).ecall();
+        });
+        for cpu in cpus {
+            let regs = cpu.get_registers();
+            assert_eq!(regs[S11 as usize], 2, "{:?}", cpu.execution_mode);
+            assert_eq!(regs[S9 as usize], 1 << 5 | 1 << 7);
+            assert_eq!(regs[A2 as usize], 0x1D99);
+            assert_eq!(regs[A3 as usize], 0x200401FF);
+        }
+    }
+}
@@ -1,26 +1,65 @@
-use std::sync::{atomic::AtomicU32, Arc};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
 use rb::{RbConsumer, RbInspector, RbProducer, RB};
 use rodio::Source;
 
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+// Format of samples guest writes, channels are interleaved
+pub const AUDIO_CHANNELS: u16 = 2;
+pub const AUDIO_SAMPLE_RATE: u32 = 44100;
+
+// Where samples written by guest go. Guest reads fill level to pace itself, sinks that don't
+// play in real time are always empty.
+pub trait AudioSink {
+    fn write(&mut self, sample: i16);
+    fn get_size(&self) -> u32 {
+        0
+    }
+    // Called by host when guest is done, buffered samples have to be written out
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    fn save(&self, w: &mut SnapshotWriter) {
+        w.u32(self.get_size());
+    }
+    fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        r.u32()?;
+        Ok(())
+    }
+}
+
+// Plays through rodio, paired with PrimitiveAudioProducer
 pub struct PrimitiveAudioReciever {
     internal_buffer: rb::Producer<i16>,
     buffer: rb::SpscRb<i16>,
 }
-impl PrimitiveAudioReciever {
-    pub fn write(&mut self, val: i16) {
+impl AudioSink for PrimitiveAudioReciever {
+    fn write(&mut self, val: i16) {
         self.internal_buffer.write(&[val]).unwrap();
     }
-    pub fn get_size(&self) -> u32 {
+    fn get_size(&self) -> u32 {
         self.buffer.count() as u32
     }
+    // Queued samples belong to host playback and can't be read back, only fill level
+    // visible to guest is kept. Restored queue is silence of the same length.
+    fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let size = r.u32()? as usize;
+        self.buffer.clear();
+        self.internal_buffer
+            .write(&vec![0; size])
+            .map_err(|_| SnapshotError::Corrupt)?;
+        Ok(())
+    }
 }
 pub struct PrimitiveAudioProducer {
     internal_buffer: rb::Consumer<i16>,
 
-}
-impl PrimitiveAudioProducer {
-    
 }
 
 impl Source for PrimitiveAudioProducer {
@@ -29,11 +68,11 @@ impl Source for PrimitiveAudioProducer {
     }
 
     fn channels(&self) -> u16 {
-        2
+        AUDIO_CHANNELS
     }
 
     fn sample_rate(&self) -> u32 {
-        44100
+        AUDIO_SAMPLE_RATE
     }
 
     fn total_duration(&self) -> Option<std::time::Duration> {
@@ -57,4 +96,156 @@ pub fn create_primitive_audio_pair() -> (PrimitiveAudioReciever, PrimitiveAudioP
     let rb = rb::SpscRb::new(44100 * 8);
     let (cons, prod) = (rb.consumer(), rb.producer());
     (PrimitiveAudioReciever { internal_buffer: prod, buffer: rb }, PrimitiveAudioProducer { internal_buffer: cons } )
-}
\ No newline at end of file
+}
+
+// Discards everything, for machines without sound device
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn write(&mut self, _sample: i16) {}
+}
+
+// Keeps samples for host to check, clones share them
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    pub samples: Arc<Mutex<Vec<i16>>>,
+}
+
+impl AudioSink for MemorySink {
+    fn write(&mut self, sample: i16) {
+        self.samples.lock().unwrap().push(sample);
+    }
+}
+
+// 16-bit PCM WAV file, sizes in header are filled on flush
+pub struct WavSink<W: Write + Seek> {
+    writer: W,
+    data_len: u32,
+    // Write errors are reported by flush
+    error: Option<io::Error>,
+}
+
+const WAV_HEADER_SIZE: u32 = 44;
+
+impl WavSink<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        WavSink::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write + Seek> WavSink<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&wav_header(0))?;
+        Ok(WavSink { writer, data_len: 0, error: None })
+    }
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavSink<W> {
+    fn write(&mut self, sample: i16) {
+        // Size fields are 32 bits, rest of audio doesn't fit
+        if self.error.is_some() || self.data_len > u32::MAX - WAV_HEADER_SIZE - 2 {
+            return;
+        }
+        match self.writer.write_all(&sample.to_le_bytes()) {
+            Ok(()) => self.data_len += 2,
+            Err(e) => self.error = Some(e),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&wav_header(self.data_len))?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+fn wav_header(data_len: u32) -> Vec<u8> {
+    let block_align = AUDIO_CHANNELS * 2;
+    let mut header = Vec::with_capacity(WAV_HEADER_SIZE as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(WAV_HEADER_SIZE - 8 + data_len).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&AUDIO_CHANNELS.to_le_bytes());
+    header.extend_from_slice(&AUDIO_SAMPLE_RATE.to_le_bytes());
+    header.extend_from_slice(&(AUDIO_SAMPLE_RATE * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        assembler::*,
+        cpu::EXECUTION_MODES,
+        mmu::PRIMITIVE_AUDIO_ADDRESS,
+        snapshot,
+        emulator::Emulator,
+    };
+
+    use super::{AudioSink, MemorySink, WavSink, WAV_HEADER_SIZE};
+
+    // Writes triangle wave and reads fill level into a2
+    fn program() -> Assembler {
+        let mut asm = Assembler::new();
+        asm.li(A0, PRIMITIVE_AUDIO_ADDRESS).li(A1, -300i32 as u32).li(T0, 300);
+        asm.label("loop").sh(A1, 0, A0).addi(A1, A1, 100).blt(A1, T0, "loop");
+        asm.lw(A2, 0, A0).ecall();
+        asm
+    }
+
+    #[test]
+    fn test_memory_sink() {
+        for &mode in EXECUTION_MODES {
+            let sink = MemorySink::default();
+            let mut cpu = program().cpu();
+            cpu.mmu.audio = Box::new(sink.clone());
+            cpu.execution_mode = mode;
+            let _ = cpu.run(1000, &mut 0);
+            assert_eq!(*sink.samples.lock().unwrap(), [-300, -200, -100, 0, 100, 200], "{mode:?}");
+            assert_eq!(cpu.get_registers()[A2 as usize], 0, "{mode:?}");
+
+            // Sink stays with machine when snapshot is restored
+            let mut emu = Emulator::new(cpu);
+            let saved = snapshot::save(&emu);
+            snapshot::restore(&mut emu, &saved).unwrap();
+            emu.harts[0].mmu.audio.write(7);
+            assert_eq!(sink.samples.lock().unwrap().last(), Some(&7));
+        }
+    }
+
+    #[test]
+    fn test_wav_sink() {
+        let mut sink = WavSink::new(Cursor::new(vec![])).unwrap();
+        for sample in [1, -1, 0x1234, i16::MIN] {
+            sink.write(sample);
+        }
+        let wav = sink.into_inner().unwrap().into_inner();
+        assert_eq!(wav.len(), WAV_HEADER_SIZE as usize + 8);
+        let word = |at: usize| u32::from_le_bytes(wav[at..at + 4].try_into().unwrap());
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(word(4), 36 + 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        // PCM, 2 channels
+        assert_eq!(word(20), 0x2_0001);
+        assert_eq!(word(24), 44100);
+        assert_eq!(word(28), 44100 * 4);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(word(40), 8);
+        assert_eq!(&wav[44..], [1, 0, 0xff, 0xff, 0x34, 0x12, 0x00, 0x80]);
+    }
+}
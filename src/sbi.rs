@@ -0,0 +1,384 @@
+// Built-in SBI firmware. When enabled, ecalls from S mode are serviced here instead of by M mode
+// code, so kernels can run without OpenSBI. Arguments come in a0-a5, extension id in a7 and
+// function id in a6, result is error in a0 and value in a1. Legacy extensions return only a0.
+// State shared by harts lives on bus, requests to other harts are applied when they are scheduled.
+
+use crate::{
+    cpu::{PrivilegeMode, CPU, MSTATUS_SIE},
+    csr::{DELEGABLE_EXCEPTIONS, SUPERVISOR_INTERRUPTS},
+    emulator::Emulator,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+};
+
+pub const SBI_SUCCESS: i32 = 0;
+pub const SBI_ERR_NOT_SUPPORTED: i32 = -2;
+pub const SBI_ERR_INVALID_PARAM: i32 = -3;
+pub const SBI_ERR_ALREADY_AVAILABLE: i32 = -6;
+
+pub const EXT_LEGACY_SET_TIMER: u32 = 0x00;
+pub const EXT_LEGACY_CONSOLE_PUTCHAR: u32 = 0x01;
+pub const EXT_LEGACY_CONSOLE_GETCHAR: u32 = 0x02;
+pub const EXT_LEGACY_SHUTDOWN: u32 = 0x08;
+pub const EXT_BASE: u32 = 0x10;
+pub const EXT_TIME: u32 = 0x54494D45;
+pub const EXT_IPI: u32 = 0x735049;
+pub const EXT_RFENCE: u32 = 0x52464E43;
+pub const EXT_HSM: u32 = 0x48534D;
+pub const EXT_SRST: u32 = 0x53525354;
+
+const EXTENSIONS: [u32; 10] = [
+    EXT_LEGACY_SET_TIMER,
+    EXT_LEGACY_CONSOLE_PUTCHAR,
+    EXT_LEGACY_CONSOLE_GETCHAR,
+    EXT_LEGACY_SHUTDOWN,
+    EXT_BASE,
+    EXT_TIME,
+    EXT_IPI,
+    EXT_RFENCE,
+    EXT_HSM,
+    EXT_SRST,
+];
+
+// Version 2.0
+pub const SBI_SPEC_VERSION: u32 = 2 << 24;
+// Not in list of registered implementations
+pub const SBI_IMPL_ID: u32 = 0x7276;
+pub const SBI_IMPL_VERSION: u32 = 1;
+
+pub const MIP_SSIP: u32 = 1 << 1;
+
+const A0: usize = 10;
+const A1: usize = 11;
+const A6: usize = 16;
+const A7: usize = 17;
+
+// Values are HSM hart status codes
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HartState {
+    Started,
+    Stopped,
+    // hart_start was called, hart starts when it is scheduled
+    StartPending { address: u32, opaque: u32 },
+}
+
+impl HartState {
+    fn status(self) -> u32 {
+        match self {
+            HartState::Started => 0,
+            HartState::Stopped => 1,
+            HartState::StartPending { .. } => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Sbi {
+    // Indexed by hartid
+    pub harts: Vec<HartState>,
+    // Software interrupts sent with send_ipi and not yet delivered
+    pub ipi: Vec<bool>,
+    // Set by system reset shutdown
+    pub exit_code: Option<u32>,
+}
+
+impl Sbi {
+    // Only hart 0 is running
+    pub fn new(harts: usize) -> Self {
+        let mut states = vec![HartState::Stopped; harts];
+        states[0] = HartState::Started;
+        Sbi {
+            harts: states,
+            ipi: vec![false; harts],
+            exit_code: None,
+        }
+    }
+    // Harts selected by hart_mask and hart_mask_base, base of -1 means all harts
+    fn selected(&self, mask: u32, base: u32) -> Result<Vec<usize>, i32> {
+        if base == u32::MAX {
+            return Ok((0..self.harts.len()).collect());
+        }
+        let harts: Vec<_> = (0..32)
+            .filter(|bit| mask & (1 << bit) != 0)
+            .map(|bit| base as usize + bit)
+            .collect();
+        match harts.iter().all(|&hart| hart < self.harts.len()) {
+            true => Ok(harts),
+            false => Err(SBI_ERR_INVALID_PARAM),
+        }
+    }
+    pub fn save(&self, w: &mut SnapshotWriter) {
+        w.u32(self.harts.len() as u32);
+        for (state, ipi) in self.harts.iter().zip(&self.ipi) {
+            let (address, opaque) = match *state {
+                HartState::StartPending { address, opaque } => (address, opaque),
+                _ => (0, 0),
+            };
+            w.u8(state.status() as u8);
+            w.u32(address);
+            w.u32(opaque);
+            w.bool(*ipi);
+        }
+        w.option_u64(self.exit_code.map(|c| c as u64));
+    }
+    pub fn restore(r: &mut SnapshotReader) -> Result<Self, SnapshotError> {
+        let count = r.u32()? as usize;
+        let mut sbi = Sbi::new(count.max(1));
+        sbi.harts.clear();
+        sbi.ipi.clear();
+        for _ in 0..count {
+            let status = r.u8()?;
+            let (address, opaque) = (r.u32()?, r.u32()?);
+            sbi.harts.push(match status {
+                0 => HartState::Started,
+                1 => HartState::Stopped,
+                2 => HartState::StartPending { address, opaque },
+                _ => return Err(SnapshotError::Corrupt),
+            });
+            sbi.ipi.push(r.bool()?);
+        }
+        sbi.exit_code = r.option_u64()?.map(|c| c as u32);
+        Ok(sbi)
+    }
+}
+
+// Delivers software interrupt and starts hart if other harts asked for it. Called when hart
+// gets bus and after its own ecalls.
+pub fn update(cpu: &mut CPU) {
+    let hart = cpu.hartid as usize;
+    let Some(sbi) = &mut cpu.mmu.sbi else {
+        return;
+    };
+    if std::mem::take(&mut sbi.ipi[hart]) {
+        cpu.mip |= MIP_SSIP;
+    }
+    if let HartState::StartPending { address, opaque } = sbi.harts[hart] {
+        sbi.harts[hart] = HartState::Started;
+        let mut regs = cpu.get_registers();
+        regs[A0] = cpu.hartid;
+        regs[A1] = opaque;
+        cpu.set_registers(regs);
+        cpu.pc = address;
+        cpu.privilege = PrivilegeMode::Supervisor;
+        cpu.mstatus &= !MSTATUS_SIE;
+        cpu.satp = 0;
+        cpu.stopped = false;
+        cpu.wfi = false;
+    }
+}
+
+// Services ecall from S mode, pc still points to ecall
+pub fn handle_ecall(cpu: &mut CPU) {
+    let mut regs = cpu.get_registers();
+    let (extension, function) = (regs[A7], regs[A6]);
+    let args: [u32; 6] = regs[A0..A0 + 6].try_into().unwrap();
+    if extension < EXT_BASE {
+        regs[A0] = legacy(cpu, extension, args);
+    } else {
+        let (error, value) = match call(cpu, extension, function, args) {
+            Ok(value) => (SBI_SUCCESS, value),
+            Err(error) => (error, 0),
+        };
+        regs[A0] = error as u32;
+        regs[A1] = value;
+    }
+    cpu.set_registers(regs);
+    cpu.pc = cpu.pc.wrapping_add(4);
+    update(cpu);
+}
+
+fn legacy(cpu: &mut CPU, extension: u32, args: [u32; 6]) -> u32 {
+    match extension {
+        EXT_LEGACY_SET_TIMER => {
+            set_timer(cpu, (args[1] as u64) << 32 | args[0] as u64);
+            0
+        }
+        EXT_LEGACY_CONSOLE_PUTCHAR => {
+            cpu.mmu.uart.emu_push(args[0] as u8);
+            0
+        }
+        // -1 when there is no input
+        EXT_LEGACY_CONSOLE_GETCHAR => cpu.mmu.uart.emu_try_get_byte().map_or(u32::MAX, |b| b as u32),
+        EXT_LEGACY_SHUTDOWN => {
+            shutdown(cpu, 0);
+            0
+        }
+        _ => SBI_ERR_NOT_SUPPORTED as u32,
+    }
+}
+
+fn call(cpu: &mut CPU, extension: u32, function: u32, args: [u32; 6]) -> Result<u32, i32> {
+    let sbi = cpu.mmu.sbi.as_mut().unwrap();
+    match (extension, function) {
+        (EXT_BASE, 0) => Ok(SBI_SPEC_VERSION),
+        (EXT_BASE, 1) => Ok(SBI_IMPL_ID),
+        (EXT_BASE, 2) => Ok(SBI_IMPL_VERSION),
+        (EXT_BASE, 3) => Ok(EXTENSIONS.contains(&args[0]) as u32),
+        (EXT_BASE, 4) => Ok(cpu.read_csr(0xF11).unwrap_or(0)),
+        (EXT_BASE, 5) => Ok(cpu.read_csr(0xF12).unwrap_or(0)),
+        (EXT_BASE, 6) => Ok(cpu.read_csr(0xF13).unwrap_or(0)),
+        (EXT_TIME, 0) => {
+            set_timer(cpu, (args[1] as u64) << 32 | args[0] as u64);
+            Ok(0)
+        }
+        (EXT_IPI, 0) => {
+            for hart in sbi.selected(args[0], args[1])? {
+                sbi.ipi[hart] = true;
+            }
+            Ok(0)
+        }
+        (EXT_RFENCE, 0..=2) => {
+            sbi.selected(args[0], args[1])?;
+            // Decode cache is shared by harts. Without TLB sfence.vma has nothing to flush.
+            if function == 0 {
+                cpu.decode_cache.clear();
+            }
+            Ok(0)
+        }
+        (EXT_HSM, 0) => {
+            let hart = sbi.harts.get_mut(args[0] as usize).ok_or(SBI_ERR_INVALID_PARAM)?;
+            if *hart != HartState::Stopped {
+                return Err(SBI_ERR_ALREADY_AVAILABLE);
+            }
+            *hart = HartState::StartPending {
+                address: args[1],
+                opaque: args[2],
+            };
+            Ok(0)
+        }
+        (EXT_HSM, 1) => {
+            sbi.harts[cpu.hartid as usize] = HartState::Stopped;
+            cpu.stopped = true;
+            Ok(0)
+        }
+        (EXT_HSM, 2) => {
+            let hart = sbi.harts.get(args[0] as usize).ok_or(SBI_ERR_INVALID_PARAM)?;
+            Ok(hart.status())
+        }
+        // Default retentive suspend behaves like WFI
+        (EXT_HSM, 3) if args[0] == 0 => {
+            cpu.wfi = true;
+            Ok(0)
+        }
+        // Only shutdown, reason is exit code
+        (EXT_SRST, 0) if args[0] == 0 => {
+            shutdown(cpu, args[1]);
+            Ok(0)
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+// Timer interrupt is cleared until mtimecmp is reached, see clint::update
+fn set_timer(cpu: &mut CPU, value: u64) {
+    cpu.mmu.clint.mtimecmp[cpu.hartid as usize] = value;
+    cpu.next_timer_check = 0;
+}
+
+fn shutdown(cpu: &mut CPU, code: u32) {
+    cpu.mmu.sbi.as_mut().unwrap().exit_code = Some(code);
+    cpu.stopped = true;
+}
+
+impl Emulator {
+    // Replaces M mode firmware. Harts start in S mode at their pc, but only hart 0 runs until
+    // others are started through HSM. Traps that firmware would forward are delegated to S mode.
+    // Call after hart count is set.
+    pub fn with_sbi(mut self) -> Self {
+        self.harts[0].mmu.sbi = Some(Sbi::new(self.harts.len()));
+        for hart in &mut self.harts {
+            hart.privilege = PrivilegeMode::Supervisor;
+            // Ecalls from S mode come to firmware
+            hart.medeleg = DELEGABLE_EXCEPTIONS & !(1 << 9);
+            hart.mideleg = SUPERVISOR_INTERRUPTS;
+            hart.counters.mcounteren = u32::MAX;
+            hart.pmp.allow_all();
+            hart.stopped = hart.hartid != 0;
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        clock::Clock,
+        cpu::EXECUTION_MODES,
+        emulator::{Emulator, StopReason},
+    };
+
+    use super::{EXT_BASE, EXT_HSM, EXT_IPI, EXT_LEGACY_CONSOLE_PUTCHAR, EXT_SRST, EXT_TIME};
+
+    fn sbi_call(asm: &mut Assembler, extension: u32, function: u32) -> &mut Assembler {
+        asm.li(A7, extension).li(A6, function).ecall()
+    }
+
+    fn emulator(asm: &Assembler, harts: usize) -> Emulator {
+        let mut cpu = asm.cpu();
+        cpu.mmu.clint.clock = Clock::virtual_time(10);
+        Emulator::new(cpu).with_harts(harts).with_sbi()
+    }
+
+    #[test]
+    fn test_base_console_and_timer() {
+        let mut asm = Assembler::new();
+        sbi_call(&mut asm, EXT_BASE, 0).mv(S0, A1);
+        asm.li(A0, EXT_TIME);
+        sbi_call(&mut asm, EXT_BASE, 3).mv(S1, A1);
+        for c in "hi".bytes() {
+            asm.li(A0, c as u32);
+            sbi_call(&mut asm, EXT_LEGACY_CONSOLE_PUTCHAR, 0);
+        }
+        // Timer interrupt 100 ticks later ends program
+        asm.la(T0, "handler").csrw(0x105, T0).li(T0, 1 << 5).csrw(0x104, T0).csrrsi(ZERO, 0x100, 2);
+        asm.csrr(A0, 0xC01).addi(A0, A0, 100).li(A1, 0);
+        sbi_call(&mut asm, EXT_TIME, 0);
+        asm.label("wait").wfi().j("wait");
+        asm.label("handler").csrr(S2, 0x142).li(A0, 0).li(A1, 7);
+        sbi_call(&mut asm, EXT_SRST, 0);
+        for &mode in EXECUTION_MODES {
+            let mut emu = emulator(&asm, 1);
+            emu.harts[0].execution_mode = mode;
+            assert_eq!(emu.run(100_000), StopReason::PowerOff(7), "{mode:?}");
+            let regs = emu.harts[0].get_registers();
+            assert_eq!(regs[S0 as usize], 2 << 24);
+            assert_eq!(regs[S1 as usize], 1);
+            assert_eq!(regs[S2 as usize], 0x80000005);
+            let uart: Vec<_> = std::iter::from_fn(|| emu.harts[0].mmu.uart.try_get_byte()).collect();
+            assert_eq!(uart, b"hi");
+            assert!(emu.executed < 100);
+        }
+    }
+
+    #[test]
+    fn test_hsm_and_ipi() {
+        // Hart 0 starts hart 1, which reports its arguments, wakes hart 0 and stops
+        let mut asm = Assembler::new();
+        asm.la(T0, "handler").csrw(0x105, T0).li(T0, 1 << 1).csrw(0x104, T0).csrrsi(ZERO, 0x100, 2);
+        asm.li(A0, 1).la(A1, "second").li(A2, 0x1234);
+        sbi_call(&mut asm, EXT_HSM, 0).mv(S0, A0);
+        asm.li(A0, 1).la(A1, "second");
+        sbi_call(&mut asm, EXT_HSM, 0).mv(S1, A0);
+        asm.label("wait").wfi().j("wait");
+        asm.label("handler").csrrci(ZERO, 0x144, 2);
+        asm.label("poll").li(A0, 1);
+        sbi_call(&mut asm, EXT_HSM, 2).li(T0, 1).bne(A1, T0, "poll");
+        asm.li(A0, 0).li(A1, 0);
+        sbi_call(&mut asm, EXT_SRST, 0);
+        asm.label("second").li(T0, 0x80100000).sw(A0, 0, T0).sw(A1, 4, T0);
+        asm.li(A0, 1).li(A1, 0);
+        sbi_call(&mut asm, EXT_IPI, 0);
+        sbi_call(&mut asm, EXT_HSM, 1);
+        for &mode in EXECUTION_MODES {
+            let mut emu = emulator(&asm, 2);
+            emu.harts.iter_mut().for_each(|hart| hart.execution_mode = mode);
+            assert_eq!(emu.run(100_000), StopReason::PowerOff(0), "{mode:?}");
+            let regs = emu.harts[0].get_registers();
+            assert_eq!(regs[S0 as usize], 0);
+            assert_eq!(regs[S1 as usize], -6i32 as u32);
+            assert_eq!(emu.harts[0].mmu.read_word(0x80100000), Ok(1));
+            assert_eq!(emu.harts[0].mmu.read_word(0x80100004), Ok(0x1234));
+            assert!(emu.harts[1].stopped);
+        }
+    }
+}
@@ -0,0 +1,359 @@
+// RISC-V semihosting: ebreak between `slli x0, x0, 0x1f` and `srai x0, x0, 7` asks host for
+// ARM-compatible operation in a0 with argument or parameter block address in a1, result is
+// returned in a0. Console handles come from opening ":tt". Open files aren't saved in snapshots.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    time::Instant,
+};
+
+use crate::{
+    cpu::CPU,
+    emulator::Emulator,
+    linux_user::{errno, in_ram, read_guest, read_string, write_guest, EBADF, EFAULT, EINVAL, ENOSYS, ESPIPE},
+    manual_debugger::read_instruction,
+    mmu::MMU,
+    newlib::{sandboxed_path, Console},
+};
+
+pub const SEMIHOSTING_ENTRY: u32 = 0x01f01013;
+pub const SEMIHOSTING_EXIT: u32 = 0x40705013;
+
+pub const SYS_OPEN: u32 = 0x01;
+pub const SYS_CLOSE: u32 = 0x02;
+pub const SYS_WRITEC: u32 = 0x03;
+pub const SYS_WRITE0: u32 = 0x04;
+pub const SYS_WRITE: u32 = 0x05;
+pub const SYS_READ: u32 = 0x06;
+pub const SYS_ISTTY: u32 = 0x09;
+pub const SYS_SEEK: u32 = 0x0A;
+pub const SYS_FLEN: u32 = 0x0C;
+pub const SYS_CLOCK: u32 = 0x10;
+pub const SYS_ERRNO: u32 = 0x13;
+pub const SYS_GET_CMDLINE: u32 = 0x15;
+pub const SYS_EXIT: u32 = 0x18;
+pub const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+// Reason of SYS_EXIT for normal exit, others are failures
+pub const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+const A0: usize = 10;
+const A1: usize = 11;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+pub struct Semihosting {
+    console: Console,
+    // Directory files are opened in, without it only console can be opened
+    root: Option<PathBuf>,
+    cmdline: String,
+    handles: Vec<Option<Handle>>,
+    // Error of last failed operation, for SYS_ERRNO
+    errno: i32,
+    start: Instant,
+    pub exit_code: Option<u32>,
+}
+
+impl Default for Semihosting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Semihosting {
+    pub fn new() -> Self {
+        Semihosting {
+            console: Console::Uart,
+            root: None,
+            cmdline: String::new(),
+            handles: vec![],
+            errno: 0,
+            start: Instant::now(),
+            exit_code: None,
+        }
+    }
+    pub fn with_console(mut self, console: Console) -> Self {
+        self.console = console;
+        self
+    }
+    pub fn with_root(mut self, root: PathBuf) -> Self {
+        self.root = Some(root);
+        self
+    }
+    pub fn with_cmdline(mut self, cmdline: String) -> Self {
+        self.cmdline = cmdline;
+        self
+    }
+    fn handle(&mut self, handle: u32) -> Result<&mut Handle, i32> {
+        self.handles.get_mut(handle as usize).and_then(|h| h.as_mut()).ok_or(EBADF)
+    }
+    // Parameter block of `count` words at `address`
+    fn block(mmu: &MMU, address: u32, count: u32) -> Result<Vec<u32>, i32> {
+        let bytes = read_guest(mmu, address, count * 4)?;
+        Ok(bytes.chunks_exact(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect())
+    }
+    // Results for failed operations are -1 unless operation returns count of bytes left
+    fn call(&mut self, mmu: &mut MMU, operation: u32, argument: u32) -> Result<u32, i32> {
+        let console = self.console;
+        match operation {
+            SYS_OPEN => {
+                let block = Self::block(mmu, argument, 3)?;
+                let name = read_string(mmu, block[0])?;
+                let mode = block[1];
+                let handle = if name == ":tt" {
+                    match mode {
+                        0..=3 => Handle::Stdin,
+                        4..=7 => Handle::Stdout,
+                        _ => Handle::Stderr,
+                    }
+                } else {
+                    // fopen modes "r", "r+", "w", "w+", "a", "a+", each also with "b"
+                    let path = sandboxed_path(self.root.as_deref(), &name)?;
+                    let (write, plus) = (mode >= 4, mode & 2 != 0);
+                    let file = OpenOptions::new()
+                        .read(mode < 4 || plus)
+                        .write(write || plus)
+                        .create(write)
+                        .truncate((4..8).contains(&mode))
+                        .append(mode >= 8)
+                        .open(path)
+                        .map_err(errno)?;
+                    Handle::File(file)
+                };
+                let index = match self.handles.iter().position(|h| h.is_none()) {
+                    Some(free) => free,
+                    None => {
+                        self.handles.push(None);
+                        self.handles.len() - 1
+                    }
+                };
+                self.handles[index] = Some(handle);
+                Ok(index as u32)
+            }
+            SYS_CLOSE => {
+                let handle = Self::block(mmu, argument, 1)?[0];
+                self.handle(handle)?;
+                self.handles[handle as usize] = None;
+                Ok(0)
+            }
+            SYS_WRITEC => {
+                let data = read_guest(mmu, argument, 1)?;
+                output(console, mmu, false, &data).map_err(errno)?;
+                Ok(0)
+            }
+            SYS_WRITE0 => {
+                let data = read_string(mmu, argument)?;
+                output(console, mmu, false, data.as_bytes()).map_err(errno)?;
+                Ok(0)
+            }
+            // Returns number of bytes that weren't written
+            SYS_WRITE => {
+                let block = Self::block(mmu, argument, 3)?;
+                let data = read_guest(mmu, block[1], block[2])?;
+                let result = match self.handle(block[0])? {
+                    Handle::Stdin => return Ok(block[2]),
+                    Handle::Stdout => output(console, mmu, false, &data),
+                    Handle::Stderr => output(console, mmu, true, &data),
+                    Handle::File(file) => file.write_all(&data),
+                };
+                result.map_err(errno)?;
+                Ok(0)
+            }
+            // Returns number of bytes that weren't read, all of them mean end of file
+            SYS_READ => {
+                let block = Self::block(mmu, argument, 3)?;
+                let handle = self.handle(block[0])?;
+                if !in_ram(block[1], block[2]) {
+                    return Err(EFAULT);
+                }
+                let mut data = vec![0; block[2] as usize];
+                let read = match (console, handle) {
+                    (Console::Uart, Handle::Stdin) => {
+                        let bytes = std::iter::from_fn(|| mmu.uart.emu_try_get_byte()).take(data.len());
+                        bytes.zip(data.iter_mut()).map(|(byte, slot)| *slot = byte).count()
+                    }
+                    (Console::Host, Handle::Stdin) => io::stdin().read(&mut data).map_err(errno)?,
+                    (_, Handle::File(file)) => file.read(&mut data).map_err(errno)?,
+                    _ => 0,
+                };
+                write_guest(mmu, block[1], &data[..read])?;
+                Ok(block[2] - read as u32)
+            }
+            SYS_ISTTY => {
+                let handle = Self::block(mmu, argument, 1)?[0];
+                Ok(!matches!(self.handle(handle)?, Handle::File(_)) as u32)
+            }
+            SYS_SEEK => {
+                let block = Self::block(mmu, argument, 2)?;
+                let Handle::File(file) = self.handle(block[0])? else {
+                    return Err(ESPIPE);
+                };
+                file.seek(SeekFrom::Start(block[1] as u64)).map_err(errno)?;
+                Ok(0)
+            }
+            SYS_FLEN => {
+                let handle = Self::block(mmu, argument, 1)?[0];
+                let Handle::File(file) = self.handle(handle)? else {
+                    return Err(ESPIPE);
+                };
+                Ok(file.metadata().map_err(errno)?.len() as u32)
+            }
+            // Centiseconds since start
+            SYS_CLOCK => {
+                let elapsed = mmu.clint.clock.since(self.start, mmu.clint.time());
+                Ok((elapsed.as_millis() / 10) as u32)
+            }
+            SYS_ERRNO => Ok(self.errno as u32),
+            // Buffer size in block is updated to length of command line without terminator
+            SYS_GET_CMDLINE => {
+                let block = Self::block(mmu, argument, 2)?;
+                let cmdline = [self.cmdline.as_bytes(), &[0]].concat();
+                if cmdline.len() > block[1] as usize {
+                    return Err(EINVAL);
+                }
+                write_guest(mmu, block[0], &cmdline)?;
+                write_guest(mmu, argument + 4, &(self.cmdline.len() as u32).to_le_bytes())?;
+                Ok(0)
+            }
+            // On RV32 argument is reason itself, so only success and failure can be told apart
+            SYS_EXIT => {
+                self.exit_code = Some((argument != ADP_STOPPED_APPLICATION_EXIT) as u32);
+                Ok(0)
+            }
+            SYS_EXIT_EXTENDED => {
+                let block = Self::block(mmu, argument, 2)?;
+                self.exit_code = Some(if block[0] == ADP_STOPPED_APPLICATION_EXIT { block[1] } else { 1 });
+                Ok(0)
+            }
+            _ => Err(ENOSYS),
+        }
+    }
+}
+
+fn output(console: Console, mmu: &mut MMU, stderr: bool, data: &[u8]) -> io::Result<()> {
+    match console {
+        Console::Uart => {
+            data.iter().for_each(|b| mmu.uart.emu_push(*b));
+            Ok(())
+        }
+        Console::Host if stderr => io::stderr().write_all(data),
+        Console::Host => io::stdout().write_all(data),
+    }
+}
+
+// ebreak at pc is semihosting call if it is surrounded by marker instructions
+pub fn is_call(mmu: &MMU, pc: u32) -> bool {
+    read_instruction(mmu, pc.wrapping_sub(4)) == Some(SEMIHOSTING_ENTRY)
+        && read_instruction(mmu, pc.wrapping_add(4)) == Some(SEMIHOSTING_EXIT)
+}
+
+pub fn handle_call(cpu: &mut CPU) {
+    let mut regs = cpu.get_registers();
+    let mut semihosting = cpu.mmu.semihosting.take().unwrap();
+    cpu.sync_bus_time();
+    regs[A0] = semihosting.call(&mut cpu.mmu, regs[A0], regs[A1]).unwrap_or_else(|errno| {
+        semihosting.errno = errno;
+        u32::MAX
+    });
+    cpu.stopped = semihosting.exit_code.is_some();
+    cpu.mmu.semihosting = Some(semihosting);
+    cpu.set_registers(regs);
+}
+
+impl Emulator {
+    pub fn with_semihosting(mut self, semihosting: Semihosting) -> Self {
+        self.harts[0].mmu.semihosting = Some(semihosting);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        cpu::{PrivilegeMode, EXECUTION_MODES},
+        emulator::{Emulator, StopReason},
+        errors::EmulatorError,
+        mmu::RAM_ADDRESS,
+    };
+
+    use super::{Semihosting, SYS_CLOCK, SYS_EXIT_EXTENDED, SYS_GET_CMDLINE, SYS_OPEN, SYS_WRITE, SYS_WRITE0, SYS_WRITEC};
+
+    fn call(asm: &mut Assembler, operation: u32) -> &mut Assembler {
+        asm.li(A0, operation).slli(ZERO, ZERO, 0x1f).ebreak().srai(ZERO, ZERO, 7)
+    }
+
+    #[test]
+    fn test_operations() {
+        // Parameter blocks and buffers are at `data`
+        let data = RAM_ADDRESS + 0x1000;
+        let mut asm = Assembler::new();
+        asm.li(S0, data);
+        asm.la(A1, "message");
+        call(&mut asm, SYS_WRITE0);
+        asm.la(A1, "message");
+        call(&mut asm, SYS_WRITEC);
+        asm.la(T0, "tt").sw(T0, 0, S0).li(T0, 4).sw(T0, 4, S0).li(T0, 3).sw(T0, 8, S0).mv(A1, S0);
+        call(&mut asm, SYS_OPEN).mv(S1, A0);
+        asm.sw(S1, 0, S0).la(T0, "message").sw(T0, 4, S0).li(T0, 2).sw(T0, 8, S0).mv(A1, S0);
+        call(&mut asm, SYS_WRITE).mv(S2, A0);
+        asm.addi(T0, S0, 64).sw(T0, 0, S0).li(T0, 32).sw(T0, 4, S0).mv(A1, S0);
+        call(&mut asm, SYS_GET_CMDLINE).lw(S3, 4, S0);
+        call(&mut asm, SYS_CLOCK).mv(S4, A0);
+        asm.li(T0, 0x20026).sw(T0, 0, S0).li(T0, 7).sw(T0, 4, S0).mv(A1, S0);
+        call(&mut asm, SYS_EXIT_EXTENDED);
+        asm.label("loop").j("loop");
+        asm.label("message").instr(u32::from_le_bytes(*b"ok\n\0"));
+        asm.label("tt").instr(u32::from_le_bytes(*b":tt\0"));
+
+        for &mode in EXECUTION_MODES {
+            let mut cpu = asm.cpu();
+            cpu.execution_mode = mode;
+            let semihosting = Semihosting::new().with_cmdline("test --quiet".to_string());
+            let mut emu = Emulator::new(cpu).with_semihosting(semihosting);
+            assert_eq!(emu.run(1000), StopReason::PowerOff(7), "{mode:?}");
+            let output: Vec<u8> = std::iter::from_fn(|| emu.harts[0].mmu.uart.try_get_byte()).collect();
+            assert_eq!(output, b"ok\nook", "{mode:?}");
+            let regs = emu.harts[0].get_registers();
+            assert_eq!(regs[S1 as usize], 0);
+            assert_eq!(regs[S2 as usize], 0);
+            assert_eq!(regs[S3 as usize], 12);
+            assert!(regs[S4 as usize] < 100);
+            let cmdline: Vec<u8> = (0..13).map(|i| emu.harts[0].mmu.read_raw_from_ram(data + 64 + i).unwrap()).collect();
+            assert_eq!(cmdline, b"test --quiet\0");
+        }
+    }
+
+    #[test]
+    fn test_plain_ebreak() {
+        let mut asm = Assembler::new();
+        asm.li(A0, SYS_WRITE0).ebreak().srai(ZERO, ZERO, 7);
+        let mut emu = asm.emulator().with_semihosting(Semihosting::new());
+        // Without marker before it ebreak traps to unset handler
+        assert_eq!(emu.run(100), StopReason::Fatal(EmulatorError::UnsetTrapHandler));
+        assert_eq!(emu.harts[0].pc, RAM_ADDRESS + 4);
+    }
+    #[test]
+    fn test_machine_mode_only() {
+        let mut asm = Assembler::new();
+        asm.la(A1, "message");
+        call(&mut asm, SYS_WRITE0);
+        asm.label("message").instr(u32::from_le_bytes(*b"ok\n\0"));
+        for mode in [PrivilegeMode::User, PrivilegeMode::Supervisor] {
+            let mut cpu = asm.cpu();
+            cpu.privilege = mode;
+            cpu.pmp.allow_all();
+            let mut emu = Emulator::new(cpu).with_semihosting(Semihosting::new());
+            // Marked ebreak is plain breakpoint outside of M mode
+            assert_eq!(emu.run(100), StopReason::Fatal(EmulatorError::UnsetTrapHandler), "{mode:?}");
+            assert_eq!(emu.harts[0].mmu.uart.try_get_byte(), None, "{mode:?}");
+        }
+    }
+}
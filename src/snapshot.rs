@@ -0,0 +1,353 @@
+// Save states of whole machine. File is header (magic, version) followed by sections in
+// fixed order, all integers little-endian. Caches of decoded or translated code aren't saved,
+// they are rebuilt after restore.
+
+use std::{fs, io, mem, path::Path};
+
+use crate::{
+    cpu::{PrivilegeMode, CPU},
+    emulator::Emulator,
+    mmu::MMU,
+    primitive_audio::NullSink,
+};
+
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"RVEMUSNP";
+// Bumped when layout changes, older files are rejected
+pub const SNAPSHOT_VERSION: u32 = 5;
+// Sanity limit for hart count read from file
+pub const MAX_HARTS: usize = 1024;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    // File ended early or has invalid value
+    Corrupt,
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(value: io::Error) -> Self {
+        SnapshotError::Io(value)
+    }
+}
+
+#[derive(Default)]
+pub struct SnapshotWriter {
+    pub data: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn option_u64(&mut self, value: Option<u64>) {
+        self.bool(value.is_some());
+        self.u64(value.unwrap_or(0));
+    }
+    // Length prefixed
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        SnapshotReader { data }
+    }
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.data.len() < len {
+            return Err(SnapshotError::Corrupt);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Corrupt),
+        }
+    }
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub fn option_u64(&mut self) -> Result<Option<u64>, SnapshotError> {
+        let some = self.bool()?;
+        let value = self.u64()?;
+        Ok(some.then_some(value))
+    }
+    pub fn bytes(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let len = self.u64()?;
+        self.take(usize::try_from(len).map_err(|_| SnapshotError::Corrupt)?)
+    }
+}
+
+fn save_hart(w: &mut SnapshotWriter, cpu: &CPU) {
+    for x in cpu.get_registers() {
+        w.u32(x);
+    }
+    w.u32(cpu.pc);
+    w.u8(cpu.privilege as u8);
+    w.bool(cpu.wfi);
+    w.bool(cpu.stopped);
+    w.u64(cpu.mstatus);
+    for csr in [
+        cpu.mscratch,
+        cpu.mtvec,
+        cpu.mie,
+        cpu.mip,
+        cpu.mepc,
+        cpu.mtval,
+        cpu.mcause,
+        cpu.satp,
+        cpu.medeleg,
+        cpu.mideleg,
+        cpu.sscratch,
+        cpu.stvec,
+        cpu.sepc,
+        cpu.stval,
+        cpu.scause,
+    ] {
+        w.u32(csr);
+    }
+    w.u64(cpu.instret);
+    cpu.counters.save(w);
+    cpu.pmp.save(w);
+}
+
+fn restore_hart(r: &mut SnapshotReader, cpu: &mut CPU) -> Result<(), SnapshotError> {
+    let mut regs = [0; 32];
+    for x in &mut regs {
+        *x = r.u32()?;
+    }
+    cpu.set_registers(regs);
+    cpu.pc = r.u32()?;
+    cpu.privilege = PrivilegeMode::from_bits(r.u8()?);
+    cpu.wfi = r.bool()?;
+    cpu.stopped = r.bool()?;
+    cpu.mstatus = r.u64()?;
+    for csr in [
+        &mut cpu.mscratch,
+        &mut cpu.mtvec,
+        &mut cpu.mie,
+        &mut cpu.mip,
+        &mut cpu.mepc,
+        &mut cpu.mtval,
+        &mut cpu.mcause,
+        &mut cpu.satp,
+        &mut cpu.medeleg,
+        &mut cpu.mideleg,
+        &mut cpu.sscratch,
+        &mut cpu.stvec,
+        &mut cpu.sepc,
+        &mut cpu.stval,
+        &mut cpu.scause,
+    ] {
+        *csr = r.u32()?;
+    }
+    cpu.instret = r.u64()?;
+    cpu.counters.restore(r)?;
+    cpu.pmp.restore(r)?;
+    cpu.next_timer_check = 0;
+    Ok(())
+}
+
+// Harts first, then shared bus
+pub fn save(emu: &Emulator) -> Vec<u8> {
+    let mut w = SnapshotWriter::default();
+    w.data.extend_from_slice(SNAPSHOT_MAGIC);
+    w.u32(SNAPSHOT_VERSION);
+    w.u64(emu.executed);
+    w.u64(emu.quantum);
+    w.u32(emu.current as u32);
+    w.u32(emu.harts.len() as u32);
+    for hart in &emu.harts {
+        save_hart(&mut w, hart);
+    }
+    emu.harts[0].mmu.save(&mut w);
+    w.data
+}
+
+// Restores state saved by `save` into existing emulator, which keeps its host connections
+// (audio output, stop flag, tracer, syscall layers, CSR table), breakpoints and execution
+// mode. Hart count follows the snapshot. Machine is built anew and replaces the old one only
+// when whole snapshot is valid, so emulator is unchanged after error.
+pub fn restore(emu: &mut Emulator, data: &[u8]) -> Result<(), SnapshotError> {
+    let (mut mmu, _audio) = MMU::new();
+    // Sink restores its fill level in place, it is given back if snapshot is rejected
+    mmu.audio = mem::replace(&mut emu.harts[0].mmu.audio, Box::new(NullSink));
+    let mut fresh = Emulator::new(CPU::new(mmu));
+    fresh.harts[0].execution_mode = emu.harts[0].execution_mode;
+    if let Err(e) = parse(&mut fresh, data) {
+        emu.harts[0].mmu.audio = mem::replace(&mut fresh.harts[0].mmu.audio, Box::new(NullSink));
+        return Err(e);
+    }
+    let (old, new) = (&mut emu.harts[0].mmu, &mut fresh.harts[0].mmu);
+    new.linux_user = old.linux_user.take();
+    new.newlib = old.newlib.take();
+    new.semihosting = old.semihosting.take();
+    for (new, old) in fresh.harts.iter_mut().zip(&mut emu.harts) {
+        new.stopflag = old.stopflag.take();
+        new.tracer = old.tracer.take();
+        new.csrs = mem::take(&mut old.csrs);
+        new.execution_mode = old.execution_mode;
+    }
+    fresh.breakpoints = mem::take(&mut emu.breakpoints);
+    *emu = fresh;
+    Ok(())
+}
+
+fn parse(emu: &mut Emulator, data: &[u8]) -> Result<(), SnapshotError> {
+    let mut r = SnapshotReader::new(data);
+    if r.take(SNAPSHOT_MAGIC.len()).ok() != Some(SNAPSHOT_MAGIC.as_slice()) {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = r.u32()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    emu.executed = r.u64()?;
+    emu.quantum = r.u64()?.max(1);
+    let current = r.u32()? as usize;
+    let harts = r.u32()? as usize;
+    if current >= harts || harts > MAX_HARTS {
+        return Err(SnapshotError::Corrupt);
+    }
+    emu.set_harts(harts);
+    emu.current = current;
+    for hart in &mut emu.harts {
+        restore_hart(&mut r, hart)?;
+    }
+    let bus = &mut emu.harts[0];
+    bus.mmu.restore(&mut r)?;
+    if bus.mmu.clint.msip.len() != harts {
+        return Err(SnapshotError::Corrupt);
+    }
+    // Code in RAM changed under caches
+    bus.decode_cache.clear();
+    if !r.data.is_empty() {
+        return Err(SnapshotError::Corrupt);
+    }
+    Ok(())
+}
+
+impl Emulator {
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        fs::write(path, save(self))?;
+        Ok(())
+    }
+    pub fn load_snapshot(&mut self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        restore(self, &fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        clock::Clock,
+        cpu::EXECUTION_MODES,
+        emulator::{Emulator, StopReason},
+        htif::Htif,
+        mmu::RAM_ADDRESS,
+    };
+
+    use super::{restore, save, SnapshotError};
+
+    fn emulator() -> Emulator {
+        let mut cpu = Assembler::new().cpu();
+        cpu.mmu.clint.clock = Clock::virtual_time(10);
+        Emulator::new(cpu)
+    }
+
+    #[test]
+    fn test_resume_from_snapshot() {
+        // Prints counter to UART, writes it to memory and counts hpm events
+        let mut asm = Assembler::new();
+        asm.li(T0, 1).csrw(0x323, T0).li(A1, 0x80100000).li(A2, 0x10000000);
+        asm.label("loop").addi(A0, A0, 1).sw(A0, 0, A1).addi(A1, A1, 4).sb(A0, 0, A2);
+        asm.lw(T1, -4, A1).j("loop");
+        for &mode in EXECUTION_MODES {
+            let mut original = emulator();
+            asm.load(&mut original.harts[0].mmu, RAM_ADDRESS);
+            original.harts[0].mmu.htif = Some(Htif::new(0x80200000, None));
+            original.harts[0].execution_mode = mode;
+            assert_eq!(original.run(1000), StopReason::BudgetExhausted);
+            let data = save(&original);
+
+            let mut restored = emulator();
+            restored.harts[0].execution_mode = mode;
+            restore(&mut restored, &data).unwrap();
+            assert_eq!(save(&restored), data);
+            for emu in [&mut original, &mut restored] {
+                assert_eq!(emu.run(5000), StopReason::BudgetExhausted);
+            }
+            assert_eq!(original.harts[0].get_registers(), restored.harts[0].get_registers());
+            assert_eq!(original.harts[0].read_csr(0xC03), restored.harts[0].read_csr(0xC03));
+            assert_eq!(original.harts[0].read_csr(0xC01), restored.harts[0].read_csr(0xC01));
+            assert_eq!(save(&original), save(&restored), "{mode:?}");
+            let restored_uart: Vec<_> = std::iter::from_fn(|| restored.harts[0].mmu.uart.try_get_byte()).collect();
+            let original_uart: Vec<_> = std::iter::from_fn(|| original.harts[0].mmu.uart.try_get_byte()).collect();
+            assert_eq!(restored_uart, original_uart);
+            assert!(restored_uart.len() > 900);
+        }
+    }
+
+    #[test]
+    fn test_smp_snapshot() {
+        let mut asm = Assembler::new();
+        asm.csrr(A0, 0xF14).slli(A0, A0, 2).li(A1, 0x80100000).add(A1, A1, A0);
+        asm.label("loop").lr_w(T0, A1).addi(T0, T0, 1).sc_w(T1, T0, A1).j("loop");
+        let mut original = emulator().with_harts(3).with_quantum(5);
+        asm.load(&mut original.harts[0].mmu, RAM_ADDRESS);
+        original.run(1003);
+        let data = save(&original);
+        let mut restored = emulator();
+        restore(&mut restored, &data).unwrap();
+        assert_eq!(restored.harts.len(), 3);
+        for emu in [&mut original, &mut restored] {
+            emu.run(1000);
+        }
+        assert_eq!(save(&original), save(&restored));
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let mut emu = emulator();
+        Assembler::new().li(A0, 5).sw(A0, 0x100, A0).load(&mut emu.harts[0].mmu, RAM_ADDRESS);
+        emu.run(2);
+        let before = save(&emu);
+        // Valid until its last byte, harts and RAM are parsed before error
+        let mut other = emulator().with_harts(2);
+        other.harts[1].pc = 0x1234;
+        let mut data = save(&other);
+        assert!(matches!(restore(&mut emu, &data[..data.len() - 1]), Err(SnapshotError::Corrupt)));
+        data[8] = 99;
+        assert!(matches!(restore(&mut emu, &data), Err(SnapshotError::UnsupportedVersion(99))));
+        assert!(matches!(restore(&mut emu, b"garbage"), Err(SnapshotError::BadMagic)));
+        assert_eq!(emu.harts.len(), 1);
+        assert_eq!(save(&emu), before);
+    }
+}
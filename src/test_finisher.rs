@@ -0,0 +1,126 @@
+// SiFive test finisher like in QEMU virt machine. Guest writes status word to it to end
+// simulation: 0x5555 is pass, 0x3333 | code << 16 is failure with code and 0x7777 is reset.
+// Unlike faulting on purpose, this can't be mistaken for real fault.
+
+use crate::{
+    mmu::MMU,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+};
+
+pub const TEST_FINISHER_ADDRESS: u32 = 0x10_0000;
+pub const TEST_FINISHER_SIZE: u32 = 0x1000;
+pub const TEST_FINISHER_ADDRESS_END: u32 = TEST_FINISHER_ADDRESS + TEST_FINISHER_SIZE - 1;
+
+pub const FINISHER_FAIL: u32 = 0x3333;
+pub const FINISHER_PASS: u32 = 0x5555;
+pub const FINISHER_RESET: u32 = 0x7777;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FinisherRequest {
+    Exit(u32),
+    Reset,
+}
+
+impl FinisherRequest {
+    // Other values are ignored
+    pub fn decode(value: u32) -> Option<Self> {
+        match value & 0xFFFF {
+            FINISHER_PASS => Some(FinisherRequest::Exit(0)),
+            // Failure without code still has to be failure
+            FINISHER_FAIL => Some(FinisherRequest::Exit((value >> 16).max(1))),
+            FINISHER_RESET => Some(FinisherRequest::Reset),
+            _ => None,
+        }
+    }
+    pub fn save(request: Option<Self>, w: &mut SnapshotWriter) {
+        w.option_u64(request.map(|r| match r {
+            FinisherRequest::Exit(code) => code as u64,
+            FinisherRequest::Reset => u64::MAX,
+        }));
+    }
+    pub fn restore(r: &mut SnapshotReader) -> Result<Option<Self>, SnapshotError> {
+        Ok(r.option_u64()?.map(|value| match value {
+            u64::MAX => FinisherRequest::Reset,
+            code => FinisherRequest::Exit(code as u32),
+        }))
+    }
+}
+
+// Word access only, like other registers it reads as zero
+pub fn read(address: u32) -> Option<u32> {
+    (address == TEST_FINISHER_ADDRESS).then_some(0)
+}
+
+// Hart that finished stops right away, so nothing after store runs
+pub fn write(mmu: &mut MMU, address: u32, value: u32) -> Option<()> {
+    if address != TEST_FINISHER_ADDRESS {
+        return None;
+    }
+    if let Some(request) = FinisherRequest::decode(value) {
+        mmu.finisher = Some(request);
+        mmu.stop_requested = true;
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::*,
+        cpu::EXECUTION_MODES,
+        emulator::{Emulator, StopReason},
+    };
+
+    use super::{FinisherRequest, TEST_FINISHER_ADDRESS};
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(FinisherRequest::decode(0x5555), Some(FinisherRequest::Exit(0)));
+        assert_eq!(FinisherRequest::decode(0x2A3333), Some(FinisherRequest::Exit(42)));
+        assert_eq!(FinisherRequest::decode(0x3333), Some(FinisherRequest::Exit(1)));
+        assert_eq!(FinisherRequest::decode(0x7777), Some(FinisherRequest::Reset));
+        assert_eq!(FinisherRequest::decode(0x1234), None);
+    }
+
+    #[test]
+    fn test_stop_reasons() {
+        for (value, reason) in [
+            (0x5555, StopReason::PowerOff(0)),
+            (0x33333, StopReason::PowerOff(3)),
+            (0x7777, StopReason::Reset),
+        ] {
+            for &mode in EXECUTION_MODES {
+                let mut asm = Assembler::new();
+                asm.li(A0, TEST_FINISHER_ADDRESS).li(A1, 0x1234).sw(A1, 0, A0);
+                asm.li(A1, value).sw(A1, 0, A0).li(A2, 1).label("loop").j("loop");
+                let mut cpu = asm.cpu();
+                cpu.execution_mode = mode;
+                let mut emu = Emulator::new(cpu);
+                assert_eq!(emu.run(100_000), reason, "{mode:?}");
+                // Store is last executed instruction
+                assert_eq!(emu.harts[0].get_registers()[A2 as usize], 0, "{mode:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_bus_access() {
+        let mut asm = Assembler::new();
+        asm.la(T0, "handler").csrw(0x305, T0).li(T1, TEST_FINISHER_ADDRESS).li(A0, 1).lw(A0, 0, T1);
+        asm.li(T0, 0x5555).sb(T0, 0, T1).sh(T0, 0, T1).lw(A1, 4, T1).amoswap_w(A1, T0, T1);
+        asm.li(A2, 1).label("loop").j("loop");
+        asm.skip_faults_handler("handler");
+        for &mode in EXECUTION_MODES {
+            let mut cpu = asm.cpu();
+            cpu.execution_mode = mode;
+            let mut emu = Emulator::new(cpu);
+            // Only word accesses at status register reach finisher, AMO included
+            assert_eq!(emu.run(100_000), StopReason::PowerOff(0), "{mode:?}");
+            let regs = emu.harts[0].get_registers();
+            assert_eq!(regs[A0 as usize], 0, "{mode:?}");
+            assert_eq!(regs[A1 as usize], 0, "{mode:?}");
+            assert_eq!(regs[A2 as usize], 0, "{mode:?}");
+            assert_eq!(regs[S11 as usize], 3, "{mode:?}");
+        }
+    }
+}
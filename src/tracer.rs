@@ -0,0 +1,320 @@
+use std::{fmt::Display, io::Write, ops::Range};
+
+use crate::{
+    cpu::PrivilegeMode,
+    disassembler::{csr_name, disassemble, instruction_length},
+    traps::{Trap, TrapType},
+};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MemoryWrite {
+    pub address: u32,
+    pub value: u32,
+    // In bytes
+    pub size: u8,
+}
+
+// Everything retired instruction changed, in the same terms as Spike's commit log.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CommitRecord {
+    pub hart: u32,
+    pub privilege: PrivilegeMode,
+    pub pc: u32,
+    pub instr: u32,
+    pub reg_write: Option<(u8, u32)>,
+    pub csr_writes: Vec<(u16, u32)>,
+    pub loads: Vec<u32>,
+    pub stores: Vec<MemoryWrite>,
+}
+
+impl CommitRecord {
+    pub fn new(hart: u32, privilege: PrivilegeMode, pc: u32, instr: u32) -> Self {
+        CommitRecord {
+            hart,
+            privilege,
+            pc,
+            instr,
+            reg_write: None,
+            csr_writes: vec![],
+            loads: vec![],
+            stores: vec![],
+        }
+    }
+}
+
+fn write_value(f: &mut std::fmt::Formatter<'_>, bits: u32, value: u32) -> std::fmt::Result {
+    match bits {
+        8 => write!(f, "0x{value:02x}"),
+        16 => write!(f, "0x{value:04x}"),
+        _ => write!(f, "0x{value:08x}"),
+    }
+}
+
+// Formats record as a `spike --log-commits` line.
+impl Display for CommitRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "core{:>4}: {} ", self.hart, self.privilege as u8)?;
+        write_value(f, 32, self.pc)?;
+        write!(f, " (")?;
+        write_value(f, instruction_length(self.instr) * 8, self.instr)?;
+        write!(f, ")")?;
+        if let Some((rd, value)) = self.reg_write {
+            write!(f, " x{rd:<2} ")?;
+            write_value(f, 32, value)?;
+        }
+        for (csr, value) in &self.csr_writes {
+            write!(f, " c{csr}_{} ", csr_name(*csr))?;
+            write_value(f, 32, *value)?;
+        }
+        for address in &self.loads {
+            write!(f, " mem ")?;
+            write_value(f, 32, *address)?;
+        }
+        for store in &self.stores {
+            write!(f, " mem ")?;
+            write_value(f, 32, store.address)?;
+            write!(f, " ")?;
+            write_value(f, store.size as u32 * 8, store.value)?;
+        }
+        Ok(())
+    }
+}
+
+fn spike_trap_name(trap: TrapType) -> &'static str {
+    match trap {
+        TrapType::InstructionAddressMisaligned => "trap_instruction_address_misaligned",
+        TrapType::InstructionAccessFault => "trap_instruction_access_fault",
+        TrapType::IllegalInstruction => "trap_illegal_instruction",
+        TrapType::Breakpoint => "trap_breakpoint",
+        TrapType::LoadAddressMisaligned => "trap_load_address_misaligned",
+        TrapType::LoadAccessFault => "trap_load_access_fault",
+        TrapType::StoreAddressMisaligned => "trap_store_address_misaligned",
+        TrapType::StoreAccessFault => "trap_store_access_fault",
+        TrapType::EnvironmentCallFromUMode => "trap_user_ecall",
+        TrapType::EnvironmentCallFromSMode => "trap_supervisor_ecall",
+        TrapType::EnvironmentCallFromMMode => "trap_machine_ecall",
+        TrapType::InstructionPageFault => "trap_instruction_page_fault",
+        TrapType::LoadPageFault => "trap_load_page_fault",
+        TrapType::StorePageFault => "trap_store_page_fault",
+        _ => "interrupt",
+    }
+}
+
+// Writes retired instructions in `spike -l --log-commits` format.
+pub struct Tracer {
+    output: Box<dyn Write>,
+    address_range: Option<Range<u32>>,
+    instruction_window: Option<Range<u64>>,
+    disassembly: bool,
+    retired: u64,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>) -> Self {
+        Tracer {
+            output,
+            address_range: None,
+            instruction_window: None,
+            disassembly: true,
+            retired: 0,
+        }
+    }
+    // Only instructions with pc inside of range are logged.
+    pub fn with_address_range(mut self, range: Range<u32>) -> Self {
+        self.address_range = Some(range);
+        self
+    }
+    // Only instructions retired in window are logged, counting from 0.
+    pub fn with_instruction_window(mut self, window: Range<u64>) -> Self {
+        self.instruction_window = Some(window);
+        self
+    }
+    // Disassembly is emitted as separate line before commit line, like `spike -l` does.
+    pub fn with_disassembly(mut self, disassembly: bool) -> Self {
+        self.disassembly = disassembly;
+        self
+    }
+    pub fn retired(&self) -> u64 {
+        self.retired
+    }
+    fn is_logged(&self, pc: u32) -> bool {
+        self.address_range.as_ref().is_none_or(|r| r.contains(&pc))
+            && self
+                .instruction_window
+                .as_ref()
+                .is_none_or(|w| w.contains(&self.retired))
+    }
+    fn log_instruction(&mut self, hart: u32, pc: u32, instr: u32) {
+        if self.disassembly {
+            let bits = instruction_length(instr) * 8;
+            let raw = if bits == 16 {
+                format!("0x{:04x}", instr as u16)
+            } else {
+                format!("0x{instr:08x}")
+            };
+            let _ = writeln!(
+                self.output,
+                "core{hart:>4}: 0x{pc:08x} ({raw}) {}",
+                disassemble(instr)
+            );
+        }
+    }
+    pub fn commit(&mut self, record: &CommitRecord) {
+        if self.is_logged(record.pc) {
+            self.log_instruction(record.hart, record.pc, record.instr);
+            let _ = writeln!(self.output, "{record}");
+        }
+        self.retired += 1;
+    }
+    // Instruction at pc raised exception instead of retiring.
+    pub fn exception(&mut self, hart: u32, pc: u32, instr: Option<u32>, trap: &Trap) {
+        if !self.is_logged(pc) {
+            return;
+        }
+        if let Some(instr) = instr {
+            self.log_instruction(hart, pc, instr);
+        }
+        let _ = writeln!(
+            self.output,
+            "core{hart:>4}: exception {}, epc 0x{pc:08x}",
+            spike_trap_name(trap.tcause)
+        );
+        if trap.has_tval() {
+            let _ = writeln!(self.output, "core{hart:>4}:           tval 0x{:08x}", trap.tval);
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let _ = self.output.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io::Write, rc::Rc};
+
+    use crate::{
+        assembler::{Assembler, A0},
+        cpu::PrivilegeMode,
+    };
+
+    use super::{CommitRecord, MemoryWrite, Tracer};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run_traced(program: &[u32], tracer: impl FnOnce(Tracer) -> Tracer) -> String {
+        let mut asm = Assembler::new();
+        for &instr in program {
+            asm.instr(instr);
+        }
+        let mut cpu = asm.cpu();
+        let buffer = SharedBuffer::default();
+        cpu.tracer = Some(tracer(Tracer::new(Box::new(buffer.clone()))));
+        while cpu.execute_instruction().is_ok() {}
+        drop(cpu);
+        let out = buffer.0.borrow();
+        String::from_utf8(out.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_commit_format() {
+        let mut record = CommitRecord::new(0, PrivilegeMode::Machine, 0x80000000, 0x00000297);
+        record.reg_write = Some((5, 0x80000000));
+        assert_eq!(record.to_string(), "core   0: 3 0x80000000 (0x00000297) x5  0x80000000");
+
+        let mut record = CommitRecord::new(0, PrivilegeMode::User, 0x80000004, 0x4501);
+        record.reg_write = Some((10, 0));
+        assert_eq!(record.to_string(), "core   0: 0 0x80000004 (0x4501) x10 0x00000000");
+
+        let mut record = CommitRecord::new(0, PrivilegeMode::Machine, 0x80000008, 0x00a581a3);
+        record.stores.push(MemoryWrite { address: 0x80001003, value: 5, size: 1 });
+        assert_eq!(record.to_string(), "core   0: 3 0x80000008 (0x00a581a3) mem 0x80001003 0x05");
+    }
+
+    #[test]
+    fn test_trace_program() {
+        let program = [
+            0x00500513, // li a0, 5
+            0x800015b7, // lui a1, 0x80001
+            0x00a5a023, // sw a0, 0(a1)
+            0x0005a603, // lw a2, 0(a1)
+            0x34051073, // csrw mscratch, a0
+            0x00a581a3, // sb a0, 3(a1)
+            0x00000073, // ecall
+        ];
+        let trace = run_traced(&program, |t| t);
+        let expected = "\
+core   0: 0x80000000 (0x00500513) li      a0, 5
+core   0: 3 0x80000000 (0x00500513) x10 0x00000005
+core   0: 0x80000004 (0x800015b7) lui     a1, 0x80001
+core   0: 3 0x80000004 (0x800015b7) x11 0x80001000
+core   0: 0x80000008 (0x00a5a023) sw      a0, 0(a1)
+core   0: 3 0x80000008 (0x00a5a023) mem 0x80001000 0x00000005
+core   0: 0x8000000c (0x0005a603) lw      a2, 0(a1)
+core   0: 3 0x8000000c (0x0005a603) x12 0x00000005 mem 0x80001000
+core   0: 0x80000010 (0x34051073) csrw    mscratch, a0
+core   0: 3 0x80000010 (0x34051073) c832_mscratch 0x00000005
+core   0: 0x80000014 (0x00a581a3) sb      a0, 3(a1)
+core   0: 3 0x80000014 (0x00a581a3) mem 0x80001003 0x05
+core   0: 0x80000018 (0x00000073) ecall
+core   0: exception trap_machine_ecall, epc 0x80000018
+";
+        assert_eq!(trace, expected);
+    }
+
+    #[test]
+    fn test_hart_id() {
+        let mut cpu = Assembler::new().li(A0, 5).ecall().cpu();
+        cpu.hartid = 2;
+        let buffer = SharedBuffer::default();
+        cpu.tracer = Some(Tracer::new(Box::new(buffer.clone())).with_disassembly(false));
+        while cpu.execute_instruction().is_ok() {}
+        drop(cpu);
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(
+            trace,
+            "core   2: 3 0x80000000 (0x00500513) x10 0x00000005\n\
+             core   2: exception trap_machine_ecall, epc 0x80000004\n"
+        );
+    }
+
+    #[test]
+    fn test_trace_filters() {
+        let program = [0x00500513, 0x800015b7, 0x00a5a023, 0x00000073];
+        let trace = run_traced(&program, |t| {
+            t.with_disassembly(false).with_instruction_window(1..2)
+        });
+        assert_eq!(trace, "core   0: 3 0x80000004 (0x800015b7) x11 0x80001000\n");
+        let trace = run_traced(&program, |t| {
+            t.with_disassembly(false).with_address_range(0x80000008..0x80000010)
+        });
+        assert_eq!(
+            trace,
+            "core   0: 3 0x80000008 (0x00a5a023) mem 0x80001000 0x00000005\n\
+             core   0: exception trap_machine_ecall, epc 0x8000000c\n"
+        );
+    }
+
+    #[test]
+    fn test_fault_tval() {
+        // lw a0, 16(zero) from unmapped address
+        let trace = run_traced(&[0x01002503], |t| t.with_disassembly(false));
+        assert_eq!(
+            trace,
+            "core   0: exception trap_load_access_fault, epc 0x80000000\n\
+             core   0:           tval 0x00000010\n"
+        );
+    }
+}
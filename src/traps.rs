@@ -41,6 +41,14 @@ impl Trap {
     pub fn is_interupt(&self) -> bool {
         self.tcause as u32 & INTERRUPT_BIT != 0
     }
+    // Exceptions that write address or instruction to mtval, Spike traces tval only for them
+    pub fn has_tval(&self) -> bool {
+        !self.is_interupt()
+            && !matches!(
+                self.tcause,
+                TrapType::EnvironmentCallFromUMode | TrapType::EnvironmentCallFromSMode | TrapType::EnvironmentCallFromMMode
+            )
+    }
 }
 impl Display for Trap {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -1,10 +1,13 @@
 use std::collections::VecDeque;
 
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
 pub struct UART {
     from_emu_buffer: VecDeque<u8>,
 
     to_emu_buffer: VecDeque<u8>,
 }
+#[allow(clippy::new_without_default)]
 impl UART {
     pub fn new() -> Self {
         UART {
@@ -18,4 +21,20 @@ impl UART {
     pub fn emu_push(&mut self, byte: u8) {
         self.from_emu_buffer.push_back(byte)
     }
+    pub fn push_byte(&mut self, byte: u8) {
+        self.to_emu_buffer.push_back(byte)
+    }
+    pub fn emu_try_get_byte(&mut self) -> Option<u8> {
+        self.to_emu_buffer.pop_front()
+    }
+    // Bytes not yet taken by either side
+    pub fn save(&self, w: &mut SnapshotWriter) {
+        w.bytes(&self.from_emu_buffer.iter().copied().collect::<Vec<_>>());
+        w.bytes(&self.to_emu_buffer.iter().copied().collect::<Vec<_>>());
+    }
+    pub fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.from_emu_buffer = r.bytes()?.iter().copied().collect();
+        self.to_emu_buffer = r.bytes()?.iter().copied().collect();
+        Ok(())
+    }
 }
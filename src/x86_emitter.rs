@@ -0,0 +1,252 @@
+// Minimal x86-64 encoder for code generated by jit.
+// Generated functions use System V convention with only caller-saved registers:
+// rdi points to guest registers, rsi to JitContext, r8 holds RAM base and r9 code page bitmap.
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Reg {
+    Eax = 0,
+    Ecx = 1,
+    Edx = 2,
+}
+
+// Two operand ALU ops, value is opcode of `op r/m32, r32`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Alu {
+    Add = 0x01,
+    Or = 0x09,
+    And = 0x21,
+    Sub = 0x29,
+    Xor = 0x31,
+    Cmp = 0x39,
+}
+
+impl Alu {
+    // ModRM reg field of `op r/m32, imm32`
+    fn digit(self) -> u8 {
+        (self as u8) >> 3
+    }
+}
+
+// ModRM reg field of shift group
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+// Low nibble of jcc and setcc opcodes
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Cond {
+    Below = 0x2,
+    AboveOrEqual = 0x3,
+    Equal = 0x4,
+    NotEqual = 0x5,
+    Above = 0x7,
+    Less = 0xC,
+    GreaterOrEqual = 0xD,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Width {
+    Byte,
+    Half,
+    Word,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Label(usize);
+
+#[derive(Default)]
+pub struct Emitter {
+    pub code: Vec<u8>,
+    // Bound offset of every label
+    labels: Vec<Option<usize>>,
+    // (position of rel32, label)
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Emitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+    fn emit_u32(&mut self, value: u32) {
+        self.emit(&value.to_le_bytes());
+    }
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+    // Resolves jumps, panics on unbound label
+    pub fn finish(mut self) -> Vec<u8> {
+        for (position, label) in std::mem::take(&mut self.fixups) {
+            let target = self.labels[label.0].expect("unbound label");
+            let rel = target as i64 - (position as i64 + 4);
+            self.code[position..position + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        self.code
+    }
+    // mov r8, [rsi]; mov r9, [rsi + 8]
+    pub fn prologue(&mut self) {
+        self.emit(&[0x4C, 0x8B, 0x06, 0x4C, 0x8B, 0x4E, 0x08]);
+    }
+    // mov reg, [rdi + 4 * index], x0 is read as xor
+    pub fn load_guest(&mut self, reg: Reg, index: u8) {
+        if index == 0 {
+            self.alu(Alu::Xor, reg, reg);
+        } else {
+            self.emit(&[0x8B, 0x47 | (reg as u8) << 3, index * 4]);
+        }
+    }
+    // mov [rdi + 4 * index], reg, writes to x0 are dropped
+    pub fn store_guest(&mut self, index: u8, reg: Reg) {
+        if index != 0 {
+            self.emit(&[0x89, 0x47 | (reg as u8) << 3, index * 4]);
+        }
+    }
+    pub fn store_guest_imm(&mut self, index: u8, imm: u32) {
+        if index != 0 {
+            self.emit(&[0xC7, 0x47, index * 4]);
+            self.emit_u32(imm);
+        }
+    }
+    pub fn mov_imm(&mut self, reg: Reg, imm: u32) {
+        self.emit(&[0xB8 + reg as u8]);
+        self.emit_u32(imm);
+    }
+    pub fn mov(&mut self, dst: Reg, src: Reg) {
+        self.emit(&[0x89, 0xC0 | (src as u8) << 3 | dst as u8]);
+    }
+    pub fn alu(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.emit(&[op as u8, 0xC0 | (src as u8) << 3 | dst as u8]);
+    }
+    pub fn alu_imm(&mut self, op: Alu, dst: Reg, imm: u32) {
+        self.emit(&[0x81, 0xC0 | op.digit() << 3 | dst as u8]);
+        self.emit_u32(imm);
+    }
+    pub fn shift_imm(&mut self, op: Shift, dst: Reg, amount: u8) {
+        self.emit(&[0xC1, 0xC0 | (op as u8) << 3 | dst as u8, amount & 0x1F]);
+    }
+    // Shift by cl, hardware masks amount to 5 bits like RV32
+    pub fn shift_cl(&mut self, op: Shift, dst: Reg) {
+        self.emit(&[0xD3, 0xC0 | (op as u8) << 3 | dst as u8]);
+    }
+    // dst = low 32 bits of dst * src
+    pub fn imul(&mut self, dst: Reg, src: Reg) {
+        self.emit(&[0x0F, 0xAF, 0xC0 | (dst as u8) << 3 | src as u8]);
+    }
+    // edx:eax = eax * src
+    pub fn mul_wide(&mut self, signed: bool, src: Reg) {
+        let digit = if signed { 5 } else { 4 };
+        self.emit(&[0xF7, 0xC0 | digit << 3 | src as u8]);
+    }
+    // eax = flags satisfy cond ? 1 : 0
+    pub fn set_eax(&mut self, cond: Cond) {
+        self.emit(&[0x0F, 0x90 | cond as u8, 0xC0, 0x0F, 0xB6, 0xC0]);
+    }
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.emit(&[0x0F, 0x80 | cond as u8]);
+        self.fixups.push((self.code.len(), label));
+        self.emit_u32(0);
+    }
+    pub fn jmp(&mut self, label: Label) {
+        self.emit(&[0xE9]);
+        self.fixups.push((self.code.len(), label));
+        self.emit_u32(0);
+    }
+    // ecx = [r8 + rax], extended to 32 bits
+    pub fn load_ram(&mut self, width: Width, signed: bool) {
+        match (width, signed) {
+            (Width::Byte, false) => self.emit(&[0x41, 0x0F, 0xB6, 0x0C, 0x00]),
+            (Width::Byte, true) => self.emit(&[0x41, 0x0F, 0xBE, 0x0C, 0x00]),
+            (Width::Half, false) => self.emit(&[0x41, 0x0F, 0xB7, 0x0C, 0x00]),
+            (Width::Half, true) => self.emit(&[0x41, 0x0F, 0xBF, 0x0C, 0x00]),
+            (Width::Word, _) => self.emit(&[0x41, 0x8B, 0x0C, 0x00]),
+        }
+    }
+    // [r8 + rax] = ecx
+    pub fn store_ram(&mut self, width: Width) {
+        match width {
+            Width::Byte => self.emit(&[0x41, 0x88, 0x0C, 0x00]),
+            Width::Half => self.emit(&[0x66, 0x41, 0x89, 0x0C, 0x00]),
+            Width::Word => self.emit(&[0x41, 0x89, 0x0C, 0x00]),
+        }
+    }
+    // Carry = bit rdx of bitmap at r9
+    pub fn test_code_page(&mut self) {
+        self.emit(&[0x49, 0x0F, 0xA3, 0x11]);
+    }
+    // cmp edx, [rsi + offset]
+    pub fn cmp_edx_context(&mut self, offset: u8) {
+        self.emit(&[0x3B, 0x56, offset]);
+    }
+    // mov dword [rsi + offset], imm32
+    pub fn store_context_imm(&mut self, offset: u8, imm: u32) {
+        self.emit(&[0xC7, 0x46, offset]);
+        self.emit_u32(imm);
+    }
+    // mov [rsi + offset], reg
+    pub fn store_context(&mut self, offset: u8, reg: Reg) {
+        self.emit(&[0x89, 0x46 | (reg as u8) << 3, offset]);
+    }
+    // add qword [rsi + offset], imm8
+    pub fn add_context_u64(&mut self, offset: u8, imm: i8) {
+        self.emit(&[0x48, 0x83, 0x46, offset, imm as u8]);
+    }
+    pub fn ret(&mut self) {
+        self.emit(&[0xC3]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Alu, Cond, Emitter, Reg, Shift, Width};
+
+    // Expected bytes are from llvm-mc -triple=x86_64 -show-encoding
+    #[test]
+    fn test_encoding() {
+        let mut e = Emitter::new();
+        e.load_guest(Reg::Ecx, 31);
+        e.store_guest(2, Reg::Edx);
+        e.store_guest_imm(2, 0x12345678);
+        e.alu(Alu::Cmp, Reg::Eax, Reg::Ecx);
+        e.alu_imm(Alu::And, Reg::Eax, 0xFFFFFFFE);
+        e.shift_imm(Shift::Sar, Reg::Eax, 3);
+        e.shift_cl(Shift::Shl, Reg::Eax);
+        e.imul(Reg::Eax, Reg::Ecx);
+        e.mul_wide(true, Reg::Ecx);
+        e.set_eax(Cond::Less);
+        e.load_ram(Width::Half, true);
+        e.store_ram(Width::Half);
+        e.test_code_page();
+        e.cmp_edx_context(20);
+        e.add_context_u64(24, 5);
+        assert_eq!(
+            e.finish(),
+            [
+                0x8b, 0x4f, 0x7c, 0x89, 0x57, 0x08, 0xc7, 0x47, 0x08, 0x78, 0x56, 0x34, 0x12,
+                0x39, 0xc8, 0x81, 0xe0, 0xfe, 0xff, 0xff, 0xff, 0xc1, 0xf8, 0x03, 0xd3, 0xe0,
+                0x0f, 0xaf, 0xc1, 0xf7, 0xe9, 0x0f, 0x9c, 0xc0, 0x0f, 0xb6, 0xc0, 0x41, 0x0f,
+                0xbf, 0x0c, 0x00, 0x66, 0x41, 0x89, 0x0c, 0x00, 0x49, 0x0f, 0xa3, 0x11, 0x3b,
+                0x56, 0x14, 0x48, 0x83, 0x46, 0x18, 0x05,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jump_fixup() {
+        let mut e = Emitter::new();
+        let label = e.label();
+        e.jcc(Cond::Equal, label);
+        e.ret();
+        e.bind(label);
+        e.ret();
+        assert_eq!(e.finish(), [0x0f, 0x84, 0x01, 0x00, 0x00, 0x00, 0xc3, 0xc3]);
+    }
+}
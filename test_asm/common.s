@@ -11,9 +11,11 @@ sent_to_uart:
     addi sp, sp, 4; # sp = sp + 4
     ret
 
-stop_by_fault:
-    li a0, 0x0
-    lw x0, 0(a0)
+# Results are already in UART, test finisher ends simulation
+finish:
+    li a0, 0x100000
+    li a1, 0x5555
+    sw a1, 0(a0)
 
 .macro test_insert_nops amount
 .if \amount
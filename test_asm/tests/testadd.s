@@ -19,4 +19,4 @@ __start:
     TEST_RR_OP 14, add, 0x0000000000000000, 0xffffffffffffffff, 0x0000000000000001;
     TEST_RR_OP 15, add, 0xfffffffffffffffe, 0xffffffffffffffff, 0xffffffffffffffff;
     TEST_RR_OP 16, add, 0x0000000080000000, 0x0000000000000001, 0x000000007fffffff;
-    call stop_by_fault
+    call finish
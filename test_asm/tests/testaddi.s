@@ -18,4 +18,4 @@ __start:
     TEST_IMM_OP 14, addi, 0x0000000000000000, 0xffffffffffffffff, 0x001;
     TEST_IMM_OP 15, addi, 0xfffffffffffffffe, 0xffffffffffffffff, 0xfff;
     TEST_IMM_OP 16, addi, 0x0000000080000000, 0x7fffffff, 0x001;
-    call stop_by_fault
+    call finish
@@ -28,4 +28,4 @@ __start:
     TEST_RR_OP 19, sll, 0x0000001090909080, 0x0000000021212121, 0xffffffffffffffc7;
     TEST_RR_OP 20, sll, 0x0000084848484000, 0x0000000021212121, 0xffffffffffffffce;
 
-    call stop_by_fault
+    call finish